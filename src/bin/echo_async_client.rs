@@ -42,8 +42,8 @@ impl ServerHandler for MyHandler {
         info!("disconnected");
         server.shutdown();
     }
-    fn message(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, msg: Vec<u8>){
-        println!("got reply: {}", String::from_utf8(msg).unwrap());
+    fn message(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, msg: &[u8]){
+        println!("got reply: {}", String::from_utf8_lossy(msg));
         // testing channel notify
         let chan = server.notify_channel().clone();
         let uid = uid.clone();
@@ -26,7 +26,7 @@ impl ServerHandler for MyHandler {
         });
 
         // testing timeout
-        server.timeout_ms(String::from("async timeout!"), 2000u64).unwrap();
+        server.timeout_ms(String::from("async timeout!"), 2000u64).unwrap().detach();
     }
     fn connection(&mut self, _server: &mut ServerControl<Self>, uid: ConnectionUid) {
         self.connections.insert(uid);
@@ -36,10 +36,10 @@ impl ServerHandler for MyHandler {
         self.connections.remove(uid);
         info!("disconnect");
     }
-    fn message(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, msg: Vec<u8>){
+    fn message(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, msg: &[u8]){
         debug!("handler message called");
-        // println!("msg: {}", String::from_utf8(msg.clone()).unwrap());
-        server.send(uid, &msg[..]);
+        // println!("msg: {}", String::from_utf8_lossy(msg));
+        server.send(uid, msg);
     }
     fn notify(&mut self, _server: &mut ServerControl<Self>, msg: Self::Message) {
         info!("notify msg: {}", msg);
@@ -1,9 +1,5 @@
 extern crate time;
 extern crate rust_stuff;
-extern crate byteorder;
-
-use byteorder::BigEndian;
-use byteorder::ByteOrder;
 
 use rust_stuff::net::FramedTcpStream;
 use std::net::TcpStream;
@@ -18,10 +14,7 @@ fn main() {
     // msg.resize(SIZE, 1);
 
     let msg = "hello world! hello world! hello!".as_bytes();
-    let mut frame = Vec::with_capacity(4+msg.len());
-    unsafe { frame.set_len(4) };
-    BigEndian::write_u32(&mut frame[..4], msg.len() as u32);
-    frame.extend_from_slice(msg);
+    let frame = stream.preframe(msg).unwrap();
 
     println!("Sending messages of size {}", msg.len());
 
@@ -33,7 +26,7 @@ fn main() {
     loop {
         // send msg
         let sendtime = time::PreciseTime::now();
-        unsafe { stream.raw_write(&frame[..]).unwrap() };
+        stream.write_preframed(&frame).unwrap();
         stream.read_frame_into(&mut buf).unwrap();
         count += 1;
         let now = time::PreciseTime::now();
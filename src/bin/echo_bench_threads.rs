@@ -1,6 +1,5 @@
 extern crate time;
 extern crate rust_stuff;
-extern crate byteorder;
 extern crate bincode;
 
 use rust_stuff::net::FramedTcpStream;
@@ -9,7 +8,6 @@ use std::thread;
 use std::sync::{Arc, Mutex};
 use std::env;
 use std::ops::Add;
-use byteorder::{BigEndian,ByteOrder};
 
 const ADDR: &'static str = "127.0.0.1:10000";
 const SIZE: usize = 1024;
@@ -45,16 +43,13 @@ fn main() {
 
             let msg = "hello world! hello world! hello!".to_string();
             let msg = bincode::serialize(&msg).unwrap();
-            let mut frame = Vec::with_capacity(4+msg.len());
-            unsafe { frame.set_len(4) };
-            BigEndian::write_u32(&mut frame[..4], msg.len() as u32);
-            frame.extend_from_slice(&msg);
+            let frame = stream.preframe(&msg).unwrap();
 
             let mut buf: [u8; SIZE] = [0; SIZE];
             loop {
                 // send msg
                 let sendtime = time::PreciseTime::now();
-                unsafe { stream.raw_write(&frame[..]).unwrap(); }
+                stream.write_preframed(&frame).unwrap();
                 stream.read_frame_into(&mut buf).unwrap();
                 let mut c = counters.lock().unwrap();
                 let now = time::PreciseTime::now();
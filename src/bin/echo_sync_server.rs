@@ -13,7 +13,7 @@ fn main() {
         let evt = server.next().unwrap();
         //println!("{:?}", evt);
         if let Event::Recv(uid, data) = evt {
-            server.send_to(uid, data.as_slice()).is_ok();
+            server.send_to(uid, &data).is_ok();
         }
     }
 }
@@ -0,0 +1,70 @@
+extern crate time;
+extern crate rust_stuff;
+
+use rust_stuff::net::FramedUdpSocket;
+use std::env;
+use std::net::SocketAddr;
+
+const ADDR: &'static str = "127.0.0.1:10001";
+
+/// `echo_udp_bench server` echoes messages back, `echo_udp_bench`
+/// sends messages and measures round trips (like `echo_bench`).
+fn main() {
+    let addr = ADDR.parse::<SocketAddr>().unwrap();
+    if env::args().nth(1).map_or(false, |arg| arg == "server") {
+        server(addr);
+    } else {
+        client(addr);
+    }
+}
+
+fn server(addr: SocketAddr) {
+    let mut socket = FramedUdpSocket::bind(addr).unwrap();
+    socket.set_fragmentation(true);
+    println!("listening...");
+    loop {
+        if let Ok((msg, from)) = socket.recv_from() {
+            socket.send_to(&msg, from).is_ok();
+        }
+    }
+}
+
+fn client(addr: SocketAddr) {
+    let mut socket = FramedUdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_fragmentation(true);
+
+    let msg = "hello world! hello world! hello!".as_bytes();
+    println!("Sending messages of size {}", msg.len());
+
+    let mut count: u64 = 0;
+    let mut lat: u64 = 0;
+    let mut max_lat: u64 = 0;
+    let mut start = time::PreciseTime::now();
+    loop {
+        // send msg
+        let sendtime = time::PreciseTime::now();
+        socket.send_to(msg, addr).unwrap();
+        socket.recv_from().unwrap();
+        count += 1;
+        let now = time::PreciseTime::now();
+        let l = sendtime.to(now).num_microseconds().unwrap() as u64;
+        if lat == 0 {
+            lat = l;
+            max_lat = l;
+        } else {
+            lat += l;
+            lat = lat/2;
+            max_lat = if l > max_lat { l } else { max_lat }
+        }
+        let duration = start.to(now);
+        if duration.num_seconds() > 0 {
+            start = time::PreciseTime::now();
+            println!("tput: {} op/sec\tavg_lat: {} usec\tmax_lat: {}",
+                     (count*1000) as f64 / duration.num_milliseconds() as f64,
+                     lat,
+                     max_lat);
+            count = 0;
+            lat = 0;
+        }
+    }
+}
@@ -0,0 +1,196 @@
+/// Runs the same randomized workload against each tree (and the std
+/// maps) and prints one CSV line per structure:
+///
+///     tree_bench [--size N] [--ops N] [--dist uniform|sequential|skewed]
+///                [--reads PCT] [--seed N]
+///
+/// The tree is first filled with `size` entries, then `ops` operations
+/// are run: `reads`% are lookups and the rest are split between
+/// inserts and removes. One operation in `SAMPLE` is timed on its own
+/// for the latency percentiles, so the cost of reading the clock
+/// barely weighs on the throughput.
+extern crate rust_stuff;
+extern crate rand;
+extern crate time;
+
+use rust_stuff::{BTree, RBTree};
+use rust_stuff::rbtree::arena;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use rand::{Rng, SeedableRng, StdRng};
+
+trait Map {
+    fn insert(&mut self, key: u64, value: u64);
+    fn get(&self, key: &u64) -> Option<&u64>;
+    fn remove(&mut self, key: &u64);
+}
+
+macro_rules! impl_map {
+    ($t:ty) => {
+        impl Map for $t {
+            fn insert(&mut self, key: u64, value: u64) {
+                <$t>::insert(self, key, value);
+            }
+            fn get(&self, key: &u64) -> Option<&u64> {
+                <$t>::get(self, key)
+            }
+            fn remove(&mut self, key: &u64) {
+                <$t>::remove(self, key);
+            }
+        }
+    }
+}
+
+impl_map!(BTree<u64, u64>);
+impl_map!(RBTree<u64, u64>);
+impl_map!(arena::RBTree<u64, u64>);
+impl_map!(BTreeMap<u64, u64>);
+impl_map!(HashMap<u64, u64>);
+
+/// One operation in this many has its latency measured
+const SAMPLE: usize = 16;
+
+#[derive(Clone, Copy)]
+enum Op {
+    Get(u64),
+    Insert(u64),
+    Remove(u64),
+}
+
+struct Config {
+    size: usize,
+    ops: usize,
+    dist: String,
+    reads: u32,
+    seed: usize,
+}
+
+fn usage() -> ! {
+    // stdout is for the CSV
+    writeln!(io::stderr(), "usage: tree_bench [--size N] [--ops N] [--dist uniform|sequential|skewed] \
+                            [--reads PCT] [--seed N]").unwrap();
+    process::exit(1);
+}
+
+fn parse_args() -> Config {
+    let mut config = Config {
+        size: 1_000_000,
+        ops: 1_000_000,
+        dist: "uniform".to_string(),
+        reads: 90,
+        seed: 42,
+    };
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() % 2 != 0 {
+        usage();
+    }
+    for pair in args.chunks(2) {
+        let (name, value) = (&pair[0][..], &pair[1]);
+        match name {
+            "--size" => config.size = value.parse().unwrap_or_else(|_| usage()),
+            "--ops" => config.ops = value.parse().unwrap_or_else(|_| usage()),
+            "--reads" => config.reads = value.parse().unwrap_or_else(|_| usage()),
+            "--seed" => config.seed = value.parse().unwrap_or_else(|_| usage()),
+            "--dist" => config.dist = value.clone(),
+            _ => usage(),
+        }
+    }
+    if config.reads > 100 {
+        usage();
+    }
+    match &config.dist[..] {
+        "uniform" | "sequential" | "skewed" => {}
+        _ => usage(),
+    }
+    config
+}
+
+/// Keys to prefill the trees and the operations to time
+fn workload(config: &Config) -> (Vec<u64>, Vec<Op>) {
+    let mut rng: StdRng = SeedableRng::from_seed(&[config.seed][..]);
+    let space = 2 * config.size as u64 + 1;
+    let mut next_seq = 0;
+    let mut key = |rng: &mut StdRng| -> u64 {
+        match &config.dist[..] {
+            "uniform" => rng.gen_range(0, space),
+            "sequential" => {
+                next_seq += 1;
+                next_seq
+            }
+            // 90% of the keys from the first 10% of the key space
+            "skewed" => {
+                if rng.gen_range(0, 10) < 9 {
+                    rng.gen_range(0, space / 10 + 1)
+                } else {
+                    rng.gen_range(0, space)
+                }
+            }
+            _ => unreachable!(),
+        }
+    };
+    let prefill = (0..config.size).map(|_| key(&mut rng)).collect();
+    let ops = (0..config.ops).map(|_| {
+        let k = key(&mut rng);
+        match rng.gen_range(0, 100) {
+            p if p < config.reads => Op::Get(k),
+            p if p % 2 == 0 => Op::Insert(k),
+            _ => Op::Remove(k),
+        }
+    }).collect();
+    (prefill, ops)
+}
+
+/// Run one operation, returning 1 for a lookup that found its key
+fn apply<M: Map>(map: &mut M, op: Op) -> usize {
+    match op {
+        Op::Get(k) => map.get(&k).is_some() as usize,
+        Op::Insert(k) => {
+            map.insert(k, k);
+            0
+        }
+        Op::Remove(k) => {
+            map.remove(&k);
+            0
+        }
+    }
+}
+
+fn run<M: Map>(name: &str, mut map: M, config: &Config, prefill: &[u64], ops: &[Op]) {
+    for &k in prefill {
+        map.insert(k, k);
+    }
+    let mut latencies = Vec::with_capacity(ops.len() / SAMPLE + 1);
+    let mut found = 0;
+    let start = time::precise_time_ns();
+    for (i, &op) in ops.iter().enumerate() {
+        if i % SAMPLE == 0 {
+            let op_start = time::precise_time_ns();
+            found += apply(&mut map, op);
+            latencies.push(time::precise_time_ns() - op_start);
+        } else {
+            found += apply(&mut map, op);
+        }
+    }
+    let elapsed = time::precise_time_ns() - start;
+    latencies.sort();
+    let percentile = |p: usize| latencies.get(latencies.len() * p / 100).cloned().unwrap_or(0);
+    println!("{},{},{},{},{},{:.0},{},{},{},{},{}",
+             name, config.size, config.dist, config.reads, ops.len(),
+             ops.len() as f64 / (elapsed as f64 / 1e9),
+             percentile(50), percentile(90), percentile(99),
+             latencies.last().cloned().unwrap_or(0),
+             found);
+}
+
+fn main() {
+    let config = parse_args();
+    let (prefill, ops) = workload(&config);
+    println!("tree,size,dist,reads_pct,ops,ops_per_sec,p50_ns,p90_ns,p99_ns,max_ns,found");
+    run("btree", BTree::new(), &config, &prefill, &ops);
+    run("rbtree", RBTree::new(), &config, &prefill, &ops);
+    run("rbtree_arena", arena::RBTree::new(), &config, &prefill, &ops);
+    run("std_btreemap", BTreeMap::new(), &config, &prefill, &ops);
+    run("std_hashmap", HashMap::new(), &config, &prefill, &ops);
+}
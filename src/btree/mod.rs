@@ -16,6 +16,7 @@
 mod test;
 
 use std::mem;
+use std::cmp::Ordering;
 use std::vec;
 
 /// BTree root. `t` is the minimum degree.
@@ -94,7 +95,10 @@ impl<K, V> BTree<K, V> where K: Ord {
         }
 
         match kv {
-            Some((_,v)) => Some(v),
+            Some((_,v)) => {
+                self.count -= 1;
+                Some(v)
+            }
             None => None,
         }
     }
@@ -163,10 +167,11 @@ impl<K, V> Node<K, V> where K: Ord {
                     if curr.children[n].is_full(m) {
                         // child we need to recurse on is full, split it
                         curr.split_child(m, n);
-                        if key < curr.keys[n] {
-                            curr.children[n].insert(m, key, value)
-                        } else {
-                            curr.children[n+1].insert(m, key, value)
+                        match key.cmp(&curr.keys[n]) {
+                            // the key was the median moved up
+                            Ordering::Equal => Some(mem::replace(&mut curr.values[n], value)),
+                            Ordering::Less => curr.children[n].insert(m, key, value),
+                            Ordering::Greater => curr.children[n+1].insert(m, key, value),
                         }
                     } else {
                         curr.children[n].insert(m, key, value)
@@ -218,28 +223,20 @@ impl<K, V> Node<K, V> where K: Ord {
                     // here we're removing the key from an inner
                     // node. We need to "raise" a key from either left
                     // or right side, if any of them is larger then
-                    // the minimum size. If both are minimal, merge
-                    // them plus the removed key and recursively
-                    // delete on the merged node. We use `unsafe` to
-                    // get an immutable reference to the key we will
-                    // move up - we need to call delete() recursivelly
+                    // the minimum size: the predecessor (max of the
+                    // left subtree) or the successor (min of the right
+                    // subtree). If both are minimal, merge them plus
+                    // the removed key and recursively delete on the
+                    // merged node.
                     if !self.children[n].is_too_small(m) {
                         // take item from left
-                        let pred_key: &K;
-                        unsafe {
-                            pred_key = &*(self.children[n].keys.last().unwrap() as *const K);
-                        }
-                        let (mut k, mut v) = self.children[n].remove(m, pred_key).unwrap();
+                        let (mut k, mut v) = self.children[n].remove_max(m);
                         mem::swap(&mut self.keys[n], &mut k);
                         mem::swap(&mut self.values[n], &mut v);
                         Some((k,v))
                     } else if !self.children[n+1].is_too_small(m) {
                         // take item from right
-                        let succ_key: &K;
-                        unsafe {
-                            succ_key = &*(self.children[n+1].keys.first().unwrap() as *const K);
-                        }
-                        let (mut k, mut v) = self.children[n+1].remove(m, succ_key).unwrap();
+                        let (mut k, mut v) = self.children[n+1].remove_min(m);
                         mem::swap(&mut self.keys[n], &mut k);
                         mem::swap(&mut self.values[n], &mut v);
                         Some((k,v))
@@ -261,73 +258,103 @@ impl<K, V> Node<K, V> where K: Ord {
                     None
                 } else {
                     // make sure node is large enough before recursing
-                    if self.children[n].is_too_small(m) {
-                        if n > 0 && !self.children[n-1].is_too_small(m) { // take from left
-                            // move a key down to node
-                            let k = self.keys.remove(n-1);
-                            let v = self.values.remove(n-1);
-                            self.children[n].keys.insert(0, k);
-                            self.children[n].values.insert(0, v);
-                            // move a key up from left sibling
-                            let k = self.children[n-1].keys.pop().unwrap();
-                            let v = self.children[n-1].values.pop().unwrap();
-                            self.keys.insert(n-1, k);
-                            self.values.insert(n-1, v);
-                            // move child from left sibling
-                            if !self.children[n-1].is_leaf() {
-                                let c = self.children[n-1].children.pop().unwrap();
-                                self.children[n].children.insert(0,c);
-                            }
-                        }
-                        else if n < self.keys.len() && !self.children[n+1].is_too_small(m) { // take from right
-                            // move a key down to node
-                            let k = self.keys.remove(n);
-                            let v = self.values.remove(n);
-                            self.children[n].keys.push(k);
-                            self.children[n].values.push(v);
-                            // move a key up from right sibling
-                            let k = self.children[n+1].keys.remove(0);
-                            let v = self.children[n+1].values.remove(0);
-                            self.keys.insert(n, k);
-                            self.values.insert(n, v);
-                            // move child from right sibling
-                            if !self.children[n+1].is_leaf() {
-                                let c = self.children[n+1].children.remove(0);
-                                self.children[n].children.push(c);
-                            }
-                        } else {
-                            if n > 0 { // merge with left sibling
-                                // move a key down as new median
-                                let k = self.keys.remove(n-1);
-                                let v = self.values.remove(n-1);
-                                self.children[n-1].keys.push(k);
-                                self.children[n-1].values.push(v);
-                                // merge node
-                                let mut removed_node = self.children.remove(n);
-                                self.children[n-1].keys.append(&mut removed_node.keys);
-                                self.children[n-1].values.append(&mut removed_node.values);
-                                self.children[n-1].children.append(&mut removed_node.children);
-                                // corner case where `n` changes
-                                return self.children[n-1].remove(m,key);
-                            } else { // merge with right sibling
-                                // move a key down as new median
-                                let k = self.keys.remove(n);
-                                let v = self.values.remove(n);
-                                self.children[n].keys.push(k);
-                                self.children[n].values.push(v);
-                                // merge node
-                                let mut removed_node = self.children.remove(n+1);
-                                self.children[n].keys.append(&mut removed_node.keys);
-                                self.children[n].values.append(&mut removed_node.values);
-                                self.children[n].children.append(&mut removed_node.children);
-                            }
-                        }
-                    }
+                    let n = self.grow_child(m, n);
                     self.children[n].remove(m, key)
                 }
             }
         }
     }
+
+    /// Remove the item with the smallest key of the subtree
+    fn remove_min(&mut self, m: usize) -> (K,V) {
+        if self.is_leaf() {
+            (self.keys.remove(0), self.values.remove(0))
+        } else {
+            let n = self.grow_child(m, 0);
+            self.children[n].remove_min(m)
+        }
+    }
+
+    /// Remove the item with the greatest key of the subtree
+    fn remove_max(&mut self, m: usize) -> (K,V) {
+        if self.is_leaf() {
+            (self.keys.pop().unwrap(), self.values.pop().unwrap())
+        } else {
+            let last = self.children.len() - 1;
+            let n = self.grow_child(m, last);
+            self.children[n].remove_max(m)
+        }
+    }
+
+    /// Make sure child `n` is large enough to have an item removed,
+    /// taking a key from a sibling or merging with one. Returns the
+    /// index of the child to recurse on, which changes when merging
+    /// with the left sibling.
+    fn grow_child(&mut self, m: usize, n: usize) -> usize {
+        if !self.children[n].is_too_small(m) {
+            return n;
+        }
+        if n > 0 && !self.children[n-1].is_too_small(m) { // take from left
+            // move a key down to node
+            let k = self.keys.remove(n-1);
+            let v = self.values.remove(n-1);
+            self.children[n].keys.insert(0, k);
+            self.children[n].values.insert(0, v);
+            // move a key up from left sibling
+            let k = self.children[n-1].keys.pop().unwrap();
+            let v = self.children[n-1].values.pop().unwrap();
+            self.keys.insert(n-1, k);
+            self.values.insert(n-1, v);
+            // move child from left sibling
+            if !self.children[n-1].is_leaf() {
+                let c = self.children[n-1].children.pop().unwrap();
+                self.children[n].children.insert(0,c);
+            }
+            n
+        }
+        else if n < self.keys.len() && !self.children[n+1].is_too_small(m) { // take from right
+            // move a key down to node
+            let k = self.keys.remove(n);
+            let v = self.values.remove(n);
+            self.children[n].keys.push(k);
+            self.children[n].values.push(v);
+            // move a key up from right sibling
+            let k = self.children[n+1].keys.remove(0);
+            let v = self.children[n+1].values.remove(0);
+            self.keys.insert(n, k);
+            self.values.insert(n, v);
+            // move child from right sibling
+            if !self.children[n+1].is_leaf() {
+                let c = self.children[n+1].children.remove(0);
+                self.children[n].children.push(c);
+            }
+            n
+        } else if n > 0 { // merge with left sibling
+            // move a key down as new median
+            let k = self.keys.remove(n-1);
+            let v = self.values.remove(n-1);
+            self.children[n-1].keys.push(k);
+            self.children[n-1].values.push(v);
+            // merge node
+            let mut removed_node = self.children.remove(n);
+            self.children[n-1].keys.append(&mut removed_node.keys);
+            self.children[n-1].values.append(&mut removed_node.values);
+            self.children[n-1].children.append(&mut removed_node.children);
+            n-1
+        } else { // merge with right sibling
+            // move a key down as new median
+            let k = self.keys.remove(n);
+            let v = self.values.remove(n);
+            self.children[n].keys.push(k);
+            self.children[n].values.push(v);
+            // merge node
+            let mut removed_node = self.children.remove(n+1);
+            self.children[n].keys.append(&mut removed_node.keys);
+            self.children[n].values.append(&mut removed_node.values);
+            self.children[n].children.append(&mut removed_node.children);
+            n
+        }
+    }
 }
 
 
@@ -2,6 +2,7 @@ use btree::BTree;
 use btree::Node;
 use std::io::{stdout,Write};
 use std::fmt::Debug;
+use rand::{thread_rng, Rng};
 
 impl<K, V> BTree<K, V>  where K: Ord + Debug, V: Debug {
     /// Print keys in breath first order. Same level keys are printed on the same line
@@ -148,6 +149,75 @@ fn test_remove() {
     }
 }
 
+#[test]
+fn test_insert_median() {
+    let mut r: BTree<i32, i32> = BTree::new_with_order(4);
+    for n in 1..6 {
+        r.insert(n, 2*n);
+    }
+    // 4 is the median of the full leaf [3, 4, 5], moved up into the
+    // root by the split on the way down
+    assert_eq!(r.root.children[1].keys, [3, 4, 5]);
+    assert_eq!(r.insert(4, 40), Some(8));
+    assert_eq!(r.len(), 5);
+    assert_eq!(r.get(&4), Some(&40));
+    assert_eq!(r.iter().map(|(&k, _)| k).collect::<Vec<_>>(), [1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn test_remove_inner() {
+    let mut r: BTree<i32, i32> = BTree::new_with_order(4);
+    for n in 0..200 {
+        r.insert(n, 2*n);
+    }
+    // always remove a key of the root, an inner node while the tree
+    // has more than one level
+    let mut removed = vec![];
+    while r.height() > 1 {
+        let k = r.root.keys[0];
+        assert_eq!(r.remove(&k), Some(2*k));
+        assert_eq!(r.get(&k), None);
+        removed.push(k);
+        assert_eq!(r.len(), 200 - removed.len());
+    }
+    let kept = (0..200).filter(|k| !removed.contains(k)).collect::<Vec<_>>();
+    assert_eq!(r.iter().map(|(&k, _)| k).collect::<Vec<_>>(), kept);
+    for &k in kept.iter() {
+        assert_eq!(r.get(&k), Some(&(2*k)));
+    }
+}
+
+#[test]
+fn test_remove_random() {
+    let mut rng = thread_rng();
+    let mut shuffled = (0..2000).collect::<Vec<_>>();
+    rng.shuffle(shuffled.as_mut_slice());
+    let mut r: BTree<i32, i32> = BTree::new_with_order(5);
+    for &n in shuffled.iter() {
+        r.insert(n, 2*n);
+    }
+    // replacing values doesn't add keys
+    for &n in shuffled.iter() {
+        assert_eq!(r.insert(n, 2*n), Some(2*n));
+    }
+    assert_eq!(r.len(), 2000);
+
+    // remove half of the keys, in another order
+    rng.shuffle(shuffled.as_mut_slice());
+    let (removed, kept) = shuffled.split_at(1000);
+    for &n in removed {
+        assert_eq!(r.remove(&n), Some(2*n));
+        assert_eq!(r.remove(&n), None);
+    }
+    assert_eq!(r.len(), 1000);
+    for &n in kept {
+        assert_eq!(r.get(&n), Some(&(2*n)));
+    }
+    let mut kept = kept.to_vec();
+    kept.sort();
+    assert_eq!(r.iter().map(|(&k, _)| k).collect::<Vec<_>>(), kept);
+}
+
 #[test]
 fn test_order() {
     let r: BTree<i32, i32> = BTree::new_with_order(3);
@@ -6,10 +6,31 @@ extern crate log;
 extern crate env_logger;
 extern crate bytes;
 extern crate byteorder;
+extern crate crc;
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(feature = "codec")]
+extern crate serde;
+#[cfg(feature = "codec")]
+extern crate bincode;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
+#[cfg(feature = "noise")]
+extern crate snow;
+#[cfg(feature = "async-tls")]
+extern crate rustls;
+#[cfg(feature = "ws")]
+extern crate sha1;
+#[cfg(feature = "ws")]
+extern crate base64;
 
 mod btree;
-mod rbtree;
+pub mod rbtree;
 
 pub mod net;
 pub use btree::BTree;
-pub use rbtree::RBTree;
+pub use rbtree::{RBTree, RBSet, RBMultiMap};
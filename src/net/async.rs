@@ -1,26 +1,136 @@
+use mio;
 use rand;
-use ::net::network_to_u32;
-use std::slice;
-use std::collections::{VecDeque, HashSet};
+use bytes::Bytes;
+use ::net::endian;
+use ::net::FrameHooks;
+#[cfg(feature = "codec")]
+use ::net::{Codec, NetError};
+use ::net::sync::{ReconnectPolicy, ListenerBuilder};
+use ::net::readbuf::ReadBuf;
+use ::net::tokenset::TokenSet;
+#[cfg(feature = "codec")]
+use serde::Serialize;
+#[cfg(feature = "codec")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "codec")]
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "async-tls")]
+use rustls::{self, Session};
+#[cfg(feature = "async-tls")]
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::any::Any;
+use std::cmp;
+use std::collections::{VecDeque, HashMap};
 use std::net::SocketAddr;
-pub use mio::Timeout as TimeoutUid;
-pub use mio::Sender as Sender;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+pub use mio::NotifyError;
 use mio::{Token, TimerError, EventLoop, EventSet, PollOpt, Handler, TryRead, TryWrite};
 use mio::tcp::*;
+use mio::udp::UdpSocket;
 use mio::util::Slab;
+use std::error;
+use std::fmt;
 use std::io;
-use bytes::{ByteBuf, RingBuf, Buf};
+use std::mem;
 
-const MAX_MSG_SIZE: usize = 32*1024;
+/// Largest message accepted by default, see
+/// `ServerBuilder::max_msg_size()`
+pub const DEFAULT_MAX_MSG_SIZE: usize = 32*1024;
+/// Default outbound watermarks of a connection, see
+/// `ServerBuilder::write_watermarks()`
+pub const DEFAULT_WRITE_HIGH_WATERMARK: usize = 1024*1024;
+pub const DEFAULT_WRITE_LOW_WATERMARK: usize = 256*1024;
 const MSG_HDR_SIZE: usize = 4;
 
-const SERVER: Token = Token(0);
+/// Tokens of the listening sockets start here
+const LISTENER_TOKENS: usize = ::std::usize::MAX / 4;
+/// Tokens of the UDP sockets start here, past any connection's
+const UDP_TOKENS: usize = ::std::usize::MAX / 2;
+/// Largest datagram received
+const MAX_DATAGRAM_SIZE: usize = 64*1024;
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Timer(TimerError),
     ConnectionLimit,
+    /// A peer sent a message of this size, over the server's limit
+    MsgTooBig(usize),
+    /// The server itself failed (e.g. a shard thread panicked)
+    Internal(String),
+    /// An error on a connection, with the uid (and address) of the
+    /// connection
+    Connection(ConnectionUid, Box<Error>),
+}
+
+impl Error {
+    /// The connection the error happened on, if known
+    pub fn uid(&self) -> Option<&ConnectionUid> {
+        match *self {
+            Error::Connection(ref uid, _) => Some(uid),
+            _ => None,
+        }
+    }
+
+    /// A peer broke the protocol (e.g. `MsgTooBig`)
+    pub fn is_protocol(&self) -> bool {
+        match *self {
+            Error::MsgTooBig(_) => true,
+            Error::Connection(_, ref err) => err.is_protocol(),
+            _ => false,
+        }
+    }
+
+    /// The network or the OS failed
+    pub fn is_io(&self) -> bool {
+        match *self {
+            Error::Io(_) => true,
+            Error::Connection(_, ref err) => err.is_io(),
+            _ => false,
+        }
+    }
+
+    fn on(self, uid: ConnectionUid) -> Error {
+        Error::Connection(uid, Box::new(self))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "io error: {}", err),
+            Error::Timer(ref err) => write!(f, "timer error: {:?}", err),
+            Error::ConnectionLimit => write!(f, "connection limit reached"),
+            Error::MsgTooBig(size) => write!(f, "message of {} bytes is too big", size),
+            Error::Internal(ref msg) => write!(f, "internal error: {}", msg),
+            Error::Connection(ref uid, ref err) => write!(f, "connection with {}: {}", uid.addr, err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref err) => err.description(),
+            Error::Timer(_) => "timer error",
+            Error::ConnectionLimit => "connection limit reached",
+            Error::MsgTooBig(_) => "message too big",
+            Error::Internal(_) => "internal error",
+            Error::Connection(_, ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::Connection(_, ref err) => Some(&**err),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -37,110 +147,653 @@ impl From<TimerError> for Error {
 
 /// Asynchronous IO, message-based, TCP Server
 pub struct Server<H: ServerHandler> {
-    token: Token,
-    socket: Option<TcpListener>,
+    listeners: Vec<TcpListener>,
     connections: Slab<Connection>,
-    connections_new: VecDeque<TcpStream>,
-    // TODO: HashSet is very slow for iterating: slowdown for large numbers of connections
-    connections_closed: Option<HashSet<Token>>,
-    connections_reregister: Option<HashSet<Token>>,
+    /// Accepted, with the index of their listener
+    connections_new: VecDeque<(TcpStream, SocketAddr, usize)>,
+    /// The slab of connections can grow up to this many
+    connection_limit: usize,
+    /// Options for accepted connections
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    connections_closed: Option<TokenSet>,
+    connections_reregister: Option<TokenSet>,
     handler: Option<H>,
     shutdown: bool,
     shutdown_error: Option<Error>,
+    hooks: FrameHooks,
+    /// Largest message accepted from a peer
+    max_msg_size: usize,
+    /// Queued outbound bytes (high, low) for `writable_blocked()` and
+    /// `writable_resumed()`
+    watermarks: (usize, usize),
+    idle_timeout: Option<Duration>,
+    stats_interval: Option<Duration>,
+    counters: Counters,
+    /// First event of the current event loop iteration
+    tick_start: Option<Instant>,
+    write_notices: VecDeque<(ConnectionUid, bool)>,
+    /// For `ServerHandler::sent()`
+    sent_notices: VecDeque<(ConnectionUid, u64)>,
+    /// For `ServerHandler::connection_error()`, before the connections
+    /// are closed
+    connection_errors: VecDeque<(ConnectionUid, Error)>,
+    connections_resumed: VecDeque<ConnectionUid>,
+    /// The state attached to the connection being closed, reachable
+    /// while `ServerHandler::connection_closed()` runs
+    closing: Option<(ConnectionUid, Option<Box<Any>>)>,
+    udp: HashMap<Token, UdpSocket>,
+    next_udp: usize,
+    /// Received datagrams are read here, allocated on the first
+    /// `bind_udp()`
+    udp_buf: Vec<u8>,
+    intervals: HashMap<IntervalUid, Interval<H::Timeout>>,
+    next_interval: u64,
+    timers: HashMap<TimerUid, Timer<H::Timeout>>,
+    next_timer: u64,
+    /// Timers whose handle was dropped, cleared on the next tick
+    dropped_timers: Arc<Mutex<Vec<TimerUid>>>,
+    /// Index of this server in a `ShardedServer` (0 otherwise)
+    shard: usize,
+    shards: Option<ShardHandle<H::Message>>,
+    /// Set by `shutdown_graceful()`: connections are closed once
+    /// their queued messages are written
+    draining: bool,
+    /// Accepted connections are TLS when set
+    #[cfg(feature = "async-tls")]
+    tls: Option<TlsConfig>,
 }
 
-impl<H: ServerHandler> Server<H> {
-    /// Create a new Server that doesn't bind to a local address, can
-    /// only connect to others (i.e. a client).
-    pub fn new(handler: H, max_connections: usize) -> Result<Self, Error> {
-        Ok(Server {
-            token: Token(0),
-            socket: None,
-            connections: Slab::new_starting_at(Token(1), max_connections), // max number of concurrent connections
+/// Server-wide counters, see `ServerControl::stats()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections currently open, incoming and outgoing
+    pub connections: usize,
+    /// Connections accepted from the listeners since `run()`
+    pub accepted: u64,
+    /// Messages and message bytes received and sent
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    /// Bytes waiting to be written, in total and on the connection
+    /// with the most
+    pub queued_bytes: usize,
+    pub max_queued_bytes: usize,
+    /// Time the last event loop iteration took to process its
+    /// events, and the longest so far
+    pub last_tick: Duration,
+    pub max_tick: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    accepted: u64,
+    messages_in: u64,
+    bytes_in: u64,
+    messages_out: u64,
+    bytes_out: u64,
+    last_tick: Duration,
+    max_tick: Duration,
+}
+
+/// Identifies a timer set by `ServerControl::timer_interval()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IntervalUid(u64);
+
+struct Interval<T> {
+    timeout: Box<Fn() -> T>,
+    period: Duration,
+    // when it's due next, so firing late doesn't shift later ones
+    next: Instant,
+    current: mio::Timeout,
+}
+
+impl<T> Interval<T> {
+    // move to the next period, returning the delay until it's due.
+    // Periods already missed are skipped
+    fn advance(&mut self) -> u64 {
+        let now = Instant::now();
+        self.next += self.period;
+        if self.next <= now {
+            self.next = now;
+        }
+        duration_ms(self.next - now)
+    }
+}
+
+/// Identifies a timer set by `ServerControl::timeout_ms()` or
+/// `timeout_at()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TimerUid(u64);
+
+/// Guard for a timer set by `ServerControl::timeout_ms()` or
+/// `timeout_at()`: dropping it cancels the timer, unless detached
+#[derive(Debug)]
+#[must_use = "the timer is cancelled when its handle is dropped, see `detach()`"]
+pub struct TimerHandle {
+    uid: TimerUid,
+    // where to queue the uid when dropped, None once detached
+    dropped: Option<Arc<Mutex<Vec<TimerUid>>>>,
+}
+
+impl TimerHandle {
+    pub fn uid(&self) -> TimerUid {
+        self.uid
+    }
+
+    /// Let the timer fire even though the handle is dropped
+    pub fn detach(mut self) {
+        self.dropped = None;
+    }
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        if let Some(ref dropped) = self.dropped {
+            dropped.lock().unwrap().push(self.uid);
+        }
+    }
+}
+
+struct Timer<T> {
+    timeout: T,
+    current: mio::Timeout,
+}
+
+// ms until deadline, 0 if it's past
+fn deadline_ms(deadline: Instant) -> u64 {
+    let now = Instant::now();
+    if deadline > now {
+        duration_ms(deadline - now)
+    } else {
+        0
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// Options for creating a `Server`
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    max_connections: usize,
+    connection_limit: Option<usize>,
+    listener: ListenerBuilder,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    max_msg_size: usize,
+    watermarks: (usize, usize),
+    idle_timeout: Option<Duration>,
+    stats_interval: Option<Duration>,
+    #[cfg(feature = "async-tls")]
+    tls: Option<TlsConfig>,
+}
+
+#[cfg(feature = "async-tls")]
+#[derive(Clone)]
+struct TlsConfig(Arc<rustls::ServerConfig>);
+
+#[cfg(feature = "async-tls")]
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ServerConfig")
+    }
+}
+
+impl ServerBuilder {
+    pub fn new(max_connections: usize) -> Self {
+        ServerBuilder {
+            max_connections: max_connections,
+            connection_limit: Some(max_connections),
+            listener: ListenerBuilder::new(),
+            nodelay: true,
+            keepalive: None,
+            max_msg_size: DEFAULT_MAX_MSG_SIZE,
+            watermarks: (DEFAULT_WRITE_HIGH_WATERMARK, DEFAULT_WRITE_LOW_WATERMARK),
+            idle_timeout: None,
+            stats_interval: None,
+            #[cfg(feature = "async-tls")]
+            tls: None,
+        }
+    }
+
+    /// Let the connection slab grow past `max_connections` once full,
+    /// doubling each time, up to `limit` connections (or without a
+    /// cap for `None`). By default it doesn't grow.
+    pub fn connection_limit(mut self, limit: Option<usize>) -> Self {
+        self.connection_limit = limit;
+        self
+    }
+
+    /// Socket options of the listeners (backlog, `SO_REUSEADDR`,
+    /// `SO_REUSEPORT`, `IPV6_V6ONLY`)
+    pub fn listener(mut self, builder: ListenerBuilder) -> Self {
+        self.listener = builder;
+        self
+    }
+
+    /// `TCP_NODELAY` on accepted and outgoing connections, on by default
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Enable TCP keepalive on accepted and outgoing connections, probing idle
+    /// ones after `idle`
+    pub fn keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.keepalive = idle;
+        self
+    }
+
+    /// Largest message accepted from a peer (32KB by default). The
+    /// read buffers of the connections grow up to this size as
+    /// needed, and shrink back once idle. Peers sending bigger ones
+    /// are disconnected, after `ServerHandler::protocol_error()`.
+    pub fn max_msg_size(mut self, size: usize) -> Self {
+        self.max_msg_size = size;
+        self
+    }
+
+    /// Call `ServerHandler::writable_blocked()` when the bytes queued
+    /// to send on a connection go over `high` (1MB by default), and
+    /// `writable_resumed()` once they fall to `low` (256KB)
+    pub fn write_watermarks(mut self, high: usize, low: usize) -> Self {
+        self.watermarks = (high, cmp::min(low, high));
+        self
+    }
+
+    /// Close connections that receive nothing for this long (and
+    /// whose reads aren't paused), calling
+    /// `ServerHandler::connection_closed()`
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Call `ServerHandler::stats()` this often
+    pub fn stats_interval(mut self, interval: Option<Duration>) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// Terminate TLS on accepted connections. The handshake is done
+    /// in the event loop, messages sent before it ends are queued.
+    #[cfg(feature = "async-tls")]
+    pub fn tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(TlsConfig(config));
+        self
+    }
+
+    /// Create a Server that doesn't bind to a local address, see
+    /// `Server::new()`
+    pub fn build<H: ServerHandler>(self, handler: H) -> Result<Server<H>, Error> {
+        Ok(self.server(Vec::new(), handler))
+    }
+
+    /// Create a Server bound to the given address
+    pub fn bind<H: ServerHandler>(self, addr: &SocketAddr, handler: H) -> Result<Server<H>, Error> {
+        self.bind_all(&[*addr], handler)
+    }
+
+    /// Create a Server accepting connections on each of the given
+    /// addresses. `ConnectionUid::listener()` tells them apart.
+    pub fn bind_all<H: ServerHandler>(self, addrs: &[SocketAddr], handler: H) -> Result<Server<H>, Error> {
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let listener = try!(self.listener.bind(*addr));
+            listeners.push(try!(TcpListener::from_listener(listener, addr)));
+        }
+        Ok(self.server(listeners, handler))
+    }
+
+    fn server<H: ServerHandler>(self, listeners: Vec<TcpListener>, handler: H) -> Server<H> {
+        Server {
+            listeners: listeners,
+            connections: Slab::new_starting_at(Token(1), self.max_connections), // max number of concurrent connections
             connections_new: VecDeque::new(),
-            connections_closed: Some(HashSet::new()), // Some(HashSet::with_capacity(max_connections)),
-            connections_reregister: Some(HashSet::new()),// Some(HashSet::with_capacity(max_connections)),
+            nodelay: self.nodelay,
+            keepalive: self.keepalive,
+            connection_limit: cmp::max(self.connection_limit.unwrap_or(LISTENER_TOKENS - 1), self.max_connections),
+            connections_closed: Some(TokenSet::with_capacity(self.max_connections + 1)),
+            connections_reregister: Some(TokenSet::with_capacity(self.max_connections + 1)),
             handler: Some(handler),
             shutdown: false,
             shutdown_error: None,
+            hooks: FrameHooks::new(),
+            max_msg_size: self.max_msg_size,
+            watermarks: self.watermarks,
+            idle_timeout: self.idle_timeout,
+            stats_interval: self.stats_interval,
+            counters: Counters::default(),
+            tick_start: None,
+            write_notices: VecDeque::new(),
+            sent_notices: VecDeque::new(),
+            connection_errors: VecDeque::new(),
+            connections_resumed: VecDeque::new(),
+            closing: None,
+            udp: HashMap::new(),
+            next_udp: UDP_TOKENS,
+            udp_buf: Vec::new(),
+            intervals: HashMap::new(),
+            next_interval: 0,
+            timers: HashMap::new(),
+            next_timer: 0,
+            dropped_timers: Arc::new(Mutex::new(Vec::new())),
+            shard: 0,
+            shards: None,
+            draining: false,
+            #[cfg(feature = "async-tls")]
+            tls: self.tls,
+        }
+    }
+
+    /// Create a server for each of `handlers`, all accepting
+    /// connections on the given address, see `ShardedServer`
+    pub fn bind_sharded<H>(self, addr: &SocketAddr, handlers: Vec<H>) -> Result<ShardedServer<H>, Error>
+        where H: ServerHandler + Send + 'static {
+        let listener = try!(self.listener.bind(*addr));
+        let socket = try!(TcpListener::from_listener(listener, addr));
+        Ok(ShardedServer {
+            builder: self,
+            socket: socket,
+            handlers: handlers,
         })
     }
+}
+
+/// Several async servers on their own threads (one event loop each),
+/// sharing a listening socket: each accepted connection belongs to
+/// the server that accepted it. Handlers reach connections of other
+/// shards through `ServerControl::shards()`.
+pub struct ShardedServer<H: ServerHandler> {
+    builder: ServerBuilder,
+    socket: TcpListener,
+    handlers: Vec<H>,
+}
+
+impl<H> ShardedServer<H> where H: ServerHandler + Send + 'static {
+    /// Run every shard until all of them shut down, returning the
+    /// first error
+    pub fn run(self) -> Result<(), Error> {
+        let n = self.handlers.len();
+        let (senders_tx, senders_rx) = mpsc::channel();
+        let mut handle_txs = Vec::with_capacity(n);
+        let mut threads = Vec::with_capacity(n);
+        for (shard, handler) in self.handlers.into_iter().enumerate() {
+            let socket = try!(self.socket.try_clone());
+            let builder = self.builder.clone();
+            let senders_tx = senders_tx.clone();
+            let (handle_tx, handle_rx) = mpsc::channel();
+            handle_txs.push(handle_tx);
+            threads.push(thread::spawn(move || {
+                let mut server = builder.server(vec![socket], handler);
+                server.shard = shard;
+                let mut evl = match server.event_loop() {
+                    Ok(evl) => evl,
+                    Err(err) => {
+                        senders_tx.send((shard, None)).is_ok();
+                        return Err(err);
+                    }
+                };
+                senders_tx.send((shard, Some(evl.channel()))).is_ok();
+                match handle_rx.recv() {
+                    Ok(shards) => server.shards = Some(shards),
+                    // another shard couldn't start
+                    Err(_) => return Ok(()),
+                }
+                server.run_event_loop(&mut evl)
+            }));
+        }
+        drop(senders_tx);
+        let mut senders: Vec<_> = (0..n).map(|_| None).collect();
+        for (shard, sender) in senders_rx.iter().take(n) {
+            senders[shard] = sender;
+        }
+        if senders.iter().all(Option::is_some) {
+            let shards = ShardHandle {
+                senders: senders.into_iter().map(Option::unwrap).collect(),
+            };
+            for handle_tx in &handle_txs {
+                handle_tx.send(shards.clone()).is_ok();
+            }
+        }
+        drop(handle_txs);
+        let mut res = Ok(());
+        for (shard, thread) in threads.into_iter().enumerate() {
+            let shard_res = match thread.join() {
+                Ok(shard_res) => shard_res,
+                Err(_) => Err(Error::Internal(format!("shard {} panicked", shard))),
+            };
+            if res.is_ok() {
+                res = shard_res;
+            }
+        }
+        res
+    }
+}
+
+/// Messages for a server's event loop
+pub enum ServerMsg<M> {
+    /// For `ServerHandler::notify()`
+    User(M),
+    /// Send a message to a connection of this server
+    Send(ConnectionUid, Vec<u8>),
+    /// Shutdown the server
+    Shutdown,
+}
+
+/// Sends notify messages to a server's event loop, from any thread
+pub struct Sender<M> {
+    sender: mio::Sender<ServerMsg<M>>,
+}
+
+impl<M> Clone for Sender<M> {
+    fn clone(&self) -> Self {
+        Sender {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<M: Send> Sender<M> {
+    pub fn send(&self, msg: M) -> Result<(), NotifyError<M>> {
+        self.sender.send(ServerMsg::User(msg)).or_else(|err| Err(match err {
+            NotifyError::Io(err) => NotifyError::Io(err),
+            NotifyError::Full(ServerMsg::User(msg)) => NotifyError::Full(msg),
+            NotifyError::Closed(Some(ServerMsg::User(msg))) => NotifyError::Closed(Some(msg)),
+            _ => NotifyError::Closed(None),
+        }))
+    }
+}
+
+/// Reaches the servers of a `ShardedServer` from any thread
+pub struct ShardHandle<M> {
+    senders: Vec<mio::Sender<ServerMsg<M>>>,
+}
+
+impl<M> Clone for ShardHandle<M> {
+    fn clone(&self) -> Self {
+        ShardHandle {
+            senders: self.senders.clone(),
+        }
+    }
+}
+
+impl<M: Send> ShardHandle<M> {
+    /// Number of shards
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+    /// Send a msg to a connection of any shard. Will ignore a non
+    /// existing connection
+    pub fn send(&self, uid: &ConnectionUid, msg: Vec<u8>) -> Result<(), Error> {
+        self.notify_shard(uid.shard, ServerMsg::Send(*uid, msg))
+    }
+    /// Deliver a notify msg to the handler of one shard
+    pub fn notify(&self, shard: usize, msg: M) -> Result<(), Error> {
+        self.notify_shard(shard, ServerMsg::User(msg))
+    }
+    /// Shutdown every shard
+    pub fn shutdown(&self) {
+        for shard in 0..self.senders.len() {
+            self.notify_shard(shard, ServerMsg::Shutdown).is_ok();
+        }
+    }
+    fn notify_shard(&self, shard: usize, msg: ServerMsg<M>) -> Result<(), Error> {
+        let sender = match self.senders.get(shard) {
+            Some(sender) => sender,
+            None => return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "no such shard"))),
+        };
+        sender.send(msg).or_else(|err| Err(match err {
+            NotifyError::Io(err) => Error::Io(err),
+            NotifyError::Full(_) => Error::Io(io::Error::new(io::ErrorKind::WouldBlock, "event loop queue is full")),
+            NotifyError::Closed(_) => Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "event loop is not running")),
+        }))
+    }
+}
+
+impl<H: ServerHandler> Server<H> {
+    /// Create a new Server that doesn't bind to a local address, can
+    /// only connect to others (i.e. a client).
+    pub fn new(handler: H, max_connections: usize) -> Result<Self, Error> {
+        ServerBuilder::new(max_connections).build(handler)
+    }
 
     /// Create a new Server bound to the given address
     pub fn bind(addr: &SocketAddr, handler: H, max_connections: usize) -> Result<Self, Error> {
-        let s = try!(TcpListener::bind(&addr));
-        Ok(Server {
-            token: Token(0),
-            socket: Some(s),
-            connections: Slab::new_starting_at(Token(1), max_connections), // max number of concurrent connections
-            connections_new: VecDeque::new(),
-            connections_closed: Some(HashSet::new()), // Some(HashSet::with_capacity(max_connections)),
-            connections_reregister: Some(HashSet::new()),// Some(HashSet::with_capacity(max_connections)),
-            handler: Some(handler),
-            shutdown: false,
-            shutdown_error: None,
-        })
+        ServerBuilder::new(max_connections).bind(addr, handler)
+    }
+
+    /// Call these hooks for every message sent or received, on any
+    /// connection. Sent messages are reported once queued.
+    pub fn set_frame_hooks(&mut self, hooks: FrameHooks) {
+        self.hooks = hooks;
     }
 
     /// Start the server's event loop, accepting new connections
     pub fn run(&mut self) -> Result<(), Error> {
+        let mut evl = try!(self.event_loop());
+        self.run_event_loop(&mut evl)
+    }
+
+    fn event_loop(&self) -> Result<EventLoop<Self>, Error> {
         let mut evl = try!(EventLoop::new());
-        if let Some(ref s) = self.socket {
-            try!(evl.register(s, self.token,
+        for (i, s) in self.listeners.iter().enumerate() {
+            try!(evl.register(s, Token(LISTENER_TOKENS + i),
                               EventSet::readable(),
                               PollOpt::edge()));
         }
+        Ok(evl)
+    }
+
+    fn run_event_loop(&mut self, evl: &mut EventLoop<Self>) -> Result<(), Error> {
+        if let Some(interval) = self.stats_interval {
+            try!(evl.timeout_ms(ServerTimeout::Stats, duration_ms(interval)));
+        }
         let mut h = self.handler.take().unwrap();
-        h.init(&mut ServerControl::new(self, &mut evl));
+        h.init(&mut ServerControl::new(self, evl));
         self.handler = Some(h);
         try!(evl.run(self));
         Ok(())
     }
 
-    fn accept(&mut self) {
+    /// Apply the `ServerBuilder` socket options, failing to do so is
+    /// not fatal for the connection
+    fn configure_socket(&self, s: &TcpStream, addr: &SocketAddr) {
+        if let Err(err) = s.set_nodelay(self.nodelay) {
+            debug!("could not set nodelay for {:?}: {:?}", addr, err);
+        }
+        if let Err(err) = s.set_keepalive(self.keepalive.map(|idle| idle.as_secs() as u32)) {
+            debug!("could not set keepalive for {:?}: {:?}", addr, err);
+        }
+    }
+
+    fn accept(&mut self, listener: usize) {
         loop {
-            match self.socket.as_ref().unwrap().accept() {
-                Ok(Some((s, _))) => {
-                    self.connections_new.push_back(s);
+            // events may still come for listeners closed by
+            // `shutdown_graceful()`
+            let res = match self.listeners.get(listener) {
+                Some(s) => s.accept(),
+                None => break,
+            };
+            match res {
+                Ok(Some((s, addr))) => {
+                    self.configure_socket(&s, &addr);
+                    self.connections_new.push_back((s, addr, listener));
+                    self.counters.accepted += 1;
                 }
                 Ok(None) => break,
                 Err(err) => {
                     error!("error accepting connection: {}", err);
                     self.shutdown_with_err(Error::from(err));
+                    break;
                 }
             }
         }
     }
 
     fn register_new_connections(&mut self, evloop: &mut EventLoop<Self>) {
-        while let Some(conn) = self.connections_new.pop_front() {
-            match self.connections.insert_with(
-                move |token| Connection::new(token, conn, ConnectionState::ReadSize)
-            ) {
+        let max_msg_size = self.max_msg_size;
+        let shard = self.shard;
+        while let Some((conn, addr, listener)) = self.connections_new.pop_front() {
+            match self.insert_connection(move |token| {
+                let mut c = Connection::new(token, Some(conn), ConnectionState::ReadSize, addr, max_msg_size, shard);
+                c.uid.listener = Some(listener);
+                c
+            }) {
                 Some(token) => {
+                    #[cfg(feature = "async-tls")]
+                    {
+                        if let Some(TlsConfig(ref config)) = self.tls {
+                            self.connections[token].tls = Some(Box::new(rustls::ServerSession::new(config)));
+                        }
+                    }
+                    let uid = self.connections[token].uid.clone();
                     if let Err(err) = self.connections[token].register(evloop) {
                         error!("could not register new connection on event loop");
-                        self.shutdown_with_err(Error::from(err));
+                        self.shutdown_with_err(err.on(uid));
+                    }
+                    if let Some(timeout) = self.idle_timeout {
+                        self.arm_idle_timer(evloop, uid, timeout);
                     }
                     let mut h = self.handler.take().unwrap();
-                    let uid = self.connections[token].uid.clone();
                     debug!("new connection {:?}", uid);
                     h.connection(&mut ServerControl::new(self, evloop), uid);
                     self.handler = Some(h);
                 }
                 None => {
                     error!("cannot accept new connection: limit reached");
+                    let mut h = self.handler.take().unwrap();
+                    h.accept_rejected(&mut ServerControl::new(self, evloop), &addr, Error::ConnectionLimit);
+                    self.handler = Some(h);
                 }
             }
         }
     }
 
+    // insert into the slab, growing it if full and under the limit
+    fn insert_connection<F>(&mut self, f: F) -> Option<Token> where F: FnOnce(Token) -> Connection {
+        let count = self.connections.count();
+        if !self.connections.has_remaining() && count < self.connection_limit {
+            let grow = cmp::max(cmp::min(count, self.connection_limit - count), 1);
+            debug!("growing connection slab from {} to {}", count, count + grow);
+            self.connections.grow(grow);
+        }
+        self.connections.insert_with(f)
+    }
+
     fn reregister_connections(&mut self, evloop: &mut EventLoop<Self>) {
         let mut uids = self.connections_reregister.take().unwrap();
-        for uid in uids.drain() {
-            if let Err(err) = self.connections[uid].reregister(evloop) {
+        for token in uids.drain() {
+            if let Err(err) = self.connections[token].reregister(evloop) {
                 error!("could not reregister connection on event loop");
-                self.shutdown_with_err(Error::from(err));
+                let uid = self.connections[token].uid;
+                self.shutdown_with_err(err.on(uid));
             }
         }
         self.connections_reregister = Some(uids);
@@ -149,37 +802,247 @@ impl<H: ServerHandler> Server<H> {
     fn remove_closed_connections(&mut self, evloop: &mut EventLoop<Self>) {
         let mut to_close = self.connections_closed.take().unwrap();
         for token in to_close.drain() {
-            if let Some(ref mut c) = self.connections.remove(token) {
+            if let Some(mut c) = self.connections.remove(token) {
                 if let Err(err) = c.deregister(evloop) {
                     error!("could not deregister connection from event loop");
-                    self.shutdown_with_err(Error::from(err));
+                    self.shutdown_with_err(err.on(c.uid));
                 }
+                let uid = c.uid;
+                self.closing = Some((uid, c.data.take()));
+                drop(c);
                 let mut h = self.handler.take().unwrap();
-                h.connection_closed(&mut ServerControl::new(self, evloop), &c.uid);
+                h.connection_closed(&mut ServerControl::new(self, evloop), &uid);
                 self.handler = Some(h);
+                self.closing = None;
             }
         }
         self.connections_closed = Some(to_close);
     }
 
+    // schedule the next attempt of a failed connect, or give up
+    // calling connect_failed() and freeing its slot. The socket stays
+    // registered (but disarmed) until it is replaced on retry
+    fn retry_or_fail(&mut self, evloop: &mut EventLoop<Self>, token: Token) {
+        let uid = self.connections[token].uid;
+        let delay = match self.connections[token].retry {
+            Some((ref policy, ref mut attempts)) => {
+                *attempts += 1;
+                policy.retry_delay(*attempts)
+            }
+            None => None,
+        };
+        if let Some(delay) = delay {
+            let ms = duration_ms(delay);
+            debug!("retrying connect to {} in {}ms", uid.addr, ms);
+            if evloop.timeout_ms(ServerTimeout::Retry(uid), ms).is_ok() {
+                return;
+            }
+        }
+        if let Err(err) = self.connections[token].deregister(evloop) {
+            error!("could not deregister connection from event loop");
+            self.shutdown_with_err(err.on(uid));
+        }
+        let mut h = self.handler.take().unwrap();
+        h.connect_failed(&mut ServerControl::new(self, evloop), &uid);
+        self.handler = Some(h);
+        self.connections.remove(token);
+    }
+
+    fn close_drained_connections(&mut self) {
+        for c in self.connections.iter() {
+            // queued counts what a TLS session holds too, which is
+            // already out of to_send
+            let drained = match c.state {
+                ConnectionState::Connecting(_) => true,
+                _ => c.queued == 0,
+            };
+            if drained {
+                self.connections_closed.as_mut().unwrap().insert(c.token);
+            }
+        }
+    }
+
+    // deliver the messages of a connection until its reads would
+    // block or are paused
+    fn read_messages(&mut self, evloop: &mut EventLoop<Self>, client: Token) {
+        let uid = self.connections[client].uid;
+        loop {
+            if self.connections[client].reads_paused {
+                self.connections_reregister.as_mut().unwrap().insert(client);
+                break;
+            }
+            let start = self.hooks.start();
+            match self.connections[client].read_msg() {
+                Ok(ReadResult::Msg(size)) => {
+                    self.hooks.received(start, size);
+                    self.counters.messages_in += 1;
+                    self.counters.bytes_in += size as u64;
+                    // the handler borrows the message where it was
+                    // read, the buffer is out of the connection meanwhile
+                    let buf = mem::replace(&mut self.connections[client].buf, ReadBuf::empty());
+                    let mut h = self.handler.take().unwrap();
+                    h.message(&mut ServerControl::new(self, evloop), &uid, buf.taken(size));
+                    self.handler = Some(h);
+                    // closed connections stay until the end of the tick
+                    self.connections[client].buf = buf;
+                }
+                Ok(ReadResult::None) => {
+                    self.connections_reregister.as_mut().unwrap().insert(client);
+                    break;
+                }
+                Ok(ReadResult::Closed) => {
+                    self.connections_closed.as_mut().unwrap().insert(client);
+                    break;
+                }
+                Err(Error::MsgTooBig(size)) => {
+                    debug!("message of {} bytes from {:?} is too big", size, uid.addr);
+                    self.connections_closed.as_mut().unwrap().insert(client);
+                    let mut h = self.handler.take().unwrap();
+                    h.protocol_error(&mut ServerControl::new(self, evloop), &uid, Error::MsgTooBig(size).on(uid));
+                    self.handler = Some(h);
+                    break;
+                }
+                Err(e) => {
+                    debug!("read error from {:?}: {:?}", uid.addr, e);
+                    self.connection_errors.push_back((uid, e));
+                    self.connections_closed.as_mut().unwrap().insert(client);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn clear_dropped_timers(&mut self, evloop: &mut EventLoop<Self>) {
+        let dropped = mem::replace(&mut *self.dropped_timers.lock().unwrap(), Vec::new());
+        for uid in dropped {
+            if let Some(timer) = self.timers.remove(&uid) {
+                evloop.clear_timeout(timer.current);
+            }
+        }
+    }
+
+    // connections resumed by the handler may have whole messages
+    // buffered already, with nothing new to read
+    fn read_resumed(&mut self, evloop: &mut EventLoop<Self>) {
+        while let Some(uid) = self.connections_resumed.pop_front() {
+            match self.connections.get(uid.token) {
+                Some(c) if c.uid == uid && !c.reads_paused => {}
+                _ => continue,
+            }
+            self.read_messages(evloop, uid.token);
+        }
+    }
+
+    fn udp_ready(&mut self, evloop: &mut EventLoop<Self>, token: Token) {
+        let mut buf = mem::replace(&mut self.udp_buf, Vec::new());
+        loop {
+            let res = match self.udp.get(&token) {
+                Some(socket) => socket.recv_from(&mut buf),
+                // closed by the handler
+                None => break,
+            };
+            match res {
+                Ok(Some((len, from))) => {
+                    let uid = UdpUid {
+                        token: token,
+                    };
+                    let mut h = self.handler.take().unwrap();
+                    h.datagram(&mut ServerControl::new(self, evloop), &uid, &from, &buf[..len]);
+                    self.handler = Some(h);
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    debug!("udp receive error: {:?}", err);
+                    break;
+                }
+            }
+        }
+        self.udp_buf = buf;
+    }
+
+    // a single timer per connection, rearmed when it fires for the
+    // time left since the last read
+    fn arm_idle_timer(&mut self, evloop: &mut EventLoop<Self>, uid: ConnectionUid, delay: Duration) {
+        if let Err(err) = evloop.timeout_ms(ServerTimeout::Idle(uid), duration_ms(delay)) {
+            error!("could not set idle timer: {:?}", err);
+        }
+    }
+
+    fn notify_connection_errors(&mut self, evloop: &mut EventLoop<Self>) {
+        while let Some((uid, err)) = self.connection_errors.pop_front() {
+            let mut h = self.handler.take().unwrap();
+            h.connection_error(&mut ServerControl::new(self, evloop), &uid, err.on(uid));
+            self.handler = Some(h);
+        }
+    }
+
+    fn tick_started(&mut self) {
+        if self.tick_start.is_none() {
+            self.tick_start = Some(Instant::now());
+        }
+    }
+
+    fn notify_watermarks(&mut self, evloop: &mut EventLoop<Self>) {
+        while let Some((uid, blocked)) = self.write_notices.pop_front() {
+            match self.connections.get(uid.token) {
+                Some(c) if c.uid == uid => {}
+                _ => continue,
+            }
+            let mut h = self.handler.take().unwrap();
+            if blocked {
+                h.writable_blocked(&mut ServerControl::new(self, evloop), &uid);
+            } else {
+                h.writable_resumed(&mut ServerControl::new(self, evloop), &uid);
+            }
+            self.handler = Some(h);
+        }
+    }
+
+    fn notify_sent(&mut self, evloop: &mut EventLoop<Self>) {
+        while let Some((uid, token)) = self.sent_notices.pop_front() {
+            let mut h = self.handler.take().unwrap();
+            h.sent(&mut ServerControl::new(self, evloop), &uid, token);
+            self.handler = Some(h);
+        }
+    }
+
     fn shutdown_with_err(&mut self, err: Error) {
         self.shutdown_error = Some(err);
     }
 }
 
 pub enum ServerTimeout<T> {
-    User(T),
+    /// A timer of `ServerControl::timeout_ms()/timeout_at()`
+    Timer(TimerUid),
+    /// Next attempt of a `connect_with_retry()`
+    Retry(ConnectionUid),
+    /// Next tick of a `timer_interval()`
+    Interval(IntervalUid),
+    /// End of a `shutdown_graceful()`
+    Drain,
+    /// Next `ServerHandler::stats()` call
+    Stats,
+    /// Check whether a connection has been idle for the
+    /// `ServerBuilder::idle_timeout()`
+    Idle(ConnectionUid),
 }
 
 impl<H: ServerHandler> Handler for Server<H> {
-    type Message = H::Message;
+    type Message = ServerMsg<H::Message>;
     type Timeout = ServerTimeout<H::Timeout>;
 
     fn ready(&mut self, evloop: &mut EventLoop<Self>, token: Token, events: EventSet) {
+        self.tick_started();
         match token {
-            SERVER => {
+            udp if udp.0 >= UDP_TOKENS => {
+                self.udp_ready(evloop, udp);
+            }
+            listener if listener.0 >= LISTENER_TOKENS => {
+                if self.draining {
+                    return;
+                }
                 if events.is_readable() {
-                    self.accept();
+                    self.accept(listener.0 - LISTENER_TOKENS);
                 } else {
                     panic!("invalid event set for server socket: {:?}", events);
                 }
@@ -191,11 +1054,7 @@ impl<H: ServerHandler> Handler for Server<H> {
                     debug!("hup/error event for {:?}", uid);
                     match self.connections[client].state {
                         ConnectionState::Connecting(_) => {
-                            let uid = self.connections[client].uid;
-                            self.connections[client].deregister(evloop).unwrap();
-                            let mut h = self.handler.take().unwrap();
-                            h.connect_failed(&mut ServerControl::new(self, evloop), &uid);
-                            self.handler = Some(h);
+                            self.retry_or_fail(evloop, client);
                         }
                         _ => {self.connections_closed.as_mut().unwrap().insert(client);}
                     }
@@ -203,44 +1062,35 @@ impl<H: ServerHandler> Handler for Server<H> {
                 }
                 // readable
                 if events.is_readable() {
-                    loop {
-                        match self.connections[client].read_msg() {
-                            Ok(ReadResult::Msg(msg)) => {
-                                let mut h = self.handler.take().unwrap();
-                                h.message(&mut ServerControl::new(self, evloop), &uid, msg);
-                                self.handler = Some(h);
-                            }
-                            Ok(ReadResult::None) => {
-                                self.connections_reregister.as_mut().unwrap().insert(client);
-                                break;
-                            }
-                            Ok(ReadResult::Closed) => {
-                                self.connections_closed.as_mut().unwrap().insert(client);
-                                break;
-                            }
-                            Err(e) => {
-                                debug!("read error from {:?}: {:?}", uid.addr, e);
-                                self.connections_closed.as_mut().unwrap().insert(client);
-                                break;
-                            }
-                        }
-                    }
+                    self.read_messages(evloop, client);
                 }
                 // writable
                 if events.is_writable(){
                     if let ConnectionState::Connecting(addr) = self.connections[client].state {
                         debug!("connected to {}", addr);
                         self.connections[client].state = ConnectionState::ReadSize;
-                        self.connections[client].interest.insert(EventSet::readable());
+                        self.connections[client].connected_at = Some(SystemTime::now());
+                        if !self.connections[client].reads_paused {
+                            self.connections[client].interest.insert(EventSet::readable());
+                        }
                         let uid = self.connections[client].uid;
+                        self.connections[client].last_read = Instant::now();
+                        if let Some(timeout) = self.idle_timeout {
+                            self.arm_idle_timer(evloop, uid, timeout);
+                        }
                         let mut h = self.handler.take().unwrap();
                         h.connection(&mut ServerControl::new(self, evloop), uid);
                         self.handler = Some(h);
                     }
                     if let Err(e) = self.connections[client].write() {
                         debug!("write error for {:?}: {:?}", uid.addr, e);
+                        self.connection_errors.push_back((uid, e));
                         self.connections_closed.as_mut().unwrap().insert(client);
                     } else {
+                        if let Some(blocked) = self.connections[client].cross_watermarks(self.watermarks) {
+                            self.write_notices.push_back((uid, blocked));
+                        }
+                        self.connections[client].take_sent(&mut self.sent_notices);
                         self.connections_reregister.as_mut().unwrap().insert(client);
                     }
                 }
@@ -250,19 +1100,124 @@ impl<H: ServerHandler> Handler for Server<H> {
 
     #[allow(unused_variables)]
     fn notify(&mut self, evloop: &mut EventLoop<Self>, msg: Self::Message) {
-        let mut h = self.handler.take().unwrap();
-        h.notify(&mut ServerControl::new(self, evloop), msg);
-        self.handler = Some(h);
+        self.tick_started();
+        match msg {
+            ServerMsg::User(msg) => {
+                let mut h = self.handler.take().unwrap();
+                h.notify(&mut ServerControl::new(self, evloop), msg);
+                self.handler = Some(h);
+            }
+            ServerMsg::Send(uid, msg) => {
+                ServerControl::new(self, evloop).send_owned(&uid, msg);
+            }
+            ServerMsg::Shutdown => {
+                self.shutdown = true;
+            }
+        }
     }
 
     #[allow(unused_variables)]
     fn timeout(&mut self, evloop: &mut EventLoop<Self>, timeout: Self::Timeout) {
+        self.tick_started();
         match timeout {
-            ServerTimeout::User(timeout) => {
+            ServerTimeout::Timer(uid) => {
+                // its handle may have been dropped earlier in this tick
+                self.clear_dropped_timers(evloop);
+                let timeout = match self.timers.remove(&uid) {
+                    Some(timer) => timer.timeout,
+                    None => return,
+                };
                 let mut h = self.handler.take().unwrap();
                 h.timeout(&mut ServerControl::new(self, evloop), timeout);
                 self.handler = Some(h);
             }
+            ServerTimeout::Interval(uid) => {
+                let (timeout, rearmed) = match self.intervals.get_mut(&uid) {
+                    Some(interval) => {
+                        // rearm first, so the handler can cancel it
+                        let delay = interval.advance();
+                        let rearmed = match evloop.timeout_ms(ServerTimeout::Interval(uid), delay) {
+                            Ok(current) => {
+                                interval.current = current;
+                                true
+                            }
+                            Err(err) => {
+                                error!("could not rearm interval timer: {:?}", err);
+                                false
+                            }
+                        };
+                        ((interval.timeout)(), rearmed)
+                    }
+                    // cancelled meanwhile
+                    None => return,
+                };
+                if !rearmed {
+                    self.intervals.remove(&uid);
+                }
+                let mut h = self.handler.take().unwrap();
+                h.timeout(&mut ServerControl::new(self, evloop), timeout);
+                self.handler = Some(h);
+            }
+            ServerTimeout::Stats => {
+                if let Some(interval) = self.stats_interval {
+                    if let Err(err) = evloop.timeout_ms(ServerTimeout::Stats, duration_ms(interval)) {
+                        error!("could not rearm stats timer: {:?}", err);
+                    }
+                }
+                let mut h = self.handler.take().unwrap();
+                let mut server = ServerControl::new(self, evloop);
+                let stats = server.stats();
+                h.stats(&mut server, &stats);
+                self.handler = Some(h);
+            }
+            ServerTimeout::Idle(uid) => {
+                let timeout = self.idle_timeout.unwrap();
+                let idle = match self.connections.get(uid.token) {
+                    Some(c) if c.uid == uid => {
+                        if c.reads_paused {
+                            Duration::from_secs(0)
+                        } else {
+                            c.last_read.elapsed()
+                        }
+                    }
+                    // closed meanwhile
+                    _ => return,
+                };
+                if idle >= timeout {
+                    debug!("closing idle connection {:?}", uid);
+                    self.connections_closed.as_mut().unwrap().insert(uid.token);
+                } else {
+                    self.arm_idle_timer(evloop, uid, timeout - idle);
+                }
+            }
+            ServerTimeout::Drain => {
+                debug!("graceful shutdown timed out, closing the remaining connections");
+                for c in self.connections.iter() {
+                    self.connections_closed.as_mut().unwrap().insert(c.token);
+                }
+                self.shutdown = true;
+            }
+            ServerTimeout::Retry(uid) => {
+                match self.connections.get(uid.token) {
+                    Some(conn) if conn.uid == uid => {}
+                    // closed meanwhile
+                    _ => return,
+                }
+                match TcpStream::connect(&uid.addr) {
+                    Ok(socket) => {
+                        self.configure_socket(&socket, &uid.addr);
+                        self.connections[uid.token].reset_socket(socket);
+                        if let Err(err) = self.connections[uid.token].register(evloop) {
+                            error!("could not register socket on event loop");
+                            self.shutdown_with_err(err.on(uid));
+                        }
+                    }
+                    Err(err) => {
+                        debug!("could not connect to {}: {:?}", uid.addr, err);
+                        self.retry_or_fail(evloop, uid.token);
+                    }
+                }
+            }
         }
     }
 
@@ -272,8 +1227,25 @@ impl<H: ServerHandler> Handler for Server<H> {
 
     fn tick(&mut self, evloop: &mut EventLoop<Self>) {
         self.register_new_connections(evloop);
+        self.clear_dropped_timers(evloop);
+        // before reregistering, for what the handler sends when resumed
+        self.notify_watermarks(evloop);
+        self.notify_sent(evloop);
+        self.read_resumed(evloop);
         self.reregister_connections(evloop);
+        if self.draining {
+            self.close_drained_connections();
+        }
+        self.notify_connection_errors(evloop);
         self.remove_closed_connections(evloop);
+        if self.draining && self.connections.count() == 0 {
+            self.shutdown = true;
+        }
+        if let Some(start) = self.tick_start.take() {
+            let elapsed = start.elapsed();
+            self.counters.last_tick = elapsed;
+            self.counters.max_tick = cmp::max(self.counters.max_tick, elapsed);
+        }
         // shutdown check should be the last thing here, to catch the ServerHandler request for shutdown
         if self.shutdown {
             evloop.shutdown();
@@ -284,6 +1256,14 @@ impl<H: ServerHandler> Handler for Server<H> {
     }
 }
 
+/// Message with its size header, as written
+fn encode_msg(msg: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(MSG_HDR_SIZE + msg.len());
+    frame.extend_from_slice(&endian::u32_bytes(msg.len() as u32));
+    frame.extend_from_slice(msg);
+    frame
+}
+
 /// Used to control the async server: send messages, schedule
 /// timeouts, close connections, shutdown and so on
 pub struct ServerControl<'a, H: 'a + ServerHandler> {
@@ -301,24 +1281,107 @@ impl<'a, H: ServerHandler> ServerControl<'a, H>{
     }
     /// Send a msg to the destination. Will ignore a non existing connection
     pub fn send(&mut self, uid: &ConnectionUid, msg: &[u8]) {
+        self.send_frame(uid, &encode_msg(msg));
+    }
+    /// Same as `send()`, moving `msg` into the connection's send queue
+    /// instead of copying it, if it can't be written right away (for
+    /// big messages)
+    pub fn send_owned(&mut self, uid: &ConnectionUid, msg: Vec<u8>) {
+        let len = msg.len();
+        self.send_with(uid, len, move |c| c.send_buf(SendBuf::Owned(msg, 0)));
+    }
+    /// Same as `send_owned()`, for a buffer shared between many sends
+    /// (e.g. the same message to many connections)
+    pub fn send_shared(&mut self, uid: &ConnectionUid, msg: Arc<Vec<u8>>) {
+        let len = msg.len();
+        self.send_with(uid, len, move |c| c.send_buf(SendBuf::Shared(msg, 0)));
+    }
+    /// Same as `send_shared()`, for a `Bytes` (e.g. a slice of a bigger
+    /// buffer)
+    pub fn send_bytes(&mut self, uid: &ConnectionUid, msg: Bytes) {
+        let len = msg.len();
+        self.send_with(uid, len, move |c| c.send_buf(SendBuf::Bytes(msg, 0)));
+    }
+    /// Same as `send()`, calling `ServerHandler::sent()` with `token`
+    /// once the message is written to the socket (not if the
+    /// connection is closed before that)
+    pub fn send_tracked(&mut self, uid: &ConnectionUid, msg: &[u8], token: u64) {
+        self.send(uid, msg);
+        if let Some(c) = self.server.connections.get_mut(uid.token) {
+            if &c.uid == uid {
+                c.track(token);
+                c.take_sent(&mut self.server.sent_notices);
+            }
+        }
+    }
+    fn send_frame(&mut self, uid: &ConnectionUid, frame: &[u8]) {
+        self.send_with(uid, frame.len() - MSG_HDR_SIZE, |c| c.send_frame(frame));
+    }
+    fn send_with<F>(&mut self, uid: &ConnectionUid, len: usize, send: F)
+        where F: FnOnce(&mut Connection) -> Result<bool, Error> {
         if let Some(c) = self.server.connections.get_mut(uid.token) {
             if &c.uid == uid {
-                match c.send_msg(msg) {
+                let start = self.server.hooks.start();
+                let res = send(c);
+                if res.is_ok() {
+                    self.server.hooks.sent(start, len);
+                    self.server.counters.messages_out += 1;
+                    self.server.counters.bytes_out += len as u64;
+                    // the handler is notified after its current call
+                    if let Some(blocked) = c.cross_watermarks(self.server.watermarks) {
+                        self.server.write_notices.push_back((c.uid, blocked));
+                    }
+                }
+                match res {
                     Ok(false) => {self.server.connections_reregister.as_mut().unwrap().insert(uid.token);}
-                    Err(_) => {self.server.connections_closed.as_mut().unwrap().insert(uid.token);}
+                    Err(e) => {
+                        self.server.connection_errors.push_back((*uid, e));
+                        self.server.connections_closed.as_mut().unwrap().insert(uid.token);
+                    }
                     Ok(true) => (), // message already sent, no need to reregister
                 }
             }
         }
     }
+    /// Send a message serialized with `codec` to the destination (see
+    /// `send()`). Received ones can be decoded with `Codec::decode`.
+    /// Only fails if the message can't be serialized.
+    #[cfg(feature = "codec")]
+    pub fn send_msg<C: Codec, M: Serialize>(&mut self, uid: &ConnectionUid, codec: &C, msg: &M)
+                                            -> Result<(), NetError> {
+        let frame = try!(codec.encode(msg));
+        self.send(uid, &frame);
+        Ok(())
+    }
     /// Send a msg to the destination. Will ignore non existing connections
     pub fn multicast(&mut self, uids: &mut Iterator<Item=&ConnectionUid>, msg: &[u8]) {
+        let frame = encode_msg(msg);
         for uid in uids {
-            self.send(uid, msg);
+            self.send_frame(uid, &frame);
+        }
+    }
+    /// Send a msg to every established connection
+    pub fn broadcast(&mut self, msg: &[u8]) {
+        self.broadcast_frame(None, msg);
+    }
+    /// Same as `broadcast()`, skipping `uid` (e.g. the sender of a
+    /// message being relayed)
+    pub fn broadcast_except(&mut self, uid: &ConnectionUid, msg: &[u8]) {
+        self.broadcast_frame(Some(uid), msg);
+    }
+    fn broadcast_frame(&mut self, except: Option<&ConnectionUid>, msg: &[u8]) {
+        let uids: Vec<ConnectionUid> = self.server.connections.iter()
+            .filter(|c| match c.state {
+                ConnectionState::Connecting(_) => false,
+                _ => except != Some(&c.uid),
+            })
+            .map(|c| c.uid)
+            .collect();
+        let frame = encode_msg(msg);
+        for uid in &uids {
+            self.send_frame(uid, &frame);
         }
     }
-
-    // TODO: implement connect with retry policies?
 
     /// Asynchronously connect to the given address. An `Ok(uid)`
     /// result *does not* mean the connection was successful. The
@@ -327,12 +1390,11 @@ impl<'a, H: ServerHandler> ServerControl<'a, H>{
     /// success/failure
     pub fn connect(&mut self, addr: SocketAddr) -> Result<ConnectionUid, Error> {
         let conn = try!(TcpStream::connect(&addr));
-        let token = try!(match self.server.connections.insert_with(
-            move |token| Connection::new(token, conn, ConnectionState::Connecting(addr))
-        ) {
-            Some(token) => Ok(token),
-            None => Err(Error::ConnectionLimit),
-        });
+        self.connect_socket(addr, conn)
+    }
+    fn connect_socket(&mut self, addr: SocketAddr, conn: TcpStream) -> Result<ConnectionUid, Error> {
+        self.server.configure_socket(&conn, &addr);
+        let token = try!(self.insert_connecting(addr, Some(conn)));
         self.server.connections[token].interest.insert(EventSet::writable() | EventSet::error());
         try!(self.server.connections[token].register(self.evloop).or_else(|e| {
             self.server.connections.remove(token);
@@ -341,28 +1403,344 @@ impl<'a, H: ServerHandler> ServerControl<'a, H>{
         }));
         Ok(self.server.connections[token].uid)
     }
+    /// Same as `connect()`, over TLS. `hostname` is checked against
+    /// the server's certificate.
+    #[cfg(feature = "async-tls")]
+    pub fn connect_tls(&mut self, addr: SocketAddr, config: Arc<rustls::ClientConfig>, hostname: &str)
+                       -> Result<ConnectionUid, Error> {
+        let uid = try!(self.connect(addr));
+        self.server.connections[uid.token].tls = Some(Box::new(rustls::ClientSession::new(&config, hostname)));
+        Ok(uid)
+    }
+    /// Same as `connect()`, retrying as set by `policy` when the
+    /// connection fails: `connection()` is called once an attempt
+    /// succeeds, and `connect_failed()` only after the policy gives
+    /// up. All attempts share the returned `uid`
+    pub fn connect_with_retry(&mut self, addr: SocketAddr, policy: ReconnectPolicy)
+                              -> Result<ConnectionUid, Error> {
+        let err = match TcpStream::connect(&addr) {
+            Ok(conn) => {
+                let uid = try!(self.connect_socket(addr, conn));
+                self.server.connections[uid.token].retry = Some((policy, 0));
+                return Ok(uid);
+            }
+            Err(err) => err,
+        };
+        // the first attempt failed right away, the policy decides
+        // whether to wait for the next one without a socket
+        let delay = match policy.retry_delay(1) {
+            Some(delay) => delay,
+            None => return Err(Error::Io(err)),
+        };
+        debug!("could not connect to {}: {:?}, retrying in {:?}", addr, err, delay);
+        let token = try!(self.insert_connecting(addr, None));
+        let uid = self.server.connections[token].uid;
+        if let Err(err) = self.evloop.timeout_ms(ServerTimeout::Retry(uid), duration_ms(delay)) {
+            self.server.connections.remove(token);
+            return Err(Error::from(err));
+        }
+        self.server.connections[token].retry = Some((policy, 1));
+        Ok(uid)
+    }
+    fn insert_connecting(&mut self, addr: SocketAddr, socket: Option<TcpStream>) -> Result<Token, Error> {
+        let max_msg_size = self.server.max_msg_size;
+        let shard = self.server.shard;
+        match self.server.insert_connection(
+            move |token| Connection::new(token, socket, ConnectionState::Connecting(addr), addr, max_msg_size, shard)
+        ) {
+            Some(token) => Ok(token),
+            None => Err(Error::ConnectionLimit),
+        }
+    }
+    /// Bind a UDP socket on the event loop: datagrams received are
+    /// passed to `ServerHandler::datagram()`
+    pub fn bind_udp(&mut self, addr: &SocketAddr) -> Result<UdpUid, Error> {
+        let socket = try!(UdpSocket::bound(addr));
+        let token = Token(self.server.next_udp);
+        try!(self.evloop.register(&socket, token, EventSet::readable(), PollOpt::edge()));
+        self.server.next_udp += 1;
+        self.server.udp.insert(token, socket);
+        if self.server.udp_buf.is_empty() {
+            self.server.udp_buf = vec![0; MAX_DATAGRAM_SIZE];
+        }
+        Ok(UdpUid {
+            token: token,
+        })
+    }
+    /// Send a datagram from a socket of `bind_udp()`. It's dropped
+    /// (with an error) if it can't be sent right away
+    pub fn send_datagram(&mut self, udp: &UdpUid, to: &SocketAddr, data: &[u8]) -> Result<(), Error> {
+        let socket = match self.server.udp.get(&udp.token) {
+            Some(socket) => socket,
+            None => return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "udp socket is closed"))),
+        };
+        match try!(socket.send_to(data, to)) {
+            Some(_) => Ok(()),
+            None => Err(Error::Io(io::Error::new(io::ErrorKind::WouldBlock, "udp socket is not ready"))),
+        }
+    }
+    /// Local address of a socket of `bind_udp()` (e.g. when bound to
+    /// port 0)
+    pub fn udp_local_addr(&self, udp: &UdpUid) -> Result<SocketAddr, Error> {
+        match self.server.udp.get(&udp.token) {
+            Some(socket) => socket.local_addr().or_else(|err| Err(Error::from(err))),
+            None => Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "udp socket is closed"))),
+        }
+    }
+    /// Close a socket of `bind_udp()`
+    pub fn close_udp(&mut self, udp: &UdpUid) {
+        if let Some(socket) = self.server.udp.remove(&udp.token) {
+            self.evloop.deregister(&socket).is_ok();
+        }
+    }
+    /// Addresses, age and state of a connection, `None` if it's
+    /// closed
+    pub fn connection_info(&self, uid: &ConnectionUid) -> Option<ConnectionInfo> {
+        match self.server.connections.get(uid.token) {
+            Some(c) if &c.uid == uid => Some(ConnectionInfo {
+                peer_addr: c.uid.addr,
+                local_addr: c.socket.as_ref().and_then(|s| s.local_addr().ok()),
+                connected_at: c.connected_at,
+                queued_bytes: c.queued,
+                state: match c.state {
+                    ConnectionState::Connecting(_) => ConnectionStatus::Connecting,
+                    _ if c.blocked => ConnectionStatus::Blocked,
+                    _ => ConnectionStatus::Open,
+                },
+            }),
+            _ => None,
+        }
+    }
+    /// Stop reading from a connection (no more `message()` calls for
+    /// it) until `resume_reads()`, leaving what the peer sends in the
+    /// socket buffers. The idle timeout doesn't run meanwhile.
+    pub fn pause_reads(&mut self, uid: &ConnectionUid) {
+        if let Some(c) = self.server.connections.get_mut(uid.token) {
+            if &c.uid == uid && !c.reads_paused {
+                c.reads_paused = true;
+                c.interest.remove(EventSet::readable());
+                self.server.connections_reregister.as_mut().unwrap().insert(uid.token);
+            }
+        }
+    }
+    pub fn resume_reads(&mut self, uid: &ConnectionUid) {
+        if let Some(c) = self.server.connections.get_mut(uid.token) {
+            if &c.uid == uid && c.reads_paused {
+                c.reads_paused = false;
+                // the idle timeout starts over, the pause isn't the
+                // peer's doing
+                c.last_read = Instant::now();
+                if let ConnectionState::Connecting(_) = c.state {
+                    return;
+                }
+                c.interest.insert(EventSet::readable());
+                self.server.connections_reregister.as_mut().unwrap().insert(uid.token);
+                self.server.connections_resumed.push_back(*uid);
+            }
+        }
+    }
+    /// Attach handler state to a connection, replacing any previous
+    /// one. It can still be read or taken from
+    /// `ServerHandler::connection_closed()`, and is dropped after it.
+    /// Returns false if the connection is already closed.
+    pub fn set_data<T: Any>(&mut self, uid: &ConnectionUid, data: T) -> bool {
+        match self.server.connections.get_mut(uid.token) {
+            Some(c) if &c.uid == uid => {
+                c.data = Some(Box::new(data));
+                true
+            }
+            _ => false,
+        }
+    }
+    /// The state attached with `set_data()`, if it is a `T`
+    pub fn data<T: Any>(&self, uid: &ConnectionUid) -> Option<&T> {
+        self.data_slot(uid).and_then(|data| data.as_ref()).and_then(|data| data.downcast_ref())
+    }
+    /// Same as `data()`, mutably, also during `connection_closed()`
+    pub fn data_mut<T: Any>(&mut self, uid: &ConnectionUid) -> Option<&mut T> {
+        self.data_slot_mut(uid).and_then(|data| data.as_mut()).and_then(|data| data.downcast_mut())
+    }
+    /// Detach the state of a connection
+    pub fn take_data<T: Any>(&mut self, uid: &ConnectionUid) -> Option<T> {
+        match self.data_slot_mut(uid) {
+            Some(slot) if slot.as_ref().map_or(false, |data| data.is::<T>()) => {
+                slot.take().and_then(|data| data.downcast().ok()).map(|data| *data)
+            }
+            _ => None,
+        }
+    }
+    // the state of an open connection, or of the one being closed
+    fn data_slot(&self, uid: &ConnectionUid) -> Option<&Option<Box<Any>>> {
+        if let Some((ref closing, ref data)) = self.server.closing {
+            if closing == uid {
+                return Some(data);
+            }
+        }
+        match self.server.connections.get(uid.token) {
+            Some(c) if &c.uid == uid => Some(&c.data),
+            _ => None,
+        }
+    }
+    fn data_slot_mut(&mut self, uid: &ConnectionUid) -> Option<&mut Option<Box<Any>>> {
+        if let Some((ref closing, ref mut data)) = self.server.closing {
+            if closing == uid {
+                return Some(data);
+            }
+        }
+        match self.server.connections.get_mut(uid.token) {
+            Some(c) if &c.uid == uid => Some(&mut c.data),
+            _ => None,
+        }
+    }
+    /// Connection and traffic totals
+    pub fn stats(&self) -> ServerStats {
+        let counters = &self.server.counters;
+        let mut stats = ServerStats {
+            connections: 0,
+            accepted: counters.accepted,
+            messages_in: counters.messages_in,
+            bytes_in: counters.bytes_in,
+            messages_out: counters.messages_out,
+            bytes_out: counters.bytes_out,
+            queued_bytes: 0,
+            max_queued_bytes: 0,
+            last_tick: counters.last_tick,
+            max_tick: counters.max_tick,
+        };
+        for c in self.server.connections.iter() {
+            stats.connections += 1;
+            stats.queued_bytes += c.queued;
+            stats.max_queued_bytes = cmp::max(stats.max_queued_bytes, c.queued);
+        }
+        stats
+    }
     /// Close the connection
     pub fn close_connection(&mut self, uid: ConnectionUid) {
-        self.server.connections_closed.as_mut().unwrap().insert(uid.token);
+        // the token may be reused already
+        match self.server.connections.get(uid.token) {
+            Some(c) if c.uid == uid => {self.server.connections_closed.as_mut().unwrap().insert(uid.token);}
+            _ => (),
+        }
     }
     /// Shutdown the server
     pub fn shutdown(&mut self) {
         self.server.shutdown = true;
     }
+    /// Close the listeners, and shutdown the server once the messages
+    /// queued to the existing connections are written (closing each
+    /// as it's done), or after `timeout` ms
+    pub fn shutdown_graceful(&mut self, timeout: u64) -> Result<(), Error> {
+        if self.server.draining {
+            return Ok(());
+        }
+        try!(self.evloop.timeout_ms(ServerTimeout::Drain, timeout).or_else(|err| Err(Error::from(err))));
+        // close the listening sockets, so new clients are refused
+        // instead of waiting in the backlog
+        for s in self.server.listeners.drain(..) {
+            self.evloop.deregister(&s).is_ok();
+        }
+        self.server.connections_new.clear();
+        self.server.draining = true;
+        Ok(())
+    }
     /// Get a channel for notify msgs
     pub fn notify_channel(&mut self) -> Sender<H::Message> {
-        self.evloop.channel()
+        Sender {
+            sender: self.evloop.channel(),
+        }
+    }
+    /// The servers of the `ShardedServer` this one is part of
+    pub fn shards(&self) -> Option<ShardHandle<H::Message>> {
+        self.server.shards.clone()
     }
-    /// Schedule a timeout event
-    pub fn timeout_ms(&mut self, timeout: H::Timeout, delay: u64) -> Result<TimeoutUid, Error> {
-        self.evloop.timeout_ms(ServerTimeout::User(timeout), delay).or_else(|err| Err(Error::from(err)))
+    /// Schedule a timeout event in `delay` ms. It's cancelled if the
+    /// returned handle is dropped, see `TimerHandle::detach()`
+    pub fn timeout_ms(&mut self, timeout: H::Timeout, delay: u64) -> Result<TimerHandle, Error> {
+        let uid = TimerUid(self.server.next_timer);
+        let current = try!(self.evloop.timeout_ms(ServerTimeout::Timer(uid), delay)
+                           .or_else(|err| Err(Error::from(err))));
+        self.server.next_timer += 1;
+        self.server.timers.insert(uid, Timer {
+            timeout: timeout,
+            current: current,
+        });
+        Ok(TimerHandle {
+            uid: uid,
+            dropped: Some(self.server.dropped_timers.clone()),
+        })
+    }
+    /// Same as `timeout_ms()`, with the timeout due at `deadline`
+    /// (right away if it's already past)
+    pub fn timeout_at(&mut self, timeout: H::Timeout, deadline: Instant) -> Result<TimerHandle, Error> {
+        let delay = deadline_ms(deadline);
+        self.timeout_ms(timeout, delay)
     }
     /// Cancel a scheduled timeout
-    pub fn timeout_cancel(&mut self, timeout: TimeoutUid) {
-        self.evloop.clear_timeout(timeout);
+    pub fn timeout_cancel(&mut self, handle: TimerHandle) {
+        if let Some(timer) = self.server.timers.remove(&handle.uid) {
+            self.evloop.clear_timeout(timer.current);
+        }
+        handle.detach();
+    }
+    /// Move a scheduled timeout to `deadline`. `Ok(false)` if it
+    /// already fired (or was cancelled)
+    pub fn timeout_reschedule(&mut self, handle: &TimerHandle, deadline: Instant) -> Result<bool, Error> {
+        let timer = match self.server.timers.get_mut(&handle.uid) {
+            Some(timer) => timer,
+            None => return Ok(false),
+        };
+        self.evloop.clear_timeout(timer.current);
+        timer.current = try!(self.evloop.timeout_ms(ServerTimeout::Timer(handle.uid), deadline_ms(deadline))
+                             .or_else(|err| Err(Error::from(err))));
+        Ok(true)
+    }
+    /// Schedule a timeout event every `period` ms, until cancelled
+    /// with `interval_cancel()`. Ticks are kept on the original
+    /// schedule even if one fires late, skipping those missed
+    pub fn timer_interval(&mut self, timeout: H::Timeout, period: u64) -> Result<IntervalUid, Error>
+        where H::Timeout: Clone + 'static {
+        let uid = IntervalUid(self.server.next_interval);
+        let current = try!(self.evloop.timeout_ms(ServerTimeout::Interval(uid), period)
+                           .or_else(|err| Err(Error::from(err))));
+        self.server.next_interval += 1;
+        let period = Duration::from_millis(period);
+        self.server.intervals.insert(uid, Interval {
+            timeout: Box::new(move || timeout.clone()),
+            period: period,
+            next: Instant::now() + period,
+            current: current,
+        });
+        Ok(uid)
+    }
+    /// Stop a timer set by `timer_interval()`
+    pub fn interval_cancel(&mut self, uid: IntervalUid) {
+        if let Some(interval) = self.server.intervals.remove(&uid) {
+            self.evloop.clear_timeout(interval.current);
+        }
     }
 }
 
+/// See `ServerControl::connection_info()`
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer_addr: SocketAddr,
+    pub local_addr: Option<SocketAddr>,
+    /// `None` until an outgoing connection is established
+    pub connected_at: Option<SystemTime>,
+    /// Bytes waiting to be written
+    pub queued_bytes: usize,
+    pub state: ConnectionStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Outgoing, not established yet
+    Connecting,
+    Open,
+    /// Over the high watermark, see `ServerHandler::writable_blocked()`
+    Blocked,
+}
+
 enum ConnectionState {
     Connecting(SocketAddr),
     ReadSize,
@@ -372,16 +1750,44 @@ enum ConnectionState {
 struct Connection {
     uid: ConnectionUid,
     state: ConnectionState,
-    buf: RingBuf,
-    to_send: VecDeque<ByteBuf>, // TODO: copy into a single buffer to avoid calling read multiple times?
+    buf: ReadBuf,
+    max_msg_size: usize,
+    // policy and failed attempts of connect_with_retry()
+    retry: Option<(ReconnectPolicy, u32)>,
+    to_send: VecDeque<SendBuf>, // TODO: copy into a single buffer to avoid calling read multiple times?
+    // bytes left in to_send, plus tls_pending
+    queued: usize,
+    // bytes written out so far
+    written: u64,
+    // tokens of send_tracked(), with the value of written once their
+    // message is out
+    tracked: VecDeque<(u64, u64)>,
+    // queued went over the high watermark, and not yet to the low one
+    blocked: bool,
+    connected_at: Option<SystemTime>,
+    // set by the handler, see ServerControl::set_data()
+    data: Option<Box<Any>>,
+    // see ServerControl::pause_reads()
+    reads_paused: bool,
+    // for the idle timeout
+    last_read: Instant,
+    // to_send is written through it, once the handshake is done
+    #[cfg(feature = "async-tls")]
+    tls: Option<Box<Session>>,
+    // bytes fed to the TLS session since it last had no records left
+    // to write
+    #[cfg(feature = "async-tls")]
+    tls_pending: usize,
     token: Token,
-    socket: TcpStream,
+    // None while waiting to retry a connect that failed right away
+    socket: Option<TcpStream>,
     interest: EventSet,
 }
 
 enum ReadResult {
-    // read a message
-    Msg(Vec<u8>),
+    // read a message of this size, the last bytes taken from the
+    // connection's buffer
+    Msg(usize),
     // would block
     None,
     // connection closed
@@ -389,27 +1795,79 @@ enum ReadResult {
 }
 
 
+/// A buffer queued to be sent, and how much of it was already written
+enum SendBuf {
+    Owned(Vec<u8>, usize),
+    Shared(Arc<Vec<u8>>, usize),
+    Bytes(Bytes, usize),
+}
+
+impl SendBuf {
+    fn bytes(&self) -> &[u8] {
+        match *self {
+            SendBuf::Owned(ref buf, pos) => &buf[pos..],
+            SendBuf::Shared(ref buf, pos) => &buf[pos..],
+            SendBuf::Bytes(ref buf, pos) => &buf[pos..],
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes().len()
+    }
+
+    fn advance(&mut self, n: usize) {
+        match *self {
+            SendBuf::Owned(_, ref mut pos) | SendBuf::Shared(_, ref mut pos) |
+            SendBuf::Bytes(_, ref mut pos) => *pos += n,
+        }
+    }
+}
+
+/// Identifies a UDP socket of `ServerControl::bind_udp()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UdpUid {
+    token: Token,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 /// Unique id identifying a given connection.
 pub struct ConnectionUid {
     id: u32,
     token: Token,
     addr: SocketAddr,
+    shard: usize,
+    listener: Option<usize>,
+}
+
+impl ConnectionUid {
+    /// Address of the peer
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// Index of the server with this connection in a `ShardedServer`
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+    /// Index (in `ServerBuilder::bind_all()`) of the address the
+    /// connection was accepted on, `None` for outgoing ones
+    pub fn listener(&self) -> Option<usize> {
+        self.listener
+    }
 }
 
 impl Connection {
-    fn new(token: Token, socket: TcpStream, state: ConnectionState) -> Self {
-        socket.set_nodelay(true).unwrap();
-        let addr;
+    fn new(token: Token, socket: Option<TcpStream>, state: ConnectionState, addr: SocketAddr,
+           max_msg_size: usize, shard: usize) -> Self {
         let interest;
+        let connected_at;
         match state {
-            ConnectionState::Connecting(_addr) => {
-                addr = _addr;
+            ConnectionState::Connecting(_) => {
                 interest = EventSet::writable() | EventSet::error() | EventSet::hup();
+                connected_at = None;
             }
             ConnectionState::ReadSize => {
-                addr = socket.peer_addr().unwrap();
                 interest = EventSet::readable() | EventSet::error() | EventSet::hup();
+                connected_at = Some(SystemTime::now());
             }
             _ => {
                 panic!("invalid initial connection state");
@@ -420,56 +1878,84 @@ impl Connection {
                 id: rand::random::<u32>(),
                 token: token,
                 addr: addr,
+                shard: shard,
+                listener: None,
             },
             state: state,
-            buf: RingBuf::new(MAX_MSG_SIZE+MSG_HDR_SIZE),
+            buf: ReadBuf::new(),
+            max_msg_size: max_msg_size,
+            retry: None,
             to_send: VecDeque::new(),
+            queued: 0,
+            blocked: false,
+            written: 0,
+            tracked: VecDeque::new(),
+            connected_at: connected_at,
+            data: None,
+            reads_paused: false,
+            last_read: Instant::now(),
+            #[cfg(feature = "async-tls")]
+            tls: None,
+            #[cfg(feature = "async-tls")]
+            tls_pending: 0,
             token: token,
             socket: socket,
             interest: interest,
         }
     }
 
+    // replace the socket of a failed connect with a new attempt
+    fn reset_socket(&mut self, socket: TcpStream) {
+        self.socket = Some(socket);
+        self.interest = EventSet::writable() | EventSet::error() | EventSet::hup();
+    }
+
+    // registration is skipped while there is no socket, until the
+    // next connect attempt
     fn register<H: ServerHandler>(&mut self, evloop: &mut EventLoop<Server<H>>) -> Result<(), Error> {
-        try!(evloop.register(&self.socket, self.token, self.interest, PollOpt::edge() | PollOpt::oneshot()));
+        if let Some(ref socket) = self.socket {
+            try!(evloop.register(socket, self.token, self.interest, PollOpt::edge() | PollOpt::oneshot()));
+        }
         Ok(())
     }
 
     fn reregister<H: ServerHandler>(&mut self, evloop: &mut EventLoop<Server<H>>) -> Result<(), Error> {
-        try!(evloop.reregister(&self.socket, self.token, self.interest, PollOpt::edge() | PollOpt::oneshot()));
+        if let Some(ref socket) = self.socket {
+            try!(evloop.reregister(socket, self.token, self.interest, PollOpt::edge() | PollOpt::oneshot()));
+        }
         Ok(())
     }
 
     fn deregister<H: ServerHandler>(&mut self, evloop: &mut EventLoop<Server<H>>) -> Result<(), Error> {
-        try!(evloop.deregister(&self.socket));
+        if let Some(ref socket) = self.socket {
+            try!(evloop.deregister(socket));
+        }
         Ok(())
     }
 
-    // try to parse a message from the connection buffer
-    fn try_parse_msg(&mut self) -> Option<Vec<u8>> {
+    // try to parse a message from the connection buffer, failing if
+    // it couldn't fit in it
+    fn try_parse_msg(&mut self) -> Result<Option<usize>, Error> {
         loop {
             match self.state {
                 ConnectionState::ReadSize => {
-                    if self.buf.remaining() >= 4 {
-                        let size = network_to_u32(self.buf.bytes()) as usize;
-                        self.buf.advance(4);
+                    if self.buf.remaining() >= MSG_HDR_SIZE {
+                        let size = endian::read_u32(self.buf.take(MSG_HDR_SIZE)) as usize;
+                        if size > self.max_msg_size {
+                            return Err(Error::MsgTooBig(size));
+                        }
                         self.state = ConnectionState::ReadData(size);
                     } else {
-                        return None;
+                        return Ok(None);
                     }
                 }
                 ConnectionState::ReadData(size) => {
                     if self.buf.remaining() >= size {
                         self.state = ConnectionState::ReadSize;
-                        // TODO: use a fixed buf/vec in the connection
-                        // to avoid allocation for every msg (passing
-                        // slice to the handler)
-                        let mut msg = Vec::with_capacity(size);
-                        let r = self.buf.try_read_buf(&mut msg).unwrap().unwrap();
-                        debug_assert_eq!(size, r);
-                        return Some(msg);
+                        self.buf.take(size);
+                        return Ok(Some(size));
                     } else {
-                        return None;
+                        return Ok(None);
                     }
                 }
                 _ => {
@@ -481,20 +1967,28 @@ impl Connection {
 
     // try to read a whole message
     fn read_msg(&mut self) -> Result<ReadResult, Error> {
-        if let Some(msg) = self.try_parse_msg() {
-            return Ok(ReadResult::Msg(msg));
+        if let Some(size) = try!(self.try_parse_msg()) {
+            return Ok(ReadResult::Msg(size));
         }
         loop {
-            match self.socket.try_read_buf(&mut self.buf) {
+            // room for the whole message being read
+            let needed = match self.state {
+                ConnectionState::ReadData(size) => size,
+                _ => MSG_HDR_SIZE,
+            };
+            match self.read_some(needed) {
                 Ok(None) => {
+                    self.buf.shrink();
                     return Ok(ReadResult::None);
                 }
                 Ok(Some(r)) => {
                     if r == 0 {
                         return Ok(ReadResult::Closed);
                     }
-                    if let Some(msg) = self.try_parse_msg() {
-                        return Ok(ReadResult::Msg(msg));
+                    self.buf.filled(r);
+                    self.last_read = Instant::now();
+                    if let Some(size) = try!(self.try_parse_msg()) {
+                        return Ok(ReadResult::Msg(size));
                     }
                 }
                 Err(e) => {
@@ -504,22 +1998,63 @@ impl Connection {
         }
     }
 
-    // Result(true) if the message has already been written out (no
+    // Result(true) if the frame has already been written out (no
     // need to reregister the connection)
-    fn send_msg(&mut self, msg: &[u8]) -> Result<bool, Error> {
-        let hdr: &[u8];
-        let len = (msg.len() as u32).to_be();
-        unsafe {
-            hdr = slice::from_raw_parts((&len as *const u32) as *const u8, 4);
-        }
-        let mut buf = ByteBuf::mut_with_capacity(MSG_HDR_SIZE + msg.len());
-        buf.write_slice(hdr);
-        buf.write_slice(msg);
-        let mut buf = buf.flip();
-        // try to write immediatelly
-        while buf.remaining() != 0 {
-            match self.socket.try_write_buf(&mut buf) {
-                Ok(Some(_)) => (),
+    fn send_frame(&mut self, frame: &[u8]) -> Result<bool, Error> {
+        #[cfg(feature = "async-tls")]
+        {
+            if self.tls.is_some() {
+                self.queue(frame);
+                try!(self.write_tls());
+                return Ok(!self.interest.is_writable());
+            }
+        }
+        let written = try!(self.write_now(frame));
+        if written < frame.len() {
+            self.queue(&frame[written..]);
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    // same as send_frame(), moving the message into the queue instead
+    // of copying it (only the header is)
+    fn send_buf(&mut self, msg: SendBuf) -> Result<bool, Error> {
+        let hdr = endian::u32_bytes(msg.remaining() as u32);
+        let hdr_sent = try!(self.send_frame(&hdr));
+        #[cfg(feature = "async-tls")]
+        {
+            if self.tls.is_some() {
+                self.push(msg);
+                try!(self.write_tls());
+                return Ok(!self.interest.is_writable());
+            }
+        }
+        let mut msg = msg;
+        let written = try!(self.write_now(msg.bytes()));
+        msg.advance(written);
+        if msg.remaining() > 0 {
+            self.push(msg);
+            Ok(false)
+        } else {
+            Ok(hdr_sent)
+        }
+    }
+
+    // try to write immediatelly, unless behind queued ones
+    fn write_now(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let socket = match self.socket {
+            Some(ref mut socket) => socket,
+            None => return Ok(0),
+        };
+        let mut written = 0;
+        while self.to_send.is_empty() && written < data.len() {
+            match socket.try_write(&data[written..]) {
+                Ok(Some(n)) => {
+                    written += n;
+                    self.written += n as u64;
+                }
                 Ok(None) => break, // retry later
                 Err(e) => {
                     debug!("write error for {:?}: {:?}", self.uid.addr, e);
@@ -527,19 +2062,105 @@ impl Connection {
                 }
             }
         }
-        if buf.remaining() != 0 {
-            self.to_send.push_back(buf);
-            self.interest.insert(EventSet::writable());
-            Ok(false)
-        } else {
-            Ok(true)
+        Ok(written)
+    }
+
+    fn queue(&mut self, data: &[u8]) {
+        self.push(SendBuf::Owned(data.to_vec(), 0));
+    }
+
+    fn push(&mut self, buf: SendBuf) {
+        self.queued += buf.remaining();
+        self.to_send.push_back(buf);
+        self.interest.insert(EventSet::writable());
+    }
+
+    // read into the buffer, decrypted for TLS connections
+    fn read_some(&mut self, needed: usize) -> io::Result<Option<usize>> {
+        #[cfg(feature = "async-tls")]
+        {
+            if self.tls.is_some() {
+                return self.read_tls(needed);
+            }
+        }
+        self.socket.as_mut().unwrap().try_read(self.buf.space(needed))
+    }
+
+    #[cfg(feature = "async-tls")]
+    fn read_tls(&mut self, needed: usize) -> io::Result<Option<usize>> {
+        loop {
+            let read = try!(self.tls.as_mut().unwrap().read(self.buf.space(needed)));
+            if read > 0 {
+                return Ok(Some(read));
+            }
+            match self.tls.as_mut().unwrap().read_tls(self.socket.as_mut().unwrap()) {
+                Ok(0) => return Ok(Some(0)),
+                Ok(_) => {
+                    if let Err(err) = self.tls.as_mut().unwrap().process_new_packets() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("tls error: {:?}", err)));
+                    }
+                    // handshake replies, or messages queued until it's done
+                    try!(self.write_tls());
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // write pending TLS records, then feed to_send to the session one
+    // buffer at a time, so what the socket doesn't take stays queued
+    #[cfg(feature = "async-tls")]
+    fn write_tls(&mut self) -> io::Result<()> {
+        let session = self.tls.as_mut().unwrap();
+        let socket = match self.socket {
+            Some(ref mut socket) => socket,
+            None => return Ok(()),
+        };
+        loop {
+            while session.wants_write() {
+                match session.write_tls(socket) {
+                    Ok(_) => (),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        self.interest.insert(EventSet::writable());
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            // all the records of what was fed are out
+            self.queued -= self.tls_pending;
+            self.written += self.tls_pending as u64;
+            self.tls_pending = 0;
+            if session.is_handshaking() {
+                break;
+            }
+            match self.to_send.pop_front() {
+                Some(buf) => {
+                    self.tls_pending = buf.remaining();
+                    try!(session.write_all(buf.bytes()));
+                }
+                None => break,
+            }
         }
+        self.interest.remove(EventSet::writable());
+        Ok(())
     }
 
     fn write(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "async-tls")]
+        {
+            if self.tls.is_some() {
+                return self.write_tls().or_else(|err| Err(Error::from(err)));
+            }
+        }
         while !self.to_send.is_empty() {
-            match self.socket.try_write_buf(&mut self.to_send[0]) {
-                Ok(Some(_)) => (),
+            match self.socket.as_mut().unwrap().try_write(self.to_send[0].bytes()) {
+                Ok(Some(n)) => {
+                    self.queued -= n;
+                    self.written += n as u64;
+                    self.to_send[0].advance(n);
+                }
                 Ok(None) => break, // retry later
                 Err(e) => return Err(Error::from(e)),
             }
@@ -552,6 +2173,34 @@ impl Connection {
         }
         Ok(())
     }
+
+    // the last message queued is tracked with token
+    fn track(&mut self, token: u64) {
+        let end = self.written + self.queued as u64;
+        self.tracked.push_back((end, token));
+    }
+
+    // tokens of the tracked messages written out since the last call
+    fn take_sent(&mut self, out: &mut VecDeque<(ConnectionUid, u64)>) {
+        while self.tracked.front().map_or(false, |&(end, _)| end <= self.written) {
+            let (_, token) = self.tracked.pop_front().unwrap();
+            out.push_back((self.uid, token));
+        }
+    }
+
+    // Some(true) when the queued bytes just went over the high
+    // watermark, Some(false) when they just fell to the low one
+    fn cross_watermarks(&mut self, (high, low): (usize, usize)) -> Option<bool> {
+        if !self.blocked && self.queued > high {
+            self.blocked = true;
+            Some(true)
+        } else if self.blocked && self.queued <= low {
+            self.blocked = false;
+            Some(false)
+        } else {
+            None
+        }
+    }
 }
 
 // TODO: make the ServerHandler methods return Self? Would it facilitate STM-ish implementations?
@@ -571,11 +2220,14 @@ pub trait ServerHandler {
     /// connection.
     fn connection(&mut self, server: &mut ServerControl<Self>, uid: ConnectionUid) where Self: Sized {
     }
-    /// Called on a disconnect
+    /// Called on a disconnect. The state attached by
+    /// `ServerControl::set_data()` can still be taken meanwhile.
     fn connection_closed(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid) where Self: Sized {
     }
-    /// Called when a new network message is received
-    fn message(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, msg: Vec<u8>) where Self: Sized;
+    /// Called when a new network message is received. `msg` is only
+    /// borrowed from the read buffer of the connection, reused for the
+    /// next ones
+    fn message(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, msg: &[u8]) where Self: Sized;
     /// Called when a new notify message is received through the
     /// server's channel (possibly from outside the event loop)
     fn notify(&mut self, server: &mut ServerControl<Self>, msg: Self::Message) where Self: Sized {
@@ -586,8 +2238,241 @@ pub trait ServerHandler {
     /// Called when the server is shutting down.
     fn shutting_down(&mut self, err: Option<Error>) where Self: Sized {
     }
-    /// Called whan a connect fails
+    /// Called when a peer breaks the protocol (e.g. with
+    /// `Error::MsgTooBig`, wrapped in `Error::Connection`), right
+    /// before its connection is closed
+    fn protocol_error(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, err: Error)
+        where Self: Sized {
+    }
+    /// Called when the bytes queued to send to `uid` go over the high
+    /// watermark (see `ServerBuilder::write_watermarks()`), so the
+    /// handler can stop producing for it
+    fn writable_blocked(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid)
+        where Self: Sized {
+    }
+    /// Called when the queued bytes of a blocked `uid` fall to the
+    /// low watermark
+    fn writable_resumed(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid)
+        where Self: Sized {
+    }
+    /// Called once a message of `ServerControl::send_tracked()` is
+    /// written to the socket
+    fn sent(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, token: u64)
+        where Self: Sized {
+    }
+    /// Called when reading from or writing to a connection fails
+    /// (with an `Error::Connection`), right before it's closed
+    fn connection_error(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, err: Error)
+        where Self: Sized {
+    }
+    /// Called whan a connect fails (for `connect_with_retry()`, once
+    /// its policy gives up)
     fn connect_failed(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid)
         where Self: Sized {
     }
+    /// Called when an accepted connection from `addr` is dropped
+    /// (with `Error::ConnectionLimit`)
+    fn accept_rejected(&mut self, server: &mut ServerControl<Self>, addr: &SocketAddr, err: Error)
+        where Self: Sized {
+    }
+    /// Called when a datagram is received on a socket of
+    /// `ServerControl::bind_udp()`
+    fn datagram(&mut self, server: &mut ServerControl<Self>, udp: &UdpUid, from: &SocketAddr, data: &[u8])
+        where Self: Sized {
+    }
+    /// Called every `ServerBuilder::stats_interval()`, e.g. to export
+    /// the counters
+    fn stats(&mut self, server: &mut ServerControl<Self>, stats: &ServerStats) where Self: Sized {
+    }
+}
+
+/// `ServerHandler` exchanging messages of a single type instead of
+/// raw bytes, (de)serialized with a `Codec`. Run it on a `Server`
+/// wrapped in a `Typed`.
+#[cfg(feature = "codec")]
+#[allow(unused_variables)]
+pub trait TypedServerHandler {
+    type Msg: Serialize + DeserializeOwned;
+    type Codec: Codec;
+    type Message: Send;
+    type Timeout;
+    /// See `ServerHandler::init()`
+    fn init(&mut self, server: &mut TypedControl<Self>) where Self: Sized {
+    }
+    /// See `ServerHandler::connection()`
+    fn connection(&mut self, server: &mut TypedControl<Self>, uid: ConnectionUid) where Self: Sized {
+    }
+    /// See `ServerHandler::connection_closed()`
+    fn connection_closed(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid) where Self: Sized {
+    }
+    /// Called with each message received, decoded
+    fn message(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid, msg: Self::Msg) where Self: Sized;
+    /// Called with a message that couldn't be decoded. The connection
+    /// is left open.
+    fn invalid_message(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid, err: NetError)
+        where Self: Sized {
+    }
+    /// See `ServerHandler::notify()`
+    fn notify(&mut self, server: &mut TypedControl<Self>, msg: Self::Message) where Self: Sized {
+    }
+    /// See `ServerHandler::timeout()`
+    fn timeout(&mut self, server: &mut TypedControl<Self>, timeout: Self::Timeout) where Self: Sized {
+    }
+    /// See `ServerHandler::shutting_down()`
+    fn shutting_down(&mut self, err: Option<Error>) where Self: Sized {
+    }
+    /// See `ServerHandler::protocol_error()`
+    fn protocol_error(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid, err: Error)
+        where Self: Sized {
+    }
+    /// See `ServerHandler::writable_blocked()`
+    fn writable_blocked(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid)
+        where Self: Sized {
+    }
+    /// See `ServerHandler::writable_resumed()`
+    fn writable_resumed(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid)
+        where Self: Sized {
+    }
+    /// See `ServerHandler::sent()`
+    fn sent(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid, token: u64)
+        where Self: Sized {
+    }
+    /// See `ServerHandler::connection_error()`
+    fn connection_error(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid, err: Error)
+        where Self: Sized {
+    }
+    /// See `ServerHandler::connect_failed()`
+    fn connect_failed(&mut self, server: &mut TypedControl<Self>, uid: &ConnectionUid)
+        where Self: Sized {
+    }
+    /// See `ServerHandler::accept_rejected()`
+    fn accept_rejected(&mut self, server: &mut TypedControl<Self>, addr: &SocketAddr, err: Error)
+        where Self: Sized {
+    }
+    /// See `ServerHandler::datagram()`
+    fn datagram(&mut self, server: &mut TypedControl<Self>, udp: &UdpUid, from: &SocketAddr, data: &[u8])
+        where Self: Sized {
+    }
+    /// See `ServerHandler::stats()`
+    fn stats(&mut self, server: &mut TypedControl<Self>, stats: &ServerStats) where Self: Sized {
+    }
+}
+
+/// Runs a `TypedServerHandler` as a `ServerHandler`
+#[cfg(feature = "codec")]
+pub struct Typed<H: TypedServerHandler> {
+    handler: H,
+    codec: H::Codec,
+}
+
+#[cfg(feature = "codec")]
+impl<H: TypedServerHandler> Typed<H> {
+    pub fn new(handler: H, codec: H::Codec) -> Self {
+        Typed {
+            handler: handler,
+            codec: codec,
+        }
+    }
+
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+}
+
+/// `ServerControl` (which it derefs to) sending messages of the
+/// handler's type
+#[cfg(feature = "codec")]
+pub struct TypedControl<'a, 'b: 'a, H: 'b + TypedServerHandler> {
+    server: &'a mut ServerControl<'b, Typed<H>>,
+    codec: &'a H::Codec,
+}
+
+#[cfg(feature = "codec")]
+impl<'a, 'b, H: TypedServerHandler> TypedControl<'a, 'b, H> {
+    /// Send a message to the destination (see `ServerControl::send()`).
+    /// Only fails if the message can't be serialized.
+    pub fn send(&mut self, uid: &ConnectionUid, msg: &H::Msg) -> Result<(), NetError> {
+        let frame = try!(self.codec.encode(msg));
+        self.server.send(uid, &frame);
+        Ok(())
+    }
+    /// Send a message to each destination, serialized only once
+    pub fn multicast(&mut self, uids: &mut Iterator<Item=&ConnectionUid>, msg: &H::Msg) -> Result<(), NetError> {
+        let frame = try!(self.codec.encode(msg));
+        self.server.multicast(uids, &frame);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "codec")]
+impl<'a, 'b, H: TypedServerHandler> Deref for TypedControl<'a, 'b, H> {
+    type Target = ServerControl<'b, Typed<H>>;
+    fn deref(&self) -> &Self::Target {
+        &*self.server
+    }
+}
+
+#[cfg(feature = "codec")]
+impl<'a, 'b, H: TypedServerHandler> DerefMut for TypedControl<'a, 'b, H> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.server
+    }
+}
+
+#[cfg(feature = "codec")]
+impl<H: TypedServerHandler> ServerHandler for Typed<H> {
+    type Message = H::Message;
+    type Timeout = H::Timeout;
+    fn init(&mut self, server: &mut ServerControl<Self>) {
+        self.handler.init(&mut TypedControl { server: server, codec: &self.codec });
+    }
+    fn connection(&mut self, server: &mut ServerControl<Self>, uid: ConnectionUid) {
+        self.handler.connection(&mut TypedControl { server: server, codec: &self.codec }, uid);
+    }
+    fn connection_closed(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid) {
+        self.handler.connection_closed(&mut TypedControl { server: server, codec: &self.codec }, uid);
+    }
+    fn message(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, msg: &[u8]) {
+        let mut server = TypedControl { server: server, codec: &self.codec };
+        match self.codec.decode(msg) {
+            Ok(msg) => self.handler.message(&mut server, uid, msg),
+            Err(err) => self.handler.invalid_message(&mut server, uid, err),
+        }
+    }
+    fn notify(&mut self, server: &mut ServerControl<Self>, msg: Self::Message) {
+        self.handler.notify(&mut TypedControl { server: server, codec: &self.codec }, msg);
+    }
+    fn timeout(&mut self, server: &mut ServerControl<Self>, timeout: Self::Timeout) {
+        self.handler.timeout(&mut TypedControl { server: server, codec: &self.codec }, timeout);
+    }
+    fn shutting_down(&mut self, err: Option<Error>) {
+        self.handler.shutting_down(err);
+    }
+    fn protocol_error(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, err: Error) {
+        self.handler.protocol_error(&mut TypedControl { server: server, codec: &self.codec }, uid, err);
+    }
+    fn writable_blocked(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid) {
+        self.handler.writable_blocked(&mut TypedControl { server: server, codec: &self.codec }, uid);
+    }
+    fn writable_resumed(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid) {
+        self.handler.writable_resumed(&mut TypedControl { server: server, codec: &self.codec }, uid);
+    }
+    fn sent(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, token: u64) {
+        self.handler.sent(&mut TypedControl { server: server, codec: &self.codec }, uid, token);
+    }
+    fn connection_error(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid, err: Error) {
+        self.handler.connection_error(&mut TypedControl { server: server, codec: &self.codec }, uid, err);
+    }
+    fn connect_failed(&mut self, server: &mut ServerControl<Self>, uid: &ConnectionUid) {
+        self.handler.connect_failed(&mut TypedControl { server: server, codec: &self.codec }, uid);
+    }
+    fn accept_rejected(&mut self, server: &mut ServerControl<Self>, addr: &SocketAddr, err: Error) {
+        self.handler.accept_rejected(&mut TypedControl { server: server, codec: &self.codec }, addr, err);
+    }
+    fn datagram(&mut self, server: &mut ServerControl<Self>, udp: &UdpUid, from: &SocketAddr, data: &[u8]) {
+        self.handler.datagram(&mut TypedControl { server: server, codec: &self.codec }, udp, from, data);
+    }
+    fn stats(&mut self, server: &mut ServerControl<Self>, stats: &ServerStats) {
+        self.handler.stats(&mut TypedControl { server: server, codec: &self.codec }, stats);
+    }
 }
@@ -0,0 +1,103 @@
+/// Client side of a `sync::Server`: a single connection, received
+/// from a background thread, with the same events as the server.
+
+use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+
+use net::sync::{Server, Event, ServerError, ReconnectPolicy, Uid};
+
+pub struct Client {
+    /// Doesn't listen, only has our connection
+    server: Server,
+    uid: Uid,
+}
+
+impl Client {
+    /// Connect and wait for the connection to be ready, so `send()`
+    /// can be called right away
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client, ServerError> {
+        let mut server = Server::new_multi(&[]);
+        let uid = try!(server.connect(addr));
+        Client::wait_connected(server, uid)
+    }
+
+    /// Same as `connect()`, to a server listening on a unix socket
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Client, ServerError> {
+        let mut server = Server::new_multi(&[]);
+        let uid = try!(server.connect_unix(path));
+        Client::wait_connected(server, uid)
+    }
+
+    /// Same as `connect()`, reconnecting as set by `policy` when the
+    /// connection is lost (see `Server::connect_reconnecting()`).
+    /// `send()` fails between the `Disconnected` and `Connected`
+    /// events.
+    pub fn connect_reconnecting<A: ToSocketAddrs>(addr: A, policy: ReconnectPolicy)
+                                                  -> Result<Client, ServerError> {
+        let mut server = Server::new_multi(&[]);
+        let uid = try!(server.connect_reconnecting(addr, policy));
+        Client::wait_connected(server, uid)
+    }
+
+    fn wait_connected(mut server: Server, uid: Uid) -> Result<Client, ServerError> {
+        match server.next() {
+            Some(Event::Connected(connected)) if connected == uid => {
+                Ok(Client {
+                    server: server,
+                    uid: uid,
+                })
+            }
+            Some(Event::UnexpectedError(err)) => Err(err),
+            _ => Err(ServerError::NotConnected),
+        }
+    }
+
+    /// Uid of the connection in the events
+    pub fn uid(&self) -> Uid {
+        self.uid
+    }
+
+    /// The underlying server, e.g. for `connection_info()`
+    pub fn server(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> Result<(), ServerError> {
+        self.server.send_to(self.uid, data)
+    }
+
+    /// See `Server::try_next_event()`
+    pub fn try_next_event(&mut self) -> Option<Event> {
+        self.server.try_next_event()
+    }
+
+    /// See `Server::next_event_timeout()`
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.server.next_event_timeout(timeout)
+    }
+
+    /// Close the connection. Its `Event::Disconnected` and then
+    /// `Event::Shutdown` are still delivered.
+    pub fn shutdown(&mut self) -> Result<(), ServerError> {
+        self.server.shutdown()
+    }
+}
+
+impl Iterator for Client {
+    type Item = Event;
+    /// Block waiting for the next `Event` (`Recv` from the server, then
+    /// `Disconnected` when it closes the connection), see
+    /// `Server::next()`
+    fn next(&mut self) -> Option<Event> {
+        self.server.next()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.server.shutdown().is_ok();
+    }
+}
@@ -0,0 +1,92 @@
+/// Typed messages over framed streams and servers: each message is
+/// serialized (with serde) into a single frame by a `Codec`. Bincode
+/// is the default, JSON and CBOR are available behind the `json` and
+/// `cbor` features.
+
+use bincode;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
+use serde_json;
+#[cfg(feature = "cbor")]
+use serde_cbor;
+
+use std::io::{Read, Write};
+
+use net::{NetError, Framed};
+
+/// Serialization of messages into frames, shared by `Framed` and the
+/// sync and async servers
+pub trait Codec {
+    fn encode<M: Serialize>(&self, msg: &M) -> Result<Vec<u8>, NetError>;
+    fn decode<M: DeserializeOwned>(&self, frame: &[u8]) -> Result<M, NetError>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<M: Serialize>(&self, msg: &M) -> Result<Vec<u8>, NetError> {
+        bincode::serialize(msg).map_err(|err| NetError::Codec(err))
+    }
+
+    fn decode<M: DeserializeOwned>(&self, frame: &[u8]) -> Result<M, NetError> {
+        bincode::deserialize(frame).map_err(|err| NetError::Codec(err))
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<M: Serialize>(&self, msg: &M) -> Result<Vec<u8>, NetError> {
+        serde_json::to_vec(msg).map_err(|err| NetError::Codec(Box::new(err)))
+    }
+
+    fn decode<M: DeserializeOwned>(&self, frame: &[u8]) -> Result<M, NetError> {
+        serde_json::from_slice(frame).map_err(|err| NetError::Codec(Box::new(err)))
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<M: Serialize>(&self, msg: &M) -> Result<Vec<u8>, NetError> {
+        serde_cbor::to_vec(msg).map_err(|err| NetError::Codec(Box::new(err)))
+    }
+
+    fn decode<M: DeserializeOwned>(&self, frame: &[u8]) -> Result<M, NetError> {
+        serde_cbor::from_slice(frame).map_err(|err| NetError::Codec(Box::new(err)))
+    }
+}
+
+impl<T: Read + Write> Framed<T> {
+    /// Send a message serialized with bincode
+    pub fn send_msg<M: Serialize>(&mut self, msg: &M) -> Result<(), NetError> {
+        self.send_msg_with(&Bincode, msg)
+    }
+
+    /// Receive a message serialized with bincode. Messages that fail
+    /// to deserialize return `NetError::Codec`, the stream can still
+    /// be used.
+    pub fn recv_msg<M: DeserializeOwned>(&mut self) -> Result<M, NetError> {
+        self.recv_msg_with(&Bincode)
+    }
+
+    pub fn send_msg_with<C: Codec, M: Serialize>(&mut self, codec: &C, msg: &M)
+                                                 -> Result<(), NetError> {
+        let frame = try!(codec.encode(msg));
+        self.write_frame(&frame)
+    }
+
+    pub fn recv_msg_with<C: Codec, M: DeserializeOwned>(&mut self, codec: &C)
+                                                        -> Result<M, NetError> {
+        let frame = try!(self.read_frame_ref());
+        codec.decode(frame)
+    }
+}
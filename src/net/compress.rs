@@ -0,0 +1,28 @@
+/// Deflate compression of frame payloads, used by `FramedTcpStream`
+/// when built with the `compression` feature.
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use std::io;
+use std::io::{Read, Write};
+
+use net::NetError;
+
+pub fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), Compression::Default);
+    try!(encoder.write_all(data));
+    encoder.finish()
+}
+
+/// Decompress a payload, failing with `NetError::FrameTooBig` if it
+/// expands to more than `max` bytes
+pub fn decompress(data: &[u8], max: usize) -> Result<Vec<u8>, NetError> {
+    let mut out = vec![];
+    try!(DeflateDecoder::new(data).take(max as u64 + 1).read_to_end(&mut out));
+    if out.len() > max {
+        return Err(NetError::FrameTooBig(out.len()));
+    }
+    Ok(out)
+}
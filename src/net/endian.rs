@@ -0,0 +1,99 @@
+/// Conversions between integers and their network (big-endian)
+/// encoding, plus the LEB128 varints used by `FrameHeader::varint`.
+///
+/// The fixed width readers panic if the slice is shorter than the
+/// integer, like indexing would.
+
+use byteorder::{ByteOrder, BigEndian};
+
+use std::io::Read;
+
+use net::NetError;
+
+/// Longest varint for a u64
+pub const MAX_VARINT_LEN: usize = 10;
+
+pub fn read_u16(bytes: &[u8]) -> u16 {
+    BigEndian::read_u16(bytes)
+}
+
+pub fn read_u32(bytes: &[u8]) -> u32 {
+    BigEndian::read_u32(bytes)
+}
+
+pub fn read_u64(bytes: &[u8]) -> u64 {
+    BigEndian::read_u64(bytes)
+}
+
+pub fn write_u16(bytes: &mut [u8], n: u16) {
+    BigEndian::write_u16(bytes, n)
+}
+
+pub fn write_u32(bytes: &mut [u8], n: u32) {
+    BigEndian::write_u32(bytes, n)
+}
+
+pub fn write_u64(bytes: &mut [u8], n: u64) {
+    BigEndian::write_u64(bytes, n)
+}
+
+pub fn u16_bytes(n: u16) -> [u8; 2] {
+    let mut bytes = [0; 2];
+    write_u16(&mut bytes, n);
+    bytes
+}
+
+pub fn u32_bytes(n: u32) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    write_u32(&mut bytes, n);
+    bytes
+}
+
+pub fn u64_bytes(n: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    write_u64(&mut bytes, n);
+    bytes
+}
+
+/// Appends `n` as a varint: 7 bits per byte, least significant group
+/// first, with the high bit set on all bytes but the last
+pub fn encode_varint(n: u64, buf: &mut Vec<u8>) {
+    let mut v = n;
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Decodes a varint at the start of `bytes`, returning the value and
+/// the number of bytes it took, or `None` if `bytes` ends before it
+/// does
+pub fn decode_varint(bytes: &[u8]) -> Result<Option<(u64, usize)>, NetError> {
+    let mut n = 0u64;
+    for (i, byte) in bytes.iter().take(MAX_VARINT_LEN).enumerate() {
+        n |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((n, i + 1)));
+        }
+    }
+    if bytes.len() >= MAX_VARINT_LEN {
+        Err(NetError::Protocol("varint is too long"))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads a varint one byte at a time
+pub fn read_varint<R: Read>(r: &mut R) -> Result<u64, NetError> {
+    let mut byte = [0; 1];
+    let mut n = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        try!(r.read_exact(&mut byte));
+        n |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(NetError::Protocol("varint is too long"))
+}
@@ -0,0 +1,553 @@
+/// Encoding and decoding of frames over any `Read`/`Write`, shared by
+/// the framed streams.
+///
+/// On the wire a frame is:
+///
+/// - the size header (see `FrameHeader`)
+/// - a flags byte, only when compression or heartbeats are enabled
+/// - a type tag byte, only when tags are enabled
+/// - the payload
+/// - a CRC32 of the payload, only when checksums are enabled
+///
+/// Heartbeats are empty frames with the ping or pong flag set.
+
+use crc::crc32;
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "compression")]
+use net::compress;
+use net::{NetError, FrameFormat, FrameHeader, DEFAULT_MAX_FRAME_SIZE};
+use net::endian;
+
+/// Bits of the flags byte
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_PING: u8 = 2;
+const FLAG_PONG: u8 = 4;
+const FLAGS_CONTROL: u8 = FLAG_PING | FLAG_PONG;
+
+/// Buffered frames are written once they reach this size
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Most recycled buffers kept for reuse
+const MAX_POOLED_BUFFERS: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Framing {
+    /// Format of the size header
+    pub header: FrameHeader,
+    /// Largest frame accepted by the read methods
+    pub max_frame_size: usize,
+    /// Frames are followed by a CRC32 of their contents
+    pub checksum: bool,
+    /// Frames are preceded by a flags byte and the ones with at least
+    /// this size are compressed
+    pub compress_threshold: Option<usize>,
+    /// Ping interval and how many can be missed before the peer is
+    /// considered dead
+    pub heartbeat: Option<(Duration, u32)>,
+    /// Frames carry a type tag byte
+    pub tagged: bool,
+    /// When the last frame was received
+    pub last_seen: Instant,
+    /// Reads fail with `NetError::Closed` once no data frame arrived
+    /// for this long
+    pub idle_timeout: Option<Duration>,
+    /// When the last data frame (not a heartbeat) was received
+    pub last_frame: Instant,
+    /// Taken for each write when other handles of the stream write
+    /// to it too, so frames don't get mixed up
+    pub write_lock: Option<Arc<Mutex<()>>>,
+    /// The stream has a read timeout, so reads failing with
+    /// `WouldBlock` or `TimedOut` return `NetError::Timeout`
+    pub read_timeout: bool,
+    /// Same as `read_timeout`, for writes
+    pub write_timeout: bool,
+    /// Encoded outgoing frames not written yet (see
+    /// `write_frame_buffered`)
+    wbuf: Vec<u8>,
+    /// Frame returned by `read_frame_ref`
+    rbuf: Vec<u8>,
+    /// Recycled buffers for `read_frame`
+    pool: Vec<Vec<u8>>,
+}
+
+impl Framing {
+    pub fn new(header: FrameHeader) -> Self {
+        Framing {
+            header: header,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            checksum: false,
+            compress_threshold: None,
+            heartbeat: None,
+            tagged: false,
+            last_seen: Instant::now(),
+            idle_timeout: None,
+            last_frame: Instant::now(),
+            write_lock: None,
+            read_timeout: false,
+            write_timeout: false,
+            wbuf: vec![],
+            rbuf: vec![],
+            pool: vec![],
+        }
+    }
+
+    /// Read and return the next frame, in a recycled buffer if there
+    /// is one
+    pub fn read_frame<T: Read + Write>(&mut self, r: &mut BufReader<T>) -> Result<Vec<u8>, NetError> {
+        self.read_tagged_frame(r).map(|(_, frame)| frame)
+    }
+
+    /// Same as `read_frame`, also returning the tag of the frame
+    pub fn read_tagged_frame<T: Read + Write>(&mut self, r: &mut BufReader<T>)
+                                              -> Result<(u8, Vec<u8>), NetError> {
+        let mut frame = self.pool.pop().unwrap_or_else(Vec::new);
+        let tag = try!(self.read_frame_to_vec(r, &mut frame));
+        Ok((tag, frame))
+    }
+
+    /// Read the next frame into an internal buffer, valid until the
+    /// next read
+    pub fn read_frame_ref<T: Read + Write>(&mut self, r: &mut BufReader<T>) -> Result<&[u8], NetError> {
+        let mut rbuf = mem::replace(&mut self.rbuf, vec![]);
+        let res = self.read_frame_to_vec(r, &mut rbuf);
+        self.rbuf = rbuf;
+        let _tag = try!(res);
+        Ok(&self.rbuf)
+    }
+
+    /// Keep a buffer (usually a frame returned by `read_frame`) to be
+    /// reused by the next reads
+    pub fn recycle(&mut self, buf: Vec<u8>) {
+        if self.pool.len() < MAX_POOLED_BUFFERS {
+            self.pool.push(buf);
+        }
+    }
+
+    /// Read the next frame into `buf`, replacing its contents, and
+    /// return its tag
+    fn read_frame_to_vec<T: Read + Write>(&mut self, r: &mut BufReader<T>, buf: &mut Vec<u8>)
+                                          -> Result<u8, NetError> {
+        let res = self.read_frame_to_vec_inner(r, buf);
+        res.map_err(|err| err.timed_out(self.read_timeout))
+    }
+
+    fn read_frame_to_vec_inner<T: Read + Write>(&mut self, r: &mut BufReader<T>, buf: &mut Vec<u8>)
+                                                -> Result<u8, NetError> {
+        let (len, flags, tag) = try!(self.read_head(r));
+        buf.clear();
+        buf.resize(len, 0);
+        try!(r.read_exact(buf));
+        try!(self.check_frame(r, buf));
+        if flags & FLAG_COMPRESSED != 0 {
+            *buf = try!(self.decompress(buf));
+        }
+        Ok(tag)
+    }
+
+    /// Whether `buf` starts with a whole (non heartbeat) frame
+    pub fn has_frame(&self, buf: &[u8]) -> bool {
+        let mut r = buf;
+        let len = match self.header.decode(&mut r) {
+            Ok(len) => len,
+            Err(_) => return false,
+        };
+        let mut extra = 0;
+        if self.has_flags() {
+            if r.is_empty() || r[0] & FLAGS_CONTROL != 0 {
+                return false;
+            }
+            extra += 1;
+        }
+        if self.tagged {
+            extra += 1;
+        }
+        if self.checksum {
+            extra += 4;
+        }
+        r.len() >= extra && r.len() - extra >= len
+    }
+
+    /// Decode the first frame of `buf` if it arrived whole, for
+    /// streams read without blocking (the caller keeps the bytes until
+    /// then). Returns how many bytes it took, and the frame unless it
+    /// was a heartbeat (pings are answered on `w`).
+    pub fn decode_frame<W: Write>(&mut self, buf: &[u8], w: &mut W)
+                                  -> Result<Option<(usize, Option<Vec<u8>>)>, NetError> {
+        let mut r = buf;
+        let len = match self.header.decode(&mut r) {
+            Ok(len) => len,
+            Err(_) => return Ok(None),
+        };
+        if len > self.max_frame_size {
+            return Err(NetError::FrameTooBig(len));
+        }
+        let mut extra = if self.checksum { 4 } else { 0 };
+        if self.has_flags() {
+            extra += 1;
+        }
+        if self.tagged {
+            extra += 1;
+        }
+        if r.len() < extra + len {
+            return Ok(None);
+        }
+        let flags = try!(self.read_flags(&mut r));
+        let _tag = try!(self.read_tag(&mut r));
+        let (payload, mut r) = r.split_at(len);
+        try!(self.check_frame(&mut r, payload));
+        let size = buf.len() - r.len();
+        self.last_seen = Instant::now();
+        if flags & FLAGS_CONTROL != 0 {
+            if len != 0 {
+                return Err(NetError::Protocol("heartbeat with a payload"));
+            }
+            if flags & FLAG_PING != 0 {
+                try!(self.write_control(w, FLAG_PONG));
+            }
+            return Ok(Some((size, None)));
+        }
+        self.last_frame = self.last_seen;
+        let frame = if flags & FLAG_COMPRESSED != 0 {
+            try!(self.decompress(payload))
+        } else {
+            let mut frame = self.pool.pop().unwrap_or_else(Vec::new);
+            frame.clear();
+            frame.extend_from_slice(payload);
+            frame
+        };
+        Ok(Some((size, Some(frame))))
+    }
+
+    /// Send a ping, answered by the peer when it reads it
+    pub fn ping<W: Write>(&self, w: &mut W) -> Result<(), NetError> {
+        self.write_control(w, FLAG_PING)
+    }
+
+    /// Error if the peer missed too many heartbeats
+    pub fn check_alive(&self) -> Result<(), NetError> {
+        match self.heartbeat {
+            Some((interval, max_missed)) if self.last_seen.elapsed() >= interval * max_missed => {
+                Err(NetError::PeerDead)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the next frame into the given buffer and return its size
+    pub fn read_frame_into<T: Read + Write>(&mut self, r: &mut BufReader<T>, buf: &mut [u8])
+                                            -> Result<usize, NetError> {
+        let res = self.read_frame_into_inner(r, buf);
+        res.map_err(|err| err.timed_out(self.read_timeout))
+    }
+
+    fn read_frame_into_inner<T: Read + Write>(&mut self, r: &mut BufReader<T>, buf: &mut [u8])
+                                              -> Result<usize, NetError> {
+        let (len, flags, _tag) = try!(self.read_head(r));
+        if flags & FLAG_COMPRESSED != 0 {
+            let mut payload = vec![0; len];
+            try!(r.read_exact(&mut payload));
+            try!(self.check_frame(r, &payload));
+            let msg = try!(self.decompress(&payload));
+            if buf.len() < msg.len() {
+                return Err(NetError::FrameTooBig(msg.len()));
+            }
+            buf[..msg.len()].copy_from_slice(&msg);
+            return Ok(msg.len());
+        }
+        if buf.len() < len {
+            Err(NetError::FrameTooBig(len))
+        } else {
+            try!(r.read_exact(&mut buf[0..len]));
+            try!(self.check_frame(r, &buf[0..len]));
+            Ok(len)
+        }
+    }
+
+    /// Write a frame with its header (and trailer), after any
+    /// buffered ones, in a single write
+    pub fn write_frame<W: Write>(&mut self, w: &mut W, frame: &[u8]) -> Result<(), NetError> {
+        self.write_tagged_frame(w, 0, frame)
+    }
+
+    /// Same as `write_frame`, with the given tag (tags must be enabled)
+    pub fn write_tagged_frame<W: Write>(&mut self, w: &mut W, tag: u8, frame: &[u8])
+                                        -> Result<(), NetError> {
+        try!(self.encode_frame(tag, frame));
+        self.flush(w)
+    }
+
+    /// Encode a frame, without sending it
+    pub fn encode(&mut self, frame: &[u8]) -> Result<Vec<u8>, NetError> {
+        let start = self.wbuf.len();
+        try!(self.encode_frame(0, frame));
+        Ok(self.wbuf.split_off(start))
+    }
+
+    /// Add a frame to the write buffer, only writing it out when the
+    /// buffer gets large
+    pub fn write_frame_buffered<W: Write>(&mut self, w: &mut W, frame: &[u8]) -> Result<(), NetError> {
+        try!(self.encode_frame(0, frame));
+        if self.wbuf.len() >= WRITE_BUFFER_SIZE {
+            try!(self.flush(w));
+        }
+        Ok(())
+    }
+
+    /// Write out the buffered frames. On errors they are dropped,
+    /// since part of them may have been sent.
+    pub fn flush<W: Write>(&mut self, w: &mut W) -> Result<(), NetError> {
+        let res = {
+            let _lock = self.lock_writes();
+            w.write_all(&self.wbuf).and_then(|_| w.flush())
+        };
+        self.wbuf.clear();
+        try!(res.map_err(|err| NetError::from(err).timed_out(self.write_timeout)));
+        Ok(())
+    }
+
+    /// Append the encoded frame to `wbuf`
+    fn encode_frame(&mut self, tag: u8, frame: &[u8]) -> Result<(), NetError> {
+        if tag != 0 && !self.tagged {
+            return Err(NetError::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                   "frame tags are not enabled")));
+        }
+        let compressed = try!(self.compress(frame));
+        let payload = match compressed {
+            Some(ref c) => &c[..],
+            None => frame,
+        };
+        let mut wbuf = mem::replace(&mut self.wbuf, vec![]);
+        let res = self.encode_head(tag, payload.len(), compressed.is_some(), &mut wbuf);
+        if res.is_ok() {
+            wbuf.extend_from_slice(payload);
+            self.encode_tail(payload, &mut wbuf);
+        }
+        self.wbuf = wbuf;
+        res
+    }
+
+    /// Encode what goes around the payload of a frame, so the same
+    /// payload can be written to several streams without copying it.
+    /// Returns the header, the payload if it was compressed, and the
+    /// trailer.
+    pub fn encode_parts(&self, frame: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>, Vec<u8>), NetError> {
+        let compressed = try!(self.compress(frame));
+        let mut head = vec![];
+        let mut tail = vec![];
+        {
+            let payload = match compressed {
+                Some(ref c) => &c[..],
+                None => frame,
+            };
+            try!(self.encode_head(0, payload.len(), compressed.is_some(), &mut head));
+            self.encode_tail(payload, &mut tail);
+        }
+        Ok((head, compressed, tail))
+    }
+
+    /// Append the size header, flags and tag of a frame
+    fn encode_head(&self, tag: u8, len: usize, compressed: bool, buf: &mut Vec<u8>) -> Result<(), NetError> {
+        try!(self.header.encode(len, buf));
+        if self.has_flags() {
+            buf.push(if compressed { FLAG_COMPRESSED } else { 0 });
+        }
+        if self.tagged {
+            buf.push(tag);
+        }
+        Ok(())
+    }
+
+    /// Append the checksum of a frame, if enabled
+    fn encode_tail(&self, payload: &[u8], buf: &mut Vec<u8>) {
+        if self.checksum {
+            buf.extend_from_slice(&endian::u32_bytes(crc32::checksum_ieee(payload)));
+        }
+    }
+
+    /// Write a heartbeat frame, straight to the stream
+    fn write_control<W: Write>(&self, w: &mut W, flag: u8) -> Result<(), NetError> {
+        let mut frame = Vec::with_capacity(16);
+        try!(self.header.encode(0, &mut frame));
+        frame.push(flag);
+        if self.tagged {
+            frame.push(0);
+        }
+        if self.checksum {
+            frame.extend_from_slice(&[0; 4]); // CRC32 of nothing
+        }
+        let _lock = self.lock_writes();
+        try!(w.write_all(&frame).map_err(|err| NetError::from(err).timed_out(self.write_timeout)));
+        Ok(())
+    }
+
+    /// Hold the write lock, if shared
+    pub fn lock_writes(&self) -> Option<MutexGuard<()>> {
+        self.write_lock.as_ref().map(|lock| lock.lock().unwrap())
+    }
+
+    /// The options used by `encode()` and `encode_parts()`
+    pub fn format(&self) -> FrameFormat {
+        FrameFormat {
+            header: self.header,
+            checksum: self.checksum,
+            compress_threshold: self.compress_threshold,
+            flags: self.has_flags(),
+            tagged: self.tagged,
+        }
+    }
+
+    /// Whether frames have a flags byte
+    fn has_flags(&self) -> bool {
+        self.compress_threshold.is_some() || self.heartbeat.is_some()
+    }
+
+    /// Read the size, flags and tag of the next data frame, handling
+    /// the heartbeats before it
+    fn read_head<T: Read + Write>(&mut self, r: &mut BufReader<T>)
+                                  -> Result<(usize, u8, u8), NetError> {
+        loop {
+            try!(self.wait_frame(r));
+            let len = try!(self.read_len(r));
+            let flags = try!(self.read_flags(r));
+            let tag = try!(self.read_tag(r));
+            self.last_seen = Instant::now();
+            if flags & FLAGS_CONTROL == 0 {
+                self.last_frame = self.last_seen;
+                return Ok((len, flags, tag));
+            }
+            if len != 0 {
+                return Err(NetError::Protocol("heartbeat with a payload"));
+            }
+            try!(self.check_frame(r, &[]));
+            if flags & FLAG_PING != 0 {
+                try!(self.write_control(r.get_mut(), FLAG_PONG));
+            }
+        }
+    }
+
+    /// With heartbeats or an idle timeout enabled, wait for the next
+    /// frame to start. Each time the read timeout expires (which should
+    /// be the heartbeat interval) a ping is sent, or without heartbeats
+    /// the timeout is returned. Both rely on the read timeout, so the
+    /// stream is taken to have one.
+    fn wait_frame<T: Read + Write>(&self, r: &mut BufReader<T>) -> Result<(), NetError> {
+        if self.heartbeat.is_none() && self.idle_timeout.is_none() {
+            return Ok(());
+        }
+        loop {
+            let err = match r.fill_buf() {
+                Ok(_) => return Ok(()),
+                Err(err) => NetError::from(err).timed_out(true),
+            };
+            match err {
+                NetError::Timeout => {
+                    try!(self.check_idle());
+                    if self.heartbeat.is_none() {
+                        return Err(NetError::Timeout);
+                    }
+                    try!(self.check_alive());
+                    try!(self.ping(r.get_mut()));
+                }
+                NetError::Io(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                err => return Err(err),
+            }
+        }
+    }
+
+    /// `NetError::Closed` if no frame arrived within the idle timeout
+    fn check_idle(&self) -> Result<(), NetError> {
+        match self.idle_timeout {
+            Some(timeout) if self.last_frame.elapsed() >= timeout => Err(NetError::Closed),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the size header of the next frame
+    fn read_len<R: Read>(&self, r: &mut R) -> Result<usize, NetError> {
+        // only an EOF before the first byte is a clean close
+        let mut first = [0; 1];
+        loop {
+            match r.read(&mut first) {
+                Ok(0) => return Err(NetError::Closed),
+                Ok(_) => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(NetError::from(err)),
+            }
+        }
+        let len = try!(self.header.decode(&mut (&first[..]).chain(r)));
+        if len > self.max_frame_size {
+            return Err(NetError::FrameTooBig(len));
+        }
+        Ok(len)
+    }
+
+    /// Read the flags byte, only sent when compression or heartbeats
+    /// are enabled
+    fn read_flags<R: Read>(&self, r: &mut R) -> Result<u8, NetError> {
+        if !self.has_flags() {
+            return Ok(0);
+        }
+        let mut flags = [0; 1];
+        try!(r.read_exact(&mut flags));
+        if flags[0] & !(FLAG_COMPRESSED | FLAGS_CONTROL) != 0 {
+            return Err(NetError::Protocol("unknown frame flags"));
+        }
+        Ok(flags[0])
+    }
+
+    /// Read the tag byte, only sent when tags are enabled
+    fn read_tag<R: Read>(&self, r: &mut R) -> Result<u8, NetError> {
+        if !self.tagged {
+            return Ok(0);
+        }
+        let mut tag = [0; 1];
+        try!(r.read_exact(&mut tag));
+        Ok(tag[0])
+    }
+
+    /// Read the checksum trailer (if enabled) and check it against
+    /// the frame contents
+    fn check_frame<R: Read>(&self, r: &mut R, frame: &[u8]) -> Result<(), NetError> {
+        if self.checksum {
+            let mut trailer = [0; 4];
+            try!(r.read_exact(&mut trailer));
+            if endian::read_u32(&trailer) != crc32::checksum_ieee(frame) {
+                return Err(NetError::Corrupt);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compressed payload if `frame` is worth compressing
+    #[cfg(feature = "compression")]
+    fn compress(&self, frame: &[u8]) -> Result<Option<Vec<u8>>, NetError> {
+        match self.compress_threshold {
+            Some(threshold) if frame.len() >= threshold => {
+                let compressed = try!(compress::compress(frame));
+                Ok(if compressed.len() < frame.len() { Some(compressed) } else { None })
+            }
+            _ => Ok(None),
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn compress(&self, _frame: &[u8]) -> Result<Option<Vec<u8>>, NetError> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "compression")]
+    fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, NetError> {
+        compress::decompress(payload, self.max_frame_size)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn decompress(&self, _payload: &[u8]) -> Result<Vec<u8>, NetError> {
+        Err(NetError::Protocol("compressed frame without compression support"))
+    }
+}
@@ -0,0 +1,104 @@
+/// Optional handshake before the normal frames: the client sends a
+/// magic number, its protocol version and an auth token, and the
+/// server answers with its version and whether it accepts them.
+///
+/// Both messages are sent as frames (with the stream's options):
+///
+/// - client: magic (4 bytes), version (u32 big-endian), token
+/// - server: magic (4 bytes), version (u32 big-endian), status (u8)
+
+use std::io::{Read, Write};
+
+use net::{NetError, Framed};
+use net::endian;
+
+const MAGIC: &'static [u8; 4] = b"RSFS";
+
+const STATUS_OK: u8 = 0;
+const STATUS_BAD_VERSION: u8 = 1;
+const STATUS_BAD_TOKEN: u8 = 2;
+
+/// Side of the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Why a handshake failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The peer doesn't speak this protocol
+    BadMagic,
+    VersionMismatch { local: u32, remote: u32 },
+    /// The server rejected the client's token (or, on the server, the
+    /// client sent the wrong one)
+    BadToken,
+}
+
+impl<T: Read + Write> Framed<T> {
+    /// Exchange the protocol version and auth token with the peer,
+    /// failing with `NetError::Handshake` if they don't match. Only the
+    /// server checks the token.
+    pub fn handshake(&mut self, role: Role, version: u32, token: &[u8]) -> Result<(), NetError> {
+        match role {
+            Role::Client => {
+                let mut hello = Vec::with_capacity(8 + token.len());
+                hello.extend_from_slice(MAGIC);
+                hello.extend_from_slice(&endian::u32_bytes(version));
+                hello.extend_from_slice(token);
+                try!(self.write_frame(&hello));
+
+                let reply = try!(self.read_frame_ref());
+                if reply.len() != 9 || &reply[..4] != MAGIC {
+                    return Err(NetError::Handshake(HandshakeError::BadMagic));
+                }
+                let remote = endian::read_u32(&reply[4..8]);
+                match reply[8] {
+                    STATUS_OK => Ok(()),
+                    STATUS_BAD_VERSION => Err(NetError::Handshake(HandshakeError::VersionMismatch {
+                        local: version,
+                        remote: remote,
+                    })),
+                    STATUS_BAD_TOKEN => Err(NetError::Handshake(HandshakeError::BadToken)),
+                    _ => Err(NetError::Protocol("invalid handshake status")),
+                }
+            }
+            Role::Server => {
+                let (remote, status) = {
+                    let hello = try!(self.read_frame_ref());
+                    if hello.len() < 8 || &hello[..4] != MAGIC {
+                        return Err(NetError::Handshake(HandshakeError::BadMagic));
+                    }
+                    let remote = endian::read_u32(&hello[4..8]);
+                    let status = if remote != version {
+                        STATUS_BAD_VERSION
+                    } else if !token_eq(&hello[8..], token) {
+                        STATUS_BAD_TOKEN
+                    } else {
+                        STATUS_OK
+                    };
+                    (remote, status)
+                };
+                let mut reply = Vec::with_capacity(9);
+                reply.extend_from_slice(MAGIC);
+                reply.extend_from_slice(&endian::u32_bytes(version));
+                reply.push(status);
+                try!(self.write_frame(&reply));
+                match status {
+                    STATUS_OK => Ok(()),
+                    STATUS_BAD_VERSION => Err(NetError::Handshake(HandshakeError::VersionMismatch {
+                        local: version,
+                        remote: remote,
+                    })),
+                    _ => Err(NetError::Handshake(HandshakeError::BadToken)),
+                }
+            }
+        }
+    }
+}
+
+/// Compare tokens in time independent of where they differ
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
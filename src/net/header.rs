@@ -0,0 +1,134 @@
+/// Length prefix sent before each frame.
+///
+/// By default frames are preceded by a 4 bytes big-endian length, but
+/// the width (2, 4 or 8 bytes), the byte order or a variable length
+/// encoding (LEB128 varint) can be chosen to talk to other services.
+
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+use std::io;
+use std::io::Read;
+use std::u16;
+use std::u32;
+use std::u64;
+use std::usize;
+
+use net::NetError;
+use net::endian;
+
+/// Encoding of the frame length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPrefix {
+    U16,
+    U32,
+    U64,
+    /// 7 bits per byte, least significant group first, with the high
+    /// bit set on all bytes but the last (LEB128)
+    Varint,
+}
+
+/// Byte order of fixed width length prefixes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub prefix: LengthPrefix,
+    /// Ignored for `Varint`
+    pub endianness: Endianness,
+}
+
+impl Default for FrameHeader {
+    /// 4 bytes big-endian length
+    fn default() -> Self {
+        FrameHeader::new(LengthPrefix::U32, Endianness::Big)
+    }
+}
+
+impl FrameHeader {
+    pub fn new(prefix: LengthPrefix, endianness: Endianness) -> Self {
+        FrameHeader {
+            prefix: prefix,
+            endianness: endianness,
+        }
+    }
+
+    pub fn varint() -> Self {
+        FrameHeader::new(LengthPrefix::Varint, Endianness::Big)
+    }
+
+    /// Largest frame length the prefix can carry
+    pub fn max_len(&self) -> u64 {
+        match self.prefix {
+            LengthPrefix::U16 => u16::MAX as u64,
+            LengthPrefix::U32 => u32::MAX as u64,
+            LengthPrefix::U64 | LengthPrefix::Varint => u64::MAX,
+        }
+    }
+
+    /// Appends the header of a frame of `len` bytes to `buf`
+    pub fn encode(&self, len: usize, buf: &mut Vec<u8>) -> Result<(), NetError> {
+        if len as u64 > self.max_len() {
+            return Err(NetError::FrameTooBig(len));
+        }
+        match (self.prefix, self.endianness) {
+            (LengthPrefix::Varint, _) => endian::encode_varint(len as u64, buf),
+            (_, Endianness::Big) => encode_fixed::<BigEndian>(self.prefix, len, buf),
+            (_, Endianness::Little) => encode_fixed::<LittleEndian>(self.prefix, len, buf),
+        }
+        Ok(())
+    }
+
+    /// Reads a header and returns the frame length
+    pub fn decode<R: Read>(&self, r: &mut R) -> Result<usize, NetError> {
+        let len = match (self.prefix, self.endianness) {
+            (LengthPrefix::Varint, _) => try!(endian::read_varint(r)),
+            (_, Endianness::Big) => try!(decode_fixed::<BigEndian, R>(self.prefix, r)),
+            (_, Endianness::Little) => try!(decode_fixed::<LittleEndian, R>(self.prefix, r)),
+        };
+        if len > usize::MAX as u64 {
+            return Err(NetError::FrameTooBig(usize::MAX));
+        }
+        Ok(len as usize)
+    }
+}
+
+fn encode_fixed<B: ByteOrder>(prefix: LengthPrefix, len: usize, buf: &mut Vec<u8>) {
+    let mut bytes = [0; 8];
+    let n = match prefix {
+        LengthPrefix::U16 => {
+            B::write_u16(&mut bytes, len as u16);
+            2
+        }
+        LengthPrefix::U32 => {
+            B::write_u32(&mut bytes, len as u32);
+            4
+        }
+        LengthPrefix::U64 | LengthPrefix::Varint => {
+            B::write_u64(&mut bytes, len as u64);
+            8
+        }
+    };
+    buf.extend_from_slice(&bytes[..n]);
+}
+
+fn decode_fixed<B: ByteOrder, R: Read>(prefix: LengthPrefix, r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    Ok(match prefix {
+        LengthPrefix::U16 => {
+            try!(r.read_exact(&mut bytes[..2]));
+            B::read_u16(&bytes) as u64
+        }
+        LengthPrefix::U32 => {
+            try!(r.read_exact(&mut bytes[..4]));
+            B::read_u32(&bytes) as u64
+        }
+        LengthPrefix::U64 | LengthPrefix::Varint => {
+            try!(r.read_exact(&mut bytes));
+            B::read_u64(&bytes)
+        }
+    })
+}
@@ -1,18 +1,199 @@
+#[cfg(test)]
+mod test;
 pub mod sync;
 pub mod async;
+pub mod endian;
+mod header;
+mod framing;
+#[cfg(feature = "compression")]
+mod compress;
+#[cfg(feature = "tls")]
+mod tls;
+mod udp;
+mod handshake;
+mod ratelimit;
+mod reconnect;
+mod mux;
+mod proxy;
+mod tunnel;
+mod trace;
+mod socket;
+mod readbuf;
+mod tokenset;
+mod client;
+#[cfg(feature = "noise")]
+mod noise;
+#[cfg(feature = "ws")]
+pub mod ws;
+pub mod rpc;
+pub mod pubsub;
+#[cfg(feature = "codec")]
+pub mod codec;
+#[cfg(feature = "codec")]
+mod typed;
 
-use byteorder::{ByteOrder, BigEndian};
+pub use self::header::{FrameHeader, LengthPrefix, Endianness};
+pub use self::udp::{FramedUdpSocket, DEFAULT_MAX_DATAGRAM_SIZE};
+pub use self::handshake::{Role, HandshakeError};
+pub use self::ratelimit::RateLimited;
+pub use self::reconnect::{ReconnectingFramedStream, ReconnectEvent};
+pub use self::mux::Mux;
+pub use self::proxy::{ProxyHeader, read_proxy_header};
+pub use self::tunnel::ProxyKind;
+pub use self::trace::{FrameHooks, FrameHook, FrameEvent, Direction};
+#[cfg(feature = "noise")]
+pub use self::noise::{NoiseStream, NoisePattern, NoiseKeypair};
+#[cfg(feature = "codec")]
+pub use self::codec::{Codec, Bincode};
+#[cfg(feature = "json")]
+pub use self::codec::Json;
+#[cfg(feature = "cbor")]
+pub use self::codec::Cbor;
+#[cfg(feature = "tls")]
+pub use self::tls::FramedTlsStream;
 
+use self::framing::Framing;
+
+use net2::TcpStreamExt;
+
+#[cfg(feature = "tls")]
+use native_tls;
+
+use std::borrow::Cow;
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::io;
-use std::io::{BufReader, Write, Read};
-use std::net::{TcpStream, Shutdown};
+use std::io::{BufRead, BufReader, IoSlice, Read, Write};
+use std::net::{TcpStream, Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default limit on the size of received frames
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
 
-const HDR: usize = 4;
+/// Payload size of the frames sent by `write_frame_from_reader`
+const TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+
+/// First byte of transfer chunks: whether more chunks follow
+const CHUNK_LAST: u8 = 0;
+const CHUNK_MORE: u8 = 1;
+
+/// Most bytes taken by each read of `read_available_frames`
+const AVAILABLE_READ_SIZE: usize = 16 * 1024;
 
 #[derive(Debug)]
 pub enum NetError {
     Io(io::Error),
     FrameTooBig(usize),
+    /// A read or write timeout expired
+    Timeout,
+    /// The peer closed the connection (between frames)
+    Closed,
+    /// The checksum of a received frame doesn't match its contents
+    Corrupt,
+    /// The peer sent something that isn't a valid frame
+    Protocol(&'static str),
+    /// The peer missed too many heartbeats
+    PeerDead,
+    /// The peer was rejected during the handshake (or rejected us)
+    Handshake(HandshakeError),
+    /// Error on the connection with the given peer
+    Peer(SocketAddr, Box<NetError>),
+    /// The TLS handshake failed
+    #[cfg(feature = "tls")]
+    Tls(native_tls::Error),
+    /// A message couldn't be (de)serialized
+    #[cfg(feature = "codec")]
+    Codec(Box<error::Error + Send + Sync>),
+}
+
+impl NetError {
+    /// Add the address of the peer to the error
+    pub fn with_peer(self, addr: SocketAddr) -> NetError {
+        match self {
+            NetError::Peer(_, err) => NetError::Peer(addr, err),
+            err => NetError::Peer(addr, Box::new(err)),
+        }
+    }
+
+    /// The error without the added context
+    pub fn root(&self) -> &NetError {
+        match *self {
+            NetError::Peer(_, ref err) => err.root(),
+            ref err => err,
+        }
+    }
+
+    /// `NetError::Timeout` if this is the error of an expired socket
+    /// timeout (either kind, depending on the platform) and `timeout`
+    /// tells one was set. Otherwise, e.g. on non-blocking sockets, the
+    /// error is left as is.
+    fn timed_out(self, timeout: bool) -> NetError {
+        if let NetError::Io(ref err) = self {
+            match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut if timeout => return NetError::Timeout,
+                _ => {}
+            }
+        }
+        self
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetError::Io(ref err) => write!(f, "io error: {}", err),
+            NetError::FrameTooBig(len) => write!(f, "frame of {} bytes is too big", len),
+            NetError::Timeout => write!(f, "timed out"),
+            NetError::Closed => write!(f, "connection closed by the peer"),
+            NetError::Corrupt => write!(f, "frame checksum mismatch"),
+            NetError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            NetError::PeerDead => write!(f, "peer stopped answering heartbeats"),
+            NetError::Handshake(HandshakeError::BadMagic) => write!(f, "handshake failed: unknown protocol"),
+            NetError::Handshake(HandshakeError::VersionMismatch { local, remote }) => {
+                write!(f, "handshake failed: protocol version {} (peer has {})", local, remote)
+            }
+            NetError::Handshake(HandshakeError::BadToken) => write!(f, "handshake failed: bad token"),
+            NetError::Peer(addr, ref err) => write!(f, "{} (peer {})", err, addr),
+            #[cfg(feature = "tls")]
+            NetError::Tls(ref err) => write!(f, "tls error: {}", err),
+            #[cfg(feature = "codec")]
+            NetError::Codec(ref err) => write!(f, "codec error: {}", err),
+        }
+    }
+}
+
+impl error::Error for NetError {
+    fn description(&self) -> &str {
+        match *self {
+            NetError::Io(ref err) => err.description(),
+            NetError::FrameTooBig(_) => "frame too big",
+            NetError::Timeout => "timed out",
+            NetError::Closed => "connection closed",
+            NetError::Corrupt => "frame checksum mismatch",
+            NetError::Protocol(msg) => msg,
+            NetError::PeerDead => "peer stopped answering heartbeats",
+            NetError::Handshake(_) => "handshake failed",
+            NetError::Peer(_, ref err) => err.description(),
+            #[cfg(feature = "tls")]
+            NetError::Tls(ref err) => err.description(),
+            #[cfg(feature = "codec")]
+            NetError::Codec(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            NetError::Io(ref err) => Some(err),
+            NetError::Peer(_, ref err) => Some(&**err),
+            #[cfg(feature = "tls")]
+            NetError::Tls(ref err) => Some(err),
+            #[cfg(feature = "codec")]
+            NetError::Codec(ref err) => Some(&**err),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for NetError {
@@ -21,82 +202,819 @@ impl From<io::Error> for NetError {
     }
 }
 
-pub struct FramedTcpStream {
-    /// Tcp connection
-    instream: BufReader<TcpStream>,
-    outstream: TcpStream,
-    /// Size header len,
-    h: [u8; HDR],
+/// Frames over any `Read + Write` stream: TCP (`FramedTcpStream`),
+/// unix sockets, pipes, in-memory buffers...
+pub struct Framed<T: Read + Write> {
+    /// Buffered for reads. Writes go through `get_mut()`, which leaves
+    /// buffered input alone.
+    stream: BufReader<T>,
+    /// Frame format
+    framing: Framing,
+    /// PROXY protocol header read before wrapping, if any
+    proxy: Option<ProxyHeader>,
+    /// Called for every frame
+    hooks: FrameHooks,
 }
 
-impl FramedTcpStream {
-    /// Create a new `TcpFrameReader` reading from the given
-    /// stream. Each frame is preceded by a 4 bytes 'len' header.
-    pub fn new(stream: TcpStream) -> FramedTcpStream {
-        stream.set_nodelay(true).unwrap();
-        FramedTcpStream {instream: BufReader::new(stream.try_clone().unwrap()),
-                         outstream: stream,
-                         h: [0; HDR]}
+pub type FramedTcpStream = Framed<TcpStream>;
+
+impl<T: Read + Write> Framed<T> {
+    /// Wrap the given stream. Each frame is preceded by a 4 bytes
+    /// 'len' header.
+    pub fn wrap(stream: T) -> Framed<T> {
+        Framed::wrap_with_header(stream, FrameHeader::default())
     }
 
-    pub fn shutdown(&mut self, how: Shutdown) -> Result<(), NetError> {
-        try!(self.outstream.shutdown(how));
-        Ok(())
+    /// Same as `wrap()`, with frames preceded by the given header
+    pub fn wrap_with_header(stream: T, header: FrameHeader) -> Framed<T> {
+        Framed::with_framing(stream, Framing::new(header))
+    }
+
+    fn with_framing(stream: T, mut framing: Framing) -> Framed<T> {
+        framing.last_seen = Instant::now();
+        framing.last_frame = framing.last_seen;
+        Framed {
+            stream: BufReader::new(stream),
+            framing: framing,
+            proxy: None,
+            hooks: FrameHooks::new(),
+        }
+    }
+
+    /// Another handle of the stream, with the same framing and hooks
+    fn clone_with<U: Read + Write>(&self, stream: U) -> Framed<U> {
+        let mut framed = Framed::with_framing(stream, self.framing.clone());
+        framed.hooks = self.hooks.clone();
+        framed
+    }
+
+    /// Serialize the writes with the other handles sharing `lock`
+    fn share_writes(&mut self, lock: Arc<Mutex<()>>) {
+        self.framing.write_lock = Some(lock);
+    }
+
+    /// The PROXY protocol header the stream started with (see
+    /// `FramedTcpStreamBuilder::proxy_protocol`)
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy.as_ref()
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &T {
+        self.stream.get_ref()
+    }
+
+    /// The underlying stream. Reading from it directly will likely
+    /// leave the framed stream out of sync.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.stream.get_mut()
+    }
+
+    /// Set the largest frame the read methods (and the iterator)
+    /// accept. Longer frames return `NetError::FrameTooBig` before
+    /// anything is allocated, leaving the stream in the middle of the
+    /// frame, so it should be closed.
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.framing.max_frame_size = size;
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.framing.max_frame_size
+    }
+
+    /// Follow each frame with a 4 bytes CRC32 (IEEE) of its contents,
+    /// checked when reading (mismatches return `NetError::Corrupt`).
+    /// Both ends should have the same setting.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.framing.checksum = checksum;
+    }
+
+    /// Compress frames of at least `threshold` bytes (when it makes
+    /// them smaller). Each frame gets a flags byte after the size
+    /// header telling if it is compressed, so both ends should have
+    /// compression enabled, but the thresholds can differ. The max
+    /// frame size also limits the decompressed size.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.framing.compress_threshold = threshold;
+    }
+
+    /// Add a type tag byte to every frame, so applications can tell
+    /// control messages from data. Both ends should have the same
+    /// setting. `write_frame()` sends tag 0 and `read_frame()` ignores
+    /// the tags.
+    pub fn set_tagged(&mut self, tagged: bool) {
+        self.framing.tagged = tagged;
+    }
+
+    /// Exchange heartbeats with the peer, which should also have them
+    /// enabled (they add a flags byte to every frame). While waiting
+    /// for a frame, a ping is sent each time the stream's read timeout
+    /// expires, so it should be set to `interval` (see
+    /// `FramedTcpStreamBuilder::heartbeat`). Pings are answered while
+    /// reading. Once nothing is received for `max_missed` intervals the
+    /// read methods return `NetError::PeerDead`.
+    pub fn set_heartbeat(&mut self, interval: Option<Duration>, max_missed: u32) {
+        self.framing.heartbeat = interval.map(|interval| (interval, max_missed));
+        self.framing.last_seen = Instant::now();
     }
 
-    /// Read and return the next frame
+    /// Give up on peers that send no frames (heartbeats don't count)
+    /// for `timeout`: the read methods return `NetError::Closed` and
+    /// the stream should be dropped. It is checked when the stream's
+    /// read timeout expires, so that should be set too (see
+    /// `FramedTcpStreamBuilder::idle_timeout`).
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.framing.idle_timeout = timeout;
+        self.framing.last_frame = Instant::now();
+    }
+
+    /// Call `hook` after each frame is written (or buffered), with its
+    /// size and how long the write took
+    pub fn on_frame_sent<F>(&mut self, hook: F)
+        where F: Fn(&FrameEvent) + Send + Sync + 'static
+    {
+        self.hooks.on_sent = Some(Arc::new(hook));
+    }
+
+    /// Call `hook` after each frame is read, with its size and how long
+    /// the read blocked. Heartbeats aren't reported.
+    pub fn on_frame_received<F>(&mut self, hook: F)
+        where F: Fn(&FrameEvent) + Send + Sync + 'static
+    {
+        self.hooks.on_received = Some(Arc::new(hook));
+    }
+
+    /// Replace both hooks, e.g. with ones shared with other streams
+    pub fn set_frame_hooks(&mut self, hooks: FrameHooks) {
+        self.hooks = hooks;
+    }
+
+    /// When the last frame (or heartbeat) was received
+    pub fn last_seen(&self) -> Instant {
+        self.framing.last_seen
+    }
+
+    /// Returns `NetError::PeerDead` if the peer missed too many
+    /// heartbeats
+    pub fn check_alive(&self) -> Result<(), NetError> {
+        self.framing.check_alive()
+    }
+
+    /// Send a ping now (heartbeats must be enabled)
+    pub fn ping(&mut self) -> Result<(), NetError> {
+        self.framing.ping(self.stream.get_mut())
+    }
+
+    /// Iterator over the received frames, ending when the peer closes
+    /// the connection. Other errors are returned once, ending the
+    /// iteration too.
+    pub fn frames(&mut self) -> Frames<T> {
+        Frames { stream: self, done: false }
+    }
+
+    /// Read and return the next frame (see also `read_frame_ref()`
+    /// and `recycle()`)
     pub fn read_frame(&mut self) -> Result<Vec<u8>, NetError> {
-        try!(self.instream.read_exact(&mut self.h));
-        let len = BigEndian::read_u32(&self.h) as usize;
-        let mut msg = vec![0;len];
-        try!(self.instream.read_exact(msg.as_mut_slice()));
-        Ok(msg)
+        let start = self.hooks.start();
+        let frame = try!(self.framing.read_frame(&mut self.stream));
+        self.hooks.received(start, frame.len());
+        Ok(frame)
+    }
+
+    /// Read a frame (blocking if needed) and then as many frames as
+    /// are already buffered, up to `max`, appending them to `frames`.
+    /// Returns how many were read. On errors, the frames read before
+    /// are still appended.
+    pub fn read_frames_batch(&mut self, frames: &mut Vec<Vec<u8>>, max: usize)
+                             -> Result<usize, NetError> {
+        let mut n = 0;
+        while n < max {
+            if n > 0 && !self.framing.has_frame(self.stream.buffer()) {
+                break;
+            }
+            frames.push(try!(self.read_frame()));
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Read once, and append to `frames` the frames that completes.
+    /// `partial` keeps the bytes of a frame not received whole yet
+    /// between calls, so it never blocks on a non-blocking stream (or
+    /// a blocking one known to be readable). Returns how many bytes
+    /// were read, 0 if the read would block. On errors, the frames
+    /// completed before are still appended.
+    pub fn read_available_frames(&mut self, partial: &mut Vec<u8>, frames: &mut Vec<Vec<u8>>)
+                                 -> Result<usize, NetError> {
+        // left by earlier reads through the buffer
+        let buffered = self.stream.buffer().len();
+        partial.extend_from_slice(self.stream.buffer());
+        self.stream.consume(buffered);
+        let mut chunk = [0; AVAILABLE_READ_SIZE];
+        let n = match self.stream.get_mut().read(&mut chunk) {
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::Interrupted => return Ok(0),
+            Err(err) => return Err(NetError::from(err)),
+        };
+        partial.extend_from_slice(&chunk[..n]);
+        let mut used = 0;
+        let res = loop {
+            let start = self.hooks.start();
+            match self.framing.decode_frame(&partial[used..], self.stream.get_mut()) {
+                Ok(Some((size, frame))) => {
+                    used += size;
+                    if let Some(frame) = frame {
+                        self.hooks.received(start, frame.len());
+                        frames.push(frame);
+                    }
+                }
+                Ok(None) => break Ok(n),
+                Err(err) => break Err(err),
+            }
+        };
+        partial.drain(..used);
+        match res {
+            Ok(0) if partial.is_empty() => Err(NetError::Closed),
+            Ok(0) => Err(NetError::from(io::Error::new(io::ErrorKind::UnexpectedEof, "closed within a frame"))),
+            res => res,
+        }
+    }
+
+    /// Read the next frame and its tag (see `set_tagged()`)
+    pub fn read_tagged_frame(&mut self) -> Result<(u8, Vec<u8>), NetError> {
+        let start = self.hooks.start();
+        let (tag, frame) = try!(self.framing.read_tagged_frame(&mut self.stream));
+        self.hooks.received(start, frame.len());
+        Ok((tag, frame))
+    }
+
+    /// Read the next frame into a buffer owned by the stream, avoiding
+    /// an allocation per frame. The frame is valid until the next
+    /// read.
+    pub fn read_frame_ref(&mut self) -> Result<&[u8], NetError> {
+        let start = self.hooks.start();
+        let frame = try!(self.framing.read_frame_ref(&mut self.stream));
+        self.hooks.received(start, frame.len());
+        Ok(frame)
+    }
+
+    /// Give back a frame returned by `read_frame()` once done with it,
+    /// so its allocation is reused by the next reads
+    pub fn recycle(&mut self, frame: Vec<u8>) {
+        self.framing.recycle(frame);
     }
 
     /// Read the next frame into the given buffer and return its
     /// size. The buffer should be large enough to contain the
     /// message.
     pub fn read_frame_into(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
-        try!(self.instream.read_exact(&mut self.h));
-        let len = BigEndian::read_u32(&self.h) as usize;
-        if buf.len() < len {
-            Err(NetError::FrameTooBig(len))
-        } else {
-            try!(self.instream.read_exact(&mut buf[0..len]));
-            Ok(len)
-        }
+        let start = self.hooks.start();
+        let len = try!(self.framing.read_frame_into(&mut self.stream, buf));
+        self.hooks.received(start, len);
+        Ok(len)
     }
 
-    /// Writes a frame preceded by its length to the stream
+    /// Writes a frame preceded by its length to the stream (after
+    /// any buffered frames)
     pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
-        let len = frame.len() as u32;
-        let mut bytes = Vec::with_capacity(4);
-        unsafe { bytes.set_len(4) };
-        BigEndian::write_u32(&mut bytes[..4], len);
-        try!(self.outstream.write_all(&bytes[..4]));
-        try!(self.outstream.write_all(frame));
+        let start = self.hooks.start();
+        try!(self.framing.write_frame(self.stream.get_mut(), frame));
+        self.hooks.sent(start, frame.len());
+        Ok(())
+    }
+
+    /// Writes a frame with the given tag (see `set_tagged()`)
+    pub fn write_tagged_frame(&mut self, tag: u8, frame: &[u8]) -> Result<(), NetError> {
+        let start = self.hooks.start();
+        try!(self.framing.write_tagged_frame(self.stream.get_mut(), tag, frame));
+        self.hooks.sent(start, frame.len());
+        Ok(())
+    }
+
+    /// Buffers a frame, to be sent with the next `flush()` or
+    /// `write_frame()`, so many small frames go out in a single
+    /// syscall. The buffer is also written out when it grows past
+    /// 64KiB. Frames still buffered when the stream is dropped are
+    /// lost.
+    pub fn write_frame_buffered(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        let start = self.hooks.start();
+        try!(self.framing.write_frame_buffered(self.stream.get_mut(), frame));
+        self.hooks.sent(start, frame.len());
+        Ok(())
+    }
+
+    /// Sends the buffered frames
+    pub fn flush(&mut self) -> Result<(), NetError> {
+        self.framing.flush(self.stream.get_mut())
+    }
+
+    /// Send `len` bytes from `reader` as a series of frames, without
+    /// holding them all in memory. The other end should receive them
+    /// with `read_frame_to_writer()`. If `reader` ends early an error
+    /// is returned and the transfer is left incomplete, so the stream
+    /// should be closed.
+    pub fn write_frame_from_reader<R: Read>(&mut self, reader: &mut R, len: u64)
+                                            -> Result<(), NetError> {
+        let chunk_size = cmp::max(1, cmp::min(TRANSFER_CHUNK_SIZE,
+                                              self.framing.max_frame_size.saturating_sub(1)));
+        let mut chunk = vec![0; chunk_size + 1];
+        let mut left = len;
+        loop {
+            let n = cmp::min(left, chunk_size as u64) as usize;
+            try!(reader.read_exact(&mut chunk[1..n + 1]));
+            left -= n as u64;
+            chunk[0] = if left > 0 { CHUNK_MORE } else { CHUNK_LAST };
+            try!(self.write_frame(&chunk[..n + 1]));
+            if left == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receive a transfer sent with `write_frame_from_reader()` into
+    /// `writer`, returning its size. `max_frame_size` applies to each
+    /// chunk, not to the whole transfer.
+    pub fn read_frame_to_writer<W: Write>(&mut self, writer: &mut W) -> Result<u64, NetError> {
+        let mut len = 0;
+        loop {
+            let chunk = try!(self.read_frame_ref());
+            if chunk.is_empty() {
+                return Err(NetError::Protocol("empty transfer chunk"));
+            }
+            try!(writer.write_all(&chunk[1..]));
+            len += chunk.len() as u64 - 1;
+            match chunk[0] {
+                CHUNK_LAST => return Ok(len),
+                CHUNK_MORE => {}
+                _ => return Err(NetError::Protocol("invalid transfer chunk flag")),
+            }
+        }
+    }
+
+    /// Encode a frame once with this stream's options, to send it
+    /// (possibly many times) with `write_preframed()`
+    pub fn preframe(&mut self, frame: &[u8]) -> Result<FrameBuf, NetError> {
+        Ok(FrameBuf {
+            bytes: try!(self.framing.encode(frame)),
+            size: frame.len(),
+        })
+    }
+
+    /// Writes a frame encoded by `preframe()` (after any buffered
+    /// frames). It should come from a stream with the same options.
+    pub fn write_preframed(&mut self, frame: &FrameBuf) -> Result<(), NetError> {
+        let start = self.hooks.start();
+        try!(self.flush());
+        {
+            let _lock = self.framing.lock_writes();
+            let res = self.stream.get_mut().write_all(&frame.bytes);
+            try!(res.map_err(|err| NetError::from(err).timed_out(self.framing.write_timeout)));
+        }
+        self.hooks.sent(start, frame.size);
         Ok(())
     }
 
-    /// Writes data directly into the underlying stream (no framing)
-    pub unsafe fn raw_write(&mut self, bytes: &[u8]) -> Result<(), NetError> {
-        try!(self.outstream.write_all(bytes));
+    /// Encode the header and trailer of a frame once with this
+    /// stream's options, to send it to many streams with
+    /// `write_parts()` without copying the payload
+    pub fn frame_parts<'a>(&self, frame: &'a [u8]) -> Result<FrameParts<'a>, NetError> {
+        let (head, compressed, tail) = try!(self.framing.encode_parts(frame));
+        Ok(FrameParts {
+            head: head,
+            payload: compressed.map_or(Cow::Borrowed(frame), Cow::Owned),
+            tail: tail,
+            size: frame.len(),
+        })
+    }
+
+    /// How this stream encodes frames: the ones from `preframe()` or
+    /// `frame_parts()` can be written to the streams with the same
+    /// format
+    pub fn frame_format(&self) -> FrameFormat {
+        self.framing.format()
+    }
+
+    /// Writes a frame encoded by `frame_parts()` with vectored writes
+    /// (after any buffered frames). It should come from a stream with
+    /// the same options.
+    pub fn write_parts(&mut self, frame: &FrameParts) -> Result<(), NetError> {
+        let start = self.hooks.start();
+        try!(self.flush());
+        {
+            let _lock = self.framing.lock_writes();
+            let res = write_all_vectored(self.stream.get_mut(), &[&frame.head, &frame.payload, &frame.tail]);
+            try!(res.map_err(|err| NetError::from(err).timed_out(self.framing.write_timeout)));
+        }
+        self.hooks.sent(start, frame.size);
         Ok(())
     }
 }
 
-impl Iterator for FramedTcpStream {
-    type Item = Vec<u8>;
-    /// Returns the next frame or None in case of error
-    fn next(&mut self) -> Option<Vec<u8>> {
-        self.read_frame().ok()
+/// The options deciding how a stream encodes its frames, see
+/// `Framed::frame_format()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFormat {
+    header: FrameHeader,
+    checksum: bool,
+    compress_threshold: Option<usize>,
+    /// Frames have a flags byte
+    flags: bool,
+    tagged: bool,
+}
+
+/// Frame split around its payload, see `Framed::frame_parts()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameParts<'a> {
+    head: Vec<u8>,
+    payload: Cow<'a, [u8]>,
+    tail: Vec<u8>,
+    /// Size of the payload before compression
+    size: usize,
+}
+
+impl<'a> FrameParts<'a> {
+    /// The frame in a single buffer, e.g. to queue it
+    pub fn to_frame_buf(&self) -> FrameBuf {
+        let mut bytes = Vec::with_capacity(self.head.len() + self.payload.len() + self.tail.len());
+        bytes.extend_from_slice(&self.head);
+        bytes.extend_from_slice(&self.payload);
+        bytes.extend_from_slice(&self.tail);
+        FrameBuf {
+            bytes: bytes,
+            size: self.size,
+        }
     }
 }
 
-pub fn network_to_u32(bytes: &[u8]) -> u32 {
-    debug_assert!(bytes.len() >= 4);
-    let p = &bytes[0] as *const u8 as *const u32;
-    unsafe {
-        u32::from_be(*p)
+/// `write_all()` of several buffers, in as few writes as possible
+fn write_all_vectored<W: Write>(w: &mut W, bufs: &[&[u8]]) -> io::Result<()> {
+    let total = bufs.iter().map(|buf| buf.len()).fold(0, |sum, len| sum + len);
+    let mut written = 0;
+    while written < total {
+        let mut skip = written;
+        let mut slices = Vec::with_capacity(bufs.len());
+        for buf in bufs {
+            if skip >= buf.len() {
+                skip -= buf.len();
+            } else {
+                slices.push(IoSlice::new(&buf[skip..]));
+                skip = 0;
+            }
+        }
+        match w.write_vectored(&slices) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole frame")),
+            Ok(n) => written += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Encoded frame, see `Framed::preframe()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameBuf {
+    bytes: Vec<u8>,
+    /// Size of the payload
+    size: usize,
+}
+
+impl FrameBuf {
+    /// The frame as sent, with its header (and trailer)
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Framed<TcpStream> {
+    /// Create a new `FramedTcpStream` over the given stream, with
+    /// `TCP_NODELAY` set (when the socket allows it). Each frame is
+    /// preceded by a 4 bytes 'len' header.
+    pub fn new(stream: TcpStream) -> FramedTcpStream {
+        FramedTcpStream::with_header(stream, FrameHeader::default())
+    }
+
+    /// Same as `new()`, with frames preceded by the given header
+    pub fn with_header(stream: TcpStream, header: FrameHeader) -> FramedTcpStream {
+        // only a latency optimization, not worth failing for
+        stream.set_nodelay(true).is_ok();
+        Framed::wrap_with_header(stream, header)
+    }
+
+    /// Connect to `addr` with the default options (use `builder()`
+    /// for others)
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<FramedTcpStream, NetError> {
+        FramedTcpStreamBuilder::new().connect(addr)
+    }
+
+    /// Connect to `target` ("host:port") through the SOCKS5 proxy at
+    /// `proxy`, with the default options
+    pub fn connect_via_proxy<A: ToSocketAddrs>(proxy: A, target: &str)
+                                               -> Result<FramedTcpStream, NetError> {
+        FramedTcpStreamBuilder::new().connect_via_proxy(proxy, ProxyKind::Socks5, target)
+    }
+
+    pub fn builder() -> FramedTcpStreamBuilder {
+        FramedTcpStreamBuilder::new()
+    }
+
+    /// Reads blocking for longer than `timeout` return
+    /// `NetError::Timeout`. A timeout in the middle of a frame leaves
+    /// the stream out of sync, so it should be closed.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), NetError> {
+        try!(self.get_ref().set_read_timeout(timeout));
+        self.framing.read_timeout = timeout.is_some();
+        Ok(())
+    }
+
+    /// Writes blocking for longer than `timeout` return
+    /// `NetError::Timeout`
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> Result<(), NetError> {
+        try!(self.get_ref().set_write_timeout(timeout));
+        self.framing.write_timeout = timeout.is_some();
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(try!(self.get_ref().peer_addr()))
+    }
+
+    /// Address of the client: the one in the PROXY protocol header if
+    /// there was one, otherwise the peer's
+    pub fn client_addr(&self) -> Result<SocketAddr, NetError> {
+        match self.proxy {
+            Some(ref header) => Ok(header.source),
+            None => self.peer_addr(),
+        }
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<(), NetError> {
+        try!(self.get_ref().shutdown(how));
+        Ok(())
+    }
+}
+
+/// Options for creating `FramedTcpStream`s (and `FramedTlsStream`s).
+/// The same builder can be used for many streams.
+#[derive(Debug, Clone)]
+pub struct FramedTcpStreamBuilder {
+    framing: Framing,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    linger: Option<Duration>,
+    proxy_protocol: bool,
+    hooks: FrameHooks,
+}
+
+impl FramedTcpStreamBuilder {
+    pub fn new() -> Self {
+        FramedTcpStreamBuilder {
+            framing: Framing::new(FrameHeader::default()),
+            read_timeout: None,
+            write_timeout: None,
+            connect_timeout: None,
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger: None,
+            proxy_protocol: false,
+            hooks: FrameHooks::new(),
+        }
+    }
+
+    pub fn header(mut self, header: FrameHeader) -> Self {
+        self.framing.header = header;
+        self
+    }
+
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.framing.max_frame_size = size;
+        self
     }
+
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Only used by `connect()`
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// `TCP_NODELAY`, on by default
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Enable TCP keepalive, probing idle connections after `idle`
+    pub fn keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.keepalive = idle;
+        self
+    }
+
+    /// `SO_SNDBUF`, the system default if not set
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// `SO_RCVBUF`, the system default if not set
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// `SO_LINGER`: closing the stream blocks up to `timeout` for
+    /// unsent data
+    pub fn linger(mut self, timeout: Option<Duration>) -> Self {
+        self.linger = timeout;
+        self
+    }
+
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.framing.checksum = checksum;
+        self
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, threshold: Option<usize>) -> Self {
+        self.framing.compress_threshold = threshold;
+        self
+    }
+
+    pub fn tagged(mut self, tagged: bool) -> Self {
+        self.framing.tagged = tagged;
+        self
+    }
+
+    /// Enable heartbeats (see `Framed::set_heartbeat`), also setting the
+    /// read timeout to `interval`
+    pub fn heartbeat(mut self, interval: Duration, max_missed: u32) -> Self {
+        self.framing.heartbeat = Some((interval, max_missed));
+        self.read_timeout = Some(interval);
+        self
+    }
+
+    /// Close streams that receive no frames for `timeout` (see
+    /// `Framed::set_idle_timeout`), also setting the read timeout to
+    /// `timeout` if there is none
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.framing.idle_timeout = Some(timeout);
+        if self.read_timeout.is_none() {
+            self.read_timeout = Some(timeout);
+        }
+        self
+    }
+
+    /// Expect accepted streams (passed to `build()`) to start with a
+    /// PROXY protocol header, available with `Framed::proxy_header()`
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// Hooks given to every stream (see `Framed::on_frame_sent` and
+    /// `Framed::on_frame_received`)
+    pub fn frame_hooks(mut self, hooks: FrameHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Connect to `addr` (trying each address it resolves to) and wrap
+    /// the stream with the configured options
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<FramedTcpStream, NetError> {
+        let stream = try!(self.connect_tcp(addr));
+        try!(self.configure(&stream));
+        Ok(self.wrap_configured(stream))
+    }
+
+    /// Connect to `target` ("host:port", resolved by the proxy) through
+    /// the proxy at `proxy`. The connect timeout applies to the proxy,
+    /// the read and write timeouts also to the proxy handshake.
+    pub fn connect_via_proxy<A: ToSocketAddrs>(&self, proxy: A, kind: ProxyKind, target: &str)
+                                               -> Result<FramedTcpStream, NetError> {
+        let mut stream = try!(self.connect_tcp(proxy));
+        try!(self.configure(&stream));
+        try!(tunnel::handshake(&mut stream, kind, target).map_err(|err| {
+            err.timed_out(self.read_timeout.is_some() || self.write_timeout.is_some())
+        }));
+        Ok(self.wrap_configured(stream))
+    }
+
+    /// Connect to the first address that accepts
+    fn connect_tcp<A: ToSocketAddrs>(&self, addr: A) -> Result<TcpStream, NetError> {
+        let mut last_err = None;
+        for addr in try!(addr.to_socket_addrs()) {
+            let res = match self.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(NetError::from(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+        })).timed_out(self.connect_timeout.is_some()))
+    }
+
+    /// Wrap the given stream with the configured options, reading its
+    /// PROXY protocol header first if enabled
+    pub fn build(&self, mut stream: TcpStream) -> Result<FramedTcpStream, NetError> {
+        try!(self.configure(&stream));
+        let proxy = if self.proxy_protocol {
+            let timeout = self.read_timeout.is_some();
+            try!(read_proxy_header(&mut stream).map_err(|err| err.timed_out(timeout)))
+        } else {
+            None
+        };
+        let mut framed = self.wrap_configured(stream);
+        framed.proxy = proxy;
+        Ok(framed)
+    }
+
+    /// Wrap any stream with the configured frame options (the socket
+    /// options only apply to TCP)
+    pub fn wrap<T: Read + Write>(&self, stream: T) -> Framed<T> {
+        let mut framed = Framed::with_framing(stream, self.framing.clone());
+        framed.hooks = self.hooks.clone();
+        framed
+    }
+
+    /// Same as `wrap()`, for a stream `configure()` was applied to:
+    /// reads and writes outlasting its timeouts return
+    /// `NetError::Timeout`
+    fn wrap_configured<T: Read + Write>(&self, stream: T) -> Framed<T> {
+        let mut framed = self.wrap(stream);
+        framed.framing.read_timeout = self.read_timeout.is_some();
+        framed.framing.write_timeout = self.write_timeout.is_some();
+        framed
+    }
+
+    /// Set the socket options
+    fn configure(&self, stream: &TcpStream) -> Result<(), NetError> {
+        try!(stream.set_read_timeout(self.read_timeout));
+        try!(stream.set_write_timeout(self.write_timeout));
+        try!(stream.set_nodelay(self.nodelay));
+        try!(stream.set_keepalive(self.keepalive));
+        if let Some(size) = self.send_buffer_size {
+            try!(stream.set_send_buffer_size(size));
+        }
+        if let Some(size) = self.recv_buffer_size {
+            try!(stream.set_recv_buffer_size(size));
+        }
+        if self.linger.is_some() {
+            try!(stream.set_linger(self.linger));
+        }
+        Ok(())
+    }
+}
+
+/// Iterator returned by `Framed::frames()`
+pub struct Frames<'a, T: Read + Write + 'a> {
+    stream: &'a mut Framed<T>,
+    done: bool,
+}
+
+impl<'a, T: Read + Write + 'a> Iterator for Frames<'a, T> {
+    type Item = Result<Vec<u8>, NetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.stream.read_frame() {
+            Ok(frame) => Some(Ok(frame)),
+            Err(NetError::Closed) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Same as `endian::read_u32`
+pub fn network_to_u32(bytes: &[u8]) -> u32 {
+    endian::read_u32(bytes)
 }
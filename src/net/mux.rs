@@ -0,0 +1,152 @@
+/// Logical channels over a single framed stream. Each frame starts with
+/// the channel id (u32 big-endian) and a kind byte (data or close).
+/// Frames for other channels than the one being read are queued, so
+/// e.g. an RPC channel and a bulk transfer channel can share a
+/// connection.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+
+use net::{NetError, Framed};
+use net::endian;
+
+const MUX_HEADER_SIZE: usize = 5;
+
+const KIND_DATA: u8 = 0;
+const KIND_CLOSE: u8 = 1;
+
+pub struct Mux<T: Read + Write> {
+    inner: Framed<T>,
+    /// Frames received but not read yet, per channel
+    queues: HashMap<u32, VecDeque<Vec<u8>>>,
+    /// Channels closed by us: no more sends, received frames dropped
+    closed_local: HashSet<u32>,
+    /// Channels closed by the peer
+    closed_remote: HashSet<u32>,
+    buf: Vec<u8>,
+}
+
+impl<T: Read + Write> Mux<T> {
+    pub fn new(inner: Framed<T>) -> Mux<T> {
+        Mux {
+            inner: inner,
+            queues: HashMap::new(),
+            closed_local: HashSet::new(),
+            closed_remote: HashSet::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &Framed<T> {
+        &self.inner
+    }
+
+    /// Reading or writing frames directly will confuse the peer
+    pub fn get_mut(&mut self) -> &mut Framed<T> {
+        &mut self.inner
+    }
+
+    /// Send a frame on `channel`. Channels don't need to be opened,
+    /// but fail with `NetError::Closed` once closed by either end.
+    pub fn send(&mut self, channel: u32, frame: &[u8]) -> Result<(), NetError> {
+        if self.is_closed(channel) {
+            return Err(NetError::Closed);
+        }
+        self.write(channel, KIND_DATA, frame)
+    }
+
+    /// Receive the next frame of `channel`, queuing the frames of the
+    /// other channels read meanwhile. Returns `NetError::Closed` once
+    /// the peer closed the channel and its queue is empty.
+    pub fn recv(&mut self, channel: u32) -> Result<Vec<u8>, NetError> {
+        if self.closed_local.contains(&channel) {
+            return Err(NetError::Closed);
+        }
+        loop {
+            if let Some(frame) = self.queues.get_mut(&channel).and_then(|queue| queue.pop_front()) {
+                return Ok(frame);
+            }
+            if self.closed_remote.contains(&channel) {
+                return Err(NetError::Closed);
+            }
+            match try!(self.read()) {
+                (ch, Some(frame)) => {
+                    if ch == channel {
+                        return Ok(frame);
+                    }
+                    self.queues.entry(ch).or_insert_with(VecDeque::new).push_back(frame);
+                }
+                (ch, None) => {
+                    if ch == channel {
+                        return Err(NetError::Closed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Receive the next frame of any channel, queued ones first
+    pub fn recv_any(&mut self) -> Result<(u32, Vec<u8>), NetError> {
+        let queued = self.queues.iter().filter(|&(_, queue)| !queue.is_empty())
+            .map(|(&ch, _)| ch).next();
+        if let Some(ch) = queued {
+            return Ok((ch, self.queues.get_mut(&ch).unwrap().pop_front().unwrap()));
+        }
+        loop {
+            if let (ch, Some(frame)) = try!(self.read()) {
+                return Ok((ch, frame));
+            }
+        }
+    }
+
+    /// Close `channel` on both ends, dropping its queued frames. The
+    /// other channels are unaffected.
+    pub fn close(&mut self, channel: u32) -> Result<(), NetError> {
+        self.queues.remove(&channel);
+        if !self.closed_local.insert(channel) {
+            return Ok(());
+        }
+        if self.closed_remote.contains(&channel) {
+            return Ok(());
+        }
+        self.write(channel, KIND_CLOSE, &[])
+    }
+
+    pub fn is_closed(&self, channel: u32) -> bool {
+        self.closed_local.contains(&channel) || self.closed_remote.contains(&channel)
+    }
+
+    fn write(&mut self, channel: u32, kind: u8, frame: &[u8]) -> Result<(), NetError> {
+        self.buf.clear();
+        self.buf.extend_from_slice(&endian::u32_bytes(channel));
+        self.buf.push(kind);
+        self.buf.extend_from_slice(frame);
+        self.inner.write_frame(&self.buf)
+    }
+
+    /// Next frame from the stream, `None` when the peer closed the
+    /// channel. Frames of locally closed channels are dropped.
+    fn read(&mut self) -> Result<(u32, Option<Vec<u8>>), NetError> {
+        loop {
+            let mut frame = try!(self.inner.read_frame());
+            if frame.len() < MUX_HEADER_SIZE {
+                return Err(NetError::Protocol("mux frame shorter than its header"));
+            }
+            let channel = endian::read_u32(&frame[..4]);
+            match frame[4] {
+                KIND_DATA => {
+                    if self.closed_local.contains(&channel) {
+                        continue;
+                    }
+                    frame.drain(..MUX_HEADER_SIZE);
+                    return Ok((channel, Some(frame)));
+                }
+                KIND_CLOSE => {
+                    self.closed_remote.insert(channel);
+                    return Ok((channel, None));
+                }
+                _ => return Err(NetError::Protocol("invalid mux frame kind")),
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+/// Encrypted and authenticated frames with the Noise protocol (XX or
+/// IK handshake, 25519/ChaChaPoly/BLAKE2s), for links between nodes
+/// that know each other's static keys without setting up TLS.
+///
+/// The handshake messages are sent as frames. Afterwards each frame is
+/// encrypted into one or more Noise messages (at most 64KiB each),
+/// whose first plaintext byte tells if more follow.
+
+use snow;
+use snow::{NoiseBuilder, Session};
+
+use std::cmp;
+use std::io::{Read, Write};
+
+use net::{NetError, Framed, CHUNK_LAST, CHUNK_MORE};
+
+/// Largest Noise message
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Size of the authentication tag of each message
+const TAG_SIZE: usize = 16;
+
+/// Payload bytes per message, after the more/last byte
+const MAX_CHUNK_SIZE: usize = MAX_MESSAGE_SIZE - TAG_SIZE - 1;
+
+/// Handshake pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoisePattern {
+    /// Both sides send their static keys (encrypted) during the
+    /// handshake, check them with `remote_static()`
+    XX,
+    /// The initiator already knows the responder's static key, saving
+    /// a message
+    IK,
+}
+
+impl NoisePattern {
+    fn params(&self) -> snow::params::NoiseParams {
+        match *self {
+            NoisePattern::XX => "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap(),
+            NoisePattern::IK => "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap(),
+        }
+    }
+}
+
+/// Static key pair, identifying a node
+#[derive(Debug, Clone)]
+pub struct NoiseKeypair {
+    pub private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+impl NoiseKeypair {
+    pub fn generate() -> Result<NoiseKeypair, NetError> {
+        match NoiseBuilder::new(NoisePattern::XX.params()).generate_keypair() {
+            Ok(keypair) => Ok(NoiseKeypair {
+                private: keypair.private,
+                public: keypair.public,
+            }),
+            Err(_) => Err(NetError::Protocol("noise key generation failed")),
+        }
+    }
+}
+
+pub struct NoiseStream<T: Read + Write> {
+    inner: Framed<T>,
+    session: Session,
+    /// Encrypted message or decrypted payload
+    buf: Vec<u8>,
+}
+
+impl<T: Read + Write> NoiseStream<T> {
+    /// Run the handshake as the initiator (the client). `remote_key`
+    /// is the responder's public key, required for IK.
+    pub fn initiator(inner: Framed<T>, pattern: NoisePattern, local_key: &[u8],
+                     remote_key: Option<&[u8]>) -> Result<NoiseStream<T>, NetError> {
+        let mut builder = NoiseBuilder::new(pattern.params()).local_private_key(local_key);
+        if let Some(key) = remote_key {
+            builder = builder.remote_public_key(key);
+        } else if pattern == NoisePattern::IK {
+            return Err(NetError::Protocol("noise IK initiator needs the remote key"));
+        }
+        match builder.build_initiator() {
+            Ok(session) => NoiseStream::handshake(inner, session, true),
+            Err(_) => Err(NetError::Protocol("invalid noise key")),
+        }
+    }
+
+    /// Run the handshake as the responder (the server)
+    pub fn responder(inner: Framed<T>, pattern: NoisePattern, local_key: &[u8])
+                     -> Result<NoiseStream<T>, NetError> {
+        match NoiseBuilder::new(pattern.params()).local_private_key(local_key).build_responder() {
+            Ok(session) => NoiseStream::handshake(inner, session, false),
+            Err(_) => Err(NetError::Protocol("invalid noise key")),
+        }
+    }
+
+    fn handshake(mut inner: Framed<T>, mut session: Session, initiator: bool)
+                 -> Result<NoiseStream<T>, NetError> {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        // the initiator sends the first message, then they alternate
+        let mut send = initiator;
+        while !session.is_handshake_finished() {
+            if send {
+                let len = try!(session.write_message(&[], &mut buf)
+                               .map_err(|_| NetError::Protocol("noise handshake failed")));
+                try!(inner.write_frame(&buf[..len]));
+            } else {
+                let msg = try!(inner.read_frame_ref());
+                try!(session.read_message(msg, &mut buf)
+                     .map_err(|_| NetError::Protocol("noise handshake failed")));
+            }
+            send = !send;
+        }
+        let session = try!(session.into_transport_mode()
+                           .map_err(|_| NetError::Protocol("noise handshake failed")));
+        Ok(NoiseStream {
+            inner: inner,
+            session: session,
+            buf: buf,
+        })
+    }
+
+    /// The peer's static public key, to check who it is
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.session.get_remote_static()
+    }
+
+    pub fn get_ref(&self) -> &Framed<T> {
+        &self.inner
+    }
+
+    /// Writing or reading frames directly will break the encryption
+    pub fn get_mut(&mut self) -> &mut Framed<T> {
+        &mut self.inner
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        let mut plain = Vec::with_capacity(cmp::min(frame.len(), MAX_CHUNK_SIZE) + 1);
+        let mut chunks = frame.chunks(MAX_CHUNK_SIZE).peekable();
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            plain.clear();
+            plain.push(if chunks.peek().is_some() { CHUNK_MORE } else { CHUNK_LAST });
+            plain.extend_from_slice(chunk);
+            let len = try!(self.session.write_message(&plain, &mut self.buf)
+                           .map_err(|_| NetError::Protocol("noise encryption failed")));
+            try!(self.inner.write_frame(&self.buf[..len]));
+            if chunks.peek().is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Messages that fail to decrypt (tampered with, or out of order)
+    /// return `NetError::Corrupt`, and the stream can't be used after
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, NetError> {
+        let mut frame = Vec::new();
+        loop {
+            let len = {
+                let msg = try!(self.inner.read_frame_ref());
+                try!(self.session.read_message(msg, &mut self.buf).map_err(|_| NetError::Corrupt))
+            };
+            if len == 0 {
+                return Err(NetError::Protocol("empty noise message"));
+            }
+            if frame.len() + len - 1 > self.inner.max_frame_size() {
+                return Err(NetError::FrameTooBig(frame.len() + len - 1));
+            }
+            frame.extend_from_slice(&self.buf[1..len]);
+            match self.buf[0] {
+                CHUNK_LAST => return Ok(frame),
+                CHUNK_MORE => {}
+                _ => return Err(NetError::Protocol("invalid noise chunk flag")),
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+/// HAProxy PROXY protocol (v1 and v2) headers, sent by load balancers
+/// at the start of each connection with the address of the client
+/// they are forwarding. Only the header is read from the stream, so
+/// it can be wrapped afterwards.
+
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use net::NetError;
+use net::endian;
+
+const V2_SIGNATURE: &'static [u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, with the CRLF
+const V1_MAX_LEN: usize = 107;
+
+const V2_CMD_LOCAL: u8 = 0x0;
+const V2_CMD_PROXY: u8 = 0x1;
+
+const V2_AF_INET: u8 = 0x1;
+const V2_AF_INET6: u8 = 0x2;
+
+/// Addresses of the proxied connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The client
+    pub source: SocketAddr,
+    /// Where the client connected to (the load balancer)
+    pub destination: SocketAddr,
+}
+
+/// Read a v1 or v2 header from the start of `r`. Returns `None` for
+/// connections made by the proxy itself (health checks) and for
+/// address families other than IPv4/IPv6. A missing or invalid header
+/// returns `NetError::Protocol`.
+pub fn read_proxy_header<R: Read>(r: &mut R) -> Result<Option<ProxyHeader>, NetError> {
+    let mut start = [0; 12];
+    try!(r.read_exact(&mut start));
+    if &start == V2_SIGNATURE {
+        read_v2(r)
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(r, &start)
+    } else {
+        Err(NetError::Protocol("missing PROXY protocol header"))
+    }
+}
+
+/// "PROXY TCP4 <src> <dst> <src port> <dst port>\r\n", a byte at a
+/// time to not read past it
+fn read_v1<R: Read>(r: &mut R, start: &[u8]) -> Result<Option<ProxyHeader>, NetError> {
+    let mut line = start.to_vec();
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(NetError::Protocol("PROXY protocol header too long"));
+        }
+        try!(r.read_exact(&mut byte));
+        line.push(byte[0]);
+    }
+    let line = match String::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => return Err(NetError::Protocol("invalid PROXY protocol header")),
+    };
+    let fields: Vec<&str> = line.trim_right().split(' ').collect();
+    match fields.get(1) {
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {}
+        Some(&"UNKNOWN") => return Ok(None),
+        _ => return Err(NetError::Protocol("invalid PROXY protocol header")),
+    }
+    match (fields[2].parse::<IpAddr>(), fields[3].parse::<IpAddr>(),
+           fields[4].parse::<u16>(), fields[5].parse::<u16>()) {
+        (Ok(src), Ok(dst), Ok(src_port), Ok(dst_port)) => Ok(Some(ProxyHeader {
+            source: SocketAddr::new(src, src_port),
+            destination: SocketAddr::new(dst, dst_port),
+        })),
+        _ => Err(NetError::Protocol("invalid PROXY protocol header")),
+    }
+}
+
+/// Binary header: version and command, family and protocol, length of
+/// the addresses (u16), and the addresses (plus TLVs, ignored)
+fn read_v2<R: Read>(r: &mut R) -> Result<Option<ProxyHeader>, NetError> {
+    let mut head = [0; 4];
+    try!(r.read_exact(&mut head));
+    if head[0] >> 4 != 2 {
+        return Err(NetError::Protocol("unsupported PROXY protocol version"));
+    }
+    let mut addrs = vec![0; endian::read_u16(&head[2..]) as usize];
+    try!(r.read_exact(&mut addrs));
+    match head[0] & 0xf {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {}
+        _ => return Err(NetError::Protocol("invalid PROXY protocol command")),
+    }
+    match head[1] >> 4 {
+        V2_AF_INET if addrs.len() >= 12 => {
+            let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(&addrs[0..4]), endian::read_u16(&addrs[8..10])),
+                destination: SocketAddr::new(ip(&addrs[4..8]), endian::read_u16(&addrs[10..12])),
+            }))
+        }
+        V2_AF_INET6 if addrs.len() >= 36 => {
+            let ip = |b: &[u8]| {
+                let mut segments = [0; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = endian::read_u16(&b[i * 2..]);
+                }
+                IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                         segments[4], segments[5], segments[6], segments[7]))
+            };
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(&addrs[0..16]), endian::read_u16(&addrs[32..34])),
+                destination: SocketAddr::new(ip(&addrs[16..32]), endian::read_u16(&addrs[34..36])),
+            }))
+        }
+        V2_AF_INET | V2_AF_INET6 => Err(NetError::Protocol("PROXY protocol addresses too short")),
+        _ => Ok(None),
+    }
+}
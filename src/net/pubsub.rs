@@ -0,0 +1,216 @@
+/// Topic-based publish/subscribe over a `sync::Server`. Clients
+/// (`PubSubClient`) subscribe to topics and publish frames on them,
+/// which the server (`PubSubServer`) fans out to the subscribers.
+///
+/// Each frame starts with a kind byte and the topic (u16 big-endian
+/// length, then UTF-8), followed by the payload for publications.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::str;
+
+use net::{NetError, Framed};
+use net::endian;
+use net::sync::{Server, Event, ServerError, Uid};
+
+const KIND_SUBSCRIBE: u8 = 0;
+const KIND_UNSUBSCRIBE: u8 = 1;
+const KIND_PUBLISH: u8 = 2;
+const KIND_MESSAGE: u8 = 3;
+
+/// Longest topic, in bytes
+pub const MAX_TOPIC_LEN: usize = 0xffff;
+
+/// Client side of the protocol, over any framed stream
+pub struct PubSubClient<T: Read + Write> {
+    inner: Framed<T>,
+    buf: Vec<u8>,
+}
+
+impl<T: Read + Write> PubSubClient<T> {
+    pub fn new(inner: Framed<T>) -> PubSubClient<T> {
+        PubSubClient {
+            inner: inner,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &Framed<T> {
+        &self.inner
+    }
+
+    /// Reading or writing frames directly will confuse the server
+    pub fn get_mut(&mut self) -> &mut Framed<T> {
+        &mut self.inner
+    }
+
+    pub fn subscribe(&mut self, topic: &str) -> Result<(), NetError> {
+        self.write(KIND_SUBSCRIBE, topic, &[])
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<(), NetError> {
+        self.write(KIND_UNSUBSCRIBE, topic, &[])
+    }
+
+    /// Send `payload` to the subscribers of `topic` (including us, if
+    /// subscribed)
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), NetError> {
+        self.write(KIND_PUBLISH, topic, payload)
+    }
+
+    /// Next message published on one of our topics, with its topic
+    pub fn recv(&mut self) -> Result<(String, Vec<u8>), NetError> {
+        let frame = try!(self.inner.read_frame());
+        match try!(decode(&frame)) {
+            (KIND_MESSAGE, topic, payload) => Ok((topic.to_string(), payload.to_vec())),
+            _ => Err(NetError::Protocol("unexpected pubsub frame from the server")),
+        }
+    }
+
+    fn write(&mut self, kind: u8, topic: &str, payload: &[u8]) -> Result<(), NetError> {
+        try!(encode(&mut self.buf, kind, topic, payload));
+        self.inner.write_frame(&self.buf)
+    }
+}
+
+/// Server side: keeps the topics registry and relays publications.
+/// Iterating over it runs the server.
+pub struct PubSubServer {
+    server: Server,
+    /// Subscribers of each topic
+    topics: HashMap<String, HashSet<Uid>>,
+    /// Topics of each subscriber, to clean up on disconnection
+    subscriptions: HashMap<Uid, HashSet<String>>,
+    buf: Vec<u8>,
+}
+
+impl PubSubServer {
+    /// Wrap the server, started or not
+    pub fn new(server: Server) -> PubSubServer {
+        PubSubServer {
+            server: server,
+            topics: HashMap::new(),
+            subscriptions: HashMap::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// The wrapped server, for everything besides frames (`start()`,
+    /// `shutdown()`...)
+    pub fn server(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> Server {
+        self.server
+    }
+
+    /// Publish from the server itself. Returns the number of
+    /// subscribers it was sent to.
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<usize, ServerError> {
+        let subscribers: Vec<Uid> = match self.topics.get(topic) {
+            Some(subscribers) => subscribers.iter().cloned().collect(),
+            None => return Ok(0),
+        };
+        try!(encode(&mut self.buf, KIND_MESSAGE, topic, payload));
+        // the ones that failed are shut down, and unsubscribed once
+        // their Disconnected event comes
+        let failed = try!(self.server.send_to_many(&subscribers, &self.buf));
+        Ok(subscribers.len() - failed.len())
+    }
+
+    /// Current subscribers of `topic`
+    pub fn subscribers(&self, topic: &str) -> Vec<Uid> {
+        self.topics.get(topic).map_or(vec![], |subscribers| subscribers.iter().cloned().collect())
+    }
+
+    /// Topics with at least one subscriber
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.keys().cloned().collect()
+    }
+
+    fn subscribe(&mut self, uid: Uid, topic: &str) {
+        self.topics.entry(topic.to_string()).or_insert_with(HashSet::new).insert(uid);
+        self.subscriptions.entry(uid).or_insert_with(HashSet::new).insert(topic.to_string());
+    }
+
+    fn unsubscribe(&mut self, uid: Uid, topic: &str) {
+        let empty = match self.topics.get_mut(topic) {
+            Some(subscribers) => {
+                subscribers.remove(&uid);
+                subscribers.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.topics.remove(topic);
+        }
+        if let Some(topics) = self.subscriptions.get_mut(&uid) {
+            topics.remove(topic);
+        }
+    }
+
+    fn handle_frame(&mut self, uid: Uid, frame: &[u8]) -> Result<(), ServerError> {
+        match try!(decode(frame)) {
+            (KIND_SUBSCRIBE, topic, _) => self.subscribe(uid, topic),
+            (KIND_UNSUBSCRIBE, topic, _) => self.unsubscribe(uid, topic),
+            (KIND_PUBLISH, topic, payload) => {
+                try!(self.publish(topic, payload));
+            }
+            _ => return Err(ServerError::from(NetError::Protocol("unexpected pubsub frame from a client"))),
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for PubSubServer {
+    type Item = Event;
+    /// Block waiting for the next `Event`, handling the clients'
+    /// frames meanwhile (so no `Recv` events are returned). Invalid
+    /// frames are returned as `Event::UnexpectedError`.
+    fn next(&mut self) -> Option<Event> {
+        loop {
+            match self.server.next() {
+                Some(Event::Recv(uid, frame)) => {
+                    if let Err(err) = self.handle_frame(uid, &frame) {
+                        return Some(Event::UnexpectedError(err));
+                    }
+                }
+                Some(Event::Disconnected(uid)) => {
+                    for topic in self.subscriptions.remove(&uid).unwrap_or_default() {
+                        self.unsubscribe(uid, &topic);
+                    }
+                    return Some(Event::Disconnected(uid));
+                }
+                evt => return evt,
+            }
+        }
+    }
+}
+
+fn encode(buf: &mut Vec<u8>, kind: u8, topic: &str, payload: &[u8]) -> Result<(), NetError> {
+    if topic.len() > MAX_TOPIC_LEN {
+        return Err(NetError::Protocol("pubsub topic too long"));
+    }
+    buf.clear();
+    buf.push(kind);
+    buf.extend_from_slice(&endian::u16_bytes(topic.len() as u16));
+    buf.extend_from_slice(topic.as_bytes());
+    buf.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Kind, topic and payload of a frame
+fn decode(frame: &[u8]) -> Result<(u8, &str, &[u8]), NetError> {
+    if frame.len() < 3 {
+        return Err(NetError::Protocol("pubsub frame shorter than its header"));
+    }
+    let len = endian::read_u16(&frame[1..3]) as usize;
+    if frame.len() < 3 + len {
+        return Err(NetError::Protocol("pubsub topic longer than its frame"));
+    }
+    match str::from_utf8(&frame[3..3 + len]) {
+        Ok(topic) => Ok((frame[0], topic, &frame[3 + len..])),
+        Err(_) => Err(NetError::Protocol("pubsub topic is not UTF-8")),
+    }
+}
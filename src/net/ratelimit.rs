@@ -0,0 +1,164 @@
+/// Outbound rate limiting for framed streams, with a token bucket for
+/// the bytes and one for the frames sent per second.
+
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use net::{NetError, Framed, FrameBuf};
+
+/// Refilled at `rate` tokens per second, up to `capacity`
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full
+    fn new(rate: u64, burst: u64) -> TokenBucket {
+        assert!(rate > 0 && burst > 0);
+        TokenBucket {
+            rate: rate as f64,
+            capacity: burst as f64,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now - self.last;
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// How long until `n` tokens can be taken. More than the capacity
+    /// can be taken once the bucket is full, leaving it in debt.
+    fn wait(&self, n: u64) -> Duration {
+        let needed = (n as f64).min(self.capacity);
+        if self.tokens >= needed {
+            return Duration::new(0, 0);
+        }
+        let nanos = ((needed - self.tokens) / self.rate * 1e9).ceil() as u64;
+        Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+
+    fn take(&mut self, n: u64) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// Framed stream whose writes are throttled. Reads go through
+/// `get_mut()` and aren't limited.
+pub struct RateLimited<T: Read + Write> {
+    inner: Framed<T>,
+    bytes: Option<TokenBucket>,
+    frames: Option<TokenBucket>,
+    blocking: bool,
+}
+
+impl<T: Read + Write> RateLimited<T> {
+    /// No limits until `set_byte_rate()` or `set_frame_rate()`
+    pub fn new(inner: Framed<T>) -> RateLimited<T> {
+        RateLimited {
+            inner: inner,
+            bytes: None,
+            frames: None,
+            blocking: true,
+        }
+    }
+
+    pub fn get_ref(&self) -> &Framed<T> {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut Framed<T> {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> Framed<T> {
+        self.inner
+    }
+
+    /// Limit the frame bytes (headers not included) sent per second,
+    /// allowing bursts of up to `burst` bytes. A frame larger than
+    /// the burst is sent once the bucket is full, and the following
+    /// ones wait for it to refill.
+    pub fn set_byte_rate(&mut self, rate: Option<u64>, burst: u64) {
+        self.bytes = rate.map(|rate| TokenBucket::new(rate, burst));
+    }
+
+    /// Limit the frames sent per second, allowing bursts of up to
+    /// `burst` frames
+    pub fn set_frame_rate(&mut self, rate: Option<u64>, burst: u64) {
+        self.frames = rate.map(|rate| TokenBucket::new(rate, burst));
+    }
+
+    /// When over the limit, writes either sleep until they can go
+    /// (the default) or fail right away with a `WouldBlock` I/O error
+    /// without sending anything
+    pub fn set_blocking(&mut self, blocking: bool) {
+        self.blocking = blocking;
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        try!(self.acquire(frame.len()));
+        self.inner.write_frame(frame)
+    }
+
+    pub fn write_tagged_frame(&mut self, tag: u8, frame: &[u8]) -> Result<(), NetError> {
+        try!(self.acquire(frame.len()));
+        self.inner.write_tagged_frame(tag, frame)
+    }
+
+    /// Frames are counted when buffered, not when flushed
+    pub fn write_frame_buffered(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        try!(self.acquire(frame.len()));
+        self.inner.write_frame_buffered(frame)
+    }
+
+    /// Preframed frames are counted with their header
+    pub fn write_preframed(&mut self, frame: &FrameBuf) -> Result<(), NetError> {
+        try!(self.acquire(frame.as_bytes().len()));
+        self.inner.write_preframed(frame)
+    }
+
+    pub fn flush(&mut self) -> Result<(), NetError> {
+        self.inner.flush()
+    }
+
+    /// Take tokens for a frame of `len` bytes, waiting for them if
+    /// blocking
+    fn acquire(&mut self, len: usize) -> Result<(), NetError> {
+        loop {
+            let now = Instant::now();
+            let mut wait = Duration::new(0, 0);
+            if let Some(ref mut bucket) = self.bytes {
+                bucket.refill(now);
+                wait = wait.max(bucket.wait(len as u64));
+            }
+            if let Some(ref mut bucket) = self.frames {
+                bucket.refill(now);
+                wait = wait.max(bucket.wait(1));
+            }
+            if wait == Duration::new(0, 0) {
+                if let Some(ref mut bucket) = self.bytes {
+                    bucket.take(len as u64);
+                }
+                if let Some(ref mut bucket) = self.frames {
+                    bucket.take(1);
+                }
+                return Ok(());
+            }
+            if !self.blocking {
+                return Err(NetError::Io(io::Error::new(io::ErrorKind::WouldBlock,
+                                                       "rate limit exceeded")));
+            }
+            thread::sleep(wait);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+/// Read buffer of the async server connections: it starts small,
+/// grows to fit the message being read and shrinks back once the
+/// connection is idle.
+
+use std::cmp;
+
+/// Size of the read buffers of new (and idle) connections
+pub const READ_BUF_SIZE: usize = 4*1024;
+
+/// Bytes received and not parsed yet
+pub struct ReadBuf {
+    data: Vec<u8>,
+    /// The unparsed bytes are `data[start..end]`
+    start: usize,
+    end: usize,
+}
+
+impl ReadBuf {
+    pub fn new() -> ReadBuf {
+        ReadBuf {
+            data: vec![0; READ_BUF_SIZE],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// A buffer without storage, standing in for one taken out of its
+    /// connection
+    pub fn empty() -> ReadBuf {
+        ReadBuf {
+            data: vec![],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Current size of the buffer
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Consume the next `len` unparsed bytes
+    pub fn take(&mut self, len: usize) -> &[u8] {
+        let start = self.start;
+        self.start += len;
+        &self.data[start..self.start]
+    }
+
+    /// The last `len` bytes consumed, still in place until the next
+    /// `space()`
+    pub fn taken(&self, len: usize) -> &[u8] {
+        &self.data[self.start - len..self.start]
+    }
+
+    /// Free space to read into, with room for at least `needed`
+    /// unparsed bytes: they are moved to the front first, and the
+    /// buffer grows if that's not enough
+    pub fn space(&mut self, needed: usize) -> &mut [u8] {
+        if self.end == self.data.len() || self.start + needed > self.data.len() {
+            self.data.drain(..self.start);
+            self.end -= self.start;
+            self.start = 0;
+            let len = cmp::max(self.data.len(), needed);
+            self.data.resize(len, 0);
+        }
+        &mut self.data[self.end..]
+    }
+
+    /// Mark `len` bytes of `space()` as received
+    pub fn filled(&mut self, len: usize) {
+        self.end += len;
+    }
+
+    /// Go back to the initial size if nothing is left to parse, after
+    /// a large message
+    pub fn shrink(&mut self) {
+        if self.remaining() == 0 && self.data.len() > READ_BUF_SIZE {
+            self.data = vec![0; READ_BUF_SIZE];
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+}
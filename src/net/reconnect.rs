@@ -0,0 +1,208 @@
+/// Framed TCP client that reconnects when the connection is lost,
+/// waiting between failed attempts as set by a `ReconnectPolicy`.
+
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use net::{NetError, FramedTcpStream, FramedTcpStreamBuilder};
+use net::sync::ReconnectPolicy;
+
+/// Reported by `ReconnectingFramedStream::take_events()`
+#[derive(Debug)]
+pub enum ReconnectEvent {
+    /// The connection was lost because of the given error
+    Disconnected(NetError),
+    /// Reconnection attempt number `attempt` failed, the next one is
+    /// in `retry_in`
+    AttemptFailed { attempt: u32, retry_in: Duration },
+    /// Connected again after `attempts` attempts
+    Reconnected { attempts: u32 },
+}
+
+pub struct ReconnectingFramedStream {
+    addrs: Vec<SocketAddr>,
+    builder: FramedTcpStreamBuilder,
+    stream: Option<FramedTcpStream>,
+    policy: ReconnectPolicy,
+    /// Failed attempts since the connection was lost
+    attempts: u32,
+    next_attempt: Instant,
+    /// Frames written while disconnected, at most `max_pending`
+    pending: VecDeque<Vec<u8>>,
+    max_pending: usize,
+    events: Vec<ReconnectEvent>,
+}
+
+impl ReconnectingFramedStream {
+    /// Connect to `addr` with the builder's options. Only this first
+    /// connection isn't retried.
+    pub fn connect<A: ToSocketAddrs>(addr: A, builder: FramedTcpStreamBuilder)
+                                     -> Result<ReconnectingFramedStream, NetError> {
+        let addrs: Vec<SocketAddr> = try!(addr.to_socket_addrs()).collect();
+        let stream = try!(builder.connect(&addrs[..]));
+        Ok(ReconnectingFramedStream {
+            addrs: addrs,
+            builder: builder,
+            stream: Some(stream),
+            policy: ReconnectPolicy::new(),
+            attempts: 0,
+            next_attempt: Instant::now(),
+            pending: VecDeque::new(),
+            max_pending: 0,
+            events: Vec::new(),
+        })
+    }
+
+    /// How long to wait between attempts, and how many to make
+    /// (`ReconnectPolicy::new()` by default). Once the policy gives up
+    /// `NetError::Closed` is returned, and the following operations
+    /// start over.
+    pub fn set_policy(&mut self, policy: ReconnectPolicy) {
+        self.policy = policy;
+    }
+
+    /// Keep up to `frames` frames written while disconnected, sending
+    /// them once reconnected. With 0 (the default) such writes fail.
+    /// A frame being written when the connection is lost may or may
+    /// not have been received.
+    pub fn set_write_buffering(&mut self, frames: usize) {
+        self.max_pending = frames;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// The current connection
+    pub fn get_ref(&self) -> Option<&FramedTcpStream> {
+        self.stream.as_ref()
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut FramedTcpStream> {
+        self.stream.as_mut()
+    }
+
+    /// Events since the last call, oldest first
+    pub fn take_events(&mut self) -> Vec<ReconnectEvent> {
+        mem::replace(&mut self.events, Vec::new())
+    }
+
+    /// Read a frame, reconnecting (and sleeping between attempts) as
+    /// many times as needed. Timeouts are returned as usual.
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, NetError> {
+        loop {
+            try!(self.wait_connected());
+            let res = self.stream.as_mut().unwrap().read_frame();
+            match res {
+                Err(err) => {
+                    if let Some(err) = self.check_error(err) {
+                        return Err(err);
+                    }
+                }
+                Ok(frame) => return Ok(frame),
+            }
+        }
+    }
+
+    /// Write a frame, or buffer it while disconnected (see
+    /// `set_write_buffering()`). A reconnection is attempted if one is
+    /// due, without waiting.
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        if self.stream.is_none() {
+            try!(self.try_reconnect());
+        }
+        if self.stream.is_some() {
+            let res = self.stream.as_mut().unwrap().write_frame(frame);
+            match res {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    if let Some(err) = self.check_error(err) {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        if self.pending.len() >= self.max_pending {
+            return Err(NetError::Closed);
+        }
+        self.pending.push_back(frame.to_vec());
+        Ok(())
+    }
+
+    /// Drop the connection if `err` means it is lost, returning the
+    /// errors that should be passed on
+    fn check_error(&mut self, err: NetError) -> Option<NetError> {
+        let lost = match *err.root() {
+            NetError::Io(ref err) => err.kind() != io::ErrorKind::WouldBlock,
+            NetError::Closed | NetError::PeerDead => true,
+            _ => false,
+        };
+        if !lost {
+            return Some(err);
+        }
+        self.stream = None;
+        self.attempts = 0;
+        self.next_attempt = Instant::now();
+        self.events.push(ReconnectEvent::Disconnected(err));
+        None
+    }
+
+    /// Reconnect, sleeping between attempts
+    fn wait_connected(&mut self) -> Result<(), NetError> {
+        while self.stream.is_none() {
+            let now = Instant::now();
+            if self.next_attempt > now {
+                thread::sleep(self.next_attempt - now);
+            }
+            try!(self.try_reconnect());
+        }
+        Ok(())
+    }
+
+    /// Make an attempt if one is due, sending the buffered frames on
+    /// success
+    fn try_reconnect(&mut self) -> Result<(), NetError> {
+        if Instant::now() < self.next_attempt {
+            return Ok(());
+        }
+        match self.builder.connect(&self.addrs[..]) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.events.push(ReconnectEvent::Reconnected { attempts: self.attempts + 1 });
+                self.attempts = 0;
+                while let Some(frame) = self.pending.pop_front() {
+                    let res = self.stream.as_mut().unwrap().write_frame(&frame);
+                    if let Err(err) = res {
+                        self.pending.push_front(frame);
+                        if let Some(err) = self.check_error(err) {
+                            return Err(err);
+                        }
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Err(_) => {
+                self.attempts += 1;
+                match self.policy.retry_delay(self.attempts) {
+                    Some(retry_in) => {
+                        self.next_attempt = Instant::now() + retry_in;
+                        self.events.push(ReconnectEvent::AttemptFailed {
+                            attempt: self.attempts,
+                            retry_in: retry_in,
+                        });
+                        Ok(())
+                    }
+                    None => {
+                        self.attempts = 0;
+                        Err(NetError::Closed)
+                    }
+                }
+            }
+        }
+    }
+}
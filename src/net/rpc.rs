@@ -0,0 +1,228 @@
+/// Request/response calls between `sync::Server`s. Each frame starts
+/// with a kind byte (request, response, error or one-way message) and
+/// a correlation id (u64 big-endian) matching responses to requests.
+/// Both ends must use an `RpcServer`.
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use net::NetError;
+use net::endian;
+use net::sync::{Server, Event, RecvBuf, ServerError, Uid};
+
+const RPC_HEADER_SIZE: usize = 9;
+
+const KIND_REQUEST: u8 = 0;
+const KIND_RESPONSE: u8 = 1;
+const KIND_ERROR: u8 = 2;
+const KIND_MESSAGE: u8 = 3;
+
+/// Answers requests: the response, or an error message for the caller
+pub type RequestHandler = Box<FnMut(Uid, &[u8]) -> Result<Vec<u8>, String> + Send>;
+
+#[derive(Debug)]
+pub enum RpcError {
+    Server(ServerError),
+    /// No response within the timeout
+    Timeout,
+    /// The connection (or the server) closed before the response
+    Disconnected,
+    /// The peer's handler failed (or it has none), with its message
+    Remote(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RpcError::Server(ref err) => write!(f, "{}", err),
+            RpcError::Timeout => write!(f, "call timed out"),
+            RpcError::Disconnected => write!(f, "disconnected before the response"),
+            RpcError::Remote(ref msg) => write!(f, "remote error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for RpcError {
+    fn description(&self) -> &str {
+        match *self {
+            RpcError::Server(ref err) => err.description(),
+            RpcError::Timeout => "call timed out",
+            RpcError::Disconnected => "disconnected before the response",
+            RpcError::Remote(ref msg) => msg,
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            RpcError::Server(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ServerError> for RpcError {
+    fn from(err: ServerError) -> RpcError {
+        RpcError::Server(err)
+    }
+}
+
+/// A decoded frame, with the header skipped in its buffer
+enum Frame {
+    Request(u64, RecvBuf),
+    Response(u64, Result<RecvBuf, String>),
+    Message(RecvBuf),
+}
+
+pub struct RpcServer {
+    server: Server,
+    handler: Option<RequestHandler>,
+    next_id: u64,
+    /// Events read while waiting for a response, delivered by the
+    /// iterator
+    events: VecDeque<Event>,
+    buf: Vec<u8>,
+}
+
+impl RpcServer {
+    /// Wrap the server, started or not
+    pub fn new(server: Server) -> RpcServer {
+        RpcServer {
+            server: server,
+            handler: None,
+            next_id: 0,
+            events: VecDeque::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// The wrapped server, for everything besides frames (`start()`,
+    /// `connect()`, `shutdown()`...). Sending frames directly will
+    /// confuse the peer.
+    pub fn server(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> Server {
+        self.server
+    }
+
+    /// Answer the requests of any connection with `handler`. Without
+    /// one, callers get `RpcError::Remote`. Requests are handled while
+    /// iterating over the events, or waiting in `call()`.
+    pub fn on_request<F>(&mut self, handler: F)
+        where F: FnMut(Uid, &[u8]) -> Result<Vec<u8>, String> + Send + 'static
+    {
+        self.handler = Some(Box::new(handler));
+    }
+
+    /// Send `request` to `dest` and wait up to `timeout` for its
+    /// response. The other events received meanwhile are kept for the
+    /// iterator, except requests which are answered right away (so
+    /// both ends can call each other). Late responses are dropped.
+    pub fn call(&mut self, dest: Uid, request: &[u8], timeout: Duration) -> Result<Vec<u8>, RpcError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        try!(self.write(dest, KIND_REQUEST, id, request));
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RpcError::Timeout);
+            }
+            let evt = match self.server.next_event_timeout(deadline - now) {
+                Some(evt) => evt,
+                // `Event::Shutdown` was already delivered
+                None if self.server.is_done() => return Err(RpcError::Disconnected),
+                None => continue,
+            };
+            match evt {
+                Event::Recv(uid, frame) => match decode(frame) {
+                    Ok(Frame::Response(resp_id, res)) => {
+                        if uid == dest && resp_id == id {
+                            return res.map(|resp| resp.to_vec()).map_err(RpcError::Remote);
+                        }
+                    }
+                    Ok(Frame::Request(req_id, req)) => self.answer(uid, req_id, &req),
+                    Ok(Frame::Message(msg)) => self.events.push_back(Event::Recv(uid, msg)),
+                    Err(err) => self.events.push_back(Event::UnexpectedError(ServerError::from(err))),
+                },
+                Event::Disconnected(uid) if uid == dest => {
+                    self.events.push_back(Event::Disconnected(uid));
+                    return Err(RpcError::Disconnected);
+                }
+                Event::Shutdown => {
+                    self.events.push_back(Event::Shutdown);
+                    return Err(RpcError::Disconnected);
+                }
+                evt => self.events.push_back(evt),
+            }
+        }
+    }
+
+    /// Send a one-way message, delivered as `Event::Recv` by the peer
+    pub fn send_to(&mut self, dest: Uid, data: &[u8]) -> Result<(), ServerError> {
+        self.write(dest, KIND_MESSAGE, 0, data)
+    }
+
+    fn answer(&mut self, uid: Uid, id: u64, request: &[u8]) {
+        let res = match self.handler {
+            Some(ref mut handler) => handler(uid, request),
+            None => Err("no request handler".to_string()),
+        };
+        // failing to answer means the connection is going away
+        match res {
+            Ok(response) => self.write(uid, KIND_RESPONSE, id, &response).is_ok(),
+            Err(msg) => self.write(uid, KIND_ERROR, id, msg.as_bytes()).is_ok(),
+        };
+    }
+
+    fn write(&mut self, dest: Uid, kind: u8, id: u64, data: &[u8]) -> Result<(), ServerError> {
+        self.buf.clear();
+        self.buf.push(kind);
+        self.buf.extend_from_slice(&endian::u64_bytes(id));
+        self.buf.extend_from_slice(data);
+        self.server.send_to(dest, &self.buf)
+    }
+}
+
+impl Iterator for RpcServer {
+    type Item = Event;
+    /// Block waiting for the next `Event`, answering requests
+    /// meanwhile. `Recv` events are the one-way messages.
+    fn next(&mut self) -> Option<Event> {
+        if let Some(evt) = self.events.pop_front() {
+            return Some(evt);
+        }
+        loop {
+            match self.server.next() {
+                Some(Event::Recv(uid, frame)) => match decode(frame) {
+                    Ok(Frame::Request(id, req)) => self.answer(uid, id, &req),
+                    // the call timed out
+                    Ok(Frame::Response(..)) => {}
+                    Ok(Frame::Message(msg)) => return Some(Event::Recv(uid, msg)),
+                    Err(err) => return Some(Event::UnexpectedError(ServerError::from(err))),
+                },
+                evt => return evt,
+            }
+        }
+    }
+}
+
+fn decode(mut frame: RecvBuf) -> Result<Frame, NetError> {
+    if frame.len() < RPC_HEADER_SIZE {
+        return Err(NetError::Protocol("rpc frame shorter than its header"));
+    }
+    let kind = frame[0];
+    let id = endian::read_u64(&frame[1..RPC_HEADER_SIZE]);
+    // the payload stays in place, so a pooled buffer is still reused
+    frame.advance(RPC_HEADER_SIZE);
+    match kind {
+        KIND_REQUEST => Ok(Frame::Request(id, frame)),
+        KIND_RESPONSE => Ok(Frame::Response(id, Ok(frame))),
+        KIND_ERROR => Ok(Frame::Response(id, Err(String::from_utf8_lossy(&frame).into_owned()))),
+        KIND_MESSAGE => Ok(Frame::Message(frame)),
+        _ => Err(NetError::Protocol("invalid rpc frame kind")),
+    }
+}
@@ -0,0 +1,388 @@
+/// Streams and listeners over either TCP or unix sockets, so that the
+/// servers can handle both the same way.
+
+use std::fmt;
+use std::io;
+use std::io::{IoSlice, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, Shutdown, SocketAddr};
+#[cfg(unix)]
+use std::os::unix;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+
+use net::Framed;
+
+/// Address of either end of a connection
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Addr {
+    Inet(SocketAddr),
+    /// Path of a unix socket, `None` if unnamed (as clients usually are)
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl Addr {
+    /// The TCP address, if it is one
+    pub fn inet(&self) -> Option<SocketAddr> {
+        match *self {
+            Addr::Inet(addr) => Some(addr),
+            #[cfg(unix)]
+            Addr::Unix(_) => None,
+        }
+    }
+
+    #[cfg(unix)]
+    fn unix(addr: unix::net::SocketAddr) -> Addr {
+        Addr::Unix(addr.as_pathname().map(Path::to_path_buf))
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Addr {
+        Addr::Inet(addr)
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Addr::Inet(ref addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Addr::Unix(Some(ref path)) => write!(f, "{}", path.display()),
+            #[cfg(unix)]
+            Addr::Unix(None) => write!(f, "(unnamed unix socket)"),
+        }
+    }
+}
+
+/// IP network such as `10.0.0.0/8`, e.g. for accept filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    ip: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// `None` if `prefix` is longer than the address
+    pub fn new(ip: IpAddr, prefix: u8) -> Option<Cidr> {
+        if prefix > max_prefix(ip) {
+            return None;
+        }
+        Some(Cidr {
+            ip: ip,
+            prefix: prefix,
+        })
+    }
+
+    /// Parse `ip/prefix`, or a single address
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let mut parts = s.splitn(2, '/');
+        let ip = match parts.next().unwrap_or("").parse() {
+            Ok(ip) => ip,
+            Err(_) => return None,
+        };
+        match parts.next() {
+            Some(prefix) => prefix.parse().ok().and_then(|prefix| Cidr::new(ip, prefix)),
+            None => Cidr::new(ip, max_prefix(ip)),
+        }
+    }
+
+    /// Whether `addr` is a TCP address in this network
+    pub fn contains(&self, addr: &Addr) -> bool {
+        let ip = match *addr {
+            Addr::Inet(addr) => addr.ip(),
+            #[cfg(unix)]
+            Addr::Unix(_) => return false,
+        };
+        match (self.ip, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(&net.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_eq(&net.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+fn max_prefix(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Whether the first `bits` bits of `a` and `b` are equal
+fn prefix_eq(a: &[u8], b: &[u8], bits: u8) -> bool {
+    let bytes = bits as usize / 8;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    let rest = bits % 8;
+    rest == 0 || (a[bytes] ^ b[bytes]) >> (8 - rest) == 0
+}
+
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn connect(addr: &Addr) -> io::Result<Socket> {
+        match *addr {
+            Addr::Inet(addr) => Ok(Socket::Tcp(try!(TcpStream::connect(addr)))),
+            #[cfg(unix)]
+            Addr::Unix(Some(ref path)) => Ok(Socket::Unix(try!(UnixStream::connect(path)))),
+            #[cfg(unix)]
+            Addr::Unix(None) => Err(io::Error::new(io::ErrorKind::InvalidInput, "unnamed unix socket")),
+        }
+    }
+
+    /// Wrap for frames, with `TCP_NODELAY` set like `FramedTcpStream::new()`
+    pub fn framed(self) -> Framed<Socket> {
+        if let Socket::Tcp(ref stream) = self {
+            stream.set_nodelay(true).is_ok();
+        }
+        Framed::wrap(self)
+    }
+
+    pub fn try_clone(&self) -> io::Result<Socket> {
+        match *self {
+            Socket::Tcp(ref stream) => stream.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Socket::Unix(ref stream) => stream.try_clone().map(Socket::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(ref stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(ref stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<Addr> {
+        match *self {
+            Socket::Tcp(ref stream) => stream.peer_addr().map(Addr::Inet),
+            #[cfg(unix)]
+            Socket::Unix(ref stream) => stream.peer_addr().map(Addr::unix),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match *self {
+            Socket::Tcp(ref stream) => stream.local_addr().map(Addr::Inet),
+            #[cfg(unix)]
+            Socket::Unix(ref stream) => stream.local_addr().map(Addr::unix),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Socket::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Socket::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.write_vectored(bufs),
+            #[cfg(unix)]
+            Socket::Unix(ref mut stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Socket::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Socket::Tcp(ref stream) => stream.as_raw_fd(),
+            #[cfg(unix)]
+            Socket::Unix(ref stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Socket options for the TCP listeners of a server (unix socket
+/// listeners ignore them). The same builder can be used for many
+/// listeners.
+#[derive(Debug, Clone)]
+pub struct ListenerBuilder {
+    reuse_address: bool,
+    reuse_port: bool,
+    only_v6: Option<bool>,
+    backlog: i32,
+}
+
+impl ListenerBuilder {
+    /// Same options as `TcpListener::bind()`
+    pub fn new() -> Self {
+        ListenerBuilder {
+            reuse_address: true,
+            reuse_port: false,
+            only_v6: None,
+            backlog: 128,
+        }
+    }
+
+    /// Bind even if connections to the port from a previous listener
+    /// linger (in TIME_WAIT). On by default.
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.reuse_address = reuse;
+        self
+    }
+
+    /// Let other listeners with this option bind the same address, so
+    /// the kernel balances the connections between them (e.g. between
+    /// processes). The connections `Server::shutdown()` makes to wake
+    /// up its accept threads may then reach the other listeners.
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// `IPV6_V6ONLY` for IPv6 addresses: don't also accept IPv4
+    /// connections on an unspecified address (`[::]`). The system
+    /// default if not set.
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// How many connections can wait to be accepted
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let builder = try!(match addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4(),
+            SocketAddr::V6(_) => TcpBuilder::new_v6(),
+        });
+        try!(builder.reuse_address(self.reuse_address));
+        if self.reuse_port {
+            try!(reuse_port(&builder));
+        }
+        if let (SocketAddr::V6(_), Some(only_v6)) = (addr, self.only_v6) {
+            try!(builder.only_v6(only_v6));
+        }
+        try!(builder.bind(addr));
+        builder.listen(self.backlog)
+    }
+}
+
+impl Listener {
+    pub fn bind(addr: &Addr, builder: &ListenerBuilder) -> io::Result<Listener> {
+        match *addr {
+            Addr::Inet(addr) => Ok(Listener::Tcp(try!(builder.bind(addr)))),
+            #[cfg(unix)]
+            Addr::Unix(Some(ref path)) => Ok(Listener::Unix(try!(UnixListener::bind(path)))),
+            #[cfg(unix)]
+            Addr::Unix(None) => Err(io::Error::new(io::ErrorKind::InvalidInput, "unnamed unix socket")),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Socket> {
+        match *self {
+            Listener::Tcp(ref l) => l.accept().map(|(stream, _)| Socket::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.accept().map(|(stream, _)| Socket::Unix(stream)),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Listener> {
+        match *self {
+            Listener::Tcp(ref l) => l.try_clone().map(Listener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.try_clone().map(Listener::Unix),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match *self {
+            Listener::Tcp(ref l) => l.local_addr().map(Addr::Inet),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.local_addr().map(Addr::unix),
+        }
+    }
+
+    /// Connect to the listener, to wake up a thread blocked in `accept()`
+    pub fn wake(&self) -> io::Result<()> {
+        let addr = match try!(self.local_addr()) {
+            Addr::Inet(addr) => Addr::Inet(wake_addr(addr)),
+            #[cfg(unix)]
+            addr => addr,
+        };
+        Socket::connect(&addr).map(|_| ())
+    }
+}
+
+#[cfg(unix)]
+fn reuse_port(builder: &TcpBuilder) -> io::Result<()> {
+    builder.reuse_port(true).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn reuse_port(_builder: &TcpBuilder) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "SO_REUSEPORT is only available on unix"))
+}
+
+/// Address to connect to a listener bound to `addr`
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), addr.port()),
+        _ => addr,
+    }
+}
@@ -1,11 +1,48 @@
-use std::collections::HashMap;
-use std::net::{TcpListener, Shutdown, SocketAddr};
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::net::{TcpStream, Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
 
-use net::{FramedTcpStream, NetError};
+use rand;
+
+use mio::{EventLoop, EventSet, Handler, PollOpt, Sender, Token};
+#[cfg(unix)]
+use mio::unix::EventedFd;
+
+use net::{Framed, FrameBuf, FrameFormat, FrameParts, FrameHooks, NetError, ProxyHeader, read_proxy_header};
+use net::socket::{Listener, Socket};
+pub use net::socket::{Addr, Cidr, ListenerBuilder};
+pub use net::client::Client;
+#[cfg(feature = "codec")]
+pub use net::typed::{TypedServer, TypedEvent};
+#[cfg(feature = "tls")]
+use net::{FramedTlsStream, FramedTcpStreamBuilder};
+#[cfg(feature = "tls")]
+use native_tls::TlsAcceptor;
+#[cfg(feature = "codec")]
+use net::Codec;
+#[cfg(feature = "codec")]
+use serde::Serialize;
+#[cfg(feature = "codec")]
+use serde::de::DeserializeOwned;
 
 #[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub struct Uid(u64);
@@ -16,14 +53,326 @@ impl Uid {
     }
 }
 
+/// Default size of the event queue
 const QUEUE_SIZE: usize = 32*1024;
 
+/// Most receive buffers kept for reuse, and the largest one kept
+const RECV_POOL_SIZE: usize = 1024;
+const MAX_POOLED_BUF_SIZE: usize = 64*1024;
+
+/// Read timeout of pooled connections, so a client that never sends
+/// its PROXY header doesn't hold up the other connections of its
+/// worker for long
+const POOL_READ_TIMEOUT_MS: u64 = 1000;
+
+/// Settings applied to each accepted connection
+#[derive(Clone, Debug, Default)]
+struct ConnectionOptions {
+    proxy_protocol: bool,
+    idle_timeout: Option<Duration>,
+    /// Ping interval and how many can be missed
+    heartbeat: Option<(Duration, u32)>,
+    /// Largest frame to receive, if not the default
+    max_frame_size: Option<usize>,
+    hooks: FrameHooks,
+    /// Perform the TLS handshake before framing
+    #[cfg(feature = "tls")]
+    tls: Option<Acceptor>,
+}
+
+impl ConnectionOptions {
+    #[cfg(feature = "tls")]
+    fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn is_tls(&self) -> bool {
+        false
+    }
+
+    /// How often receivers wake up while waiting, to send pings or
+    /// check the idle timeout
+    fn read_timeout(&self) -> Option<Duration> {
+        match (self.heartbeat, self.idle_timeout) {
+            (Some((interval, _)), Some(timeout)) => Some(cmp::min(interval, timeout)),
+            (Some((interval, _)), None) => Some(interval),
+            (None, timeout) => timeout,
+        }
+    }
+}
+
+/// The acceptor of a TLS listener, shared by its connections
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+struct Acceptor(Arc<TlsAcceptor>);
+
+#[cfg(feature = "tls")]
+impl fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsAcceptor")
+    }
+}
+
+/// Information about a connection, see `Server::connection_info()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub peer_addr: Addr,
+    pub local_addr: Addr,
+    /// The client's address: from the PROXY protocol header if enabled,
+    /// otherwise the peer's
+    pub client_addr: Addr,
+    pub connected_at: SystemTime,
+    /// Frame payload bytes received and sent
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// Server-wide counters, see `Server::stats()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections currently open (as seen through the events)
+    pub connections: usize,
+    /// Connections accepted from the listeners since `start()`
+    pub accepted: u64,
+    /// Frames and frame payload bytes received and sent
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+    /// Events waiting to be consumed
+    pub queued_events: usize,
+}
+
+/// A connection, as kept by the server
+struct Connection {
+    stream: Writer,
+    peer_addr: Addr,
+    local_addr: Addr,
+    client_addr: Addr,
+    connected_at: SystemTime,
+    /// Updated by the connection's receiver
+    bytes_in: Arc<AtomicUsize>,
+    bytes_out: u64,
+    /// Frames for the writer thread, with a send queue
+    outbound: Option<Arc<SendQueue>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // stop the writer thread
+        if let Some(ref queue) = self.outbound {
+            queue.close();
+        }
+    }
+}
+
+/// Write half of a connection
+enum Writer {
+    Plain(Framed<Socket>),
+    /// A TLS stream can't be split, so it is shared with the receiver.
+    /// The socket is for shutting down without waiting for the lock.
+    #[cfg(feature = "tls")]
+    Tls(Arc<Mutex<FramedTlsStream>>, TcpStream),
+}
+
+impl Writer {
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), NetError> {
+        match *self {
+            Writer::Plain(ref mut stream) => stream.write_frame(data),
+            #[cfg(feature = "tls")]
+            Writer::Tls(ref stream, _) => stream.lock().unwrap().write_frame(data),
+        }
+    }
+
+    fn preframe(&mut self, data: &[u8]) -> Result<FrameBuf, NetError> {
+        match *self {
+            Writer::Plain(ref mut stream) => stream.preframe(data),
+            #[cfg(feature = "tls")]
+            Writer::Tls(ref stream, _) => stream.lock().unwrap().preframe(data),
+        }
+    }
+
+    fn write_preframed(&mut self, frame: &FrameBuf) -> Result<(), NetError> {
+        match *self {
+            Writer::Plain(ref mut stream) => stream.write_preframed(frame),
+            #[cfg(feature = "tls")]
+            Writer::Tls(ref stream, _) => stream.lock().unwrap().write_preframed(frame),
+        }
+    }
+
+    fn frame_parts<'a>(&self, data: &'a [u8]) -> Result<FrameParts<'a>, NetError> {
+        match *self {
+            Writer::Plain(ref stream) => stream.frame_parts(data),
+            #[cfg(feature = "tls")]
+            Writer::Tls(ref stream, _) => stream.lock().unwrap().frame_parts(data),
+        }
+    }
+
+    fn frame_format(&self) -> FrameFormat {
+        match *self {
+            Writer::Plain(ref stream) => stream.frame_format(),
+            #[cfg(feature = "tls")]
+            Writer::Tls(ref stream, _) => stream.lock().unwrap().frame_format(),
+        }
+    }
+
+    fn write_parts(&mut self, frame: &FrameParts) -> Result<(), NetError> {
+        match *self {
+            Writer::Plain(ref mut stream) => stream.write_parts(frame),
+            #[cfg(feature = "tls")]
+            Writer::Tls(ref stream, _) => stream.lock().unwrap().write_parts(frame),
+        }
+    }
+
+    /// Another handle to write with, for a writer thread
+    fn try_clone(&self) -> io::Result<Writer> {
+        match *self {
+            Writer::Plain(ref stream) => Ok(Writer::Plain(stream.clone_with(try!(stream.get_ref().try_clone())))),
+            #[cfg(feature = "tls")]
+            Writer::Tls(ref stream, ref socket) => Ok(Writer::Tls(stream.clone(), try!(socket.try_clone()))),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        match *self {
+            Writer::Plain(ref stream) => stream.get_ref().shutdown(Shutdown::Both).is_ok(),
+            #[cfg(feature = "tls")]
+            Writer::Tls(_, ref socket) => socket.shutdown(Shutdown::Both).is_ok(),
+        };
+    }
+}
+
+/// A received frame. Derefs to its payload, and its buffer is reused
+/// for the next frames once dropped.
+pub struct RecvBuf {
+    buf: Vec<u8>,
+    /// Start of the payload in `buf`, past the bytes skipped by
+    /// `advance()`
+    start: usize,
+    pool: Option<Arc<BufPool>>,
+}
+
+impl RecvBuf {
+    fn pooled(buf: Vec<u8>, pool: &Arc<BufPool>) -> RecvBuf {
+        RecvBuf {
+            buf: buf,
+            start: 0,
+            pool: Some(pool.clone()),
+        }
+    }
+
+    /// Skip the first `n` bytes of the payload (e.g. a header already
+    /// decoded), without moving the rest. Panics if `n` is greater
+    /// than the payload length.
+    pub fn advance(&mut self, n: usize) {
+        assert!(n <= self.len(), "advance() past the end of the payload");
+        self.start += n;
+    }
+
+    /// Take the payload, which then isn't reused
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        let mut buf = mem::replace(&mut self.buf, vec![]);
+        buf.drain(..self.start);
+        buf
+    }
+}
+
+impl Deref for RecvBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+}
+
+impl DerefMut for RecvBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..]
+    }
+}
+
+impl Drop for RecvBuf {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(mem::replace(&mut self.buf, vec![]));
+        }
+    }
+}
+
+/// Not pooled
+impl From<Vec<u8>> for RecvBuf {
+    fn from(buf: Vec<u8>) -> RecvBuf {
+        RecvBuf {
+            buf: buf,
+            start: 0,
+            pool: None,
+        }
+    }
+}
+
+impl fmt::Debug for RecvBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl PartialEq for RecvBuf {
+    fn eq(&self, other: &RecvBuf) -> bool {
+        **self == **other
+    }
+}
+
+impl<'a> PartialEq<&'a [u8]> for RecvBuf {
+    fn eq(&self, other: &&'a [u8]) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<Vec<u8>> for RecvBuf {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == other[..]
+    }
+}
+
+/// Buffers of the dropped `RecvBuf`s, for the receivers to read into
+struct BufPool {
+    bufs: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufPool {
+    fn take(&self) -> Option<Vec<u8>> {
+        self.bufs.lock().unwrap().pop()
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        if buf.capacity() > MAX_POOLED_BUF_SIZE {
+            return;
+        }
+        let mut bufs = self.bufs.lock().unwrap();
+        if bufs.len() < RECV_POOL_SIZE {
+            bufs.push(buf);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Event {
-    Recv(Uid, Vec<u8>),
+    Recv(Uid, RecvBuf),
     Connected(Uid),
     Disconnected(Uid),
     UnexpectedError(ServerError),
+    /// A client turned away by the accept filter, or by the connection
+    /// limit (see `LimitPolicy::Notify`)
+    Rejected(Addr),
+    /// The send queue of a connection went over its high-water mark,
+    /// see `Server::set_send_queue()`
+    SendBackpressure(Uid),
+    /// A connection was closed for sending a frame of the given size,
+    /// over the limit of `Server::set_max_frame_size()`
+    ProtocolError(Uid, usize),
+    /// Last event after `shutdown()`
+    Shutdown,
 }
 
 #[derive(Debug)]
@@ -31,6 +380,51 @@ pub enum ServerError {
     NotConnected,
     Net(NetError),
     InvalidState(&'static str),
+    /// Frames were dropped because the event queue was full (see
+    /// `OverflowPolicy::Error`)
+    QueueOverflow,
+    /// Server threads that panicked, or were still running when
+    /// `shutdown_and_wait()` timed out
+    Threads { panicked: usize, running: usize },
+    /// The policy of a `Server::connect_reconnecting()` connection gave
+    /// up, with the last error. The Uid won't come back.
+    ReconnectFailed(Uid, io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::NotConnected => write!(f, "not connected"),
+            ServerError::Net(ref err) => write!(f, "{}", err),
+            ServerError::InvalidState(msg) => write!(f, "invalid state: {}", msg),
+            ServerError::QueueOverflow => write!(f, "event queue full, frames dropped"),
+            ServerError::Threads { panicked, running } => {
+                write!(f, "{} server threads panicked, {} still running", panicked, running)
+            }
+            ServerError::ReconnectFailed(uid, ref err) => write!(f, "gave up reconnecting {:?}: {}", uid, err),
+        }
+    }
+}
+
+impl error::Error for ServerError {
+    fn description(&self) -> &str {
+        match *self {
+            ServerError::NotConnected => "not connected",
+            ServerError::Net(ref err) => err.description(),
+            ServerError::InvalidState(msg) => msg,
+            ServerError::QueueOverflow => "event queue full, frames dropped",
+            ServerError::Threads { .. } => "server threads failed to finish",
+            ServerError::ReconnectFailed(..) => "gave up reconnecting",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ServerError::Net(ref err) => Some(err),
+            ServerError::ReconnectFailed(_, ref err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 impl From<NetError> for ServerError {
@@ -51,146 +445,1931 @@ impl From<&'static str> for ServerError {
     }
 }
 
-/// Multiplexing server for framed messages over TCP.
-pub struct Server {
-    addr: SocketAddr,
-    listener: Option<TcpListener>,
-    events: (SyncSender<Event>, Receiver<Event>),
-    connections: HashMap<Uid, FramedTcpStream>,
-    new_connections: Arc<Mutex<HashMap<Uid, FramedTcpStream>>>,
+/// What to do with received frames when the event queue is full, see
+/// `Server::set_event_queue()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Receivers wait for room, so a slow consumer slows down every
+    /// client (the default)
+    Block,
+    /// Drop the oldest queued frame to make room
+    DropOldest,
+    /// Drop the frame that doesn't fit
+    DropNewest,
+    /// Drop the frame that doesn't fit and queue an
+    /// `Event::UnexpectedError(ServerError::QueueOverflow)`, once until
+    /// it is delivered
+    Error,
 }
 
-impl Server {
-    pub fn new(addr: SocketAddr) -> Server {
-        Server {
-            addr: addr,
-            listener: None,
-            events: sync_channel(QUEUE_SIZE), // TODO: how many events should be allowed here? make it unbounded?
-            connections: HashMap::new(),
-            // new_connections is used to avoid mutexes in the critical path
-            new_connections: Arc::new(Mutex::new(HashMap::new())),
+/// What to do with the connections accepted over the limit of
+/// `Server::set_max_connections()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Close them right away
+    Reject,
+    /// Stop accepting until a connection closes, leaving the new ones
+    /// waiting in the listen backlog
+    Queue,
+    /// Close them and deliver an `Event::Rejected` with their address
+    Notify,
+}
+
+/// Lane of the send queue a frame goes through, see
+/// `Server::send_to_with_priority()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Written before the others, and queued even when the queue is
+    /// full (e.g. small control messages)
+    High,
+    /// What `send_to()` uses
+    Normal,
+    /// Written when nothing else is queued (e.g. bulk transfers)
+    Low,
+}
+
+/// How an outgoing connection is reestablished when it is lost, see
+/// `Server::connect_reconnecting()` (also used by
+/// `async::ServerControl::connect_with_retry()` and
+/// `ReconnectingFramedStream`)
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: Option<u32>,
+    jitter: f64,
+}
+
+impl ReconnectPolicy {
+    /// Keep trying, waiting from 100ms up to 30s between attempts
+    /// with a jitter of 0.5
+    pub fn new() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+            jitter: 0.5,
         }
     }
 
-    /// Start accepting connections.
-    pub fn start(&mut self) -> Result<(), ServerError> {
-        self.listener = Some(try!(TcpListener::bind(&self.addr)));
-        let l = try!(self.listener.as_ref().unwrap().try_clone());
-        let ev = self.events.0.clone();
-        let nc = self.new_connections.clone();
-        // start accept thread
-        thread::spawn(move || { stream_receiver(l, Uid(0), ev, nc) });
-        Ok(())
+    /// Wait `initial` after the first failed attempt, doubling each
+    /// time up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
     }
 
-    /// Send a frame to the given destination. It should be connected already.
-    pub fn send_to(&mut self, dest: Uid, data: &[u8]) -> Result<(), ServerError> {
-        match self.connections.get_mut(&dest) {
-            Some(s) => {
-                try!(s.write_frame(data));
-            }
-            None => {
-                return Err(ServerError::NotConnected);
+    /// Give up after this many failed attempts in a row, delivering
+    /// an `Event::UnexpectedError(ServerError::ReconnectFailed)` with
+    /// the last error
+    pub fn max_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Shorten each wait by a random fraction of up to `jitter` (from
+    /// 0 to 1), so peers losing a server at once don't all come back
+    /// at the same time
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.max(0.0).min(1.0);
+        self
+    }
+
+    /// Wait before the next attempt after `attempts` failed ones in a
+    /// row, `None` once the policy gives up
+    pub fn retry_delay(&self, attempts: u32) -> Option<Duration> {
+        if self.max_attempts.map_or(false, |max| attempts >= max) {
+            return None;
+        }
+        let shift = cmp::min(attempts.saturating_sub(1), 31);
+        let backoff = self.initial_backoff.checked_mul(1 << shift)
+            .map_or(self.max_backoff, |backoff| cmp::min(backoff, self.max_backoff));
+        let ms = backoff.as_secs() as f64 * 1000.0 + backoff.subsec_nanos() as f64 / 1_000_000.0;
+        Some(Duration::from_millis((ms * (1.0 - self.jitter * rand::random::<f64>())) as u64))
+    }
+}
+
+/// Addresses of an outgoing connection, and how to reconnect to them
+struct ReconnectTarget {
+    addrs: Vec<Addr>,
+    policy: ReconnectPolicy,
+    /// Set by `close_connection()`, so a reconnection in progress
+    /// doesn't bring the link back
+    cancelled: AtomicBool,
+}
+
+impl ReconnectTarget {
+    fn new(addrs: Vec<Addr>, policy: ReconnectPolicy) -> ReconnectTarget {
+        ReconnectTarget {
+            addrs: addrs,
+            policy: policy,
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Connect to the first address that accepts
+    fn connect(&self) -> io::Result<Socket> {
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+        for addr in &self.addrs {
+            match Socket::connect(addr) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = err,
             }
         }
-        Ok(())
+        Err(last_err)
     }
+}
 
-    pub fn shutdown(&mut self) -> Result<(), ServerError> {
-        for (_, c) in self.connections.iter_mut() {
-            c.shutdown(Shutdown::Both).is_ok(); // don't care about result
+/// Queue of events from the receivers to the server. Only `Recv`
+/// events are ever dropped: the others are needed to keep track of
+/// the connections, so they are queued even when it is full.
+struct EventQueue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct QueueState {
+    events: VecDeque<Event>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Frames dropped so far
+    dropped: u64,
+    /// A `QueueOverflow` error is queued
+    overflow_queued: bool,
+    /// The server is gone, nobody will receive
+    closed: bool,
+}
+
+impl EventQueue {
+    fn new(capacity: usize) -> EventQueue {
+        EventQueue {
+            state: Mutex::new(QueueState {
+                events: VecDeque::new(),
+                capacity: capacity,
+                policy: OverflowPolicy::Block,
+                dropped: 0,
+                overflow_queued: false,
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
         }
-        Ok(())
     }
 
-    fn next_event(&mut self) -> Result<Event, ServerError> {
-        match self.events.1.recv() {
-            Ok(Event::Connected(uid)) => {
-                let mut newc = match self.new_connections.lock() {
-                    Ok(newc) => newc,
-                    Err(_) => return Err(ServerError::from("Mutex lock() error")),
-                };
-                match newc.remove(&uid) {
-                    Some(c) => {
-                        self.connections.insert(uid, c);
-                        Ok(Event::Connected(uid))
+    /// Queue an event, following the overflow policy. Fails (like a
+    /// channel) once the server is gone.
+    fn send(&self, evt: Event) -> Result<(), Event> {
+        let mut state = self.state.lock().unwrap();
+        if let Event::Recv(..) = evt {
+            while state.events.len() >= state.capacity && !state.closed {
+                match state.policy {
+                    OverflowPolicy::Block => state = self.not_full.wait(state).unwrap(),
+                    OverflowPolicy::DropOldest => {
+                        let oldest = state.events.iter().position(|evt| match *evt {
+                            Event::Recv(..) => true,
+                            _ => false,
+                        });
+                        match oldest {
+                            Some(i) => {
+                                state.events.remove(i);
+                                state.dropped += 1;
+                            }
+                            None => break,
+                        }
                     }
-                    None => {
-                        Err(ServerError::from("Connected event with no connection"))
+                    OverflowPolicy::DropNewest => {
+                        state.dropped += 1;
+                        return Ok(());
+                    }
+                    OverflowPolicy::Error => {
+                        state.dropped += 1;
+                        if !state.overflow_queued {
+                            state.overflow_queued = true;
+                            state.events.push_back(Event::UnexpectedError(ServerError::QueueOverflow));
+                            self.not_empty.notify_one();
+                        }
+                        return Ok(());
                     }
                 }
             }
-            Ok(Event::Disconnected(uid)) => {
-                match self.connections.remove(&uid) {
-                    Some(_) => {
-                        Ok(Event::Disconnected(uid))
-                    }
-                    None => {
-                        Err(ServerError::from("Disconnected event with no connection"))
-                    }
+        }
+        if state.closed {
+            return Err(evt);
+        }
+        state.events.push_back(evt);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Wait up to `timeout` (forever if `None`) for the next event
+    fn recv(&self, timeout: Option<Duration>) -> Option<Event> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(evt) = state.events.pop_front() {
+                if let Event::UnexpectedError(ServerError::QueueOverflow) = evt {
+                    state.overflow_queued = false;
                 }
+                self.not_full.notify_one();
+                return Some(evt);
             }
-            Ok(evt) => {
-                Ok(evt)
+            match deadline {
+                None => state = self.not_empty.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    state = self.not_empty.wait_timeout(state, deadline - now).unwrap().0;
+                }
             }
-            Err(_) => Err(ServerError::from("Channel error")),
         }
     }
-}
 
-impl Iterator for Server {
-    type Item = Event;
-    /// Block waiting for the next `Event`. Will never return
-    /// `None`. Errors will be returned as `Event::UnexpectedError`.
-    fn next(&mut self) -> Option<Event> {
-        self.next_event().map_err(move |err| -> Result<Event, ServerError> {
-            Ok(Event::UnexpectedError(err))
-        }).ok()
+    /// Make the senders fail, ending the receivers
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_full.notify_all();
     }
 }
 
-fn stream_receiver(l: TcpListener,
-         uid: Uid,
-         events: SyncSender<Event>,
-         new_connections: Arc<Mutex<HashMap<Uid, FramedTcpStream>>>) {
-    match l.accept() {
-        Ok((stream, _)) => {
-            // accept more connections
-            {
-                let new_connections = new_connections.clone();
-                let events = events.clone();
-                let uid = uid.next();
-                thread::spawn(move || {
-                    stream_receiver(l, uid, events, new_connections)
-                });
-            }
-            match stream.try_clone() {
-                Ok(outstream) => {
-                    // register connection
-                    let mut nc = new_connections.lock().unwrap(); // FIXME: is unwrap fine here?
-                    nc.insert(uid, FramedTcpStream::new(outstream));
-                    drop(nc);
-                    // signal connected and start receiving
-                    let mut stream = FramedTcpStream::new(stream);
-                    if events.send(Event::Connected(uid)).is_ok() {
-                        while let Ok(frame) = stream.read_frame() {
-                            if events.send(Event::Recv(uid, frame)).is_err() {
-                                break;
-                            }
-                        }
-                    }
-                    // signal disconnect and shutdown the connection
-                    if events.send(Event::Disconnected(uid)).is_err() {
-                        stream.shutdown(Shutdown::Both).unwrap();
-                    }
-                }
-                Err(_) => {
-                    events.send(Event::UnexpectedError(ServerError::from("Error cloning stream"))).unwrap();
+/// Frames waiting to be written by a connection's writer thread, see
+/// `Server::set_send_queue()`
+struct SendQueue {
+    state: Mutex<SendState>,
+    /// Signals the writer (frames queued, or closed) as well as the
+    /// sender waiting for room
+    changed: Condvar,
+    high_water: usize,
+}
+
+struct SendState {
+    /// A queue per `Priority`, highest first
+    lanes: [VecDeque<FrameBuf>; 3],
+    /// Encoded bytes queued
+    bytes: usize,
+    /// `Event::SendBackpressure` was sent, and the queue hasn't
+    /// drained to half the high-water mark since
+    backpressure: bool,
+    /// The connection is gone or failed, nothing more is written
+    closed: bool,
+    /// The connection is being closed: the queued frames are written,
+    /// then the writer shuts it down
+    finishing: bool,
+}
+
+impl SendQueue {
+    fn new(high_water: usize) -> SendQueue {
+        SendQueue {
+            state: Mutex::new(SendState {
+                lanes: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+                bytes: 0,
+                backpressure: false,
+                closed: false,
+                finishing: false,
+            }),
+            changed: Condvar::new(),
+            high_water: high_water,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.state.lock().unwrap().bytes >= self.high_water
+    }
+
+    /// Queue a frame, waiting for room (except with `Priority::High`).
+    /// Returns whether it went over the high-water mark (for the first
+    /// time since it drained).
+    fn push(&self, frame: FrameBuf, priority: Priority) -> Result<bool, NetError> {
+        let mut state = self.state.lock().unwrap();
+        while state.bytes >= self.high_water && !state.closed && priority != Priority::High {
+            state = self.changed.wait(state).unwrap();
+        }
+        if state.closed || state.finishing {
+            return Err(NetError::Closed);
+        }
+        state.bytes += frame.as_bytes().len();
+        state.lanes[priority as usize].push_back(frame);
+        self.changed.notify_all();
+        if state.bytes >= self.high_water && !state.backpressure {
+            state.backpressure = true;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Next frame to write, from the highest priority lane with one,
+    /// waiting for one. `None` once closed, or finished and empty.
+    fn pop(&self) -> Option<FrameBuf> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            let frame = state.lanes.iter_mut().filter_map(|lane| lane.pop_front()).next();
+            if let Some(frame) = frame {
+                state.bytes -= frame.as_bytes().len();
+                if state.bytes <= self.high_water / 2 {
+                    state.backpressure = false;
                 }
+                self.changed.notify_all();
+                return Some(frame);
             }
+            if state.finishing {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Stop the writer once the queued frames are written, refusing
+    /// new ones
+    fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.finishing = true;
+        self.changed.notify_all();
+    }
+
+    /// Drop the queued frames and stop the writer
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for lane in state.lanes.iter_mut() {
+            lane.clear();
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// Decides which clients to accept, from their address
+pub type AcceptFilter = Arc<Fn(&Addr) -> bool + Send + Sync>;
+
+/// Multiplexing server for framed messages over TCP or unix sockets.
+pub struct Server {
+    addrs: Vec<Addr>,
+    listeners: Vec<Listener>,
+    accept_threads: Vec<JoinHandle<()>>,
+    events: Arc<EventQueue>,
+    /// Events received while shutting down, delivered before
+    /// `Event::Shutdown`
+    pending: VecDeque<Event>,
+    connections: HashMap<Uid, Connection>,
+    shared: Arc<Shared>,
+    options: ConnectionOptions,
+    /// Worker threads to serve connections with, if any
+    workers: Option<usize>,
+    /// Maximum number of connections, and what to do over it
+    limit: Option<(usize, LimitPolicy)>,
+    filter: Option<AcceptFilter>,
+    listener_builder: ListenerBuilder,
+    /// High-water mark of the send queues, if enabled
+    send_queue: Option<usize>,
+    /// Outgoing connections to reestablish when lost
+    reconnects: HashMap<Uid, Arc<ReconnectTarget>>,
+    /// Application names of the peers, both ways
+    peer_uids: HashMap<String, Uid>,
+    peer_names: HashMap<Uid, String>,
+    /// Frames and bytes sent, for `stats()`
+    frames_out: u64,
+    bytes_out: u64,
+    /// `Event::Shutdown` was delivered
+    done: bool,
+}
+
+/// State shared with the accept and connection threads
+struct Shared {
+    new_connections: Mutex<HashMap<Uid, Connection>>,
+    /// Next Uid to assign, for accepted and outgoing connections
+    next_uid: AtomicUsize,
+    /// Sockets of the running connection threads, to stop them
+    sockets: Mutex<HashMap<Uid, Socket>>,
+    /// Connection and worker threads that haven't finished yet
+    running: AtomicUsize,
+    /// Handles of those threads (and of the ones that panicked), see
+    /// `spawn_tracked()`
+    threads: Mutex<HashMap<usize, JoinHandle<()>>>,
+    next_thread: AtomicUsize,
+    /// Accept threads that haven't finished yet
+    accepting: AtomicUsize,
+    /// Connections dispatched and not closed yet
+    open: AtomicUsize,
+    /// Buffers to receive frames into
+    bufs: Arc<BufPool>,
+    /// Totals for `Server::stats()`
+    accepted: AtomicUsize,
+    frames_in: AtomicUsize,
+    bytes_in: AtomicUsize,
+    stopping: AtomicBool,
+    /// Channels to the pool workers, empty without a pool
+    pool: Mutex<Vec<Sender<WorkerMsg>>>,
+    next_worker: AtomicUsize,
+}
+
+impl Shared {
+    /// Keep a handle to a connection's socket, so `shutdown()` can stop
+    /// its receiver
+    fn track(&self, uid: Uid, stream: &Socket) {
+        if let Ok(socket) = stream.try_clone() {
+            self.sockets.lock().unwrap().insert(uid, socket);
+        }
+        // shutdown() may have missed the socket
+        if self.stopping.load(Ordering::SeqCst) {
+            stream.shutdown(Shutdown::Both).is_ok();
+        }
+    }
+
+    fn untrack(&self, uid: Uid) {
+        self.sockets.lock().unwrap().remove(&uid);
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Give a stream a pooled buffer to read its next frame into
+    fn lend_buf<T: Read + Write>(&self, stream: &mut Framed<T>) {
+        if let Some(buf) = self.bufs.take() {
+            stream.recycle(buf);
         }
-        Err(e) => {
-            events.send(Event::UnexpectedError(ServerError::from(NetError::from(e)))).unwrap();
+    }
+
+    /// Count a received frame, and make its event
+    fn received(&self, uid: Uid, frame: Vec<u8>) -> Event {
+        self.frames_in.fetch_add(1, Ordering::SeqCst);
+        self.bytes_in.fetch_add(frame.len(), Ordering::SeqCst);
+        Event::Recv(uid, RecvBuf::pooled(frame, &self.bufs))
+    }
+}
+
+impl Server {
+    pub fn new(addr: SocketAddr) -> Server {
+        Server::with_addrs(vec![Addr::Inet(addr)])
+    }
+
+    /// Server listening on all the given addresses (e.g. IPv4 and
+    /// IPv6), with the connections from all of them in the same event
+    /// stream
+    pub fn new_multi(addrs: &[SocketAddr]) -> Server {
+        Server::with_addrs(addrs.iter().map(|&addr| Addr::Inet(addr)).collect())
+    }
+
+    /// Server listening on a unix socket at `path`, which must not
+    /// exist yet. It is removed by `shutdown()`.
+    #[cfg(unix)]
+    pub fn new_unix<P: AsRef<Path>>(path: P) -> Server {
+        Server::with_addrs(vec![Addr::Unix(Some(path.as_ref().to_path_buf()))])
+    }
+
+    /// Server listening on TCP and unix socket addresses at once (e.g.
+    /// remote clients over TCP and local ones over a unix socket),
+    /// with all the connections in the same event stream and Uid
+    /// space. With `start_tls()`, only the TCP clients use TLS.
+    pub fn new_mixed(addrs: &[Addr]) -> Server {
+        Server::with_addrs(addrs.to_vec())
+    }
+
+    fn with_addrs(addrs: Vec<Addr>) -> Server {
+        Server {
+            addrs: addrs,
+            listeners: vec![],
+            accept_threads: vec![],
+            events: Arc::new(EventQueue::new(QUEUE_SIZE)),
+            pending: VecDeque::new(),
+            connections: HashMap::new(),
+            shared: Arc::new(Shared {
+                // new_connections is used to avoid mutexes in the critical path
+                new_connections: Mutex::new(HashMap::new()),
+                next_uid: AtomicUsize::new(0),
+                sockets: Mutex::new(HashMap::new()),
+                running: AtomicUsize::new(0),
+                threads: Mutex::new(HashMap::new()),
+                next_thread: AtomicUsize::new(0),
+                accepting: AtomicUsize::new(0),
+                open: AtomicUsize::new(0),
+                bufs: Arc::new(BufPool { bufs: Mutex::new(vec![]) }),
+                accepted: AtomicUsize::new(0),
+                frames_in: AtomicUsize::new(0),
+                bytes_in: AtomicUsize::new(0),
+                stopping: AtomicBool::new(false),
+                pool: Mutex::new(vec![]),
+                next_worker: AtomicUsize::new(0),
+            }),
+            options: ConnectionOptions::default(),
+            workers: None,
+            limit: None,
+            filter: None,
+            listener_builder: ListenerBuilder::new(),
+            send_queue: None,
+            reconnects: HashMap::new(),
+            peer_uids: HashMap::new(),
+            peer_names: HashMap::new(),
+            frames_out: 0,
+            bytes_out: 0,
+            done: false,
         }
     }
+
+    /// Expect connections to start with a PROXY protocol header (when
+    /// behind a load balancer). Connections without a valid one are
+    /// dropped, reporting an `Event::UnexpectedError`. Call before
+    /// `start()`.
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.options.proxy_protocol = proxy_protocol;
+    }
+
+    /// Disconnect clients that send nothing for `timeout`. Call before
+    /// `start()`.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.options.idle_timeout = timeout;
+    }
+
+    /// Close the connections that send a frame bigger than `size`
+    /// (16MB by default), with an `Event::ProtocolError`, before it
+    /// is read into memory. Call before `start()`.
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.options.max_frame_size = Some(size);
+    }
+
+    /// Ping the clients that send nothing for `interval`, and
+    /// disconnect the ones that don't answer for `max_missed`
+    /// intervals (e.g. half-open connections), with an
+    /// `Event::Disconnected`. The clients must enable heartbeats too
+    /// (see `Framed::set_heartbeat()`), as they change the framing.
+    /// Each connection gets its own thread, even with a worker pool.
+    /// Not supported with TLS. Call before `start()` (later calls only
+    /// apply to `connect()`).
+    pub fn set_heartbeat(&mut self, interval: Option<Duration>, max_missed: u32) {
+        self.options.heartbeat = interval.map(|interval| (interval, cmp::max(max_missed, 1)));
+    }
+
+    /// Call these hooks for every frame sent or received, on any
+    /// connection. Call before `start()`.
+    pub fn set_frame_hooks(&mut self, hooks: FrameHooks) {
+        self.options.hooks = hooks;
+    }
+
+    /// Serve the connections with a fixed number of worker threads,
+    /// each waiting for any of its connections to be readable, instead
+    /// of a thread per connection. Frames that arrive in pieces are
+    /// put together without holding up the other connections of their
+    /// worker. Writes are done from the caller's thread, unless
+    /// `set_send_queue()` is enabled too, which still starts a writer
+    /// thread per connection. Call before `start()`.
+    pub fn set_worker_pool(&mut self, workers: Option<usize>) {
+        self.workers = workers.map(|n| cmp::max(n, 1));
+    }
+
+    /// Hold up to `size` received frames waiting for the consumer,
+    /// and handle more than that according to `policy`. Can be changed
+    /// at any time.
+    pub fn set_event_queue(&mut self, size: usize, policy: OverflowPolicy) {
+        let mut state = self.events.state.lock().unwrap();
+        state.capacity = cmp::max(size, 1);
+        state.policy = policy;
+        self.events.not_full.notify_all();
+    }
+
+    /// Number of received frames dropped because the event queue was
+    /// full
+    pub fn dropped_events(&self) -> u64 {
+        self.events.state.lock().unwrap().dropped
+    }
+
+    /// Accept at most `max` connections at a time (counting the ones
+    /// from `connect()`), handling the others according to `policy`.
+    /// `None` for no limit (the default). Call before `start()`.
+    pub fn set_max_connections(&mut self, max: Option<usize>, policy: LimitPolicy) {
+        self.limit = max.map(|max| (max, policy));
+    }
+
+    /// Socket options of the listeners (e.g. `reuse_port` to share the
+    /// address with other servers). Call before `start()`.
+    pub fn set_listener_builder(&mut self, builder: ListenerBuilder) {
+        self.listener_builder = builder;
+    }
+
+    /// Accept only the clients for which `filter` returns true, given
+    /// their address. The others are closed right away, with an
+    /// `Event::Rejected`. Replaces any previous filter. Call before
+    /// `start()`.
+    pub fn set_accept_filter<F>(&mut self, filter: F)
+        where F: Fn(&Addr) -> bool + Send + Sync + 'static
+    {
+        self.filter = Some(Arc::new(filter));
+    }
+
+    /// Accept only clients from these networks (so no unix socket
+    /// clients), see `set_accept_filter()`
+    pub fn set_allowlist(&mut self, networks: Vec<Cidr>) {
+        self.set_accept_filter(move |addr| networks.iter().any(|net| net.contains(addr)));
+    }
+
+    /// Turn away the clients from these networks, see
+    /// `set_accept_filter()`
+    pub fn set_denylist(&mut self, networks: Vec<Cidr>) {
+        self.set_accept_filter(move |addr| !networks.iter().any(|net| net.contains(addr)));
+    }
+
+    /// Write to each connection from a thread of its own, queueing
+    /// the frames sent meanwhile, so a peer that stops reading can't
+    /// block `send_to()` as long as its queue holds less than
+    /// `high_water` bytes. Over that, `send_to()` waits for room,
+    /// `try_send_to()` fails with a `WouldBlock` I/O error and an
+    /// `Event::SendBackpressure` is delivered. A queued frame that
+    /// fails to be written isn't reported to the sender, only the
+    /// `Event::Disconnected` that follows. `None` to write from the
+    /// caller's thread (the default). Applies to the connections
+    /// established afterwards.
+    pub fn set_send_queue(&mut self, high_water: Option<usize>) {
+        self.send_queue = high_water.map(|n| cmp::max(n, 1));
+    }
+
+    /// Start the pool workers, if enabled and not running yet
+    fn start_pool(&mut self) -> Result<(), ServerError> {
+        let mut pool = try!(self.shared.pool.lock().map_err(|_| "Mutex lock() error"));
+        if let Some(workers) = self.workers {
+            while pool.len() < workers {
+                let worker = try!(spawn_worker(self.events.clone(), &self.shared, self.options.clone()));
+                pool.push(worker);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start accepting connections.
+    pub fn start(&mut self) -> Result<(), ServerError> {
+        let options = self.options.clone();
+        self.listen(options)
+    }
+
+    /// Same as `start()`, with the TLS handshake performed on each
+    /// accepted connection before framing. Each one gets its own
+    /// thread, even with a worker pool. Connections from `connect()`
+    /// are not affected.
+    #[cfg(feature = "tls")]
+    pub fn start_tls(&mut self, acceptor: TlsAcceptor) -> Result<(), ServerError> {
+        if self.addrs.iter().any(|addr| addr.inet().is_none()) {
+            return Err(ServerError::from("TLS needs a TCP listener"));
+        }
+        if self.options.heartbeat.is_some() {
+            return Err(ServerError::from("heartbeats aren't supported with TLS"));
+        }
+        let options = ConnectionOptions { tls: Some(Acceptor(Arc::new(acceptor))), ..self.options.clone() };
+        self.listen(options)
+    }
+
+    fn listen(&mut self, options: ConnectionOptions) -> Result<(), ServerError> {
+        if self.shared.stopping.load(Ordering::SeqCst) {
+            return Err(ServerError::from("server shut down"));
+        }
+        if self.addrs.is_empty() {
+            return Err(ServerError::from("no address to listen on"));
+        }
+        try!(self.start_pool());
+        // bind them all before accepting on any
+        let mut listeners = vec![];
+        for addr in &self.addrs {
+            listeners.push(try!(Listener::bind(addr, &self.listener_builder)));
+        }
+        for listener in listeners {
+            let l = try!(listener.try_clone());
+            self.listeners.push(listener);
+            let ev = self.events.clone();
+            let shared = self.shared.clone();
+            let options = listener_options(&l, &options);
+            let limit = self.limit;
+            let filter = self.filter.clone();
+            // start accept thread
+            self.shared.accepting.fetch_add(1, Ordering::SeqCst);
+            self.accept_threads.push(thread::spawn(move || { accept_loop(l, ev, shared, options, limit, filter) }));
+        }
+        Ok(())
+    }
+
+    /// Address the server is listening on (e.g. to find the port when
+    /// bound to port 0). The first TCP one with `new_multi()` or
+    /// `new_mixed()`.
+    pub fn local_addr(&self) -> Result<SocketAddr, ServerError> {
+        if self.listeners.is_empty() {
+            return Err(ServerError::from("server not started"));
+        }
+        for l in &self.listeners {
+            if let Some(addr) = try!(l.local_addr()).inet() {
+                return Ok(addr);
+            }
+        }
+        Err(ServerError::from("not listening on TCP"))
+    }
+
+    /// Addresses of all the listeners, TCP and unix sockets, in the
+    /// order given to the constructor
+    pub fn listen_addrs(&self) -> Result<Vec<Addr>, ServerError> {
+        if self.listeners.is_empty() {
+            return Err(ServerError::from("server not started"));
+        }
+        let mut addrs = vec![];
+        for l in &self.listeners {
+            addrs.push(try!(l.local_addr()));
+        }
+        Ok(addrs)
+    }
+
+    /// Addresses the server is listening on, in the order given to
+    /// `new_multi()`
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, ServerError> {
+        if self.listeners.is_empty() {
+            return Err(ServerError::from("server not started"));
+        }
+        let mut addrs = vec![];
+        for l in &self.listeners {
+            match try!(l.local_addr()) {
+                Addr::Inet(addr) => addrs.push(addr),
+                #[cfg(unix)]
+                Addr::Unix(_) => return Err(ServerError::from("not listening on TCP")),
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Connect to another server, so this one can act as a client too.
+    /// The connection is handled like the accepted ones: an
+    /// `Event::Connected` with the returned Uid is delivered once it
+    /// can be used (`send_to()` fails with `NotConnected` before).
+    /// The PROXY protocol isn't used for outgoing connections.
+    pub fn connect<A: ToSocketAddrs>(&mut self, addr: A) -> Result<Uid, ServerError> {
+        if self.shared.stopping.load(Ordering::SeqCst) {
+            return Err(ServerError::from("server shut down"));
+        }
+        let stream = try!(TcpStream::connect(addr));
+        self.connect_socket(Socket::Tcp(stream))
+    }
+
+    /// Same as `connect()`, to a server listening on a unix socket
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(&mut self, path: P) -> Result<Uid, ServerError> {
+        if self.shared.stopping.load(Ordering::SeqCst) {
+            return Err(ServerError::from("server shut down"));
+        }
+        let stream = try!(UnixStream::connect(path));
+        self.connect_socket(Socket::Unix(stream))
+    }
+
+    /// Same as `connect()`, reconnecting as set by `policy` whenever
+    /// the connection is lost: its `Event::Disconnected` is followed
+    /// by a new `Event::Connected` with the same Uid once it is back.
+    /// Only this first connection isn't retried, and
+    /// `close_connection()` closes it for good.
+    pub fn connect_reconnecting<A: ToSocketAddrs>(&mut self, addr: A, policy: ReconnectPolicy)
+                                                  -> Result<Uid, ServerError> {
+        let addrs = try!(addr.to_socket_addrs()).map(Addr::Inet).collect();
+        self.connect_target(ReconnectTarget::new(addrs, policy))
+    }
+
+    /// Same as `connect_reconnecting()`, to a unix socket
+    #[cfg(unix)]
+    pub fn connect_unix_reconnecting<P: AsRef<Path>>(&mut self, path: P, policy: ReconnectPolicy)
+                                                     -> Result<Uid, ServerError> {
+        let addrs = vec![Addr::Unix(Some(path.as_ref().to_path_buf()))];
+        self.connect_target(ReconnectTarget::new(addrs, policy))
+    }
+
+    fn connect_target(&mut self, target: ReconnectTarget) -> Result<Uid, ServerError> {
+        if self.shared.stopping.load(Ordering::SeqCst) {
+            return Err(ServerError::from("server shut down"));
+        }
+        let stream = try!(target.connect());
+        let uid = try!(self.connect_socket(stream));
+        self.reconnects.insert(uid, Arc::new(target));
+        Ok(uid)
+    }
+
+    fn connect_socket(&mut self, stream: Socket) -> Result<Uid, ServerError> {
+        try!(self.start_pool());
+        Ok(dispatch(stream, &self.events, &self.shared, self.outgoing_options()))
+    }
+
+    /// Options of the outgoing connections
+    fn outgoing_options(&self) -> ConnectionOptions {
+        ConnectionOptions { proxy_protocol: false, ..self.options.clone() }
+    }
+
+    /// Send a frame to the given destination. It should be connected
+    /// already. The frame is written right away, returning any I/O
+    /// error, unless `set_send_queue()` is enabled: then it is queued
+    /// instead (waiting for room if the queue is full), and a failed
+    /// write is only noticed by the `Event::Disconnected` that follows.
+    pub fn send_to(&mut self, dest: Uid, data: &[u8]) -> Result<(), ServerError> {
+        self.write_to(dest, data, Priority::Normal, true)
+    }
+
+    /// Same as `send_to()`, through the given lane of the send queue:
+    /// the queued frames of a higher priority are written first, so
+    /// e.g. control messages don't wait behind bulk transfers (a frame
+    /// already being written isn't interrupted though). Without a send
+    /// queue, the frame is written right away.
+    pub fn send_to_with_priority(&mut self, dest: Uid, priority: Priority, data: &[u8])
+                                 -> Result<(), ServerError> {
+        self.write_to(dest, data, priority, true)
+    }
+
+    /// Same as `send_to()`, failing with a `WouldBlock` I/O error
+    /// instead of waiting when the destination's send queue is full.
+    /// Fails without a send queue, see `set_send_queue()`.
+    pub fn try_send_to(&mut self, dest: Uid, data: &[u8]) -> Result<(), ServerError> {
+        self.write_to(dest, data, Priority::Normal, false)
+    }
+
+    fn write_to(&mut self, dest: Uid, data: &[u8], priority: Priority, block: bool) -> Result<(), ServerError> {
+        match self.connections.get_mut(&dest) {
+            Some(c) => {
+                let res = match c.outbound {
+                    Some(ref queue) if !block && queue.is_full() => return Err(send_queue_full()),
+                    Some(ref queue) => c.stream.preframe(data).and_then(|frame| queue.push(frame, priority)),
+                    None if block => c.stream.write_frame(data).map(|_| false),
+                    None => return Err(ServerError::from("no send queue, see set_send_queue()")),
+                };
+                match res {
+                    Ok(crossed) => {
+                        if crossed {
+                            self.events.send(Event::SendBackpressure(dest)).is_ok();
+                        }
+                    }
+                    Err(err) => return Err(peer_error(&c.peer_addr, err)),
+                }
+                c.bytes_out += data.len() as u64;
+                self.frames_out += 1;
+                self.bytes_out += data.len() as u64;
+            }
+            None => {
+                return Err(ServerError::NotConnected);
+            }
+        }
+        Ok(())
+    }
+
+    /// Name the peer of a connection (e.g. its node ID, once it has
+    /// said who it is), so it can be sent to with `send_to_name()`.
+    /// The name moves from any connection it was given to before,
+    /// e.g. when the peer reconnects. It is forgotten on
+    /// `Event::Disconnected`, except for the connections that
+    /// reconnect by themselves (see `connect_reconnecting()`).
+    pub fn set_peer_name(&mut self, uid: Uid, name: &str) -> Result<(), ServerError> {
+        if !self.connections.contains_key(&uid) {
+            return Err(ServerError::NotConnected);
+        }
+        self.unbind_peer_name(uid);
+        if let Some(old) = self.peer_uids.insert(name.to_string(), uid) {
+            self.peer_names.remove(&old);
+        }
+        self.peer_names.insert(uid, name.to_string());
+        Ok(())
+    }
+
+    /// The connection of the peer named `name`, see `set_peer_name()`
+    pub fn peer_uid(&self, name: &str) -> Option<Uid> {
+        self.peer_uids.get(name).cloned()
+    }
+
+    pub fn peer_name(&self, uid: Uid) -> Option<&str> {
+        self.peer_names.get(&uid).map(|name| &name[..])
+    }
+
+    /// `send_to()` the peer named `name`, failing with `NotConnected`
+    /// if there is none
+    pub fn send_to_name(&mut self, name: &str, data: &[u8]) -> Result<(), ServerError> {
+        match self.peer_uid(name) {
+            Some(uid) => self.send_to(uid, data),
+            None => Err(ServerError::NotConnected),
+        }
+    }
+
+    fn unbind_peer_name(&mut self, uid: Uid) {
+        if let Some(name) = self.peer_names.remove(&uid) {
+            self.peer_uids.remove(&name);
+        }
+    }
+
+    /// Send a frame to every connection, see `send_to_many()`
+    pub fn send_to_all(&mut self, data: &[u8]) -> Result<Vec<(Uid, ServerError)>, ServerError> {
+        let dests: Vec<Uid> = self.connections.keys().cloned().collect();
+        self.send_to_many(&dests, data)
+    }
+
+    /// Same as `multicast()`
+    pub fn send_to_many(&mut self, dests: &[Uid], data: &[u8]) -> Result<Vec<(Uid, ServerError)>, ServerError> {
+        self.multicast(dests, data)
+    }
+
+    /// Send a frame to the given destinations, encoding its header
+    /// only once per frame format (e.g. TLS connections have no
+    /// heartbeat flags) and writing the same payload to each (with
+    /// vectored writes), e.g. to replicate. Returns the ones it
+    /// couldn't be sent to, which are skipped (and shut down if the
+    /// write failed, so they get disconnected). The ones with a full
+    /// send queue are skipped with a `WouldBlock` I/O error instead of
+    /// waiting. Fails only if the frame can't be encoded, before
+    /// sending anything.
+    pub fn multicast(&mut self, dests: &[Uid], data: &[u8]) -> Result<Vec<(Uid, ServerError)>, ServerError> {
+        // the frame for each format among the destinations, and the
+        // same in one buffer for the send queues
+        let mut encoded: Vec<(FrameFormat, FrameParts, Option<FrameBuf>)> = vec![];
+        for dest in dests {
+            if let Some(c) = self.connections.get(dest) {
+                let format = c.stream.frame_format();
+                if encoded.iter().all(|&(f, _, _)| f != format) {
+                    encoded.push((format, try!(c.stream.frame_parts(data)), None));
+                }
+            }
+        }
+        let mut failed = vec![];
+        for &dest in dests {
+            match self.connections.get_mut(&dest) {
+                Some(c) => {
+                    let format = c.stream.frame_format();
+                    let &mut (_, ref parts, ref mut frame) = encoded.iter_mut().find(|e| e.0 == format).unwrap();
+                    let res = match c.outbound {
+                        Some(ref queue) if queue.is_full() => {
+                            failed.push((dest, send_queue_full()));
+                            continue;
+                        }
+                        Some(ref queue) => {
+                            queue.push(frame.get_or_insert_with(|| parts.to_frame_buf()).clone(), Priority::Normal)
+                        }
+                        None => c.stream.write_parts(parts).map(|_| false),
+                    };
+                    match res {
+                        Ok(crossed) => {
+                            if crossed {
+                                self.events.send(Event::SendBackpressure(dest)).is_ok();
+                            }
+                            c.bytes_out += data.len() as u64;
+                            self.frames_out += 1;
+                            self.bytes_out += data.len() as u64;
+                        }
+                        Err(err) => {
+                            c.stream.shutdown();
+                            failed.push((dest, peer_error(&c.peer_addr, err)));
+                        }
+                    }
+                }
+                None => failed.push((dest, ServerError::NotConnected)),
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Address of the client behind a connection, taken from the PROXY
+    /// protocol header if enabled
+    pub fn client_addr(&self, uid: Uid) -> Result<Addr, ServerError> {
+        match self.connections.get(&uid) {
+            Some(c) => Ok(c.client_addr.clone()),
+            None => Err(ServerError::NotConnected),
+        }
+    }
+
+    /// Close a connection, e.g. to kick a misbehaving client. Its
+    /// `Event::Disconnected` follows the frames already received. With
+    /// a send queue, the frames in it are written first, so a peer that
+    /// doesn't read them is only closed by `shutdown()`. A
+    /// connection of `connect_reconnecting()` is closed for good, even
+    /// while it is reconnecting.
+    pub fn close_connection(&mut self, uid: Uid) -> Result<(), ServerError> {
+        let reconnecting = match self.reconnects.remove(&uid) {
+            Some(target) => {
+                target.cancelled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        };
+        match self.connections.get_mut(&uid) {
+            Some(c) => {
+                match c.outbound {
+                    // the writer shuts it down after the queued frames
+                    Some(ref queue) => queue.finish(),
+                    // fails if the peer is gone already, closing it anyway
+                    None => c.stream.shutdown(),
+                }
+                Ok(())
+            }
+            None if reconnecting => {
+                // its name was kept bound while reconnecting
+                self.unbind_peer_name(uid);
+                Ok(())
+            }
+            None => Err(ServerError::NotConnected),
+        }
+    }
+
+    /// Addresses, connection time and traffic of a connection
+    pub fn connection_info(&self, uid: Uid) -> Result<ConnectionInfo, ServerError> {
+        match self.connections.get(&uid) {
+            Some(c) => Ok(ConnectionInfo {
+                peer_addr: c.peer_addr.clone(),
+                local_addr: c.local_addr.clone(),
+                client_addr: c.client_addr.clone(),
+                connected_at: c.connected_at,
+                bytes_in: c.bytes_in.load(Ordering::SeqCst) as u64,
+                bytes_out: c.bytes_out,
+            }),
+            None => Err(ServerError::NotConnected),
+        }
+    }
+
+    /// Connection and traffic totals, and the events waiting
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            connections: self.connections.len(),
+            accepted: self.shared.accepted.load(Ordering::SeqCst) as u64,
+            frames_in: self.shared.frames_in.load(Ordering::SeqCst) as u64,
+            bytes_in: self.shared.bytes_in.load(Ordering::SeqCst) as u64,
+            frames_out: self.frames_out,
+            bytes_out: self.bytes_out,
+            queued_events: self.events.state.lock().unwrap().events.len() + self.pending.len(),
+        }
+    }
+
+    /// Stop accepting, close the listener and every connection, and
+    /// wait for all the threads to finish. The events that were
+    /// pending (including the `Disconnected` ones) are still
+    /// delivered, followed by `Event::Shutdown`, after which the
+    /// iterator ends.
+    pub fn shutdown(&mut self) -> Result<(), ServerError> {
+        self.stop(None).map(|_| ())
+    }
+
+    /// Same as `shutdown()`, waiting at most `timeout` for the threads
+    /// and joining them. Fails with `ServerError::Threads` if some
+    /// panicked, or are still running (they are left detached).
+    pub fn shutdown_and_wait(&mut self, timeout: Duration) -> Result<(), ServerError> {
+        match try!(self.stop(Some(Instant::now() + timeout))) {
+            (0, 0) => Ok(()),
+            (panicked, running) => Err(ServerError::Threads { panicked: panicked, running: running }),
+        }
+    }
+
+    /// Stop everything, waiting for the threads until `deadline`.
+    /// Returns how many panicked, and how many are still running.
+    fn stop(&mut self, deadline: Option<Instant>) -> Result<(usize, usize), ServerError> {
+        if self.shared.stopping.swap(true, Ordering::SeqCst) {
+            return Ok((0, 0));
+        }
+        let waiting = || deadline.map_or(true, |deadline| Instant::now() < deadline);
+        // wake up the accept threads, blocked in accept() (the
+        // connection may reach another listener sharing the port)
+        while self.shared.accepting.load(Ordering::SeqCst) > 0 && waiting() {
+            for l in &self.listeners {
+                l.wake().is_ok();
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut panicked = 0;
+        let mut running = self.shared.accepting.load(Ordering::SeqCst);
+        if running == 0 {
+            for handle in self.accept_threads.drain(..) {
+                if handle.join().is_err() {
+                    panicked += 1;
+                }
+            }
+        }
+        for l in self.listeners.drain(..) {
+            #[cfg(unix)]
+            if let Ok(Addr::Unix(Some(path))) = l.local_addr() {
+                fs::remove_file(path).is_ok();
+            }
+        }
+
+        for (_, s) in try!(self.shared.sockets.lock().map_err(|_| "Mutex lock() error")).iter() {
+            s.shutdown(Shutdown::Both).is_ok(); // don't care about result
+        }
+        // the writer threads wait for frames until their queue is closed
+        for c in self.connections.values() {
+            if let Some(ref queue) = c.outbound {
+                queue.close();
+            }
+        }
+        for worker in try!(self.shared.pool.lock().map_err(|_| "Mutex lock() error")).drain(..) {
+            worker.send(WorkerMsg::Stop).is_ok();
+        }
+        // the connection threads may block sending their last events
+        while self.shared.running.load(Ordering::SeqCst) > 0 && waiting() {
+            if let Some(evt) = self.events.recv(Some(Duration::from_millis(10))) {
+                self.pending.push_back(evt);
+            }
+        }
+        while let Some(evt) = self.events.recv(Some(Duration::from_secs(0))) {
+            self.pending.push_back(evt);
+        }
+        self.pending.push_back(Event::Shutdown);
+        // joining doesn't block once they are all done, the handles
+        // left are those of the threads that panicked
+        running += self.shared.running.load(Ordering::SeqCst);
+        if running == 0 {
+            let threads: Vec<JoinHandle<()>> = try!(self.shared.threads.lock().map_err(|_| "Mutex lock() error"))
+                .drain().map(|(_, handle)| handle).collect();
+            for handle in threads {
+                if handle.join().is_err() {
+                    panicked += 1;
+                }
+            }
+        }
+        Ok((panicked, running))
+    }
+
+    /// The next event if there is one already, without blocking.
+    /// Returns `None` after `Event::Shutdown`, like the iterator.
+    pub fn try_next_event(&mut self) -> Option<Event> {
+        self.poll_event(Some(Duration::from_secs(0)))
+    }
+
+    /// Wait up to `timeout` for the next event, so other work can be
+    /// done in between. Returns `None` if it timed out (or after
+    /// `Event::Shutdown`).
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.poll_event(Some(timeout))
+    }
+
+    /// `Event::Shutdown` was delivered, so no more events will come
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Wait for the next event (forever if `timeout` is `None`), with
+    /// errors as `Event::UnexpectedError`
+    fn poll_event(&mut self, timeout: Option<Duration>) -> Option<Event> {
+        if self.done {
+            return None;
+        }
+        match self.next_event(timeout) {
+            Ok(evt) => evt,
+            Err(err) => Some(Event::UnexpectedError(err)),
+        }
+    }
+
+    fn next_event(&mut self, timeout: Option<Duration>) -> Result<Option<Event>, ServerError> {
+        let evt = match self.pending.pop_front() {
+            Some(evt) => evt,
+            None => match self.events.recv(timeout) {
+                Some(evt) => evt,
+                None => return Ok(None),
+            },
+        };
+        self.handle_event(evt).map(Some)
+    }
+
+    /// Keep track of the connections as their events are delivered
+    fn handle_event(&mut self, evt: Event) -> Result<Event, ServerError> {
+        match evt {
+            Event::Connected(uid) => {
+                let mut newc = match self.shared.new_connections.lock() {
+                    Ok(newc) => newc,
+                    Err(_) => return Err(ServerError::from("Mutex lock() error")),
+                };
+                match newc.remove(&uid) {
+                    Some(mut c) => {
+                        if let Some(high_water) = self.send_queue {
+                            match c.stream.try_clone() {
+                                Ok(writer) => c.outbound = Some(spawn_writer(&self.shared, writer, high_water)),
+                                // can't be written to, so it's closed
+                                Err(_) => c.stream.shutdown(),
+                            }
+                        }
+                        self.connections.insert(uid, c);
+                        Ok(Event::Connected(uid))
+                    }
+                    None => {
+                        Err(ServerError::from("Connected event with no connection"))
+                    }
+                }
+            }
+            Event::Shutdown => {
+                self.done = true;
+                self.connections.clear();
+                self.peer_uids.clear();
+                self.peer_names.clear();
+                Ok(Event::Shutdown)
+            }
+            Event::Disconnected(uid) => {
+                match self.connections.remove(&uid) {
+                    Some(_) => {
+                        match self.reconnects.get(&uid) {
+                            Some(target) => {
+                                if !self.shared.stopping.load(Ordering::SeqCst) {
+                                    spawn_reconnect(uid, target.clone(), &self.events, &self.shared,
+                                                    self.outgoing_options());
+                                }
+                            }
+                            // the name stays bound while reconnecting
+                            None => self.unbind_peer_name(uid),
+                        }
+                        Ok(Event::Disconnected(uid))
+                    }
+                    None => {
+                        Err(ServerError::from("Disconnected event with no connection"))
+                    }
+                }
+            }
+            Event::UnexpectedError(ServerError::ReconnectFailed(uid, err)) => {
+                // unless it was closed meanwhile
+                if self.reconnects.remove(&uid).is_some() {
+                    self.unbind_peer_name(uid);
+                }
+                Ok(Event::UnexpectedError(ServerError::ReconnectFailed(uid, err)))
+            }
+            evt => {
+                Ok(evt)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "codec")]
+impl Server {
+    /// Send a message serialized with `codec` to the given destination
+    pub fn send_msg_to<C: Codec, M: Serialize>(&mut self, dest: Uid, codec: &C, msg: &M)
+                                               -> Result<(), ServerError> {
+        let frame = try!(codec.encode(msg));
+        self.send_to(dest, &frame)
+    }
+}
+
+#[cfg(feature = "codec")]
+impl Event {
+    /// Deserialize the message of a `Recv` event with `codec`. Returns
+    /// `None` for other events.
+    pub fn decode_msg<C: Codec, M: DeserializeOwned>(&self, codec: &C) -> Option<Result<M, NetError>> {
+        match *self {
+            Event::Recv(_, ref data) => Some(codec.decode(data)),
+            _ => None,
+        }
+    }
+}
+
+impl Iterator for Server {
+    type Item = Event;
+    /// Block waiting for the next `Event`. Only returns `None` after
+    /// `Event::Shutdown`. Errors will be returned as
+    /// `Event::UnexpectedError`.
+    fn next(&mut self) -> Option<Event> {
+        self.poll_event(None)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.events.close();
+    }
+}
+
+fn accept_loop(l: Listener,
+         events: Arc<EventQueue>,
+         shared: Arc<Shared>,
+         options: ConnectionOptions,
+         limit: Option<(usize, LimitPolicy)>,
+         filter: Option<AcceptFilter>) {
+    loop {
+        if let Some((max, LimitPolicy::Queue)) = limit {
+            while shared.open.load(Ordering::SeqCst) >= max && !shared.stopping.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        let stream = l.accept();
+        if shared.stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(ref stream) if !filter_accepts(stream, &filter, &events) => {
+                stream.shutdown(Shutdown::Both).is_ok();
+            }
+            Ok(stream) => match limit {
+                Some((max, policy)) if policy != LimitPolicy::Queue && shared.open.load(Ordering::SeqCst) >= max => {
+                    if policy == LimitPolicy::Notify {
+                        if let Ok(addr) = stream.peer_addr() {
+                            events.send(Event::Rejected(addr)).is_ok();
+                        }
+                    }
+                    stream.shutdown(Shutdown::Both).is_ok();
+                }
+                _ => {
+                    shared.accepted.fetch_add(1, Ordering::SeqCst);
+                    dispatch(stream, &events, &shared, options.clone());
+                }
+            },
+            Err(e) => {
+                events.send(Event::UnexpectedError(ServerError::from(NetError::from(e)))).is_ok();
+                break;
+            }
+        }
+    }
+    shared.accepting.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Options of the connections accepted by a listener: the local
+/// clients of a unix socket don't use TLS
+#[cfg(feature = "tls")]
+fn listener_options(listener: &Listener, options: &ConnectionOptions) -> ConnectionOptions {
+    match *listener {
+        #[cfg(unix)]
+        Listener::Unix(_) => ConnectionOptions { tls: None, ..options.clone() },
+        Listener::Tcp(_) => options.clone(),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn listener_options(_listener: &Listener, options: &ConnectionOptions) -> ConnectionOptions {
+    options.clone()
+}
+
+/// Run the accept filter on a new client, notifying its rejection
+fn filter_accepts(stream: &Socket, filter: &Option<AcceptFilter>, events: &EventQueue) -> bool {
+    let filter = match *filter {
+        Some(ref filter) => filter,
+        None => return true,
+    };
+    match stream.peer_addr() {
+        Ok(addr) => {
+            if filter(&addr) {
+                return true;
+            }
+            events.send(Event::Rejected(addr)).is_ok();
+            false
+        }
+        // gone already
+        Err(_) => false,
+    }
+}
+
+/// Hand a new connection (accepted or outgoing) to a pool worker, or
+/// to a thread of its own
+fn dispatch(stream: Socket,
+         events: &Arc<EventQueue>,
+         shared: &Arc<Shared>,
+         options: ConnectionOptions) -> Uid {
+    let uid = Uid(shared.next_uid.fetch_add(1, Ordering::SeqCst) as u64);
+    dispatch_as(uid, stream, events, shared, options);
+    uid
+}
+
+/// `dispatch()` with a given Uid, e.g. to reconnect
+fn dispatch_as(uid: Uid,
+         stream: Socket,
+         events: &Arc<EventQueue>,
+         shared: &Arc<Shared>,
+         options: ConnectionOptions) {
+    shared.open.fetch_add(1, Ordering::SeqCst);
+    let pool = shared.pool.lock().unwrap();
+    if pool.is_empty() || options.is_tls() || options.heartbeat.is_some() {
+        drop(pool);
+        spawn_connection(stream, uid, events.clone(), shared, options);
+    } else {
+        let worker = &pool[shared.next_worker.fetch_add(1, Ordering::SeqCst) % pool.len()];
+        if worker.send(WorkerMsg::Add(uid, stream, options.proxy_protocol)).is_err() {
+            shared.open.fetch_sub(1, Ordering::SeqCst);
+            events.send(Event::UnexpectedError(ServerError::from("worker pool stopped"))).is_ok();
+        }
+    }
+}
+
+/// Start the thread reestablishing a lost outgoing connection, which
+/// is dispatched again with the same Uid
+fn spawn_reconnect(uid: Uid,
+         target: Arc<ReconnectTarget>,
+         events: &Arc<EventQueue>,
+         shared: &Arc<Shared>,
+         options: ConnectionOptions) {
+    let events = events.clone();
+    spawn_tracked(shared, move |shared| {
+        let mut attempts = 0;
+        loop {
+            match target.connect() {
+                Ok(stream) => {
+                    if !shared.stopping.load(Ordering::SeqCst) && !target.is_cancelled() {
+                        dispatch_as(uid, stream, &events, shared, options);
+                    }
+                    break;
+                }
+                Err(err) => {
+                    attempts += 1;
+                    match target.policy.retry_delay(attempts) {
+                        Some(delay) => {
+                            if !sleep_unless_stopping(shared, delay) || target.is_cancelled() {
+                                break;
+                            }
+                        }
+                        None => {
+                            // the server drops the target when handling it
+                            let err = ServerError::ReconnectFailed(uid, err);
+                            events.send(Event::UnexpectedError(err)).is_ok();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Sleep for `duration`, returning false right away if the server
+/// is shut down meanwhile
+fn sleep_unless_stopping(shared: &Shared, duration: Duration) -> bool {
+    let until = Instant::now() + duration;
+    while !shared.stopping.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        thread::sleep(cmp::min(until - now, Duration::from_millis(10)));
+    }
+    false
+}
+
+/// Start the thread receiving from a connection
+fn spawn_connection(stream: Socket,
+         uid: Uid,
+         events: Arc<EventQueue>,
+         shared: &Arc<Shared>,
+         options: ConnectionOptions) {
+    spawn_tracked(shared, move |shared| {
+        shared.track(uid, &stream);
+        connection_receiver(stream, uid, events, shared, options);
+        shared.untrack(uid);
+    });
+}
+
+/// Start a thread counted in `Shared::running`. Its handle is kept if
+/// it panics, for `Server::shutdown_and_wait()` to report.
+fn spawn_tracked<F>(shared: &Arc<Shared>, f: F) where F: FnOnce(&Arc<Shared>) + Send + 'static {
+    shared.running.fetch_add(1, Ordering::SeqCst);
+    let guard = RunningThread {
+        shared: shared.clone(),
+        id: shared.next_thread.fetch_add(1, Ordering::SeqCst),
+    };
+    let id = guard.id;
+    // the thread can't forget its handle before it is added
+    let mut threads = shared.threads.lock().unwrap();
+    let handle = thread::spawn(move || {
+        let guard = guard;
+        f(&guard.shared);
+    });
+    threads.insert(id, handle);
+}
+
+/// Held by a thread started with `spawn_tracked()` until it ends
+struct RunningThread {
+    shared: Arc<Shared>,
+    id: usize,
+}
+
+impl Drop for RunningThread {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            if let Ok(mut threads) = self.shared.threads.lock() {
+                threads.remove(&self.id);
+            }
+        }
+        self.shared.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Register a connection and receive its frames until it closes
+fn connection_receiver(stream: Socket,
+         uid: Uid,
+         events: Arc<EventQueue>,
+         shared: &Shared,
+         options: ConnectionOptions) {
+    #[cfg(feature = "tls")]
+    let stream = match options.tls.clone() {
+        Some(tls) => return tls_receiver(stream, uid, events, shared, &options, &tls.0),
+        None => stream,
+    };
+    if let Some((mut stream, bytes_in)) = register_connection(stream, uid, &events, shared, &options) {
+        if let Some(timeout) = options.read_timeout() {
+            stream.get_ref().set_read_timeout(Some(timeout)).is_ok();
+        }
+        stream.set_idle_timeout(options.idle_timeout);
+        loop {
+            shared.lend_buf(&mut stream);
+            let frame = match stream.read_frame() {
+                Ok(frame) => frame,
+                Err(err) => {
+                    report_read_error(uid, &err, &events);
+                    break;
+                }
+            };
+            bytes_in.fetch_add(frame.len(), Ordering::SeqCst);
+            if events.send(shared.received(uid, frame)).is_err() {
+                break;
+            }
+        }
+        // signal disconnect and shutdown the connection (the
+        // server's write half keeps it open until the event
+        // is handled)
+        events.send(Event::Disconnected(uid)).is_ok();
+        stream.get_ref().shutdown(Shutdown::Both).is_ok();
+    }
+}
+
+/// Read the PROXY protocol header (if enabled), give the server the
+/// write half of the connection and signal it. Returns the half to
+/// read from, and the counter of bytes received to update.
+fn register_connection(mut stream: Socket,
+         uid: Uid,
+         events: &Arc<EventQueue>,
+         shared: &Shared,
+         options: &ConnectionOptions) -> Option<(Framed<Socket>, Arc<AtomicUsize>)> {
+    let (proxy, peer_addr, local_addr) = match accept_proxy(&mut stream, options) {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            events.send(Event::UnexpectedError(err)).is_ok();
+            return None;
+        }
+    };
+    match stream.try_clone() {
+        Ok(outstream) => {
+            let mut outstream = outstream.framed();
+            outstream.proxy = proxy;
+            outstream.set_frame_hooks(options.hooks.clone());
+            let mut stream = stream.framed();
+            stream.set_frame_hooks(options.hooks.clone());
+            if let Some(size) = options.max_frame_size {
+                stream.set_max_frame_size(size);
+            }
+            if let Some((interval, max_missed)) = options.heartbeat {
+                // the receiver answers pings, between the server's writes
+                let lock = Arc::new(Mutex::new(()));
+                outstream.set_heartbeat(Some(interval), max_missed);
+                outstream.share_writes(lock.clone());
+                stream.set_heartbeat(Some(interval), max_missed);
+                stream.share_writes(lock);
+            }
+            let client_addr = outstream.proxy_header().map_or(peer_addr.clone(), |header| Addr::Inet(header.source));
+            add_connection(uid, Writer::Plain(outstream), peer_addr, local_addr, client_addr, events, shared)
+                .map(|bytes_in| (stream, bytes_in))
+        }
+        Err(_) => {
+            events.send(Event::UnexpectedError(ServerError::from("Error cloning stream"))).is_ok();
+            None
+        }
+    }
+}
+
+/// Read the PROXY header if enabled, and get the addresses of an
+/// accepted stream
+fn accept_proxy(stream: &mut Socket, options: &ConnectionOptions)
+                -> Result<(Option<ProxyHeader>, Addr, Addr), ServerError> {
+    let proxy = if options.proxy_protocol {
+        try!(read_proxy_header(stream))
+    } else {
+        None
+    };
+    Ok((proxy, try!(stream.peer_addr()), try!(stream.local_addr())))
+}
+
+/// Make a connection known to the server and signal it
+fn add_connection(uid: Uid,
+         stream: Writer,
+         peer_addr: Addr,
+         local_addr: Addr,
+         client_addr: Addr,
+         events: &Arc<EventQueue>,
+         shared: &Shared) -> Option<Arc<AtomicUsize>> {
+    let bytes_in = Arc::new(AtomicUsize::new(0));
+    let mut nc = shared.new_connections.lock().unwrap(); // FIXME: is unwrap fine here?
+    nc.insert(uid, Connection {
+        stream: stream,
+        peer_addr: peer_addr,
+        local_addr: local_addr,
+        client_addr: client_addr,
+        connected_at: SystemTime::now(),
+        bytes_in: bytes_in.clone(),
+        bytes_out: 0,
+        outbound: None,
+    });
+    drop(nc);
+    if events.send(Event::Connected(uid)).is_ok() {
+        Some(bytes_in)
+    } else {
+        None
+    }
+}
+
+/// `connection_receiver()` for TLS connections. The server writes to
+/// the same stream, so it is only locked once data arrives, and only
+/// what arrived is read then (the socket is non-blocking while
+/// locked): the rest of a frame is waited for without the lock.
+#[cfg(feature = "tls")]
+fn tls_receiver(mut stream: Socket,
+         uid: Uid,
+         events: Arc<EventQueue>,
+         shared: &Shared,
+         options: &ConnectionOptions,
+         acceptor: &TlsAcceptor) {
+    let (proxy, peer_addr, local_addr) = match accept_proxy(&mut stream, options) {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            events.send(Event::UnexpectedError(err)).is_ok();
+            return;
+        }
+    };
+    let stream = match stream {
+        Socket::Tcp(stream) => stream,
+        #[cfg(unix)]
+        Socket::Unix(_) => {
+            events.send(Event::UnexpectedError(ServerError::from("TLS needs a TCP connection"))).is_ok();
+            return;
+        }
+    };
+    let (socket, outsocket) = match (stream.try_clone(), stream.try_clone()) {
+        (Ok(socket), Ok(outsocket)) => (socket, outsocket),
+        _ => {
+            events.send(Event::UnexpectedError(ServerError::from("Error cloning stream"))).is_ok();
+            return;
+        }
+    };
+    let mut builder = FramedTcpStreamBuilder::new().frame_hooks(options.hooks.clone());
+    if let Some(timeout) = options.idle_timeout {
+        builder = builder.idle_timeout(timeout);
+    }
+    if let Some(size) = options.max_frame_size {
+        builder = builder.max_frame_size(size);
+    }
+    let mut tls = match builder.accept_tls(acceptor, stream) {
+        Ok(tls) => tls,
+        Err(err) => {
+            events.send(Event::UnexpectedError(peer_error(&peer_addr, err))).is_ok();
+            return;
+        }
+    };
+    tls.proxy = proxy;
+    let client_addr = tls.proxy_header().map_or(peer_addr.clone(), |header| Addr::Inet(header.source));
+    let tls = Arc::new(Mutex::new(tls));
+    let writer = Writer::Tls(tls.clone(), outsocket);
+    if let Some(bytes_in) = add_connection(uid, writer, peer_addr, local_addr, client_addr, &events, shared) {
+        let mut frames = vec![];
+        let mut partial = vec![];
+        let mut closed = false;
+        while !closed {
+            // wait for data without the lock (the socket shares the
+            // idle timeout)
+            let res = match socket.peek(&mut [0]) {
+                Ok(_) => {
+                    let mut tls = tls.lock().unwrap();
+                    shared.lend_buf(&mut tls);
+                    read_available_tls(&mut tls, &socket, &mut partial, &mut frames)
+                }
+                Err(err) => Err(NetError::from(err)),
+            };
+            closed = res.is_err();
+            for frame in frames.drain(..) {
+                bytes_in.fetch_add(frame.len(), Ordering::SeqCst);
+                if events.send(shared.received(uid, frame)).is_err() {
+                    closed = true;
+                    break;
+                }
+            }
+            if let Err(ref err) = res {
+                report_read_error(uid, err, &events);
+            }
+        }
+        events.send(Event::Disconnected(uid)).is_ok();
+    }
+    socket.shutdown(Shutdown::Both).is_ok();
+}
+
+/// Read the frames completed by what arrived, with the lock of the
+/// stream held (nobody else writes meanwhile). The TLS session may
+/// have taken many records off the socket, so it is read until it
+/// would block.
+#[cfg(feature = "tls")]
+fn read_available_tls(tls: &mut FramedTlsStream,
+         socket: &TcpStream,
+         partial: &mut Vec<u8>,
+         frames: &mut Vec<Vec<u8>>) -> Result<(), NetError> {
+    try!(socket.set_nonblocking(true));
+    let res = loop {
+        match tls.read_available_frames(partial, frames) {
+            Ok(0) => break Ok(()),
+            Ok(_) => (),
+            Err(err) => break Err(err),
+        }
+    };
+    try!(socket.set_nonblocking(false));
+    res
+}
+
+enum WorkerMsg {
+    /// Serve a new connection, reading its PROXY protocol header first
+    /// if set
+    Add(Uid, Socket, bool),
+    /// Close all the connections and exit
+    Stop,
+}
+
+/// Pool worker: waits (with mio) for any of its connections to be
+/// readable and reads the frames that arrived. The sockets stay in
+/// blocking mode, as the server writes to them from its own thread,
+/// so each readable connection is read once, keeping the start of a
+/// frame arriving in pieces until the rest comes.
+struct Worker {
+    events: Arc<EventQueue>,
+    shared: Arc<Shared>,
+    options: ConnectionOptions,
+    connections: HashMap<Token, (Uid, Framed<Socket>, Arc<AtomicUsize>, Vec<u8>)>,
+    next_token: usize,
+}
+
+/// Start a pool worker, returning the channel to give it connections
+fn spawn_worker(events: Arc<EventQueue>,
+         shared: &Arc<Shared>,
+         options: ConnectionOptions) -> Result<Sender<WorkerMsg>, ServerError> {
+    let (tx, rx) = sync_channel(1);
+    spawn_tracked(shared, move |shared| {
+        match EventLoop::new() {
+            Ok(mut evloop) => {
+                tx.send(Ok(evloop.channel())).is_ok();
+                if let Some(timeout) = options.idle_timeout {
+                    evloop.timeout_ms((), idle_check_ms(timeout)).is_ok();
+                }
+                let mut worker = Worker {
+                    events: events,
+                    shared: shared.clone(),
+                    options: options,
+                    connections: HashMap::new(),
+                    next_token: 0,
+                };
+                evloop.run(&mut worker).is_ok();
+            }
+            Err(err) => {
+                tx.send(Err(err)).is_ok();
+            }
+        }
+    });
+    match rx.recv() {
+        Ok(Ok(sender)) => Ok(sender),
+        Ok(Err(err)) => Err(ServerError::from(err)),
+        Err(_) => Err(ServerError::from("worker thread failed")),
+    }
+}
+
+/// How often workers look for idle connections
+fn idle_check_ms(timeout: Duration) -> u64 {
+    let ms = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1000000;
+    cmp::max(ms / 4, 10)
+}
+
+// the workers poll their connections by file descriptor
+#[cfg(unix)]
+fn register_socket(evloop: &mut EventLoop<Worker>, socket: &Socket, token: Token) -> io::Result<()> {
+    evloop.register(&EventedFd(&socket.as_raw_fd()), token, EventSet::readable() | EventSet::hup(), PollOpt::level())
+}
+
+#[cfg(unix)]
+fn deregister_socket(evloop: &mut EventLoop<Worker>, socket: &Socket) -> io::Result<()> {
+    evloop.deregister(&EventedFd(&socket.as_raw_fd()))
+}
+
+#[cfg(not(unix))]
+fn register_socket(_evloop: &mut EventLoop<Worker>, _socket: &Socket, _token: Token) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "the worker pool is only available on unix"))
+}
+
+#[cfg(not(unix))]
+fn deregister_socket(_evloop: &mut EventLoop<Worker>, _socket: &Socket) -> io::Result<()> {
+    Ok(())
+}
+
+impl Worker {
+    fn add(&mut self, evloop: &mut EventLoop<Worker>, uid: Uid, stream: Socket, proxy_protocol: bool) {
+        self.shared.track(uid, &stream);
+        // a client that never sends its PROXY header shouldn't block
+        // the worker
+        stream.set_read_timeout(Some(Duration::from_millis(POOL_READ_TIMEOUT_MS))).is_ok();
+        let options = ConnectionOptions { proxy_protocol: proxy_protocol, ..self.options.clone() };
+        let (stream, bytes_in) = match register_connection(stream, uid, &self.events, &self.shared, &options) {
+            Some(registered) => registered,
+            None => {
+                self.shared.untrack(uid);
+                return;
+            }
+        };
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        match register_socket(evloop, stream.get_ref(), token) {
+            Ok(()) => {
+                self.connections.insert(token, (uid, stream, bytes_in, vec![]));
+            }
+            Err(err) => {
+                self.events.send(Event::UnexpectedError(ServerError::from(err))).is_ok();
+                self.events.send(Event::Disconnected(uid)).is_ok();
+                self.shared.untrack(uid);
+            }
+        }
+    }
+
+    fn close(&mut self, evloop: &mut EventLoop<Worker>, token: Token) {
+        if let Some((uid, stream, _, _)) = self.connections.remove(&token) {
+            deregister_socket(evloop, stream.get_ref()).is_ok();
+            self.events.send(Event::Disconnected(uid)).is_ok();
+            stream.get_ref().shutdown(Shutdown::Both).is_ok();
+            self.shared.untrack(uid);
+        }
+    }
+}
+
+impl Handler for Worker {
+    type Timeout = ();
+    type Message = WorkerMsg;
+
+    fn ready(&mut self, evloop: &mut EventLoop<Worker>, token: Token, _events: EventSet) {
+        let closed = match self.connections.get_mut(&token) {
+            Some(&mut (uid, ref mut stream, ref bytes_in, ref mut partial)) => {
+                // the frames completed by what arrived
+                let mut frames = vec![];
+                self.shared.lend_buf(stream);
+                let res = stream.read_available_frames(partial, &mut frames);
+                let mut closed = res.is_err();
+                for frame in frames {
+                    bytes_in.fetch_add(frame.len(), Ordering::SeqCst);
+                    if self.events.send(self.shared.received(uid, frame)).is_err() {
+                        closed = true;
+                        break;
+                    }
+                }
+                if let Err(ref err) = res {
+                    report_read_error(uid, err, &self.events);
+                }
+                closed
+            }
+            None => false,
+        };
+        if closed {
+            self.close(evloop, token);
+        }
+    }
+
+    fn notify(&mut self, evloop: &mut EventLoop<Worker>, msg: WorkerMsg) {
+        match msg {
+            WorkerMsg::Add(uid, stream, proxy_protocol) => self.add(evloop, uid, stream, proxy_protocol),
+            WorkerMsg::Stop => {
+                let tokens: Vec<Token> = self.connections.keys().cloned().collect();
+                for token in tokens {
+                    self.close(evloop, token);
+                }
+                evloop.shutdown();
+            }
+        }
+    }
+
+    fn timeout(&mut self, evloop: &mut EventLoop<Worker>, _timeout: ()) {
+        if let Some(timeout) = self.options.idle_timeout {
+            let idle: Vec<Token> = self.connections.iter()
+                .filter(|&(_, &(_, ref stream, _, _))| stream.last_seen().elapsed() >= timeout)
+                .map(|(&token, _)| token)
+                .collect();
+            for token in idle {
+                self.close(evloop, token);
+            }
+            evloop.timeout_ms((), idle_check_ms(timeout)).is_ok();
+        }
+    }
+}
+
+/// Start the thread writing the frames queued for a connection. It
+/// runs until the queue is closed.
+fn spawn_writer(shared: &Arc<Shared>, mut writer: Writer, high_water: usize) -> Arc<SendQueue> {
+    let queue = Arc::new(SendQueue::new(high_water));
+    let outbound = queue.clone();
+    spawn_tracked(shared, move |_| {
+        while let Some(frame) = outbound.pop() {
+            if writer.write_preframed(&frame).is_err() {
+                // the receiver reports the disconnection
+                outbound.close();
+            }
+        }
+        // failed, finished by `close_connection()` or shut down
+        writer.shutdown();
+    });
+    queue
+}
+
+fn send_queue_full() -> ServerError {
+    ServerError::from(NetError::Io(io::Error::new(io::ErrorKind::WouldBlock, "send queue full")))
+}
+
+/// Report a frame over the size limit, the other errors just close
+/// the connection
+fn report_read_error(uid: Uid, err: &NetError, events: &EventQueue) {
+    if let NetError::FrameTooBig(size) = *err.root() {
+        events.send(Event::ProtocolError(uid, size)).is_ok();
+    }
+}
+
+/// Error on a connection, with the address of the peer if it has one
+fn peer_error(peer_addr: &Addr, err: NetError) -> ServerError {
+    match peer_addr.inet() {
+        Some(addr) => ServerError::from(err.with_peer(addr)),
+        None => ServerError::from(err),
+    }
 }
@@ -0,0 +1,3632 @@
+use net::endian;
+use net::async;
+use net::readbuf::{ReadBuf, READ_BUF_SIZE};
+use net::tokenset::TokenSet;
+use net::sync::{Server, Event, ServerError, ServerStats, OverflowPolicy, LimitPolicy, Addr, Cidr,
+                ListenerBuilder, Client, ReconnectPolicy, Priority};
+use net::pubsub::{PubSubServer, PubSubClient};
+use net::rpc::{RpcServer, RpcError};
+#[cfg(feature = "ws")]
+use net::ws::{WebSocket, Message};
+use net::{Framed, FramedTcpStream, FramedUdpSocket, FrameHeader, LengthPrefix, Endianness, NetError,
+          Role, HandshakeError, RateLimited, ReconnectingFramedStream, ReconnectEvent, Mux,
+          ProxyHeader, read_proxy_header, ProxyKind, FrameHooks, FrameEvent, Direction};
+use mio::Token;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream, Shutdown, SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Connected pair of tcp streams over localhost
+fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || TcpStream::connect(addr).unwrap());
+    let (server, _) = listener.accept().unwrap();
+    (client.join().unwrap(), server)
+}
+
+fn framed_pair(header: FrameHeader) -> (FramedTcpStream, FramedTcpStream) {
+    let (client, server) = tcp_pair();
+    (FramedTcpStream::with_header(client, header), FramedTcpStream::with_header(server, header))
+}
+
+#[test]
+fn test_token_set() {
+    let mut set = TokenSet::with_capacity(8);
+    assert!(set.insert(Token(3)));
+    assert!(set.insert(Token(1)));
+    assert!(!set.insert(Token(3)));
+    // past the initial capacity and the first word
+    assert!(set.insert(Token(64)));
+    assert!(set.insert(Token(200)));
+    assert!(!set.insert(Token(200)));
+    assert!(set.insert(Token(63)));
+    let tokens: Vec<Token> = set.drain().collect();
+    assert_eq!(tokens, [Token(3), Token(1), Token(64), Token(200), Token(63)]);
+    assert_eq!(set.drain().count(), 0);
+
+    // drain clears the whole words of the tokens it takes out, so
+    // every one of them goes back in
+    for &token in &tokens {
+        assert!(set.insert(token));
+    }
+    assert!(!set.insert(Token(1)));
+    let tokens: Vec<Token> = set.drain().collect();
+    assert_eq!(tokens, [Token(3), Token(1), Token(64), Token(200), Token(63)]);
+}
+
+#[test]
+fn test_read_buf() {
+    let mut buf = ReadBuf::new();
+    let msg: Vec<u8> = (0..3 * READ_BUF_SIZE).map(|i| i as u8).collect();
+    // the header and the start of the message fill the buffer
+    let first = {
+        let space = buf.space(4);
+        assert_eq!(space.len(), READ_BUF_SIZE);
+        space[..4].copy_from_slice(&endian::u32_bytes(msg.len() as u32));
+        let first = space.len() - 4;
+        space[4..].copy_from_slice(&msg[..first]);
+        first
+    };
+    buf.filled(READ_BUF_SIZE);
+    assert_eq!(endian::read_u32(buf.take(4)) as usize, msg.len());
+    // grown for the whole message, what was read moved to the front
+    {
+        let space = buf.space(msg.len());
+        assert_eq!(space.len(), msg.len() - first);
+        space.copy_from_slice(&msg[first..]);
+    }
+    buf.filled(msg.len() - first);
+    assert_eq!(buf.size(), msg.len());
+    // kept while there is something to parse
+    buf.shrink();
+    assert_eq!(buf.size(), msg.len());
+    assert_eq!(buf.take(msg.len()), &msg[..]);
+    assert_eq!(buf.remaining(), 0);
+    // still there for the handler
+    assert_eq!(buf.taken(msg.len()), &msg[..]);
+    assert_eq!(buf.taken(4), &msg[msg.len() - 4..]);
+    buf.shrink();
+    assert_eq!(buf.size(), READ_BUF_SIZE);
+    assert_eq!(buf.space(4).len(), READ_BUF_SIZE);
+    assert_eq!(ReadBuf::empty().size(), 0);
+}
+
+#[test]
+fn test_header_roundtrip() {
+    let headers = [FrameHeader::default(),
+                   FrameHeader::new(LengthPrefix::U16, Endianness::Little),
+                   FrameHeader::new(LengthPrefix::U64, Endianness::Big),
+                   FrameHeader::varint()];
+    for header in headers.iter() {
+        let mut buf = vec![];
+        for &len in [0, 1, 127, 128, 300, 65535].iter() {
+            header.encode(len, &mut buf).unwrap();
+        }
+        let mut r = Cursor::new(buf);
+        for &len in [0, 1, 127, 128, 300, 65535].iter() {
+            assert_eq!(header.decode(&mut r).unwrap(), len);
+        }
+    }
+}
+
+#[test]
+fn test_header_format() {
+    let mut buf = vec![];
+    FrameHeader::new(LengthPrefix::U16, Endianness::Little).encode(0x1234, &mut buf).unwrap();
+    assert_eq!(buf, vec![0x34, 0x12]);
+    buf.clear();
+    FrameHeader::default().encode(0x1234, &mut buf).unwrap();
+    assert_eq!(buf, vec![0, 0, 0x12, 0x34]);
+    buf.clear();
+    FrameHeader::varint().encode(300, &mut buf).unwrap();
+    assert_eq!(buf, vec![0xac, 0x02]);
+
+    match FrameHeader::new(LengthPrefix::U16, Endianness::Big).encode(70000, &mut buf) {
+        Err(NetError::FrameTooBig(70000)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_endian() {
+    assert_eq!(endian::u16_bytes(0x1234), [0x12, 0x34]);
+    assert_eq!(endian::u32_bytes(0x12345678), [0x12, 0x34, 0x56, 0x78]);
+    assert_eq!(endian::read_u64(&endian::u64_bytes(0x0102030405060708)), 0x0102030405060708);
+    // unaligned reads
+    let bytes = [0, 0xde, 0xad, 0xbe, 0xef];
+    assert_eq!(endian::read_u32(&bytes[1..]), 0xdeadbeef);
+    assert_eq!(endian::read_u16(&bytes[3..]), 0xbeef);
+
+    let mut buf = vec![];
+    endian::encode_varint(300, &mut buf);
+    assert_eq!(buf, vec![0xac, 0x02]);
+    assert_eq!(endian::decode_varint(&[0xac, 0x02, 7]).unwrap(), Some((300, 2)));
+    assert_eq!(endian::decode_varint(&[0xac]).unwrap(), None);
+    assert!(endian::decode_varint(&[0xff; 10]).is_err());
+    buf.clear();
+    endian::encode_varint(::std::u64::MAX, &mut buf);
+    assert_eq!(buf.len(), endian::MAX_VARINT_LEN);
+    assert_eq!(endian::read_varint(&mut Cursor::new(buf)).unwrap(), ::std::u64::MAX);
+}
+
+#[test]
+fn test_framed_stream() {
+    let (mut client, mut server) = framed_pair(FrameHeader::new(LengthPrefix::U16, Endianness::Little));
+    client.write_frame(b"hello").unwrap();
+    client.write_frame(b"").unwrap();
+    assert_eq!(server.read_frame().unwrap(), b"hello".to_vec());
+    assert_eq!(server.read_frame().unwrap(), vec![]);
+    server.write_frame(&[7; 1000]).unwrap();
+    let mut buf = [0; 1000];
+    assert_eq!(client.read_frame_into(&mut buf).unwrap(), 1000);
+    assert!(buf.iter().all(|&b| b == 7));
+}
+
+#[test]
+fn test_connect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let mut client = FramedTcpStream::builder()
+            .connect_timeout(Some(Duration::from_secs(5)))
+            .keepalive(Some(Duration::from_secs(60)))
+            .recv_buffer_size(64 * 1024)
+            .connect(addr)
+            .unwrap();
+        client.write_frame(b"hello").unwrap();
+        client.read_frame().unwrap()
+    });
+    let mut server = FramedTcpStream::new(listener.accept().unwrap().0);
+    let frame = server.read_frame().unwrap();
+    server.write_frame(&frame).unwrap();
+    assert_eq!(client.join().unwrap(), b"hello".to_vec());
+    drop(listener);
+
+    assert!(FramedTcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn test_buffered_writes() {
+    let (client, server) = tcp_pair();
+    let mut client = FramedTcpStream::new(client);
+    let mut server = FramedTcpStream::builder()
+        .read_timeout(Some(Duration::from_millis(50)))
+        .build(server)
+        .unwrap();
+    for i in 0..10u8 {
+        client.write_frame_buffered(&[i; 32]).unwrap();
+    }
+    match server.read_frame() {
+        Err(NetError::Timeout) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    client.flush().unwrap();
+    for i in 0..10u8 {
+        assert_eq!(server.read_frame().unwrap(), vec![i; 32]);
+    }
+
+    // write_frame sends the buffered frames first
+    client.write_frame_buffered(b"first").unwrap();
+    client.write_frame(b"second").unwrap();
+    assert_eq!(server.read_frame().unwrap(), b"first".to_vec());
+    assert_eq!(server.read_frame().unwrap(), b"second".to_vec());
+}
+
+#[test]
+fn test_read_frames_batch() {
+    let (client, server) = tcp_pair();
+    let mut client = FramedTcpStream::new(client);
+    let mut server = FramedTcpStream::builder()
+        .read_timeout(Some(Duration::from_millis(50)))
+        .build(server)
+        .unwrap();
+    for i in 0..10u8 {
+        client.write_frame_buffered(&[i; 10]).unwrap();
+    }
+    client.flush().unwrap();
+    thread::sleep(Duration::from_millis(20));
+
+    let mut frames = vec![];
+    assert_eq!(server.read_frames_batch(&mut frames, 4).unwrap(), 4);
+    assert_eq!(server.read_frames_batch(&mut frames, 100).unwrap(), 6);
+    assert_eq!(frames, (0..10u8).map(|i| vec![i; 10]).collect::<Vec<_>>());
+
+    // a partially received frame isn't waited for
+    client.write_frame(b"whole").unwrap();
+    client.get_mut().write_all(&[0, 0, 0, 5, b'p']).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    frames.clear();
+    assert_eq!(server.read_frames_batch(&mut frames, 100).unwrap(), 1);
+    assert_eq!(frames, vec![b"whole".to_vec()]);
+}
+
+#[test]
+fn test_transfer() {
+    let (mut client, mut server) = framed_pair(FrameHeader::default());
+    let data: Vec<u8> = (0..200000u32).map(|i| (i % 251) as u8).collect();
+    let sender = {
+        let data = data.clone();
+        thread::spawn(move || {
+            client.write_frame_from_reader(&mut &data[..], data.len() as u64).unwrap();
+            client.write_frame_from_reader(&mut &b""[..], 0).unwrap();
+            client.write_frame(b"after").unwrap();
+            // reader shorter than the announced length
+            assert!(client.write_frame_from_reader(&mut &b"abc"[..], 10).is_err());
+        })
+    };
+    let mut out = vec![];
+    assert_eq!(server.read_frame_to_writer(&mut out).unwrap(), data.len() as u64);
+    assert_eq!(out, data);
+    out.clear();
+    assert_eq!(server.read_frame_to_writer(&mut out).unwrap(), 0);
+    assert_eq!(server.read_frame().unwrap(), b"after".to_vec());
+    sender.join().unwrap();
+}
+
+#[test]
+fn test_rate_limited() {
+    let (client, mut server) = framed_pair(FrameHeader::default());
+    let mut client = RateLimited::new(client);
+    client.set_frame_rate(Some(10), 1);
+    client.set_blocking(false);
+    client.write_frame(b"a").unwrap();
+    match client.write_frame(b"b") {
+        Err(NetError::Io(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // 100 bytes at once, then 100 bytes every 100ms
+    client.set_frame_rate(None, 0);
+    client.set_byte_rate(Some(1000), 100);
+    client.set_blocking(true);
+    let start = Instant::now();
+    for _ in 0..3 {
+        client.write_frame(&[1; 100]).unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(190));
+
+    assert_eq!(server.read_frame().unwrap(), b"a".to_vec());
+    for _ in 0..3 {
+        assert_eq!(server.read_frame().unwrap(), vec![1; 100]);
+    }
+}
+
+#[test]
+fn test_reconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        // drop the first connection, answer on the second
+        drop(listener.accept().unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = FramedTcpStream::new(stream);
+        stream.write_frame(b"again").unwrap();
+        stream.read_frame().unwrap()
+    });
+    let mut client = ReconnectingFramedStream::connect(addr, FramedTcpStream::builder()).unwrap();
+    let policy = ReconnectPolicy::new().backoff(Duration::from_millis(10), Duration::from_millis(100));
+    client.set_policy(policy.clone());
+    assert_eq!(client.read_frame().unwrap(), b"again".to_vec());
+    client.write_frame(b"bye").unwrap();
+    assert_eq!(server.join().unwrap(), b"bye".to_vec());
+    let events = client.take_events();
+    match (&events[0], events.last().unwrap()) {
+        (&ReconnectEvent::Disconnected(_), &ReconnectEvent::Reconnected { .. }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // nobody listening anymore
+    client.set_policy(policy.max_attempts(Some(2)));
+    client.set_write_buffering(1);
+    match client.read_frame() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(!client.is_connected());
+    client.write_frame(b"buffered").unwrap();
+    match client.write_frame(b"dropped") {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_mux() {
+    let (a, b) = framed_pair(FrameHeader::default());
+    let mut a = Mux::new(a);
+    let mut b = Mux::new(b);
+    a.send(1, b"rpc").unwrap();
+    a.send(2, b"bulk 1").unwrap();
+    a.send(2, b"bulk 2").unwrap();
+    a.send(1, b"rpc 2").unwrap();
+    a.close(2).unwrap();
+    a.send(3, b"other").unwrap();
+    // channel 2 is read while waiting on channel 1
+    assert_eq!(b.recv(1).unwrap(), b"rpc".to_vec());
+    assert_eq!(b.recv(1).unwrap(), b"rpc 2".to_vec());
+    assert_eq!(b.recv(2).unwrap(), b"bulk 1".to_vec());
+    assert_eq!(b.recv(2).unwrap(), b"bulk 2".to_vec());
+    assert_eq!(b.recv_any().unwrap(), (3, b"other".to_vec()));
+    match b.recv(2) {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(b.is_closed(2));
+    assert!(b.send(2, b"late").is_err());
+    b.send(1, b"reply").unwrap();
+    assert_eq!(a.recv(1).unwrap(), b"reply".to_vec());
+}
+
+#[cfg(feature = "ws")]
+#[test]
+fn test_websocket() {
+    let (client, server) = tcp_pair();
+    let server = thread::spawn(move || {
+        let mut ws = WebSocket::accept(server).unwrap();
+        loop {
+            match ws.read_message() {
+                Ok(msg) => ws.write_message(&msg).unwrap(),
+                Err(NetError::Closed) => break,
+                Err(err) => panic!("unexpected {:?}", err),
+            }
+        }
+    });
+    let mut ws = WebSocket::connect(client, "localhost", "/echo").unwrap();
+    ws.write_text("hello").unwrap();
+    assert_eq!(ws.read_message().unwrap(), Message::Text("hello".to_string()));
+    ws.ping(b"ping").unwrap();
+    // 64 bits length
+    let big = vec![7; 70000];
+    ws.write_frame(&big).unwrap();
+    assert_eq!(ws.read_frame().unwrap(), big);
+    ws.close().unwrap();
+    match ws.read_message() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    server.join().unwrap();
+
+    // handshake example from the RFC
+    let (mut client, server) = tcp_pair();
+    let server = thread::spawn(move || WebSocket::accept(server).map(|_| ()));
+    client.write_all(b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+                       Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    server.join().unwrap().unwrap();
+    let mut response = [0; 129];
+    client.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], &b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                                 Connection: Upgrade\r\n\
+                                 Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"[..]);
+}
+
+#[cfg(feature = "ws")]
+#[test]
+fn test_websocket_upgrade_headers() {
+    // request without "Connection: Upgrade"
+    let (mut client, server) = tcp_pair();
+    let server = thread::spawn(move || WebSocket::accept(server).map(|_| ()));
+    client.write_all(b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    match server.join().unwrap() {
+        Err(NetError::Protocol(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    let mut response = vec![];
+    client.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 400 "));
+
+    // the connection header can list other tokens
+    let (mut client, server) = tcp_pair();
+    let server = thread::spawn(move || WebSocket::accept(server).map(|_| ()));
+    client.write_all(b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+                       Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    server.join().unwrap().unwrap();
+
+    // 101 response without "Upgrade: websocket"
+    let (client, mut server) = tcp_pair();
+    let client = thread::spawn(move || WebSocket::connect(client, "localhost", "/").map(|_| ()));
+    let mut request = io::BufReader::new(server.try_clone().unwrap());
+    let mut line = String::new();
+    while line != "\r\n" {
+        line.clear();
+        io::BufRead::read_line(&mut request, &mut line).unwrap();
+    }
+    server.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\r\n").unwrap();
+    match client.join().unwrap() {
+        Err(NetError::Protocol("invalid websocket upgrade response")) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_proxy_protocol() {
+    let header = ProxyHeader {
+        source: "192.168.0.1:56324".parse().unwrap(),
+        destination: "192.168.0.11:443".parse().unwrap(),
+    };
+    let mut r = Cursor::new(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nrest".to_vec());
+    assert_eq!(read_proxy_header(&mut r).unwrap(), Some(header));
+    assert_eq!(r.position(), 47);
+    let mut r = Cursor::new(b"PROXY UNKNOWN\r\n".to_vec());
+    assert_eq!(read_proxy_header(&mut r).unwrap(), None);
+    let mut r = Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec());
+    assert!(read_proxy_header(&mut r).is_err());
+
+    let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    v2.extend_from_slice(&[192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x01, 0xbb]);
+    let mut r = Cursor::new(v2.clone());
+    assert_eq!(read_proxy_header(&mut r).unwrap(), Some(header));
+    // LOCAL command
+    v2[12] = 0x20;
+    assert_eq!(read_proxy_header(&mut Cursor::new(v2)).unwrap(), None);
+
+    let (mut client, server) = tcp_pair();
+    client.write_all(b"PROXY TCP6 ::1 ::2 1000 2000\r\n\0\0\0\x02hi").unwrap();
+    let mut server = FramedTcpStream::builder().proxy_protocol(true).build(server).unwrap();
+    assert_eq!(server.client_addr().unwrap(), "[::1]:1000".parse().unwrap());
+    assert_eq!(server.read_frame().unwrap(), b"hi".to_vec());
+}
+
+/// Fake proxy accepting a single connection, checking the requests and
+/// sending the replies, then echoing a frame
+fn fake_proxy(exchanges: &'static [(&'static [u8], &'static [u8])])
+              -> (SocketAddr, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        for &(request, reply) in exchanges {
+            let mut buf = vec![0; request.len()];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], request);
+            stream.write_all(reply).unwrap();
+        }
+        let mut stream = FramedTcpStream::new(stream);
+        let frame = stream.read_frame().unwrap();
+        stream.write_frame(&frame).unwrap();
+    });
+    (addr, handle)
+}
+
+#[test]
+fn test_connect_via_proxy() {
+    let (proxy, handle) = fake_proxy(&[(b"\x05\x01\x00", b"\x05\x00"),
+                                       (b"\x05\x01\x00\x03\x0bexample.com\x00\x50",
+                                        b"\x05\x00\x00\x01\x7f\x00\x00\x01\x10\x00")]);
+    let mut stream = FramedTcpStream::connect_via_proxy(proxy, "example.com:80").unwrap();
+    stream.write_frame(b"hello").unwrap();
+    assert_eq!(stream.read_frame().unwrap(), b"hello".to_vec());
+    handle.join().unwrap();
+
+    let (proxy, handle) = fake_proxy(&[(b"CONNECT 10.0.0.1:443 HTTP/1.1\r\nHost: 10.0.0.1:443\r\n\r\n",
+                                        b"HTTP/1.1 200 Connection established\r\n\r\n")]);
+    let mut stream = FramedTcpStream::builder()
+        .connect_via_proxy(proxy, ProxyKind::HttpConnect, "10.0.0.1:443").unwrap();
+    stream.write_frame(b"hello").unwrap();
+    assert_eq!(stream.read_frame().unwrap(), b"hello".to_vec());
+    handle.join().unwrap();
+
+    // connection refused by the target
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 10];
+        stream.read_exact(&mut buf[..3]).unwrap();
+        stream.write_all(b"\x05\x00").unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(b"\x05\x05\x00\x01\x00\x00\x00\x00\x00\x00").unwrap();
+    });
+    match FramedTcpStream::connect_via_proxy(proxy, "10.0.0.1:80") {
+        Err(NetError::Protocol(_)) => {}
+        other => panic!("unexpected {:?}", other.map(|_| ())),
+    }
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_server_connect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    let uid = server.connect(addr).unwrap();
+    let mut peer = FramedTcpStream::new(listener.accept().unwrap().0);
+
+    match server.next() {
+        Some(Event::Connected(connected)) => assert_eq!(connected, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    server.send_to(uid, b"hello").unwrap();
+    assert_eq!(peer.read_frame().unwrap(), b"hello".to_vec());
+    peer.write_frame(b"hi").unwrap();
+    match server.next() {
+        Some(Event::Recv(from, frame)) => {
+            assert_eq!(from, uid);
+            assert_eq!(frame, b"hi".to_vec());
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    drop(peer);
+    match server.next() {
+        Some(Event::Disconnected(from)) => assert_eq!(from, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(server.connect(addr).unwrap() != uid);
+}
+
+#[test]
+fn test_server_shutdown() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = FramedTcpStream::connect(addr).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    client.write_frame(b"bye").unwrap();
+    thread::sleep(Duration::from_millis(20));
+
+    server.shutdown().unwrap();
+    match server.next() {
+        Some(Event::Recv(from, frame)) => assert_eq!((from, frame.into_vec()), (uid, b"bye".to_vec())),
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.next() {
+        Some(Event::Disconnected(gone)) => assert_eq!(gone, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.next() {
+        Some(Event::Shutdown) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(server.next().is_none());
+    match client.read_frame() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(TcpStream::connect(addr).is_err());
+    assert!(server.connect(addr).is_err());
+    assert!(server.shutdown().is_ok());
+}
+
+#[test]
+fn test_server_idle_timeout() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_idle_timeout(Some(Duration::from_millis(300)));
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut silent = FramedTcpStream::connect(addr).unwrap();
+    let silent_uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    let mut chatty = FramedTcpStream::connect(addr).unwrap();
+    match server.next() {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    silent.write_frame(b"once").unwrap();
+    let start = Instant::now();
+    let mut recv = 0;
+    loop {
+        chatty.write_frame(b"still here").unwrap();
+        match server.next_event_timeout(Duration::from_millis(50)) {
+            Some(Event::Recv(..)) => recv += 1,
+            Some(Event::Disconnected(uid)) => {
+                assert_eq!(uid, silent_uid);
+                break;
+            }
+            None => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "idle connection not closed");
+    }
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert!(recv > 1);
+    match silent.read_frame() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_heartbeat() {
+    let interval = Duration::from_millis(50);
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_heartbeat(Some(interval), 3);
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    // answers the pings while reading
+    let mut alive = FramedTcpStream::connect(addr).unwrap();
+    alive.set_heartbeat(Some(interval), 3);
+    alive.get_ref().set_read_timeout(Some(interval)).unwrap();
+    let alive_uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    let reader = thread::spawn(move || alive.read_frame().unwrap());
+    // never answers, like a peer gone without closing the connection
+    let dead = TcpStream::connect(addr).unwrap();
+    let dead_uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    let start = Instant::now();
+    match server.next() {
+        Some(Event::Disconnected(uid)) => assert_eq!(uid, dead_uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(start.elapsed() >= interval * 2);
+    match server.next_event_timeout(interval * 6) {
+        None => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    server.send_to(alive_uid, b"still here").unwrap();
+    assert_eq!(reader.join().unwrap(), b"still here");
+    drop(dead);
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_max_frame_size() {
+    for &workers in &[None, Some(1)] {
+        let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+        server.set_max_frame_size(16);
+        server.set_worker_pool(workers);
+        server.start().unwrap();
+        let mut client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_frame(b"small").unwrap();
+        client.write_frame(&[0; 100]).unwrap();
+        let uid = match server.next() {
+            Some(Event::Connected(uid)) => uid,
+            other => panic!("unexpected {:?}", other),
+        };
+        match server.next() {
+            Some(Event::Recv(from, ref data)) => {
+                assert_eq!(from, uid);
+                assert_eq!(&data[..], b"small");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match server.next() {
+            Some(Event::ProtocolError(from, size)) => {
+                assert_eq!(from, uid);
+                assert_eq!(size, 100);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match server.next() {
+            Some(Event::Disconnected(from)) => assert_eq!(from, uid),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(client.read_frame().is_err());
+    }
+}
+
+#[test]
+fn test_server_shutdown_and_wait() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let _client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    match server.next() {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown_and_wait(Duration::from_secs(5)).unwrap();
+
+    // a receiver thread panicking in a hook is reported
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_frame_hooks(FrameHooks::new().on_frame_received(|evt| {
+        if evt.size == 4 {
+            panic!("bad frame");
+        }
+    }));
+    server.start().unwrap();
+    let mut client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    client.write_frame(b"ok").unwrap();
+    client.write_frame(b"oops").unwrap();
+    match server.next() {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.next() {
+        Some(Event::Recv(_, ref data)) => assert_eq!(&data[..], b"ok"),
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.shutdown_and_wait(Duration::from_secs(5)) {
+        Err(ServerError::Threads { panicked: 1, running: 0 }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.next() {
+        Some(Event::Shutdown) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_server_worker_pool() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_worker_pool(Some(2));
+    server.set_idle_timeout(Some(Duration::from_millis(200)));
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+
+    // more connections than workers
+    let mut clients: Vec<_> = (0..5).map(|_| FramedTcpStream::connect(addr).unwrap()).collect();
+    for _ in 0..5 {
+        match server.next() {
+            Some(Event::Connected(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.write_frame(&[i as u8]).unwrap();
+        client.write_frame(&[i as u8 + 10]).unwrap();
+    }
+    let mut received = vec![];
+    for _ in 0..10 {
+        match server.next() {
+            Some(Event::Recv(uid, frame)) => {
+                server.send_to(uid, &frame).unwrap();
+                received.push(frame[0]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    received.sort();
+    assert_eq!(received, vec![0, 1, 2, 3, 4, 10, 11, 12, 13, 14]);
+    for (i, client) in clients.iter_mut().enumerate() {
+        let mut echoed = vec![client.read_frame().unwrap(), client.read_frame().unwrap()];
+        echoed.sort();
+        assert_eq!(echoed, vec![vec![i as u8], vec![i as u8 + 10]]);
+    }
+
+    // idle connections are closed by the workers too
+    for _ in 0..5 {
+        match server.next() {
+            Some(Event::Disconnected(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    let _late = FramedTcpStream::connect(addr).unwrap();
+    match server.next() {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown().unwrap();
+    match server.next() {
+        Some(Event::Disconnected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.next() {
+        Some(Event::Shutdown) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_server_worker_pool_partial_frames() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_worker_pool(Some(1));
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut slow = TcpStream::connect(addr).unwrap();
+    let mut fast = FramedTcpStream::connect(addr).unwrap();
+    for _ in 0..2 {
+        match server.next() {
+            Some(Event::Connected(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    // half a frame doesn't hold up the other connections of the worker
+    let mut frame = endian::u32_bytes(6).to_vec();
+    frame.extend_from_slice(b"pieces");
+    slow.write_all(&frame[..7]).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    fast.write_frame(b"whole").unwrap();
+    match server.next() {
+        Some(Event::Recv(_, ref frame)) => assert_eq!(&frame[..], b"whole"),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(start.elapsed() < Duration::from_millis(500));
+    slow.write_all(&frame[7..]).unwrap();
+    match server.next() {
+        Some(Event::Recv(_, ref frame)) => assert_eq!(&frame[..], b"pieces"),
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_worker_pool_without_send_queue() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_worker_pool(Some(1));
+    server.set_send_queue(None);
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = FramedTcpStream::connect(addr).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    client.write_frame(b"ping").unwrap();
+    match server.next() {
+        Some(Event::Recv(u, ref frame)) => {
+            assert_eq!(u, uid);
+            assert_eq!(&frame[..], b"ping");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    server.send_to(uid, b"pong").unwrap();
+    assert_eq!(client.read_frame().unwrap(), b"pong");
+    // no writer thread: nothing is queued, and write errors are
+    // returned to the sender
+    assert!(server.try_send_to(uid, b"x").is_err());
+    drop(client);
+    let start = Instant::now();
+    while server.send_to(uid, b"gone?").is_ok() {
+        assert!(start.elapsed() < Duration::from_secs(5), "write error never returned");
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_send_to_many() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut clients: Vec<_> = (0..3).map(|_| FramedTcpStream::connect(addr).unwrap()).collect();
+    let mut uids = vec![];
+    for _ in 0..3 {
+        match server.next() {
+            Some(Event::Connected(uid)) => uids.push(uid),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert!(server.send_to_all(b"all").unwrap().is_empty());
+    for client in clients.iter_mut() {
+        assert_eq!(client.read_frame().unwrap(), b"all".to_vec());
+    }
+
+    // unknown destinations are skipped and reported
+    let mut gone = uids[0];
+    while uids.contains(&gone) {
+        gone = gone.next();
+    }
+    let failed = server.send_to_many(&[uids[1], gone, uids[2]], b"some").unwrap();
+    assert_eq!(failed.len(), 1);
+    match failed[0] {
+        (uid, ServerError::NotConnected) => assert_eq!(uid, gone),
+        ref other => panic!("unexpected {:?}", other),
+    }
+    for client in clients[1..].iter_mut() {
+        assert_eq!(client.read_frame().unwrap(), b"some".to_vec());
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_multicast_mixed_framing() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let mut client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    match server.next() {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    // only the outgoing connection has heartbeat flags, like a TLS
+    // listener next to plain connections
+    let interval = Duration::from_secs(60);
+    server.set_heartbeat(Some(interval), 3);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uid = server.connect(listener.local_addr().unwrap()).unwrap();
+    let mut peer = FramedTcpStream::new(listener.accept().unwrap().0);
+    peer.set_heartbeat(Some(interval), 3);
+    match server.next() {
+        Some(Event::Connected(connected)) => assert_eq!(connected, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(server.send_to_all(b"both").unwrap().is_empty());
+    assert_eq!(client.read_frame().unwrap(), b"both".to_vec());
+    assert_eq!(peer.read_frame().unwrap(), b"both".to_vec());
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_event_polling() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    assert!(server.try_next_event().is_none());
+    let start = Instant::now();
+    assert!(server.next_event_timeout(Duration::from_millis(50)).is_none());
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    let _client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    match server.next_event_timeout(Duration::from_secs(5)) {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown().unwrap();
+    match server.try_next_event() {
+        Some(Event::Disconnected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.try_next_event() {
+        Some(Event::Shutdown) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(server.next_event_timeout(Duration::from_millis(10)).is_none());
+}
+
+#[test]
+fn test_server_recv_buffers() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let mut client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    match server.next() {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    let recv = |server: &mut Server, client: &mut FramedTcpStream, data: &[u8]| {
+        client.write_frame(data).unwrap();
+        match server.next() {
+            Some(Event::Recv(_, frame)) => frame,
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+    let first = recv(&mut server, &mut client, &[1; 1000]);
+    assert_eq!(first, vec![1; 1000]);
+    let ptr = first.as_ptr();
+    drop(first);
+    // the receiver may have started reading the second one before
+    let second = recv(&mut server, &mut client, &[2; 1000]);
+    let third = recv(&mut server, &mut client, &[3; 1000]);
+    assert!(second.as_ptr() == ptr || third.as_ptr() == ptr);
+    assert_eq!(&third[..], &[3; 1000][..]);
+    // taken out of the pool
+    let owned = second.into_vec();
+    assert_eq!(owned, vec![2; 1000]);
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_connection_info() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let before = SystemTime::now();
+    let mut client = FramedTcpStream::connect(addr).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    client.write_frame(b"12345").unwrap();
+    match server.next() {
+        Some(Event::Recv(..)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    server.send_to(uid, b"abc").unwrap();
+    let info = server.connection_info(uid).unwrap();
+    assert_eq!(info.peer_addr, Addr::Inet(client.get_ref().local_addr().unwrap()));
+    assert_eq!(info.client_addr, info.peer_addr);
+    assert_eq!(info.local_addr, Addr::Inet(addr));
+    assert!(info.connected_at >= before);
+    assert_eq!((info.bytes_in, info.bytes_out), (5, 3));
+    match server.connection_info(uid.next()) {
+        Err(ServerError::NotConnected) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_stats() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut uids = vec![];
+    let mut clients = vec![];
+    for _ in 0..2 {
+        clients.push(FramedTcpStream::connect(addr).unwrap());
+        match server.next() {
+            Some(Event::Connected(uid)) => uids.push(uid),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    clients[0].write_frame(b"12345").unwrap();
+    clients[1].write_frame(b"678").unwrap();
+    clients[1].write_frame(b"9").unwrap();
+    match server.next() {
+        Some(Event::Recv(..)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    // the other two are waiting
+    let start = Instant::now();
+    while server.stats().queued_events < 2 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.send_to(uids[0], b"abc").unwrap();
+    assert!(server.send_to_all(b"de").unwrap().is_empty());
+    let stats = server.stats();
+    assert_eq!(stats, ServerStats {
+        connections: 2,
+        accepted: 2,
+        frames_in: 3,
+        bytes_in: 9,
+        frames_out: 3,
+        bytes_out: 7,
+        queued_events: 2,
+    });
+    drop(clients.pop());
+    loop {
+        match server.next() {
+            Some(Event::Disconnected(_)) => break,
+            Some(Event::Recv(..)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    let stats = server.stats();
+    assert_eq!((stats.connections, stats.accepted, stats.queued_events), (1, 2, 0));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_send_queue() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_send_queue(Some(64 * 1024));
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = FramedTcpStream::connect(addr).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    server.send_to(uid, b"hello").unwrap();
+    assert_eq!(client.read_frame().unwrap(), b"hello");
+    // the client stops reading: the socket buffers fill up, then the
+    // queue
+    let frame = vec![7; 16 * 1024];
+    let mut sent = 0;
+    let start = Instant::now();
+    loop {
+        match server.try_send_to(uid, &frame) {
+            Ok(()) => sent += 1,
+            Err(ServerError::Net(NetError::Io(ref err))) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => panic!("unexpected {:?}", err),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "send queue never filled up");
+    }
+    match server.next() {
+        Some(Event::SendBackpressure(u)) => assert_eq!(u, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.send_to_many(&[uid], &frame) {
+        Ok(ref failed) if failed.len() == 1 => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    // everything queued arrives once the client reads again
+    for _ in 0..sent {
+        assert_eq!(client.read_frame().unwrap(), frame);
+    }
+    server.try_send_to(uid, b"done").unwrap();
+    assert_eq!(client.read_frame().unwrap(), b"done");
+    // the writer thread is joined with the others
+    server.shutdown_and_wait(Duration::from_secs(5)).unwrap();
+
+    // try_send_to needs a send queue, off by default
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let _client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert!(server.try_send_to(uid, b"x").is_err());
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_send_priority() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_send_queue(Some(64 * 1024));
+    server.start().unwrap();
+    let mut client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    // a full queue behind a client that doesn't read
+    let frame = vec![7; 16 * 1024];
+    let mut sent = 0;
+    let start = Instant::now();
+    loop {
+        match server.try_send_to(uid, &frame) {
+            Ok(()) => sent += 1,
+            Err(ServerError::Net(NetError::Io(ref err))) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => panic!("unexpected {:?}", err),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "send queue never filled up");
+    }
+    // doesn't wait for room, and overtakes the queued frames
+    server.send_to_with_priority(uid, Priority::High, b"urgent").unwrap();
+    let mut before = 0;
+    while client.read_frame().unwrap() == frame {
+        before += 1;
+    }
+    assert!(before < sent);
+    for _ in before..sent {
+        assert_eq!(client.read_frame().unwrap(), frame);
+    }
+    server.send_to_with_priority(uid, Priority::Low, b"bulk").unwrap();
+    assert_eq!(client.read_frame().unwrap(), b"bulk");
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_close_connection() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_send_queue(Some(64 * 1024));
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut kicked = FramedTcpStream::connect(addr).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    kicked.write_frame(b"spam").unwrap();
+    match server.next() {
+        Some(Event::Recv(..)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    // written from the send queue before closing
+    server.send_to(uid, b"bye").unwrap();
+    server.close_connection(uid).unwrap();
+    assert!(server.send_to(uid, b"too late").is_err());
+    match server.next() {
+        Some(Event::Disconnected(gone)) => assert_eq!(gone, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(kicked.read_frame().unwrap(), b"bye");
+    match kicked.read_frame() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.close_connection(uid) {
+        Err(ServerError::NotConnected) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(server.send_to(uid, b"late").is_err());
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_event_queue_overflow() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_event_queue(2, OverflowPolicy::Error);
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = FramedTcpStream::connect(addr).unwrap();
+    match server.next() {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    for i in 0..5u8 {
+        client.write_frame(&[i]).unwrap();
+    }
+    let start = Instant::now();
+    while server.dropped_events() < 3 {
+        assert!(start.elapsed() < Duration::from_secs(5), "frames not dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+    for i in 0..2u8 {
+        match server.next() {
+            Some(Event::Recv(_, ref data)) => assert_eq!(&data[..], &[i]),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    match server.next() {
+        Some(Event::UnexpectedError(ServerError::QueueOverflow)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // keep only the latest frame
+    server.set_event_queue(1, OverflowPolicy::DropOldest);
+    client.write_frame(b"old").unwrap();
+    client.write_frame(b"new").unwrap();
+    while server.dropped_events() < 4 {
+        assert!(start.elapsed() < Duration::from_secs(5), "frame not dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+    match server.next() {
+        Some(Event::Recv(_, ref data)) => assert_eq!(&data[..], b"new"),
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_server_unix() {
+    let path = env::temp_dir().join(format!("rust_stuff_test_{}.sock", process::id()));
+    fs::remove_file(&path).is_ok();
+    let mut server = Server::new_unix(&path);
+    server.start().unwrap();
+    assert!(server.local_addr().is_err());
+    let mut client = Server::new("127.0.0.1:0".parse().unwrap());
+    let client_uid = client.connect_unix(&path).unwrap();
+    match client.next() {
+        Some(Event::Connected(uid)) => assert_eq!(uid, client_uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    let info = server.connection_info(uid).unwrap();
+    assert_eq!(info.local_addr, Addr::Unix(Some(path.clone())));
+    assert_eq!(info.peer_addr, Addr::Unix(None));
+    assert_eq!(server.client_addr(uid).unwrap(), Addr::Unix(None));
+
+    client.send_to(client_uid, b"ping").unwrap();
+    match server.next() {
+        Some(Event::Recv(from, ref data)) => {
+            assert_eq!(from, uid);
+            assert_eq!(&data[..], b"ping");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    server.send_to(uid, b"pong").unwrap();
+    match client.next() {
+        Some(Event::Recv(from, ref data)) => {
+            assert_eq!(from, client_uid);
+            assert_eq!(&data[..], b"pong");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown().unwrap();
+    assert!(!path.exists());
+    client.shutdown().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_server_mixed() {
+    let path = env::temp_dir().join(format!("rust_stuff_test_mixed_{}.sock", process::id()));
+    fs::remove_file(&path).is_ok();
+    let mut server = Server::new_mixed(&[Addr::Unix(Some(path.clone())),
+                                         Addr::Inet("127.0.0.1:0".parse().unwrap())]);
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    assert_eq!(server.listen_addrs().unwrap(), vec![Addr::Unix(Some(path.clone())), Addr::Inet(addr)]);
+    let mut local = Client::connect_unix(&path).unwrap();
+    let local_uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    let mut remote = Client::connect(addr).unwrap();
+    let remote_uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert!(local_uid != remote_uid);
+    assert_eq!(server.client_addr(local_uid).unwrap(), Addr::Unix(None));
+    assert_eq!(server.client_addr(remote_uid).unwrap().inet().unwrap().ip(), addr.ip());
+    assert!(server.send_to_all(b"both").unwrap().is_empty());
+    for client in &mut [&mut local, &mut remote] {
+        match client.next() {
+            Some(Event::Recv(_, ref data)) => assert_eq!(&data[..], b"both"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    server.shutdown().unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_server_multi() {
+    let any = "127.0.0.1:0".parse().unwrap();
+    let mut server = Server::new_multi(&[any, any]);
+    server.start().unwrap();
+    let addrs = server.local_addrs().unwrap();
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs[0] != addrs[1]);
+    assert_eq!(server.local_addr().unwrap(), addrs[0]);
+    let mut uids = vec![];
+    let mut clients = vec![];
+    for addr in &addrs {
+        let mut client = FramedTcpStream::connect(addr).unwrap();
+        let uid = match server.next() {
+            Some(Event::Connected(uid)) => uid,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(server.connection_info(uid).unwrap().local_addr, Addr::Inet(*addr));
+        client.write_frame(&[uids.len() as u8]).unwrap();
+        match server.next() {
+            Some(Event::Recv(from, ref data)) => {
+                assert_eq!(from, uid);
+                assert_eq!(&data[..], &[uids.len() as u8]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(!uids.contains(&uid));
+        uids.push(uid);
+        clients.push(client);
+    }
+    server.shutdown().unwrap();
+    for addr in &addrs {
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
+
+#[test]
+fn test_server_listener_builder() {
+    let mut first = Server::new("127.0.0.1:0".parse().unwrap());
+    first.set_listener_builder(ListenerBuilder::new().reuse_port(true).backlog(16));
+    first.start().unwrap();
+    let addr = first.local_addr().unwrap();
+    // another server (or process) can share the address
+    let mut second = Server::new(addr);
+    second.set_listener_builder(ListenerBuilder::new().reuse_port(true));
+    second.start().unwrap();
+    let mut other = Server::new(addr);
+    assert!(other.start().is_err());
+    // connections are spread over both
+    let _clients: Vec<TcpStream> = (0..32).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    let mut connected = (0, 0);
+    let start = Instant::now();
+    while connected.0 + connected.1 < 32 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        if let Some(Event::Connected(_)) = first.next_event_timeout(Duration::from_millis(10)) {
+            connected.0 += 1;
+        }
+        if let Some(Event::Connected(_)) = second.next_event_timeout(Duration::from_millis(10)) {
+            connected.1 += 1;
+        }
+    }
+    assert!(connected.0 > 0 && connected.1 > 0);
+    first.shutdown().unwrap();
+    second.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_accept_filter() {
+    let net = Cidr::parse("10.1.0.0/16").unwrap();
+    assert!(net.contains(&Addr::Inet("10.1.2.3:80".parse().unwrap())));
+    assert!(!net.contains(&Addr::Inet("10.2.0.1:80".parse().unwrap())));
+    assert!(!net.contains(&Addr::Inet("[::1]:80".parse().unwrap())));
+    #[cfg(unix)]
+    assert!(!net.contains(&Addr::Unix(None)));
+    assert!(Cidr::parse("::1").unwrap().contains(&Addr::Inet("[::1]:80".parse().unwrap())));
+    assert!(Cidr::parse("10.0.0.0/7").unwrap().contains(&Addr::Inet("11.0.0.1:80".parse().unwrap())));
+    assert_eq!(Cidr::parse("1.2.3.4/33"), None);
+    assert_eq!(Cidr::parse("localhost"), None);
+
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_denylist(vec![Cidr::parse("127.0.0.0/8").unwrap()]);
+    server.start().unwrap();
+    let mut client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    match server.next() {
+        Some(Event::Rejected(addr)) => assert_eq!(addr, Addr::Inet(client.get_ref().local_addr().unwrap())),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(client.read_frame().is_err());
+    server.shutdown().unwrap();
+
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_allowlist(vec![Cidr::parse("127.0.0.1").unwrap()]);
+    server.start().unwrap();
+    let mut client = FramedTcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    server.send_to(uid, b"welcome").unwrap();
+    assert_eq!(client.read_frame().unwrap(), b"welcome");
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_max_connections() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_max_connections(Some(1), LimitPolicy::Notify);
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let first = FramedTcpStream::connect(addr).unwrap();
+    match server.next() {
+        Some(Event::Connected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    let mut extra = FramedTcpStream::connect(addr).unwrap();
+    match server.next() {
+        Some(Event::Rejected(from)) => assert_eq!(from, Addr::Inet(extra.get_ref().local_addr().unwrap())),
+        other => panic!("unexpected {:?}", other),
+    }
+    match extra.read_frame() {
+        Err(NetError::Closed) | Err(NetError::Io(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    drop(first);
+    match server.next() {
+        Some(Event::Disconnected(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    // the slot is freed right after the Disconnected event
+    let start = Instant::now();
+    loop {
+        let _client = FramedTcpStream::connect(addr).unwrap();
+        match server.next() {
+            Some(Event::Connected(_)) => break,
+            Some(Event::Rejected(_)) => assert!(start.elapsed() < Duration::from_secs(5)),
+            other => panic!("unexpected {:?}", other),
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_max_connections_queue() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.set_max_connections(Some(1), LimitPolicy::Queue);
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let first = FramedTcpStream::connect(addr).unwrap();
+    let first_uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    let mut waiting = FramedTcpStream::connect(addr).unwrap();
+    waiting.write_frame(b"queued").unwrap();
+    assert!(server.next_event_timeout(Duration::from_millis(100)).is_none());
+    drop(first);
+    match server.next() {
+        Some(Event::Disconnected(uid)) => assert_eq!(uid, first_uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    match server.next() {
+        Some(Event::Recv(from, ref data)) => {
+            assert_eq!(from, uid);
+            assert_eq!(&data[..], b"queued");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_client() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = Client::connect(addr).unwrap();
+    // ready right away
+    client.send(b"ping").unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    match server.next() {
+        Some(Event::Recv(from, ref data)) => {
+            assert_eq!(from, uid);
+            assert_eq!(&data[..], b"ping");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    server.send_to(uid, b"pong").unwrap();
+    let client_uid = client.uid();
+    match client.next() {
+        Some(Event::Recv(from, ref data)) => {
+            assert_eq!(from, client_uid);
+            assert_eq!(&data[..], b"pong");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    server.close_connection(uid).unwrap();
+    match client.next() {
+        Some(Event::Disconnected(from)) => assert_eq!(from, client_uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(client.send(b"gone").is_err());
+    server.shutdown().unwrap();
+    drop(server);
+    assert!(Client::connect(addr).is_err());
+}
+
+#[test]
+fn test_client_reconnect() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let policy = ReconnectPolicy::new()
+        .backoff(Duration::from_millis(10), Duration::from_millis(20))
+        .max_attempts(Some(3));
+    let mut client = Client::connect_reconnecting(addr, policy).unwrap();
+    let client_uid = client.uid();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    // the link comes back with the same uid
+    server.close_connection(uid).unwrap();
+    match client.next() {
+        Some(Event::Disconnected(from)) => assert_eq!(from, client_uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    match client.next() {
+        Some(Event::Connected(from)) => assert_eq!(from, client_uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    client.send(b"back").unwrap();
+    match server.next() {
+        Some(Event::Disconnected(from)) => assert_eq!(from, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    match server.next() {
+        Some(Event::Recv(from, ref data)) => {
+            assert_eq!(from, uid);
+            assert_eq!(&data[..], b"back");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    // gives up once the server is gone, forgetting the peer name
+    client.server().set_peer_name(client_uid, "server").unwrap();
+    server.shutdown().unwrap();
+    drop(server);
+    match client.next() {
+        Some(Event::Disconnected(from)) => assert_eq!(from, client_uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(client.server().peer_uid("server"), Some(client_uid));
+    match client.next() {
+        Some(Event::UnexpectedError(ServerError::ReconnectFailed(from, _))) => assert_eq!(from, client_uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(client.server().peer_uid("server"), None);
+    assert!(client.send(b"gone").is_err());
+}
+
+#[test]
+fn test_close_reconnecting() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut peer = Server::new_multi(&[]);
+    let policy = ReconnectPolicy::new().backoff(Duration::from_millis(10), Duration::from_millis(20));
+    let to_server = peer.connect_reconnecting(addr, policy).unwrap();
+    match peer.next() {
+        Some(Event::Connected(uid)) => assert_eq!(uid, to_server),
+        other => panic!("unexpected {:?}", other),
+    }
+    peer.set_peer_name(to_server, "server").unwrap();
+    server.shutdown().unwrap();
+    drop(server);
+    match peer.next() {
+        Some(Event::Disconnected(from)) => assert_eq!(from, to_server),
+        other => panic!("unexpected {:?}", other),
+    }
+    // closed for good while reconnecting
+    peer.close_connection(to_server).unwrap();
+    assert_eq!(peer.peer_uid("server"), None);
+    match peer.next_event_timeout(Duration::from_millis(200)) {
+        None => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match peer.close_connection(to_server) {
+        Err(ServerError::NotConnected) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    peer.shutdown().unwrap();
+}
+
+/// Async server tests fail if they run longer than this
+const ASYNC_TEST_TIMEOUT_MS: u64 = 10000;
+
+/// Servers `run_async_server` can run
+trait AsyncServer {
+    fn run_server(self) -> Result<(), async::Error>;
+}
+
+impl<H: async::ServerHandler> AsyncServer for async::Server<H> {
+    fn run_server(mut self) -> Result<(), async::Error> {
+        self.run()
+    }
+}
+
+impl<H: async::ServerHandler + Send + 'static> AsyncServer for async::ShardedServer<H> {
+    fn run_server(self) -> Result<(), async::Error> {
+        self.run()
+    }
+}
+
+/// Run the async server made by `server` until it shuts down, and
+/// `peer` alongside it, each on its own thread. The server is dropped
+/// (closing its connections) before waiting for the peer, whose
+/// result is returned. Fails if the server panics or either of them
+/// is still running after `ASYNC_TEST_TIMEOUT_MS`.
+fn run_async_server<A, S, P, R>(server: S, peer: P) -> R
+    where A: AsyncServer,
+          S: FnOnce() -> Result<A, async::Error> + Send + 'static,
+          P: FnOnce() -> R + Send + 'static,
+          R: Send + 'static
+{
+    let deadline = Instant::now() + Duration::from_millis(ASYNC_TEST_TIMEOUT_MS);
+    let (peer_tx, peer_rx) = mpsc::channel();
+    thread::spawn(move || peer_tx.send(peer()).is_ok());
+    let (server_tx, server_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let res = server().and_then(AsyncServer::run_server);
+        server_tx.send(res.map_err(|err| format!("{:?}", err))).is_ok();
+    });
+    wait_for(&server_rx, deadline, "server").unwrap();
+    wait_for(&peer_rx, deadline, "peer")
+}
+
+/// Result sent on `rx` by a test thread, before `deadline`
+fn wait_for<T>(rx: &mpsc::Receiver<T>, deadline: Instant, name: &str) -> T {
+    let now = Instant::now();
+    let timeout = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(mpsc::RecvTimeoutError::Timeout) => panic!("{} still running after {}ms", name, ASYNC_TEST_TIMEOUT_MS),
+        Err(mpsc::RecvTimeoutError::Disconnected) => panic!("{} panicked", name),
+    }
+}
+
+/// Bound address with nothing listening on it anymore
+fn dead_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Connect to `addr` once a server started on another thread listens
+/// on it
+fn connect_when_listening(addr: SocketAddr) -> TcpStream {
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(conn) => return conn,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+enum RetryTimeout {
+    Connect,
+    Listen,
+}
+
+struct RetryHandler {
+    addr: SocketAddr,
+    log: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl async::ServerHandler for RetryHandler {
+    type Message = ();
+    type Timeout = RetryTimeout;
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        let policy = ReconnectPolicy::new()
+            .backoff(Duration::from_millis(10), Duration::from_millis(20))
+            .max_attempts(Some(2));
+        server.connect_with_retry(self.addr, policy).unwrap();
+    }
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, _uid: async::ConnectionUid) {
+        self.log.lock().unwrap().push("connected");
+        assert_eq!(server.stats().connections, 1);
+        server.shutdown();
+    }
+    fn connect_failed(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid) {
+        self.log.lock().unwrap().push("failed");
+        // the slot is freed once this returns
+        server.timeout_ms(RetryTimeout::Connect, 10).unwrap().detach();
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+    fn timeout(&mut self, server: &mut async::ServerControl<Self>, timeout: RetryTimeout) {
+        match timeout {
+            RetryTimeout::Connect => {
+                let failures = self.log.lock().unwrap().len();
+                if failures == 1 {
+                    // gives up again
+                    let policy = ReconnectPolicy::new()
+                        .backoff(Duration::from_millis(10), Duration::from_millis(20))
+                        .max_attempts(Some(2));
+                    server.connect_with_retry(self.addr, policy).unwrap();
+                } else {
+                    // keeps trying until the peer shows up
+                    let policy = ReconnectPolicy::new()
+                        .backoff(Duration::from_millis(300), Duration::from_millis(300));
+                    server.connect_with_retry(self.addr, policy).unwrap();
+                    server.timeout_ms(RetryTimeout::Listen, 10).unwrap().detach();
+                }
+            }
+            RetryTimeout::Listen => {
+                let listener = TcpListener::bind(self.addr).unwrap();
+                thread::spawn(move || {
+                    let (mut conn, _) = listener.accept().unwrap();
+                    let _ = conn.read_to_end(&mut Vec::new());
+                });
+            }
+        }
+    }
+}
+
+#[test]
+fn test_async_connect_retry() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let handler = RetryHandler {addr: dead_addr(), log: log.clone()};
+    // a single slot, which each connect that gives up must free
+    run_async_server(move || async::ServerBuilder::new(1).build(handler), || ());
+    assert_eq!(*log.lock().unwrap(), ["failed", "failed", "connected"]);
+}
+
+const TRACKED_MSGS: u64 = 4;
+const TRACKED_MSG_SIZE: usize = 8 << 20;
+
+struct TrackedHandler {
+    addr: SocketAddr,
+    sent: Arc<Mutex<Vec<u64>>>,
+}
+
+impl async::ServerHandler for TrackedHandler {
+    type Message = ();
+    type Timeout = ();
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        server.connect(self.addr).unwrap();
+    }
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        for token in 1..TRACKED_MSGS + 1 {
+            server.send_tracked(&uid, &vec![token as u8; TRACKED_MSG_SIZE], token);
+        }
+        // more than the socket takes at once
+        assert!(server.connection_info(&uid).unwrap().queued_bytes > 0);
+        assert!(self.sent.lock().unwrap().is_empty());
+    }
+    fn sent(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid, token: u64) {
+        let mut sent = self.sent.lock().unwrap();
+        assert_eq!(token, sent.len() as u64 + 1);
+        sent.push(token);
+        // at most the messages after this one are still queued
+        let queued = server.connection_info(uid).unwrap().queued_bytes;
+        assert!(queued <= (TRACKED_MSGS - token) as usize * (4 + TRACKED_MSG_SIZE));
+        if token == TRACKED_MSGS {
+            server.shutdown();
+        }
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+}
+
+#[test]
+fn test_async_send_tracked() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let handler = TrackedHandler {addr: addr, sent: sent.clone()};
+    // drains the messages slowly, so they queue up on the server
+    let total = run_async_server(move || async::Server::new(handler, 1), move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut buf = [0; 65536];
+        let mut total = 0;
+        loop {
+            match conn.read(&mut buf).unwrap() {
+                0 => return total,
+                n => total += n,
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    });
+    assert_eq!(*sent.lock().unwrap(), [1, 2, 3, 4]);
+    assert_eq!(total, TRACKED_MSGS as usize * (4 + TRACKED_MSG_SIZE));
+}
+
+#[derive(Debug, PartialEq)]
+enum Tick {
+    Dropped,
+    Cancelled,
+    Detached,
+    Moved,
+    At,
+}
+
+struct TimerHandler {
+    start: Instant,
+    fired: Arc<Mutex<Vec<Tick>>>,
+    moved: Option<async::TimerHandle>,
+    _at: Option<async::TimerHandle>,
+}
+
+impl async::ServerHandler for TimerHandler {
+    type Message = ();
+    type Timeout = Tick;
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        drop(server.timeout_ms(Tick::Dropped, 10).unwrap());
+        let cancelled = server.timeout_ms(Tick::Cancelled, 10).unwrap();
+        server.timeout_cancel(cancelled);
+        server.timeout_ms(Tick::Detached, 10).unwrap().detach();
+        // brought forward from a minute to now
+        let moved = server.timeout_ms(Tick::Moved, 60000).unwrap();
+        assert_eq!(server.timeout_reschedule(&moved, Instant::now()).unwrap(), true);
+        self.moved = Some(moved);
+        self._at = Some(server.timeout_at(Tick::At, self.start + Duration::from_millis(300)).unwrap());
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+    fn timeout(&mut self, server: &mut async::ServerControl<Self>, timeout: Tick) {
+        match timeout {
+            Tick::Moved => {
+                // already fired
+                let moved = self.moved.as_ref().unwrap();
+                assert_eq!(server.timeout_reschedule(moved, Instant::now()).unwrap(), false);
+            }
+            Tick::At => {
+                assert!(self.start.elapsed() >= Duration::from_millis(300));
+                server.shutdown();
+            }
+            _ => {}
+        }
+        self.fired.lock().unwrap().push(timeout);
+    }
+}
+
+#[test]
+fn test_async_timers() {
+    let fired = Arc::new(Mutex::new(Vec::new()));
+    let handler = TimerHandler {
+        start: Instant::now(),
+        fired: fired.clone(),
+        moved: None,
+        _at: None,
+    };
+    run_async_server(move || async::Server::new(handler, 1), || ());
+    let fired = fired.lock().unwrap();
+    // Detached and Moved are both due right away, in either order
+    assert_eq!(fired.len(), 3);
+    assert!(fired.contains(&Tick::Detached));
+    assert!(fired.contains(&Tick::Moved));
+    assert_eq!(fired[2], Tick::At);
+}
+
+struct SlabHandler {
+    addr: SocketAddr,
+    accepted: usize,
+}
+
+impl async::ServerHandler for SlabHandler {
+    type Message = ();
+    type Timeout = ();
+    fn connection(&mut self, _server: &mut async::ServerControl<Self>, _uid: async::ConnectionUid) {
+        self.accepted += 1;
+    }
+    fn accept_rejected(&mut self, server: &mut async::ServerControl<Self>, _addr: &SocketAddr, err: async::Error) {
+        match err {
+            async::Error::ConnectionLimit => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        // grown from 1 to the limit of 3
+        assert_eq!(self.accepted, 3);
+        assert_eq!(server.stats().connections, 3);
+        match server.connect(self.addr) {
+            Err(async::Error::ConnectionLimit) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        server.shutdown();
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+}
+
+#[test]
+fn test_async_slab_growth() {
+    let addr = dead_addr();
+    let handler = SlabHandler {addr: addr, accepted: 0};
+    let server = move || async::ServerBuilder::new(1).connection_limit(Some(3)).bind(&addr, handler);
+    run_async_server(server, move || {
+        let conns: Vec<TcpStream> = (0..4).map(|_| connect_when_listening(addr)).collect();
+        for mut conn in conns {
+            let _ = conn.read_to_end(&mut Vec::new());
+        }
+    });
+}
+
+struct PauseHandler {
+    addr: SocketAddr,
+    resumed: bool,
+    received: Vec<Vec<u8>>,
+}
+
+impl async::ServerHandler for PauseHandler {
+    type Message = ();
+    type Timeout = async::ConnectionUid;
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        server.connect(self.addr).unwrap();
+    }
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        server.pause_reads(&uid);
+        // the peer only sends once it gets this
+        server.send(&uid, b"go");
+        server.timeout_ms(uid, 300).unwrap().detach();
+    }
+    fn message(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, msg: &[u8]) {
+        assert!(self.resumed);
+        self.received.push(msg.to_vec());
+        if self.received.len() == 3 {
+            assert_eq!(self.received, [b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+            server.shutdown();
+        }
+    }
+    fn timeout(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        self.resumed = true;
+        server.resume_reads(&uid);
+    }
+}
+
+#[test]
+fn test_async_pause_reads() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = PauseHandler {addr: addr, resumed: false, received: Vec::new()};
+    run_async_server(move || async::Server::new(handler, 1), move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut go = [0; 6];
+        conn.read_exact(&mut go).unwrap();
+        assert_eq!(&go[4..], b"go");
+        for msg in &[&b"one"[..], b"two", b"three"] {
+            conn.write_all(&endian::u32_bytes(msg.len() as u32)).unwrap();
+            conn.write_all(msg).unwrap();
+        }
+        let _ = conn.read_to_end(&mut Vec::new());
+    });
+}
+
+struct IdleHandler {
+    addr: SocketAddr,
+    connected: Option<Instant>,
+    pinged: bool,
+}
+
+impl async::ServerHandler for IdleHandler {
+    type Message = ();
+    type Timeout = ();
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        server.connect(self.addr).unwrap();
+    }
+    fn connection(&mut self, _server: &mut async::ServerControl<Self>, _uid: async::ConnectionUid) {
+        self.connected = Some(Instant::now());
+    }
+    fn connection_closed(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid) {
+        // idle for the timeout after the ping, sent ~150ms in
+        assert!(self.pinged);
+        assert!(self.connected.unwrap().elapsed() >= Duration::from_millis(300));
+        server.shutdown();
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, msg: &[u8]) {
+        assert_eq!(msg, b"ping");
+        self.pinged = true;
+    }
+}
+
+#[test]
+fn test_async_idle_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = IdleHandler {addr: addr, connected: None, pinged: false};
+    let server = move || {
+        async::ServerBuilder::new(1).idle_timeout(Some(Duration::from_millis(200))).build(handler)
+    };
+    run_async_server(server, move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_millis(150));
+        conn.write_all(&endian::u32_bytes(4)).unwrap();
+        conn.write_all(b"ping").unwrap();
+        // closed by the server
+        assert_eq!(conn.read_to_end(&mut Vec::new()).unwrap(), 0);
+    });
+}
+
+struct IdlePauseHandler {
+    addr: SocketAddr,
+    pinged: bool,
+}
+
+impl async::ServerHandler for IdlePauseHandler {
+    type Message = ();
+    type Timeout = async::ConnectionUid;
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        server.connect(self.addr).unwrap();
+    }
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        // paused for twice the idle timeout
+        server.pause_reads(&uid);
+        server.timeout_ms(uid, 400).unwrap().detach();
+    }
+    fn connection_closed(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid) {
+        assert!(self.pinged, "closed as idle after resuming");
+    }
+    fn message(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, msg: &[u8]) {
+        assert_eq!(msg, b"ping");
+        self.pinged = true;
+        server.shutdown();
+    }
+    fn timeout(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        server.resume_reads(&uid);
+        server.send(&uid, b"go");
+    }
+}
+
+#[test]
+fn test_async_idle_timeout_paused() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = IdlePauseHandler {addr: addr, pinged: false};
+    let server = move || {
+        async::ServerBuilder::new(1).idle_timeout(Some(Duration::from_millis(200))).build(handler)
+    };
+    run_async_server(server, move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut go = [0; 6];
+        conn.read_exact(&mut go).unwrap();
+        // well within the idle timeout counted from the resume
+        thread::sleep(Duration::from_millis(100));
+        conn.write_all(&endian::u32_bytes(4)).unwrap();
+        conn.write_all(b"ping").unwrap();
+        let _ = conn.read_to_end(&mut Vec::new());
+    });
+}
+
+/// Write a message of the async server's format: a u32 size header
+/// and the payload, in a single write
+fn write_async_msg(conn: &mut TcpStream, msg: &[u8]) {
+    let mut buf = endian::u32_bytes(msg.len() as u32).to_vec();
+    buf.extend_from_slice(msg);
+    conn.write_all(&buf).unwrap();
+}
+
+/// Read a message sent by an async server
+fn read_async_msg(conn: &mut TcpStream) -> Vec<u8> {
+    let mut hdr = [0; 4];
+    conn.read_exact(&mut hdr).unwrap();
+    let mut msg = vec![0; endian::read_u32(&hdr) as usize];
+    conn.read_exact(&mut msg).unwrap();
+    msg
+}
+
+struct MsgSizeHandler {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<usize>>>,
+}
+
+impl async::ServerHandler for MsgSizeHandler {
+    type Message = ();
+    type Timeout = ();
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        server.connect(self.addr).unwrap();
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, msg: &[u8]) {
+        self.received.lock().unwrap().push(msg.len());
+    }
+    fn protocol_error(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid,
+                      err: async::Error) {
+        assert!(err.is_protocol());
+        assert_eq!(err.uid(), Some(uid));
+        match err {
+            async::Error::Connection(_, ref err) => match **err {
+                async::Error::MsgTooBig(size) => assert_eq!(size, 40001),
+                ref other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        server.shutdown();
+    }
+}
+
+#[test]
+fn test_async_max_msg_size() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let handler = MsgSizeHandler {addr: addr, received: received.clone()};
+    // over the default limit
+    let server = move || async::ServerBuilder::new(1).max_msg_size(40000).build(handler);
+    run_async_server(server, move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        write_async_msg(&mut conn, &[1; 40000]);
+        write_async_msg(&mut conn, &[2; 40001]);
+        // closed without reading the rest
+        let _ = conn.read_to_end(&mut Vec::new());
+    });
+    assert_eq!(*received.lock().unwrap(), [40000]);
+}
+
+struct BigMsgHandler {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl async::ServerHandler for BigMsgHandler {
+    type Message = ();
+    type Timeout = ();
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        server.connect(self.addr).unwrap();
+    }
+    fn message(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, msg: &[u8]) {
+        let mut received = self.received.lock().unwrap();
+        received.push(msg.to_vec());
+        if received.len() == 4 {
+            server.shutdown();
+        }
+    }
+}
+
+#[test]
+fn test_async_read_buf_growth() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let handler = BigMsgHandler {addr: addr, received: received.clone()};
+    let big: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+    let msgs = vec![b"small".to_vec(), big.clone(), b"small again".to_vec(), big];
+    let sent = msgs.clone();
+    let server = move || async::ServerBuilder::new(1).max_msg_size(2 << 20).build(handler);
+    run_async_server(server, move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        for msg in &sent {
+            let mut buf = endian::u32_bytes(msg.len() as u32).to_vec();
+            buf.extend_from_slice(msg);
+            // in pieces, so the big messages take several reads
+            for chunk in buf.chunks(64 * 1024) {
+                conn.write_all(chunk).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        let _ = conn.read_to_end(&mut Vec::new());
+    });
+    assert_eq!(*received.lock().unwrap(), msgs);
+}
+
+#[derive(Clone)]
+enum IntervalTimeout {
+    Tick,
+    Stop,
+}
+
+struct IntervalHandler {
+    start: Instant,
+    interval: Option<async::IntervalUid>,
+    ticks: Arc<Mutex<u32>>,
+    _stop: Option<async::TimerHandle>,
+}
+
+impl async::ServerHandler for IntervalHandler {
+    type Message = ();
+    type Timeout = IntervalTimeout;
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        self.interval = Some(server.timer_interval(IntervalTimeout::Tick, 100).unwrap());
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+    fn timeout(&mut self, server: &mut async::ServerControl<Self>, timeout: IntervalTimeout) {
+        match timeout {
+            IntervalTimeout::Tick => {
+                let mut ticks = self.ticks.lock().unwrap();
+                *ticks += 1;
+                // on the schedule, not drifting by the tick delays
+                let elapsed = self.start.elapsed();
+                assert!(elapsed >= Duration::from_millis(100 * *ticks as u64));
+                assert!(elapsed < Duration::from_millis(100 * *ticks as u64 + 300));
+                if *ticks == 5 {
+                    server.interval_cancel(self.interval.take().unwrap());
+                    // no ticks after the cancel
+                    self._stop = Some(server.timeout_ms(IntervalTimeout::Stop, 300).unwrap());
+                }
+            }
+            IntervalTimeout::Stop => server.shutdown(),
+        }
+    }
+}
+
+#[test]
+fn test_async_timer_interval() {
+    let ticks = Arc::new(Mutex::new(0));
+    let handler = IntervalHandler {start: Instant::now(), interval: None, ticks: ticks.clone(), _stop: None};
+    run_async_server(move || async::Server::new(handler, 1), || ());
+    assert_eq!(*ticks.lock().unwrap(), 5);
+}
+
+const WATERMARK_MSGS: usize = 256;
+const WATERMARK_MSG_SIZE: usize = 64 * 1024;
+
+struct WatermarkHandler {
+    addr: SocketAddr,
+    notices: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl async::ServerHandler for WatermarkHandler {
+    type Message = ();
+    type Timeout = ();
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        server.connect(self.addr).unwrap();
+    }
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        // far more than the socket buffers take while the peer waits
+        for _ in 0..WATERMARK_MSGS {
+            server.send(&uid, &[7; WATERMARK_MSG_SIZE]);
+        }
+    }
+    fn writable_blocked(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid) {
+        assert!(server.connection_info(uid).unwrap().queued_bytes > 1 << 20);
+        self.notices.lock().unwrap().push("blocked");
+    }
+    fn writable_resumed(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid) {
+        assert!(server.connection_info(uid).unwrap().queued_bytes <= 256 * 1024);
+        self.notices.lock().unwrap().push("resumed");
+        // shut down once the rest is written
+        server.send_tracked(uid, b"last", 0);
+    }
+    fn sent(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _token: u64) {
+        server.shutdown();
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+}
+
+#[test]
+fn test_async_write_watermarks() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let notices = Arc::new(Mutex::new(Vec::new()));
+    let handler = WatermarkHandler {addr: addr, notices: notices.clone()};
+    let server = move || async::ServerBuilder::new(1).write_watermarks(1 << 20, 256 * 1024).build(handler);
+    let received = run_async_server(server, move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).unwrap();
+        received.len()
+    });
+    assert_eq!(*notices.lock().unwrap(), ["blocked", "resumed"]);
+    assert_eq!(received, WATERMARK_MSGS * (4 + WATERMARK_MSG_SIZE) + 4 + 4);
+}
+
+const SHARDED_CLIENTS: usize = 8;
+
+struct ShardHandler {
+    shard: usize,
+    closed: Arc<Mutex<usize>>,
+}
+
+impl async::ServerHandler for ShardHandler {
+    type Message = async::ConnectionUid;
+    type Timeout = ();
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        assert_eq!(uid.shard(), self.shard);
+        assert_eq!(server.shards().unwrap().len(), 2);
+    }
+    fn message(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid, msg: &[u8]) {
+        assert_eq!(msg, b"hello");
+        // answered by the other shard
+        server.shards().unwrap().notify(1 - self.shard, *uid).unwrap();
+    }
+    fn notify(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        assert!(uid.shard() != self.shard);
+        let reply = format!("from shard {}", self.shard).into_bytes();
+        server.shards().unwrap().send(&uid, reply).unwrap();
+    }
+    fn connection_closed(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid) {
+        let mut closed = self.closed.lock().unwrap();
+        *closed += 1;
+        if *closed == SHARDED_CLIENTS {
+            server.shards().unwrap().shutdown();
+        }
+    }
+}
+
+#[test]
+fn test_async_sharded() {
+    let addr = dead_addr();
+    let closed = Arc::new(Mutex::new(0));
+    let handlers = (0..2).map(|shard| ShardHandler {shard: shard, closed: closed.clone()}).collect();
+    let server = move || async::ServerBuilder::new(SHARDED_CLIENTS).bind_sharded(&addr, handlers);
+    let replies = run_async_server(server, move || {
+        let mut conns: Vec<TcpStream> = (0..SHARDED_CLIENTS).map(|_| connect_when_listening(addr)).collect();
+        conns.iter_mut().map(|conn| {
+            write_async_msg(conn, b"hello");
+            String::from_utf8(read_async_msg(conn)).unwrap()
+        }).collect::<Vec<_>>()
+    });
+    assert_eq!(replies.len(), SHARDED_CLIENTS);
+    for reply in replies {
+        assert!(reply == "from shard 0" || reply == "from shard 1");
+    }
+    assert_eq!(*closed.lock().unwrap(), SHARDED_CLIENTS);
+}
+
+const GRACEFUL_MSG_SIZE: usize = 4 << 20;
+
+struct GracefulHandler {
+    closed: Arc<Mutex<usize>>,
+}
+
+impl async::ServerHandler for GracefulHandler {
+    type Message = ();
+    type Timeout = ();
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        // more than the socket takes at once
+        server.send(&uid, &vec![7; GRACEFUL_MSG_SIZE]);
+        server.shutdown_graceful(5000).unwrap();
+    }
+    fn connection_closed(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid) {
+        *self.closed.lock().unwrap() += 1;
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+}
+
+#[test]
+fn test_async_shutdown_graceful() {
+    let addr = dead_addr();
+    let closed = Arc::new(Mutex::new(0));
+    let handler = GracefulHandler {closed: closed.clone()};
+    let server = move || async::ServerBuilder::new(2).bind(&addr, handler);
+    let start = Instant::now();
+    run_async_server(server, move || {
+        let mut conn = connect_when_listening(addr);
+        thread::sleep(Duration::from_millis(100));
+        // the listener is closed already
+        assert!(TcpStream::connect(addr).is_err());
+        // the queued message is still written out before the close
+        assert_eq!(read_async_msg(&mut conn).len(), GRACEFUL_MSG_SIZE);
+        assert_eq!(conn.read(&mut [0; 16]).unwrap(), 0);
+    });
+    // without waiting for the timeout
+    assert!(start.elapsed() < Duration::from_millis(5000));
+    assert_eq!(*closed.lock().unwrap(), 1);
+}
+
+/// Server and client configs with the certificate of `localhost`
+/// (in `testdata`, signed by the test CA there)
+#[cfg(feature = "async-tls")]
+fn tls_configs() -> (Arc<::rustls::ServerConfig>, Arc<::rustls::ClientConfig>) {
+    use rustls::internal::pemfile;
+    let certs = pemfile::certs(&mut &include_bytes!("testdata/localhost.pem")[..]).unwrap();
+    let mut keys = pemfile::rsa_private_keys(&mut &include_bytes!("testdata/localhost.key")[..]).unwrap();
+    let mut server = ::rustls::ServerConfig::new();
+    server.set_single_cert(certs, keys.remove(0));
+    let mut client = ::rustls::ClientConfig::new();
+    client.root_store.add_pem_file(&mut &include_bytes!("testdata/ca.pem")[..]).unwrap();
+    (Arc::new(server), Arc::new(client))
+}
+
+#[cfg(feature = "async-tls")]
+struct TlsHandler {
+    addr: SocketAddr,
+    client: Arc<::rustls::ClientConfig>,
+    reply: Arc<Mutex<Vec<u8>>>,
+}
+
+#[cfg(feature = "async-tls")]
+impl async::ServerHandler for TlsHandler {
+    type Message = ();
+    type Timeout = ();
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        // to itself
+        server.connect_tls(self.addr, self.client.clone(), "localhost").unwrap();
+    }
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        if uid.listener().is_none() {
+            // queued until the handshake is done
+            server.send(&uid, b"ping");
+        }
+    }
+    fn message(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid, msg: &[u8]) {
+        if uid.listener().is_some() {
+            assert_eq!(msg, b"ping");
+            // several TLS records
+            server.send(uid, &vec![7; 256 * 1024]);
+        } else {
+            *self.reply.lock().unwrap() = msg.to_vec();
+            server.shutdown();
+        }
+    }
+}
+
+#[cfg(feature = "async-tls")]
+#[test]
+fn test_async_tls() {
+    let addr = dead_addr();
+    let (server_config, client_config) = tls_configs();
+    let reply = Arc::new(Mutex::new(Vec::new()));
+    let handler = TlsHandler {addr: addr, client: client_config, reply: reply.clone()};
+    let server = move || async::ServerBuilder::new(4).tls(server_config).bind(&addr, handler);
+    run_async_server(server, || ());
+    assert_eq!(*reply.lock().unwrap(), vec![7; 256 * 1024]);
+}
+
+struct UdpHandler {
+    bound: mpsc::Sender<SocketAddr>,
+}
+
+impl async::ServerHandler for UdpHandler {
+    type Message = ();
+    type Timeout = ();
+    fn init(&mut self, server: &mut async::ServerControl<Self>) {
+        let udp = server.bind_udp(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        self.bound.send(server.udp_local_addr(&udp).unwrap()).unwrap();
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+    fn datagram(&mut self, server: &mut async::ServerControl<Self>, udp: &async::UdpUid, from: &SocketAddr,
+                data: &[u8]) {
+        if data == b"bye" {
+            server.close_udp(udp);
+            assert!(server.send_datagram(udp, from, b"closed").is_err());
+            server.shutdown();
+        } else {
+            let mut reply = b"echo ".to_vec();
+            reply.extend_from_slice(data);
+            server.send_datagram(udp, from, &reply).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_async_udp() {
+    let (bound_tx, bound_rx) = mpsc::channel();
+    let handler = UdpHandler {bound: bound_tx};
+    let replies = run_async_server(move || async::Server::new(handler, 1), move || {
+        let addr = bound_rx.recv().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut replies = Vec::new();
+        for msg in &[&b"one"[..], b"two"] {
+            socket.send_to(msg, addr).unwrap();
+            let mut buf = [0; 64];
+            let (n, from) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(from, addr);
+            replies.push(buf[..n].to_vec());
+        }
+        socket.send_to(b"bye", addr).unwrap();
+        replies
+    });
+    assert_eq!(replies, [b"echo one".to_vec(), b"echo two".to_vec()]);
+}
+
+struct BindAllHandler {
+    received: usize,
+}
+
+impl async::ServerHandler for BindAllHandler {
+    type Message = ();
+    type Timeout = ();
+    fn message(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid, msg: &[u8]) {
+        // each client sends the index of the address it connected to
+        assert_eq!(uid.listener(), Some(msg[0] as usize));
+        self.received += 1;
+        if self.received == 2 {
+            server.shutdown();
+        }
+    }
+}
+
+#[test]
+fn test_async_bind_all() {
+    let addrs = [dead_addr(), dead_addr()];
+    let handler = BindAllHandler {received: 0};
+    let server = move || async::ServerBuilder::new(2).bind_all(&addrs, handler);
+    run_async_server(server, move || {
+        let mut conns: Vec<TcpStream> = addrs.iter().map(|&addr| connect_when_listening(addr)).collect();
+        for (i, conn) in conns.iter_mut().enumerate() {
+            write_async_msg(conn, &[i as u8]);
+        }
+        for mut conn in conns {
+            let _ = conn.read_to_end(&mut Vec::new());
+        }
+    });
+}
+
+#[cfg(feature = "codec")]
+struct EchoTypedHandler {
+    addr: SocketAddr,
+    invalid: Arc<Mutex<usize>>,
+}
+
+#[cfg(feature = "codec")]
+impl async::TypedServerHandler for EchoTypedHandler {
+    type Msg = String;
+    type Codec = ::net::Bincode;
+    type Message = ();
+    type Timeout = ();
+    fn init(&mut self, server: &mut async::TypedControl<Self>) {
+        server.connect(self.addr).unwrap();
+    }
+    fn message(&mut self, server: &mut async::TypedControl<Self>, uid: &async::ConnectionUid, msg: String) {
+        if msg == "bye" {
+            server.shutdown();
+        } else {
+            server.send(uid, &msg.to_uppercase()).unwrap();
+        }
+    }
+    fn invalid_message(&mut self, server: &mut async::TypedControl<Self>, uid: &async::ConnectionUid,
+                       err: NetError) {
+        match err {
+            NetError::Codec(_) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        *self.invalid.lock().unwrap() += 1;
+        // left open: tell the peer it can go on
+        server.send(uid, &"invalid".to_string()).unwrap();
+    }
+}
+
+#[test]
+#[cfg(feature = "codec")]
+fn test_async_typed_server() {
+    use net::{Codec, Bincode};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let invalid = Arc::new(Mutex::new(0));
+    let handler = EchoTypedHandler {addr: addr, invalid: invalid.clone()};
+    let server = move || async::Server::new(async::Typed::new(handler, Bincode), 1);
+    run_async_server(server, move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let encode = |msg: &str| Bincode.encode(&msg.to_string()).unwrap();
+        let decode = |frame: Vec<u8>| -> String { Bincode.decode(&frame).unwrap() };
+        write_async_msg(&mut conn, &encode("hello"));
+        assert_eq!(decode(read_async_msg(&mut conn)), "HELLO");
+        write_async_msg(&mut conn, &[0xff, 0xfe]);
+        assert_eq!(decode(read_async_msg(&mut conn)), "invalid");
+        write_async_msg(&mut conn, &encode("again"));
+        assert_eq!(decode(read_async_msg(&mut conn)), "AGAIN");
+        write_async_msg(&mut conn, &encode("bye"));
+        let _ = conn.read_to_end(&mut Vec::new());
+    });
+    assert_eq!(*invalid.lock().unwrap(), 1);
+}
+
+struct BroadcastHandler {
+    connected: usize,
+    done: usize,
+}
+
+impl async::ServerHandler for BroadcastHandler {
+    type Message = ();
+    type Timeout = ();
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, _uid: async::ConnectionUid) {
+        self.connected += 1;
+        if self.connected == 3 {
+            server.broadcast(b"ready");
+        }
+    }
+    fn message(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid, msg: &[u8]) {
+        if msg == b"done" {
+            self.done += 1;
+            if self.done == 2 {
+                server.shutdown();
+            }
+        } else {
+            server.broadcast_except(uid, msg);
+        }
+    }
+}
+
+#[test]
+fn test_async_broadcast() {
+    let addr = dead_addr();
+    let handler = BroadcastHandler {connected: 0, done: 0};
+    let server = move || async::Server::bind(&addr, handler, 3);
+    run_async_server(server, move || {
+        let mut conns: Vec<TcpStream> = (0..3).map(|_| connect_when_listening(addr)).collect();
+        for conn in &mut conns {
+            assert_eq!(read_async_msg(conn), b"ready");
+        }
+        write_async_msg(&mut conns[0], b"relay");
+        for conn in &mut conns[1..] {
+            assert_eq!(read_async_msg(conn), b"relay");
+            write_async_msg(conn, b"done");
+        }
+        // the sender doesn't get its own message back
+        let mut rest = Vec::new();
+        let _ = conns[0].read_to_end(&mut rest);
+        assert!(rest.is_empty());
+    });
+}
+
+struct DataHandler {
+    closed: Arc<Mutex<Option<String>>>,
+}
+
+impl async::ServerHandler for DataHandler {
+    type Message = ();
+    type Timeout = ();
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        assert!(server.set_data(&uid, 0u32));
+    }
+    fn message(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid, msg: &[u8]) {
+        match msg {
+            b"count" => *server.data_mut::<u32>(uid).unwrap() += 1,
+            b"take" => {
+                assert!(server.data::<String>(uid).is_none());
+                assert!(server.take_data::<String>(uid).is_none());
+                let count = server.take_data::<u32>(uid).unwrap();
+                assert!(server.data::<u32>(uid).is_none());
+                server.send(uid, &[count as u8]);
+                server.set_data(uid, "label".to_string());
+            }
+            b"close" => server.close_connection(*uid),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    fn connection_closed(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid) {
+        assert!(!server.set_data(uid, 0u32));
+        assert_eq!(server.data::<String>(uid).unwrap(), "label");
+        let label = server.take_data::<String>(uid).unwrap();
+        assert!(server.data::<String>(uid).is_none());
+        *self.closed.lock().unwrap() = Some(label);
+        server.shutdown();
+    }
+}
+
+#[test]
+fn test_async_connection_data() {
+    let addr = dead_addr();
+    let closed = Arc::new(Mutex::new(None));
+    let handler = DataHandler {closed: closed.clone()};
+    let server = move || async::Server::bind(&addr, handler, 1);
+    run_async_server(server, move || {
+        let mut conn = connect_when_listening(addr);
+        write_async_msg(&mut conn, b"count");
+        write_async_msg(&mut conn, b"count");
+        write_async_msg(&mut conn, b"take");
+        assert_eq!(read_async_msg(&mut conn), [2]);
+        write_async_msg(&mut conn, b"close");
+        let _ = conn.read_to_end(&mut Vec::new());
+    });
+    assert_eq!(*closed.lock().unwrap(), Some("label".to_string()));
+}
+
+struct StatsHandler {
+    snapshot: Arc<Mutex<Option<async::ServerStats>>>,
+    reported: Arc<Mutex<Option<async::ServerStats>>>,
+}
+
+impl async::ServerHandler for StatsHandler {
+    type Message = ();
+    type Timeout = ();
+    fn message(&mut self, server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid, msg: &[u8]) {
+        server.send(uid, msg);
+        if msg == b"world!" {
+            *self.snapshot.lock().unwrap() = Some(server.stats());
+        }
+    }
+    fn stats(&mut self, server: &mut async::ServerControl<Self>, stats: &async::ServerStats) {
+        // the next report after both messages
+        if stats.messages_in == 2 {
+            *self.reported.lock().unwrap() = Some(stats.clone());
+            server.shutdown();
+        }
+    }
+}
+
+#[test]
+fn test_async_stats() {
+    let addr = dead_addr();
+    let snapshot = Arc::new(Mutex::new(None));
+    let reported = Arc::new(Mutex::new(None));
+    let handler = StatsHandler {snapshot: snapshot.clone(), reported: reported.clone()};
+    let server = move || async::ServerBuilder::new(1)
+        .stats_interval(Some(Duration::from_millis(20)))
+        .bind(&addr, handler);
+    run_async_server(server, move || {
+        let mut conn = connect_when_listening(addr);
+        write_async_msg(&mut conn, b"hello");
+        assert_eq!(read_async_msg(&mut conn), b"hello");
+        write_async_msg(&mut conn, b"world!");
+        assert_eq!(read_async_msg(&mut conn), b"world!");
+        let _ = conn.read_to_end(&mut Vec::new());
+    });
+    let snapshot = snapshot.lock().unwrap().take().unwrap();
+    assert_eq!(snapshot.connections, 1);
+    assert_eq!(snapshot.accepted, 1);
+    assert_eq!((snapshot.messages_in, snapshot.bytes_in), (2, 11));
+    assert_eq!((snapshot.messages_out, snapshot.bytes_out), (2, 11));
+    let reported = reported.lock().unwrap().take().unwrap();
+    assert_eq!(reported.accepted, 1);
+    assert_eq!((reported.messages_out, reported.bytes_out), (2, 11));
+}
+
+struct ConnErrorHandler {
+    peer_closed: mpsc::Receiver<()>,
+    events: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl async::ServerHandler for ConnErrorHandler {
+    type Message = ();
+    type Timeout = ();
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        server.send(&uid, b"hello");
+        // the event loop doesn't see the reset until this returns, so
+        // the next send fails
+        self.peer_closed.recv().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        server.send(&uid, b"again");
+    }
+    fn message(&mut self, _server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, _msg: &[u8]) {
+    }
+    fn connection_error(&mut self, _server: &mut async::ServerControl<Self>, uid: &async::ConnectionUid,
+                        err: async::Error) {
+        assert!(err.is_io());
+        assert!(!err.is_protocol());
+        assert_eq!(err.uid(), Some(uid));
+        self.events.lock().unwrap().push("error");
+    }
+    fn connection_closed(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid) {
+        self.events.lock().unwrap().push("closed");
+        server.shutdown();
+    }
+}
+
+#[test]
+fn test_async_connection_error() {
+    let addr = dead_addr();
+    let (tx, rx) = mpsc::channel();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let handler = ConnErrorHandler {peer_closed: rx, events: events.clone()};
+    let server = move || async::Server::bind(&addr, handler, 1);
+    run_async_server(server, move || {
+        let mut conn = connect_when_listening(addr);
+        // closing with unread data resets the connection
+        conn.read_exact(&mut [0; 1]).unwrap();
+        drop(conn);
+        tx.send(()).unwrap();
+    });
+    assert_eq!(*events.lock().unwrap(), ["error", "closed"]);
+}
+
+struct SocketOptsHandler {
+    received: Arc<Mutex<usize>>,
+}
+
+impl async::ServerHandler for SocketOptsHandler {
+    type Message = ();
+    type Timeout = ();
+    fn message(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, msg: &[u8]) {
+        assert_eq!(msg, b"ping");
+        *self.received.lock().unwrap() += 1;
+        server.shutdown();
+    }
+}
+
+#[test]
+fn test_async_socket_options() {
+    // another listener (or process) sharing the address
+    let other = ListenerBuilder::new().reuse_port(true).bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = other.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(0));
+    let handler = SocketOptsHandler {received: received.clone()};
+    let (bound_tx, bound_rx) = mpsc::channel();
+    let server = move || {
+        let builder = || async::ServerBuilder::new(64)
+            .nodelay(false)
+            .keepalive(Some(Duration::from_secs(60)));
+        assert!(builder().bind(&addr, SocketOptsHandler {received: Arc::new(Mutex::new(0))}).is_err());
+        let server = builder()
+            .listener(ListenerBuilder::new().reuse_port(true).backlog(64))
+            .bind(&addr, handler);
+        bound_tx.send(()).unwrap();
+        server
+    };
+    run_async_server(server, move || {
+        bound_rx.recv().unwrap();
+        // the kernel spreads the connections over both listeners
+        let conns: Vec<TcpStream> = (0..32).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for mut conn in conns {
+            let mut msg = endian::u32_bytes(4).to_vec();
+            msg.extend_from_slice(b"ping");
+            // the server may be gone already
+            let _ = conn.write_all(&msg);
+        }
+    });
+    assert!(*received.lock().unwrap() > 0);
+    drop(other);
+}
+
+struct SendBufHandler;
+
+impl async::ServerHandler for SendBufHandler {
+    type Message = ();
+    type Timeout = ();
+    fn connection(&mut self, server: &mut async::ServerControl<Self>, uid: async::ConnectionUid) {
+        server.send_owned(&uid, b"owned".to_vec());
+        let shared = Arc::new(b"shared".to_vec());
+        server.send_shared(&uid, shared.clone());
+        server.send_shared(&uid, shared);
+        let bytes = ::bytes::Bytes::from(&b"[bytes]"[..]);
+        server.send_bytes(&uid, bytes.slice(1, 6));
+        // more than the socket takes at once, the rest is queued
+        server.send_owned(&uid, vec![7; 4 << 20]);
+        server.send_bytes(&uid, bytes);
+    }
+    fn message(&mut self, server: &mut async::ServerControl<Self>, _uid: &async::ConnectionUid, msg: &[u8]) {
+        assert_eq!(msg, b"done");
+        server.shutdown();
+    }
+}
+
+#[test]
+fn test_async_send_buffers() {
+    let addr = dead_addr();
+    let server = move || async::Server::bind(&addr, SendBufHandler, 1);
+    run_async_server(server, move || {
+        let mut conn = connect_when_listening(addr);
+        assert_eq!(read_async_msg(&mut conn), b"owned");
+        assert_eq!(read_async_msg(&mut conn), b"shared");
+        assert_eq!(read_async_msg(&mut conn), b"shared");
+        assert_eq!(read_async_msg(&mut conn), b"bytes");
+        assert!(read_async_msg(&mut conn) == vec![7; 4 << 20]);
+        assert_eq!(read_async_msg(&mut conn), b"[bytes]");
+        write_async_msg(&mut conn, b"done");
+        let _ = conn.read_to_end(&mut Vec::new());
+    });
+}
+
+#[test]
+fn test_server_peer_names() {
+    let mut server = Server::new("127.0.0.1:0".parse().unwrap());
+    server.start().unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut peer = Server::new_multi(&[]);
+    let policy = ReconnectPolicy::new().backoff(Duration::from_millis(10), Duration::from_millis(20));
+    let to_server = peer.connect_reconnecting(addr, policy).unwrap();
+    match peer.next() {
+        Some(Event::Connected(uid)) => assert_eq!(uid, to_server),
+        other => panic!("unexpected {:?}", other),
+    }
+    peer.set_peer_name(to_server, "server").unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    server.set_peer_name(uid, "replica-1").unwrap();
+    assert_eq!(server.peer_uid("replica-1"), Some(uid));
+    assert_eq!(server.peer_name(uid), Some("replica-1"));
+    server.send_to_name("replica-1", b"hello").unwrap();
+    match peer.next() {
+        Some(Event::Recv(from, ref data)) => {
+            assert_eq!(from, to_server);
+            assert_eq!(&data[..], b"hello");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.send_to_name("replica-2", b"hello") {
+        Err(ServerError::NotConnected) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // the peer comes back on a new connection, named again
+    server.close_connection(uid).unwrap();
+    match server.next() {
+        Some(Event::Disconnected(from)) => assert_eq!(from, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(server.peer_uid("replica-1"), None);
+    match peer.next() {
+        Some(Event::Disconnected(from)) => assert_eq!(from, to_server),
+        other => panic!("unexpected {:?}", other),
+    }
+    // kept while reconnecting
+    assert_eq!(peer.peer_uid("server"), Some(to_server));
+    match peer.next() {
+        Some(Event::Connected(from)) => assert_eq!(from, to_server),
+        other => panic!("unexpected {:?}", other),
+    }
+    peer.send_to_name("server", b"back").unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    server.set_peer_name(uid, "replica-1").unwrap();
+    match server.next() {
+        Some(Event::Recv(from, ref data)) => {
+            assert_eq!(server.peer_name(from), Some("replica-1"));
+            assert_eq!(&data[..], b"back");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    peer.shutdown().unwrap();
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn test_rpc() {
+    let mut server = RpcServer::new(Server::new("127.0.0.1:0".parse().unwrap()));
+    server.server().start().unwrap();
+    let addr = server.server().local_addr().unwrap();
+    server.on_request(|_, req| {
+        if req.is_empty() {
+            Err("empty".to_string())
+        } else {
+            Ok(req.iter().rev().cloned().collect())
+        }
+    });
+    let handle = thread::spawn(move || {
+        let mut msgs = vec![];
+        for evt in server.by_ref() {
+            match evt {
+                Event::Recv(_, msg) => msgs.push(msg),
+                Event::Disconnected(_) => break,
+                _ => {}
+            }
+        }
+        server.server().shutdown().unwrap();
+        msgs
+    });
+
+    let mut client = RpcServer::new(Server::new("127.0.0.1:0".parse().unwrap()));
+    let uid = client.server().connect(addr).unwrap();
+    match client.next() {
+        Some(Event::Connected(connected)) => assert_eq!(connected, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(&client.call(uid, b"abc", Duration::from_secs(5)).unwrap()[..], b"cba");
+    match client.call(uid, b"", Duration::from_secs(5)) {
+        Err(RpcError::Remote(ref msg)) if msg == "empty" => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    client.send_to(uid, b"note").unwrap();
+    client.server().close_connection(uid).unwrap();
+    assert_eq!(handle.join().unwrap(), vec![b"note".to_vec()]);
+}
+
+#[test]
+fn test_rpc_timeout() {
+    let mut server = RpcServer::new(Server::new("127.0.0.1:0".parse().unwrap()));
+    server.server().start().unwrap();
+    let mut peer = FramedTcpStream::connect(server.server().local_addr().unwrap()).unwrap();
+    let uid = match server.next() {
+        Some(Event::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    match server.call(uid, b"hello?", Duration::from_millis(100)) {
+        Err(RpcError::Timeout) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    // the request, then the late response is dropped
+    let request = peer.read_frame().unwrap();
+    assert_eq!(request[0], 0);
+    assert_eq!(&request[9..], b"hello?");
+    let mut response = request[..9].to_vec();
+    response[0] = 1;
+    peer.write_frame(&response).unwrap();
+    let mut message = vec![3, 0, 0, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(b"after");
+    peer.write_frame(&message).unwrap();
+    match server.next() {
+        Some(Event::Recv(from, ref msg)) => {
+            assert_eq!(from, uid);
+            assert_eq!(&msg[..], b"after");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    server.server().shutdown().unwrap();
+}
+
+#[test]
+fn test_pubsub() {
+    let mut server = PubSubServer::new(Server::new("127.0.0.1:0".parse().unwrap()));
+    server.server().start().unwrap();
+    let addr = server.server().local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let mut disconnected = 0;
+        while disconnected < 2 {
+            match server.next() {
+                Some(Event::Disconnected(_)) => disconnected += 1,
+                Some(Event::UnexpectedError(err)) => panic!("unexpected {:?}", err),
+                _ => {}
+            }
+        }
+        server
+    });
+    let mut sub = PubSubClient::new(FramedTcpStream::connect(addr).unwrap());
+    let mut publisher = PubSubClient::new(FramedTcpStream::connect(addr).unwrap());
+    sub.subscribe("news").unwrap();
+    sub.subscribe("ping").unwrap();
+    // our own publications show that the server handled the subscriptions
+    sub.publish("ping", b"1").unwrap();
+    assert_eq!(sub.recv().unwrap(), ("ping".to_string(), b"1".to_vec()));
+
+    publisher.publish("news", b"hello").unwrap();
+    assert_eq!(sub.recv().unwrap(), ("news".to_string(), b"hello".to_vec()));
+
+    sub.unsubscribe("news").unwrap();
+    sub.publish("ping", b"2").unwrap();
+    assert_eq!(sub.recv().unwrap(), ("ping".to_string(), b"2".to_vec()));
+    publisher.publish("news", b"unheard").unwrap();
+    publisher.publish("ping", b"3").unwrap();
+    assert_eq!(sub.recv().unwrap(), ("ping".to_string(), b"3".to_vec()));
+
+    drop(sub);
+    drop(publisher);
+    let mut server = handle.join().unwrap();
+    assert!(server.topics().is_empty());
+    assert_eq!(server.publish("ping", b"nobody").unwrap(), 0);
+    server.server().shutdown().unwrap();
+}
+
+#[test]
+fn test_heartbeat() {
+    let builder = FramedTcpStream::builder().heartbeat(Duration::from_millis(20), 3);
+    let (client, server) = tcp_pair();
+    let mut client = builder.build(client).unwrap();
+    let mut server = builder.build(server).unwrap();
+
+    // both ends answer pings while waiting, so a late frame is fine
+    let writer = server.get_ref().try_clone().unwrap();
+    let late = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        (&writer).write_all(&[0, 0, 0, 4, 0, b'l', b'a', b't', b'e']).unwrap();
+    });
+    let reader = thread::spawn(move || server.read_frame());
+    assert_eq!(client.read_frame().unwrap(), b"late".to_vec());
+    assert!(client.check_alive().is_ok());
+    late.join().unwrap();
+    drop(client);
+    match reader.join().unwrap() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // nobody reading on the other end
+    let (client, server) = tcp_pair();
+    let mut client = builder.build(client).unwrap();
+    let start = Instant::now();
+    match client.read_frame() {
+        Err(NetError::PeerDead) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(60));
+    assert!(client.last_seen() <= start);
+    drop(server);
+}
+
+#[test]
+fn test_idle_timeout() {
+    let builder = FramedTcpStream::builder().idle_timeout(Duration::from_millis(80));
+    let (client, server) = tcp_pair();
+    let mut client = builder.build(client).unwrap();
+    let mut server = FramedTcpStream::new(server);
+    server.write_frame(b"hi").unwrap();
+    assert_eq!(client.read_frame().unwrap(), b"hi".to_vec());
+    let start = Instant::now();
+    match client.read_frame() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(80));
+
+    // heartbeats keep the peer alive but don't count as activity
+    let builder = builder.heartbeat(Duration::from_millis(20), 3);
+    let (client, server) = tcp_pair();
+    let mut client = builder.build(client).unwrap();
+    let mut server = builder.build(server).unwrap();
+    let pinger = thread::spawn(move || server.read_frame());
+    match client.read_frame() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    drop(client);
+    assert!(pinger.join().unwrap().is_err());
+}
+
+#[test]
+fn test_frame_hooks() {
+    let events = Arc::new(Mutex::new(Vec::<FrameEvent>::new()));
+    let sent = events.clone();
+    let received = events.clone();
+    let hooks = FrameHooks::new()
+        .on_frame_sent(move |ev| sent.lock().unwrap().push(*ev))
+        .on_frame_received(move |ev| received.lock().unwrap().push(*ev));
+    let (client, server) = tcp_pair();
+    let mut client = FramedTcpStream::builder().frame_hooks(hooks).build(client).unwrap();
+    let mut server = FramedTcpStream::new(server);
+
+    client.write_frame(b"hello").unwrap();
+    let frame = client.preframe(b"abc").unwrap();
+    client.write_preframed(&frame).unwrap();
+    assert_eq!(server.read_frame().unwrap(), b"hello".to_vec());
+    assert_eq!(server.read_frame().unwrap(), b"abc".to_vec());
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        server.write_frame(b"reply!").unwrap();
+    });
+    assert_eq!(client.read_frame_ref().unwrap(), b"reply!");
+    writer.join().unwrap();
+
+    let events = events.lock().unwrap();
+    let summary: Vec<_> = events.iter().map(|ev| (ev.direction, ev.size)).collect();
+    assert_eq!(summary, vec![(Direction::Sent, 5), (Direction::Sent, 3), (Direction::Received, 6)]);
+    assert!(events[2].elapsed >= Duration::from_millis(50));
+    assert!(events[0].at <= events[2].at);
+
+    // streams without hooks don't pay for them
+    assert!(FrameHooks::new().start().is_none());
+}
+
+#[test]
+fn test_preframed() {
+    let (client, server) = tcp_pair();
+    let builder = FramedTcpStream::builder().checksum(true);
+    let mut client = builder.build(client).unwrap();
+    let mut server = builder.build(server).unwrap();
+    let frame = client.preframe(b"again").unwrap();
+    assert_eq!(frame.as_bytes(), &[0, 0, 0, 5, b'a', b'g', b'a', b'i', b'n', 0x93, 0xa1, 0x5b, 0xfc][..]);
+    client.write_frame_buffered(b"first").unwrap();
+    for _ in 0..3 {
+        client.write_preframed(&frame).unwrap();
+    }
+    assert_eq!(server.read_frame().unwrap(), b"first".to_vec());
+    for _ in 0..3 {
+        assert_eq!(server.read_frame().unwrap(), b"again".to_vec());
+    }
+}
+
+#[test]
+fn test_frame_parts() {
+    let (client, server) = tcp_pair();
+    let builder = FramedTcpStream::builder().checksum(true);
+    let mut client = builder.build(client).unwrap();
+    let mut server = builder.build(server).unwrap();
+    let payload = b"again".to_vec();
+    let parts = client.frame_parts(&payload).unwrap();
+    assert_eq!(parts.to_frame_buf(), client.preframe(&payload).unwrap());
+    client.write_frame_buffered(b"first").unwrap();
+    for _ in 0..3 {
+        client.write_parts(&parts).unwrap();
+    }
+    assert_eq!(server.read_frame().unwrap(), b"first".to_vec());
+    for _ in 0..3 {
+        assert_eq!(server.read_frame().unwrap(), payload);
+    }
+}
+
+#[test]
+fn test_tagged_frames() {
+    let (client, server) = tcp_pair();
+    let builder = FramedTcpStream::builder().tagged(true).checksum(true);
+    let mut client = builder.build(client).unwrap();
+    let mut server = builder.build(server).unwrap();
+    client.write_tagged_frame(7, b"control").unwrap();
+    client.write_frame(b"data").unwrap();
+    client.write_tagged_frame(1, b"ignored tag").unwrap();
+    assert_eq!(server.read_tagged_frame().unwrap(), (7, b"control".to_vec()));
+    assert_eq!(server.read_tagged_frame().unwrap(), (0, b"data".to_vec()));
+    assert_eq!(server.read_frame().unwrap(), b"ignored tag".to_vec());
+
+    let (mut client, _server) = framed_pair(FrameHeader::default());
+    assert!(client.write_tagged_frame(1, b"untagged stream").is_err());
+}
+
+#[test]
+fn test_handshake() {
+    fn handshake(client_version: u32, client_token: &'static [u8])
+                 -> (Result<(), NetError>, Result<(), NetError>) {
+        let (mut client, mut server) = framed_pair(FrameHeader::default());
+        let server = thread::spawn(move || server.handshake(Role::Server, 2, b"secret"));
+        let client = client.handshake(Role::Client, client_version, client_token);
+        (client, server.join().unwrap())
+    }
+
+    match handshake(2, b"secret") {
+        (Ok(()), Ok(())) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match handshake(1, b"secret") {
+        (Err(NetError::Handshake(HandshakeError::VersionMismatch { local: 1, remote: 2 })),
+         Err(NetError::Handshake(HandshakeError::VersionMismatch { local: 2, remote: 1 }))) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match handshake(2, b"guess") {
+        (Err(NetError::Handshake(HandshakeError::BadToken)),
+         Err(NetError::Handshake(HandshakeError::BadToken))) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let (mut client, mut server) = framed_pair(FrameHeader::default());
+    client.write_frame(b"GET / HTTP/1.1").unwrap();
+    match server.handshake(Role::Server, 2, b"secret") {
+        Err(NetError::Handshake(HandshakeError::BadMagic)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_read_frame_ref() {
+    let (mut client, mut server) = framed_pair(FrameHeader::default());
+    client.write_frame(&[1; 100]).unwrap();
+    client.write_frame(b"hi").unwrap();
+    client.write_frame(b"again").unwrap();
+    client.write_frame(b"pooled").unwrap();
+    assert_eq!(server.read_frame_ref().unwrap(), &[1; 100][..]);
+    assert_eq!(server.read_frame_ref().unwrap(), b"hi");
+
+    let frame = server.read_frame().unwrap();
+    assert_eq!(frame, b"again".to_vec());
+    let ptr = frame.as_ptr();
+    server.recycle(frame);
+    let frame = server.read_frame().unwrap();
+    assert_eq!(frame, b"pooled".to_vec());
+    assert_eq!(frame.as_ptr(), ptr);
+}
+
+#[test]
+fn test_closed() {
+    let (client, server) = tcp_pair();
+    let mut raw = FramedTcpStream::new(client);
+    let mut server = FramedTcpStream::new(server);
+    raw.write_frame(b"bye").unwrap();
+    // header of a 10 bytes frame, but only 2 of them
+    raw.get_mut().write_all(&[0, 0, 0, 10, 1, 2]).unwrap();
+    raw.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(server.read_frame().unwrap(), b"bye".to_vec());
+    match server.read_frame() {
+        Err(NetError::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let (client, server) = tcp_pair();
+    let mut client = FramedTcpStream::new(client);
+    let mut server = FramedTcpStream::new(server);
+    client.write_frame(b"bye").unwrap();
+    drop(client);
+    assert_eq!(server.read_frame().unwrap(), b"bye".to_vec());
+    match server.read_frame() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_frames() {
+    let (mut client, mut server) = framed_pair(FrameHeader::default());
+    client.write_frame(b"a").unwrap();
+    client.write_frame(b"b").unwrap();
+    drop(client);
+    let frames: Vec<_> = server.frames().map(|f| f.unwrap()).collect();
+    assert_eq!(frames, vec![b"a".to_vec(), b"b".to_vec()]);
+
+    let (client, server) = tcp_pair();
+    let mut raw = FramedTcpStream::new(client);
+    let mut server = FramedTcpStream::new(server);
+    raw.write_frame(b"a").unwrap();
+    raw.get_mut().write_all(&[0, 0, 0, 10, 1]).unwrap();
+    drop(raw);
+    let mut frames = server.frames();
+    assert_eq!(frames.next().unwrap().unwrap(), b"a".to_vec());
+    assert!(frames.next().unwrap().is_err());
+    assert!(frames.next().is_none());
+}
+
+#[test]
+fn test_errors() {
+    let mut r = Cursor::new(vec![0xff; 11]);
+    match FrameHeader::varint().decode(&mut r) {
+        Err(NetError::Protocol(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    let err = NetError::Corrupt.with_peer(addr);
+    assert_eq!(err.to_string(), "frame checksum mismatch (peer 127.0.0.1:4000)");
+    match *err.root() {
+        NetError::Corrupt => {}
+        ref other => panic!("unexpected {:?}", other),
+    }
+    assert!(err.cause().is_some());
+    let err: Box<Error> = Box::new(NetError::FrameTooBig(10));
+    assert_eq!(err.to_string(), "frame of 10 bytes is too big");
+}
+
+#[test]
+fn test_framed_generic() {
+    let mut framed = FramedTcpStream::builder().checksum(true).wrap(Cursor::new(vec![]));
+    framed.write_frame(b"in").unwrap();
+    framed.write_frame(b"memory").unwrap();
+    framed.get_mut().set_position(0);
+    assert_eq!(framed.read_frame().unwrap(), b"in".to_vec());
+    assert_eq!(framed.read_frame().unwrap(), b"memory".to_vec());
+    match framed.read_frame() {
+        Err(NetError::Closed) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_framed_unix() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let mut a = Framed::wrap_with_header(a, FrameHeader::varint());
+    let mut b = Framed::wrap_with_header(b, FrameHeader::varint());
+    a.write_frame(b"ping").unwrap();
+    assert_eq!(b.read_frame().unwrap(), b"ping".to_vec());
+}
+
+#[test]
+fn test_udp() {
+    let mut a = FramedUdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut b = FramedUdpSocket::bind("127.0.0.1:0").unwrap();
+    let a_addr = a.local_addr().unwrap();
+    let b_addr = b.local_addr().unwrap();
+    a.send_to(b"hello", b_addr).unwrap();
+    a.send_to(b"", b_addr).unwrap();
+    assert_eq!(b.recv_from().unwrap(), (b"hello".to_vec(), a_addr));
+    assert_eq!(b.recv_from().unwrap(), (vec![], a_addr));
+
+    let big: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    match a.send_to(&big, b_addr) {
+        Err(NetError::FrameTooBig(5000)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    a.set_fragmentation(true);
+    b.set_fragmentation(true);
+    a.set_max_datagram_size(512);
+    a.send_to(&big, b_addr).unwrap();
+    a.send_to(b"after", b_addr).unwrap();
+    assert_eq!(b.recv_from().unwrap(), (big, a_addr));
+    assert_eq!(b.recv_from().unwrap().0, b"after".to_vec());
+
+    b.set_max_message_size(1000);
+    a.send_to(&[0; 2000], b_addr).unwrap();
+    match b.recv_from() {
+        Err(ref err) if is_frame_too_big(err) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // a fragment claiming more fragments than the message could fit
+    // in is rejected right away
+    let mut c = FramedUdpSocket::bind("127.0.0.1:0").unwrap();
+    c.set_fragmentation(true);
+    c.set_max_message_size(1000);
+    let c_addr = c.local_addr().unwrap();
+    let raw = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let fragment = [0, 0, 0, 9, 0, 0, 0xff, 0xff, 0, 0, 0, 1, 7];
+    raw.send_to(&fragment, c_addr).unwrap();
+    match c.recv_from() {
+        Err(ref err) if is_frame_too_big(err) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    let empty = [0, 0, 0, 9, 0, 0, 0, 2, 0, 0, 0, 0];
+    raw.send_to(&empty, c_addr).unwrap();
+    match c.recv_from() {
+        Err(ref err) => match *err.root() {
+            NetError::Protocol(_) => {}
+            ref other => panic!("unexpected {:?}", other),
+        },
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+fn is_frame_too_big(err: &NetError) -> bool {
+    match *err.root() {
+        NetError::FrameTooBig(_) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_max_frame_size() {
+    let (mut client, mut server) = framed_pair(FrameHeader::default());
+    server.set_max_frame_size(50);
+    client.write_frame(&[1; 50]).unwrap();
+    client.write_frame(&[1; 100]).unwrap();
+    assert_eq!(server.read_frame().unwrap().len(), 50);
+    match server.read_frame() {
+        Err(NetError::FrameTooBig(100)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_read_timeout() {
+    let (client, server) = tcp_pair();
+    let mut server = FramedTcpStream::builder()
+        .read_timeout(Some(Duration::from_millis(50)))
+        .build(server)
+        .unwrap();
+    match server.read_frame() {
+        Err(NetError::Timeout) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    drop(client);
+}
+
+#[test]
+fn test_nonblocking_would_block() {
+    let (client, server) = tcp_pair();
+    server.set_nonblocking(true).unwrap();
+    let mut server = FramedTcpStream::new(server);
+    match server.read_frame() {
+        Err(NetError::Io(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    drop(client);
+}
+
+#[test]
+fn test_checksum() {
+    let (client, server) = tcp_pair();
+    let mut raw = FramedTcpStream::new(client);
+    let mut server = FramedTcpStream::builder().checksum(true).build(server).unwrap();
+    // "hello" with its CRC32, then with a flipped bit
+    let frame = [0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o', 0x36, 0x10, 0xa6, 0x86];
+    raw.get_mut().write_all(&frame).unwrap();
+    assert_eq!(server.read_frame().unwrap(), b"hello".to_vec());
+    let mut corrupt = frame;
+    corrupt[5] ^= 1;
+    raw.get_mut().write_all(&corrupt).unwrap();
+    match server.read_frame() {
+        Err(NetError::Corrupt) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    raw.set_checksum(true);
+    raw.write_frame(b"world").unwrap();
+    assert_eq!(server.read_frame().unwrap(), b"world".to_vec());
+}
+
+#[cfg(feature = "noise")]
+#[test]
+fn test_noise() {
+    use net::{NoiseStream, NoisePattern, NoiseKeypair};
+
+    let server_key = NoiseKeypair::generate().unwrap();
+    let client_key = NoiseKeypair::generate().unwrap();
+    for &pattern in [NoisePattern::XX, NoisePattern::IK].iter() {
+        let (client, server) = framed_pair(FrameHeader::default());
+        let server_private = server_key.private.clone();
+        let server = thread::spawn(move || {
+            let mut server = NoiseStream::responder(server, pattern, &server_private).unwrap();
+            let frame = server.read_frame().unwrap();
+            server.write_frame(&frame).unwrap();
+            server.remote_static().map(|key| key.to_vec())
+        });
+        let mut client = NoiseStream::initiator(client, pattern, &client_key.private,
+                                                Some(&server_key.public)).unwrap();
+        // larger than a noise message
+        let big: Vec<u8> = (0..100000u32).map(|i| i as u8).collect();
+        client.write_frame(&big).unwrap();
+        assert_eq!(client.read_frame().unwrap(), big);
+        assert_eq!(server.join().unwrap(), Some(client_key.public.clone()));
+        assert_eq!(client.remote_static(), Some(&server_key.public[..]));
+    }
+
+    // tampered messages are rejected
+    let (client, server) = framed_pair(FrameHeader::default());
+    let server = thread::spawn(move || {
+        let mut server = NoiseStream::responder(server, NoisePattern::XX, &[9; 32]).unwrap();
+        server.get_mut().write_frame(&[0; 40]).unwrap();
+    });
+    let mut client = NoiseStream::initiator(client, NoisePattern::XX, &[7; 32], None).unwrap();
+    server.join().unwrap();
+    match client.read_frame() {
+        Err(NetError::Corrupt) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_compression() {
+    let (client, server) = tcp_pair();
+    let builder = FramedTcpStream::builder().compression(Some(100)).checksum(true);
+    let mut client = builder.build(client).unwrap();
+    let mut server = builder.max_frame_size(20000).build(server).unwrap();
+    let big = vec![b'a'; 10000];
+    client.write_frame(b"small").unwrap();
+    client.write_frame(&big).unwrap();
+    client.write_frame(&big).unwrap();
+    assert_eq!(server.read_frame().unwrap(), b"small".to_vec());
+    assert_eq!(server.read_frame().unwrap(), big);
+    let mut buf = vec![0; 10000];
+    assert_eq!(server.read_frame_into(&mut buf).unwrap(), 10000);
+    assert_eq!(buf, big);
+
+    // the decompressed size is limited too
+    server.set_max_frame_size(5000);
+    client.write_frame(&big).unwrap();
+    match server.read_frame() {
+        Err(NetError::FrameTooBig(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[cfg(feature = "codec")]
+#[test]
+fn test_codec() {
+    use net::{Codec, Bincode};
+
+    let (mut client, mut server) = framed_pair(FrameHeader::default());
+    client.send_msg(&"hello".to_string()).unwrap();
+    assert_eq!(server.recv_msg::<String>().unwrap(), "hello");
+
+    // invalid messages don't break the stream
+    client.write_frame(&[0xff, 0xfe]).unwrap();
+    client.send_msg(&"again".to_string()).unwrap();
+    match server.recv_msg::<String>() {
+        Err(NetError::Codec(_)) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(server.recv_msg::<String>().unwrap(), "again");
+
+    // codecs also decode frames received elsewhere (e.g. server events)
+    client.send_msg_with(&Bincode, &"typed".to_string()).unwrap();
+    let frame = server.read_frame().unwrap();
+    assert_eq!(Bincode.decode::<String>(&frame).unwrap(), "typed");
+}
+
+#[cfg(feature = "codec")]
+#[test]
+fn test_typed_server() {
+    use net::Bincode;
+    use net::sync::{TypedServer, TypedEvent};
+
+    let mut server: TypedServer<String> = TypedServer::new(Server::new("127.0.0.1:0".parse().unwrap()), Bincode);
+    server.server().start().unwrap();
+    let addr = server.server().local_addr().unwrap();
+    let mut client = FramedTcpStream::connect(addr).unwrap();
+    let uid = match server.next() {
+        Some(TypedEvent::Connected(uid)) => uid,
+        other => panic!("unexpected {:?}", other),
+    };
+    client.send_msg(&"hello".to_string()).unwrap();
+    match server.next() {
+        Some(TypedEvent::Recv(from, ref msg)) => {
+            assert_eq!(from, uid);
+            assert_eq!(msg, "hello");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    client.write_frame(&[0xff, 0xfe]).unwrap();
+    match server.next() {
+        Some(TypedEvent::Invalid(from, NetError::Codec(_))) => assert_eq!(from, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    server.send_to(uid, &"hi".to_string()).unwrap();
+    assert_eq!(client.recv_msg::<String>().unwrap(), "hi");
+    assert!(server.send_to_all(&"all".to_string()).unwrap().is_empty());
+    assert_eq!(client.recv_msg::<String>().unwrap(), "all");
+    assert!(server.try_next_event().is_none());
+
+    server.server().shutdown().unwrap();
+    match server.next() {
+        Some(TypedEvent::Disconnected(gone)) => assert_eq!(gone, uid),
+        other => panic!("unexpected {:?}", other),
+    }
+    match server.next() {
+        Some(TypedEvent::Shutdown) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(server.next().is_none());
+}
@@ -0,0 +1,65 @@
+/// Framed streams over TLS (native-tls), with the same frame format
+/// and options as `FramedTcpStream`.
+
+use native_tls::{TlsConnector, TlsAcceptor, TlsStream, HandshakeError};
+
+use std::net::{TcpStream, SocketAddr};
+
+use net::{NetError, Framed, FramedTcpStreamBuilder};
+
+pub type FramedTlsStream = Framed<TlsStream<TcpStream>>;
+
+impl Framed<TlsStream<TcpStream>> {
+    /// Perform the client side handshake with the server at `domain`
+    /// (checked against its certificate) over the given stream.
+    /// Frames are preceded by a 4 bytes 'len' header, use
+    /// `FramedTcpStreamBuilder::connect_tls()` for other options.
+    pub fn connect(connector: &TlsConnector, domain: &str, stream: TcpStream)
+                   -> Result<FramedTlsStream, NetError> {
+        FramedTcpStreamBuilder::new().connect_tls(connector, domain, stream)
+    }
+
+    /// Perform the server side handshake over the given stream
+    pub fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<FramedTlsStream, NetError> {
+        FramedTcpStreamBuilder::new().accept_tls(acceptor, stream)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(try!(self.get_ref().get_ref().peer_addr()))
+    }
+
+    /// Send a TLS close_notify. The TCP connection stays open.
+    pub fn shutdown(&mut self) -> Result<(), NetError> {
+        try!(self.get_mut().shutdown());
+        Ok(())
+    }
+}
+
+impl FramedTcpStreamBuilder {
+    /// Perform the client side TLS handshake on the given stream and
+    /// wrap it with the configured options. The timeouts also apply
+    /// to the handshake.
+    pub fn connect_tls(&self, connector: &TlsConnector, domain: &str, stream: TcpStream)
+                       -> Result<FramedTlsStream, NetError> {
+        try!(self.configure(&stream));
+        let stream = try!(connector.connect(domain, stream).map_err(handshake_error));
+        Ok(self.wrap_configured(stream))
+    }
+
+    /// Perform the server side TLS handshake on the given stream and
+    /// wrap it with the configured options
+    pub fn accept_tls(&self, acceptor: &TlsAcceptor, stream: TcpStream)
+                      -> Result<FramedTlsStream, NetError> {
+        try!(self.configure(&stream));
+        let stream = try!(acceptor.accept(stream).map_err(handshake_error));
+        Ok(self.wrap_configured(stream))
+    }
+}
+
+fn handshake_error(err: HandshakeError<TcpStream>) -> NetError {
+    match err {
+        HandshakeError::Failure(err) => NetError::Tls(err),
+        // only happens on non-blocking streams or when a timeout expires
+        HandshakeError::Interrupted(_) => NetError::Timeout,
+    }
+}
@@ -0,0 +1,48 @@
+/// Set of event loop tokens, as a bit vector plus the list of tokens
+/// in it: O(1) insert, and draining only visits the tokens in the set.
+/// Used by the async server for the connections to close and
+/// reregister.
+
+use std::vec;
+
+use mio::Token;
+
+pub struct TokenSet {
+    bits: Vec<u64>,
+    tokens: Vec<Token>,
+}
+
+impl TokenSet {
+    /// Empty set, with room for the tokens below `capacity` before
+    /// the bit vector needs to grow
+    pub fn with_capacity(capacity: usize) -> TokenSet {
+        TokenSet {
+            bits: vec![0; (capacity + 63) / 64],
+            tokens: Vec::new(),
+        }
+    }
+
+    /// Returns false if the token was already in the set
+    pub fn insert(&mut self, token: Token) -> bool {
+        let (word, bit) = (token.as_usize() / 64, 1 << (token.as_usize() % 64));
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        if self.bits[word] & bit != 0 {
+            return false;
+        }
+        self.bits[word] |= bit;
+        self.tokens.push(token);
+        true
+    }
+
+    /// Take the tokens out in insertion order, leaving the set empty.
+    /// Every bit set belongs to one of them, so their words are
+    /// cleared whole.
+    pub fn drain(&mut self) -> vec::Drain<Token> {
+        for token in &self.tokens {
+            self.bits[token.as_usize() / 64] = 0;
+        }
+        self.tokens.drain(..)
+    }
+}
@@ -0,0 +1,97 @@
+/// Callbacks run for every frame sent or received by a framed stream
+/// or server, to layer tracing and metrics on top without touching
+/// each call site.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// What a hook gets for each frame
+#[derive(Debug, Clone, Copy)]
+pub struct FrameEvent {
+    pub direction: Direction,
+    /// Size of the payload, without the header
+    pub size: usize,
+    /// When the frame was done being sent or received
+    pub at: Instant,
+    /// Time spent in the call, including waiting for the frame to
+    /// arrive (or for the stream to become writable)
+    pub elapsed: Duration,
+}
+
+pub type FrameHook = Arc<Fn(&FrameEvent) + Send + Sync>;
+
+/// The hooks of a stream or server. Cloning shares them.
+#[derive(Clone, Default)]
+pub struct FrameHooks {
+    pub on_sent: Option<FrameHook>,
+    pub on_received: Option<FrameHook>,
+}
+
+impl FrameHooks {
+    pub fn new() -> FrameHooks {
+        FrameHooks::default()
+    }
+
+    pub fn on_frame_sent<F>(mut self, hook: F) -> Self
+        where F: Fn(&FrameEvent) + Send + Sync + 'static
+    {
+        self.on_sent = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn on_frame_received<F>(mut self, hook: F) -> Self
+        where F: Fn(&FrameEvent) + Send + Sync + 'static
+    {
+        self.on_received = Some(Arc::new(hook));
+        self
+    }
+
+    /// Start timing a call, only if there is a hook to report it to
+    pub fn start(&self) -> Option<Instant> {
+        if self.on_sent.is_some() || self.on_received.is_some() {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    pub fn sent(&self, start: Option<Instant>, size: usize) {
+        if let (Some(ref hook), Some(start)) = (self.on_sent.as_ref(), start) {
+            hook(&FrameEvent::new(Direction::Sent, size, start));
+        }
+    }
+
+    pub fn received(&self, start: Option<Instant>, size: usize) {
+        if let (Some(ref hook), Some(start)) = (self.on_received.as_ref(), start) {
+            hook(&FrameEvent::new(Direction::Received, size, start));
+        }
+    }
+}
+
+impl fmt::Debug for FrameHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FrameHooks")
+            .field("on_sent", &self.on_sent.is_some())
+            .field("on_received", &self.on_received.is_some())
+            .finish()
+    }
+}
+
+impl FrameEvent {
+    fn new(direction: Direction, size: usize, start: Instant) -> FrameEvent {
+        let at = Instant::now();
+        FrameEvent {
+            direction: direction,
+            size: size,
+            at: at,
+            elapsed: at.duration_since(start),
+        }
+    }
+}
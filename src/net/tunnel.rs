@@ -0,0 +1,132 @@
+/// Client side of the SOCKS5 (RFC 1928, without authentication) and
+/// HTTP CONNECT handshakes, to reach a server through a proxy. Once
+/// done, the stream talks to the target directly.
+
+use std::io::{Read, Write};
+use std::net::IpAddr;
+
+use net::NetError;
+use net::endian;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+
+/// Largest HTTP CONNECT response accepted
+const MAX_HTTP_RESPONSE: usize = 8 * 1024;
+
+/// Proxy protocol to reach the target with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+/// Ask the proxy at the other end of `stream` to connect to `target`
+/// ("host:port", the host is resolved by the proxy)
+pub fn handshake<S: Read + Write>(stream: &mut S, kind: ProxyKind, target: &str) -> Result<(), NetError> {
+    let (host, port) = try!(split_target(target));
+    match kind {
+        ProxyKind::Socks5 => socks5(stream, host, port),
+        ProxyKind::HttpConnect => http_connect(stream, target),
+    }
+}
+
+fn split_target(target: &str) -> Result<(&str, u16), NetError> {
+    let colon = match target.rfind(':') {
+        Some(colon) => colon,
+        None => return Err(NetError::Protocol("proxy target without port")),
+    };
+    let port = match target[colon + 1..].parse() {
+        Ok(port) => port,
+        Err(_) => return Err(NetError::Protocol("invalid proxy target port")),
+    };
+    let host = target[..colon].trim_left_matches('[').trim_right_matches(']');
+    Ok((host, port))
+}
+
+fn socks5<S: Read + Write>(stream: &mut S, host: &str, port: u16) -> Result<(), NetError> {
+    try!(stream.write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTH]));
+    let mut reply = [0; 2];
+    try!(stream.read_exact(&mut reply));
+    if reply[0] != SOCKS_VERSION {
+        return Err(NetError::Protocol("invalid SOCKS5 reply"));
+    }
+    if reply[1] != SOCKS_NO_AUTH {
+        return Err(NetError::Protocol("SOCKS5 proxy requires authentication"));
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(NetError::Protocol("SOCKS5 target host name too long"));
+            }
+            request.push(SOCKS_ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&endian::u16_bytes(port));
+    try!(stream.write_all(&request));
+
+    let mut reply = [0; 4];
+    try!(stream.read_exact(&mut reply));
+    if reply[0] != SOCKS_VERSION {
+        return Err(NetError::Protocol("invalid SOCKS5 reply"));
+    }
+    match reply[1] {
+        0 => {}
+        2 => return Err(NetError::Protocol("SOCKS5 connection not allowed by ruleset")),
+        3 => return Err(NetError::Protocol("SOCKS5 network unreachable")),
+        4 => return Err(NetError::Protocol("SOCKS5 host unreachable")),
+        5 => return Err(NetError::Protocol("SOCKS5 connection refused")),
+        _ => return Err(NetError::Protocol("SOCKS5 connect failed")),
+    }
+    // skip the address the proxy bound
+    let len = match reply[3] {
+        SOCKS_ATYP_IPV4 => 4,
+        SOCKS_ATYP_IPV6 => 16,
+        SOCKS_ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            try!(stream.read_exact(&mut len));
+            len[0] as usize
+        }
+        _ => return Err(NetError::Protocol("invalid SOCKS5 address type")),
+    };
+    let mut bound = vec![0; len + 2];
+    try!(stream.read_exact(&mut bound));
+    Ok(())
+}
+
+/// Reads the response a byte at a time, to not read past it
+fn http_connect<S: Read + Write>(stream: &mut S, target: &str) -> Result<(), NetError> {
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    try!(stream.write_all(request.as_bytes()));
+
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE {
+            return Err(NetError::Protocol("HTTP CONNECT response too long"));
+        }
+        try!(stream.read_exact(&mut byte));
+        response.push(byte[0]);
+    }
+    let status = response.split(|&b| b == b' ').nth(1);
+    if status.map_or(true, |status| !status.starts_with(b"2")) {
+        return Err(NetError::Protocol("HTTP CONNECT refused by proxy"));
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+/// `sync::Server` exchanging messages of a single type instead of raw
+/// frames, (de)serialized with a `Codec`.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use net::{Codec, Bincode, NetError};
+use net::sync::{Server, Event, ServerError, Uid, Addr};
+
+/// `Event` with the received messages decoded
+#[derive(Debug)]
+pub enum TypedEvent<T> {
+    Recv(Uid, T),
+    /// A frame that couldn't be decoded. The connection is left open.
+    Invalid(Uid, NetError),
+    Connected(Uid),
+    Disconnected(Uid),
+    UnexpectedError(ServerError),
+    Rejected(Addr),
+    SendBackpressure(Uid),
+    ProtocolError(Uid, usize),
+    /// Last event after `shutdown()`
+    Shutdown,
+}
+
+pub struct TypedServer<T, C: Codec = Bincode> {
+    server: Server,
+    codec: C,
+    msg: PhantomData<fn(T) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> TypedServer<T, C> {
+    /// Wrap the server, started or not
+    pub fn new(server: Server, codec: C) -> TypedServer<T, C> {
+        TypedServer {
+            server: server,
+            codec: codec,
+            msg: PhantomData,
+        }
+    }
+
+    /// The wrapped server, for everything besides messages (`start()`,
+    /// `connect()`, `shutdown()`...)
+    pub fn server(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> Server {
+        self.server
+    }
+
+    pub fn send_to(&mut self, dest: Uid, msg: &T) -> Result<(), ServerError> {
+        self.server.send_msg_to(dest, &self.codec, msg)
+    }
+
+    /// Send a message to every connection, serialized only once. See
+    /// `Server::send_to_many()`.
+    pub fn send_to_all(&mut self, msg: &T) -> Result<Vec<(Uid, ServerError)>, ServerError> {
+        let frame = try!(self.codec.encode(msg));
+        self.server.send_to_all(&frame)
+    }
+
+    /// Same as `send_to_all()`, to the given connections
+    pub fn send_to_many(&mut self, dests: &[Uid], msg: &T) -> Result<Vec<(Uid, ServerError)>, ServerError> {
+        let frame = try!(self.codec.encode(msg));
+        self.server.send_to_many(dests, &frame)
+    }
+
+    /// See `Server::try_next_event()`
+    pub fn try_next_event(&mut self) -> Option<TypedEvent<T>> {
+        self.server.try_next_event().map(|evt| self.decode(evt))
+    }
+
+    /// See `Server::next_event_timeout()`
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Option<TypedEvent<T>> {
+        self.server.next_event_timeout(timeout).map(|evt| self.decode(evt))
+    }
+
+    fn decode(&self, evt: Event) -> TypedEvent<T> {
+        match evt {
+            Event::Recv(uid, data) => match self.codec.decode(&data) {
+                Ok(msg) => TypedEvent::Recv(uid, msg),
+                Err(err) => TypedEvent::Invalid(uid, err),
+            },
+            Event::Connected(uid) => TypedEvent::Connected(uid),
+            Event::Disconnected(uid) => TypedEvent::Disconnected(uid),
+            Event::UnexpectedError(err) => TypedEvent::UnexpectedError(err),
+            Event::Rejected(addr) => TypedEvent::Rejected(addr),
+            Event::SendBackpressure(uid) => TypedEvent::SendBackpressure(uid),
+            Event::ProtocolError(uid, size) => TypedEvent::ProtocolError(uid, size),
+            Event::Shutdown => TypedEvent::Shutdown,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> Iterator for TypedServer<T, C> {
+    type Item = TypedEvent<T>;
+    /// Block waiting for the next event, see `Server::next()`
+    fn next(&mut self) -> Option<TypedEvent<T>> {
+        self.server.next().map(|evt| self.decode(evt))
+    }
+}
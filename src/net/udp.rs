@@ -0,0 +1,243 @@
+/// Messages over UDP. Each datagram starts with a header carrying the
+/// length of its payload, so truncated datagrams are detected. With
+/// fragmentation enabled, messages larger than a datagram are split
+/// and reassembled on the other end (lost fragments mean the whole
+/// message is lost).
+///
+/// Header (big-endian):
+///
+/// - message id (u32)
+/// - fragment index (u16)
+/// - fragment count (u16)
+/// - payload length (u32)
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use std::u16;
+
+use net::{NetError, DEFAULT_MAX_FRAME_SIZE};
+use net::endian;
+
+const HEADER_SIZE: usize = 12;
+
+/// Default size of sent datagrams: an ethernet MTU minus the IP and
+/// UDP headers
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1472;
+
+/// Largest datagram that can be received
+const MAX_UDP_SIZE: usize = 65535;
+
+/// Most messages being reassembled at a time. The oldest is dropped
+/// to make room for new ones.
+const MAX_PARTIAL_MESSAGES: usize = 64;
+
+pub struct FramedUdpSocket {
+    socket: UdpSocket,
+    /// Size of sent datagrams, including the header
+    max_datagram_size: usize,
+    /// Largest message accepted (and reassembled)
+    max_message_size: usize,
+    fragmentation: bool,
+    /// Whether timeouts are set, see `set_read_timeout`
+    read_timeout: bool,
+    write_timeout: bool,
+    /// Id of the next sent message
+    next_id: u32,
+    buf: Vec<u8>,
+    /// Messages being reassembled, and their arrival order
+    partial: HashMap<(SocketAddr, u32), Partial>,
+    partial_order: VecDeque<(SocketAddr, u32)>,
+}
+
+/// Fragments received so far of a message
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+}
+
+impl FramedUdpSocket {
+    pub fn new(socket: UdpSocket) -> FramedUdpSocket {
+        FramedUdpSocket {
+            socket: socket,
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+            fragmentation: false,
+            read_timeout: false,
+            write_timeout: false,
+            next_id: 0,
+            buf: vec![0; MAX_UDP_SIZE],
+            partial: HashMap::new(),
+            partial_order: VecDeque::new(),
+        }
+    }
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<FramedUdpSocket, NetError> {
+        Ok(FramedUdpSocket::new(try!(UdpSocket::bind(addr))))
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(try!(self.socket.local_addr()))
+    }
+
+    /// Size of the datagrams sent, including the 12 bytes header
+    pub fn set_max_datagram_size(&mut self, size: usize) {
+        assert!(size > HEADER_SIZE && size <= MAX_UDP_SIZE);
+        self.max_datagram_size = size;
+    }
+
+    /// Largest message accepted by `recv_from`, larger ones return
+    /// `NetError::FrameTooBig`
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Split messages that don't fit in a datagram (instead of
+    /// returning `NetError::FrameTooBig`) and accept fragmented
+    /// messages. Both ends should have the same setting.
+    pub fn set_fragmentation(&mut self, fragmentation: bool) {
+        self.fragmentation = fragmentation;
+    }
+
+    /// Receives blocking for longer than `timeout` return
+    /// `NetError::Timeout`
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), NetError> {
+        try!(self.socket.set_read_timeout(timeout));
+        self.read_timeout = timeout.is_some();
+        Ok(())
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> Result<(), NetError> {
+        try!(self.socket.set_write_timeout(timeout));
+        self.write_timeout = timeout.is_some();
+        Ok(())
+    }
+
+    /// Send a message to `addr`, in as many datagrams as needed
+    pub fn send_to(&mut self, msg: &[u8], addr: SocketAddr) -> Result<(), NetError> {
+        let chunk_size = self.max_datagram_size - HEADER_SIZE;
+        let count = if msg.is_empty() { 1 } else { (msg.len() + chunk_size - 1) / chunk_size };
+        if (count > 1 && !self.fragmentation) || count > u16::MAX as usize {
+            return Err(NetError::FrameTooBig(msg.len()));
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        for i in 0..count {
+            let chunk = &msg[i * chunk_size..msg.len().min((i + 1) * chunk_size)];
+            let len = HEADER_SIZE + chunk.len();
+            endian::write_u32(&mut self.buf[0..4], id);
+            endian::write_u16(&mut self.buf[4..6], i as u16);
+            endian::write_u16(&mut self.buf[6..8], count as u16);
+            endian::write_u32(&mut self.buf[8..12], chunk.len() as u32);
+            self.buf[HEADER_SIZE..len].copy_from_slice(chunk);
+            let res = self.socket.send_to(&self.buf[..len], addr);
+            try!(res.map_err(|err| NetError::from(err).timed_out(self.write_timeout)));
+        }
+        Ok(())
+    }
+
+    /// Receive the next message and the address it came from. Invalid
+    /// datagrams return an error, but the socket can still be used.
+    pub fn recv_from(&mut self) -> Result<(Vec<u8>, SocketAddr), NetError> {
+        loop {
+            let res = self.socket.recv_from(&mut self.buf);
+            let (n, addr) = try!(res.map_err(|err| NetError::from(err).timed_out(self.read_timeout)));
+            if n < HEADER_SIZE {
+                return Err(NetError::Protocol("datagram shorter than its header").with_peer(addr));
+            }
+            let id = endian::read_u32(&self.buf[0..4]);
+            let index = endian::read_u16(&self.buf[4..6]) as usize;
+            let count = endian::read_u16(&self.buf[6..8]) as usize;
+            let len = endian::read_u32(&self.buf[8..12]) as usize;
+            if len != n - HEADER_SIZE {
+                return Err(NetError::Protocol("truncated datagram").with_peer(addr));
+            }
+            if index >= count {
+                return Err(NetError::Protocol("invalid fragment index").with_peer(addr));
+            }
+            if count == 1 {
+                if len > self.max_message_size {
+                    return Err(NetError::FrameTooBig(len).with_peer(addr));
+                }
+                return Ok((self.buf[HEADER_SIZE..n].to_vec(), addr));
+            }
+            if !self.fragmentation {
+                return Err(NetError::Protocol("unexpected fragment").with_peer(addr));
+            }
+            let payload = self.buf[HEADER_SIZE..n].to_vec();
+            if let Some(msg) = try!(self.reassemble(addr, id, index, count, payload)) {
+                return Ok((msg, addr));
+            }
+        }
+    }
+
+    /// Store a fragment, returning the message once complete
+    fn reassemble(&mut self, addr: SocketAddr, id: u32, index: usize, count: usize,
+                  payload: Vec<u8>) -> Result<Option<Vec<u8>>, NetError> {
+        // fragments aren't empty, and those before the last are all
+        // the same size: reject counts the message can't fit in before
+        // making room for them
+        if payload.is_empty() {
+            return Err(NetError::Protocol("empty fragment").with_peer(addr));
+        }
+        let min_size = if index + 1 < count { (count - 1) * payload.len() + 1 } else { count };
+        if min_size > self.max_message_size {
+            return Err(NetError::FrameTooBig(min_size).with_peer(addr));
+        }
+        let key = (addr, id);
+        if !self.partial.contains_key(&key) {
+            if self.partial_order.len() >= MAX_PARTIAL_MESSAGES {
+                let oldest = self.partial_order.pop_front().unwrap();
+                self.partial.remove(&oldest);
+            }
+            self.partial.insert(key, Partial {
+                fragments: vec![None; count],
+                received: 0,
+                size: 0,
+            });
+            self.partial_order.push_back(key);
+        }
+
+        let complete = {
+            let partial = self.partial.get_mut(&key).unwrap();
+            if partial.fragments.len() != count {
+                return Err(NetError::Protocol("inconsistent fragment count").with_peer(addr));
+            }
+            if partial.fragments[index].is_none() {
+                partial.size += payload.len();
+                partial.received += 1;
+                partial.fragments[index] = Some(payload);
+            }
+            if partial.size > self.max_message_size {
+                None
+            } else {
+                Some(partial.received == count)
+            }
+        };
+        match complete {
+            Some(false) => Ok(None),
+            Some(true) => {
+                let partial = self.remove_partial(&key);
+                let mut msg = Vec::with_capacity(partial.size);
+                for fragment in partial.fragments {
+                    msg.extend_from_slice(&fragment.unwrap());
+                }
+                Ok(Some(msg))
+            }
+            None => {
+                let partial = self.remove_partial(&key);
+                Err(NetError::FrameTooBig(partial.size).with_peer(addr))
+            }
+        }
+    }
+
+    fn remove_partial(&mut self, key: &(SocketAddr, u32)) -> Partial {
+        self.partial_order.retain(|k| k != key);
+        self.partial.remove(key).unwrap()
+    }
+}
@@ -0,0 +1,350 @@
+/// WebSocket (RFC 6455) messages over any stream: the HTTP upgrade
+/// handshake, on either end, and the frame format. Pings are answered,
+/// close frames are echoed and fragmented messages are reassembled
+/// while reading. Extensions and subprotocols aren't supported.
+
+use base64;
+use rand;
+use sha1::Sha1;
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::u16;
+
+use net::{NetError, Role, DEFAULT_MAX_FRAME_SIZE};
+use net::endian;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+/// Normal closure status code
+const CLOSE_NORMAL: u16 = 1000;
+
+/// Largest HTTP handshake accepted
+const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// A complete (reassembled) message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+pub struct WebSocket<T: Read + Write> {
+    stream: BufReader<T>,
+    /// Clients mask the frames they send, servers don't
+    role: Role,
+    max_message_size: usize,
+    /// A close frame was sent, nothing else can be
+    closed: bool,
+}
+
+impl<T: Read + Write> WebSocket<T> {
+    /// Send the upgrade request for `path` on `host` and wait for the
+    /// server to accept it
+    pub fn connect(stream: T, host: &str, path: &str) -> Result<WebSocket<T>, NetError> {
+        let mut ws = WebSocket::new(stream, Role::Client);
+        let key = base64::encode(&rand::random::<[u8; 16]>());
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\n\
+                               Connection: Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+                               Sec-WebSocket-Version: 13\r\n\r\n",
+                              path, host, key);
+        try!(ws.get_mut().write_all(request.as_bytes()));
+
+        let (status, headers) = try!(read_http_head(&mut ws.stream));
+        if status.split(' ').nth(1) != Some("101") {
+            return Err(NetError::Protocol("websocket upgrade refused"));
+        }
+        if !has_token(&headers, "upgrade", "websocket") || !has_token(&headers, "connection", "upgrade") {
+            return Err(NetError::Protocol("invalid websocket upgrade response"));
+        }
+        if header(&headers, "sec-websocket-accept") != Some(&accept_key(&key)[..]) {
+            return Err(NetError::Protocol("invalid websocket accept key"));
+        }
+        Ok(ws)
+    }
+
+    /// Wait for an upgrade request and accept it (any path). Invalid
+    /// requests get a 400 response.
+    pub fn accept(stream: T) -> Result<WebSocket<T>, NetError> {
+        let mut ws = WebSocket::new(stream, Role::Server);
+        let (request, headers) = try!(read_http_head(&mut ws.stream));
+        let valid = request.starts_with("GET ") &&
+            has_token(&headers, "upgrade", "websocket") &&
+            has_token(&headers, "connection", "upgrade") &&
+            header(&headers, "sec-websocket-version") == Some("13");
+        let key = match header(&headers, "sec-websocket-key") {
+            Some(key) if valid => key.to_string(),
+            _ => {
+                try!(ws.get_mut().write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n"));
+                return Err(NetError::Protocol("invalid websocket upgrade request"));
+            }
+        };
+        let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                                Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                               accept_key(&key));
+        try!(ws.get_mut().write_all(response.as_bytes()));
+        Ok(ws)
+    }
+
+    fn new(stream: T, role: Role) -> WebSocket<T> {
+        WebSocket {
+            stream: BufReader::new(stream),
+            role: role,
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+            closed: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.stream.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.stream.get_mut()
+    }
+
+    /// Largest message accepted, larger ones return
+    /// `NetError::FrameTooBig`
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Read the next message. Returns `NetError::Closed` once the peer
+    /// closed the connection.
+    pub fn read_message(&mut self) -> Result<Message, NetError> {
+        let mut msg = Vec::new();
+        let mut msg_opcode = None;
+        loop {
+            let left = self.max_message_size - msg.len();
+            let (fin, opcode, payload) = try!(self.read_ws_frame(left));
+            match opcode {
+                OP_PING => {
+                    if !self.closed {
+                        try!(self.write_ws_frame(OP_PONG, &payload));
+                    }
+                    continue;
+                }
+                OP_PONG => continue,
+                OP_CLOSE => {
+                    if !self.closed {
+                        let code = if payload.len() >= 2 { &payload[..2] } else { &[] };
+                        try!(self.write_ws_frame(OP_CLOSE, code));
+                        self.closed = true;
+                    }
+                    return Err(NetError::Closed);
+                }
+                OP_CONTINUATION => {
+                    if msg_opcode.is_none() {
+                        return Err(NetError::Protocol("unexpected websocket continuation frame"));
+                    }
+                    msg.extend_from_slice(&payload);
+                }
+                OP_TEXT | OP_BINARY => {
+                    if msg_opcode.is_some() {
+                        return Err(NetError::Protocol("expected websocket continuation frame"));
+                    }
+                    msg_opcode = Some(opcode);
+                    msg = payload;
+                }
+                _ => return Err(NetError::Protocol("invalid websocket opcode")),
+            }
+            if fin {
+                return match msg_opcode {
+                    Some(OP_TEXT) => String::from_utf8(msg).map(Message::Text)
+                        .map_err(|_| NetError::Protocol("websocket text message isn't utf-8")),
+                    _ => Ok(Message::Binary(msg)),
+                };
+            }
+        }
+    }
+
+    /// Read the next message, text or binary, as bytes
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, NetError> {
+        Ok(match try!(self.read_message()) {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) => data,
+        })
+    }
+
+    /// Send a binary message
+    pub fn write_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        self.write_ws_frame(OP_BINARY, frame)
+    }
+
+    pub fn write_text(&mut self, text: &str) -> Result<(), NetError> {
+        self.write_ws_frame(OP_TEXT, text.as_bytes())
+    }
+
+    pub fn write_message(&mut self, msg: &Message) -> Result<(), NetError> {
+        match *msg {
+            Message::Text(ref text) => self.write_text(text),
+            Message::Binary(ref data) => self.write_frame(data),
+        }
+    }
+
+    /// Payloads of more than 125 bytes return `NetError::FrameTooBig`
+    pub fn ping(&mut self, payload: &[u8]) -> Result<(), NetError> {
+        if payload.len() > 125 {
+            return Err(NetError::FrameTooBig(payload.len()));
+        }
+        self.write_ws_frame(OP_PING, payload)
+    }
+
+    /// Start the closing handshake. Messages can still be read until
+    /// the peer's close frame arrives (`NetError::Closed`).
+    pub fn close(&mut self) -> Result<(), NetError> {
+        if self.closed {
+            return Ok(());
+        }
+        try!(self.write_ws_frame(OP_CLOSE, &endian::u16_bytes(CLOSE_NORMAL)));
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Returns fin, the opcode and the unmasked payload. Data frames
+    /// longer than `max_len` return `NetError::FrameTooBig`.
+    fn read_ws_frame(&mut self, max_len: usize) -> Result<(bool, u8, Vec<u8>), NetError> {
+        let mut head = [0; 2];
+        if try!(self.stream.read(&mut head[..1])) == 0 {
+            return Err(NetError::Closed);
+        }
+        try!(self.stream.read_exact(&mut head[1..]));
+        if head[0] & 0x70 != 0 {
+            return Err(NetError::Protocol("websocket reserved bits set"));
+        }
+        let fin = head[0] & FIN != 0;
+        let opcode = head[0] & 0x0f;
+        let masked = head[1] & MASKED != 0;
+        if masked != (self.role == Role::Server) {
+            return Err(NetError::Protocol("invalid websocket frame masking"));
+        }
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut bytes = [0; 2];
+                try!(self.stream.read_exact(&mut bytes));
+                endian::read_u16(&bytes) as u64
+            }
+            127 => {
+                let mut bytes = [0; 8];
+                try!(self.stream.read_exact(&mut bytes));
+                endian::read_u64(&bytes)
+            }
+            len => len as u64,
+        };
+        if opcode & 0x8 != 0 {
+            if !fin || len > 125 {
+                return Err(NetError::Protocol("invalid websocket control frame"));
+            }
+        } else if len > max_len as u64 {
+            return Err(NetError::FrameTooBig(len as usize));
+        }
+        let mut mask = [0; 4];
+        if masked {
+            try!(self.stream.read_exact(&mut mask));
+        }
+        let mut payload = vec![0; len as usize];
+        try!(self.stream.read_exact(&mut payload));
+        if masked {
+            apply_mask(&mut payload, mask);
+        }
+        Ok((fin, opcode, payload))
+    }
+
+    fn write_ws_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), NetError> {
+        if self.closed {
+            return Err(NetError::Closed);
+        }
+        let mask_bit = if self.role == Role::Client { MASKED } else { 0 };
+        let mut buf = Vec::with_capacity(14 + payload.len());
+        buf.push(FIN | opcode);
+        if payload.len() < 126 {
+            buf.push(mask_bit | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            buf.push(mask_bit | 126);
+            buf.extend_from_slice(&endian::u16_bytes(payload.len() as u16));
+        } else {
+            buf.push(mask_bit | 127);
+            buf.extend_from_slice(&endian::u64_bytes(payload.len() as u64));
+        }
+        if self.role == Role::Client {
+            let mask = endian::u32_bytes(rand::random::<u32>());
+            buf.extend_from_slice(&mask);
+            let start = buf.len();
+            buf.extend_from_slice(payload);
+            apply_mask(&mut buf[start..], mask);
+        } else {
+            buf.extend_from_slice(payload);
+        }
+        try!(self.get_mut().write_all(&buf));
+        Ok(())
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+/// Reads the start line and the headers (with lowercase names) of an
+/// HTTP request or response
+fn read_http_head<R: BufRead>(r: &mut R) -> Result<(String, Vec<(String, String)>), NetError> {
+    let mut start = None;
+    let mut headers = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let n = try!(r.read_line(&mut line));
+        if n == 0 {
+            return Err(NetError::from(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                     "connection closed during handshake")));
+        }
+        size += n;
+        if size > MAX_HANDSHAKE_SIZE {
+            return Err(NetError::Protocol("websocket handshake too long"));
+        }
+        let line = line.trim_right();
+        if line.is_empty() {
+            break;
+        }
+        if start.is_none() {
+            start = Some(line.to_string());
+        } else if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_string()));
+        } else {
+            return Err(NetError::Protocol("invalid http header"));
+        }
+    }
+    match start {
+        Some(start) => Ok((start, headers)),
+        None => Err(NetError::Protocol("empty http request")),
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| &v[..])
+}
+
+/// Whether the comma separated list of header `name` has `token`
+/// (case insensitive), e.g. "keep-alive, Upgrade" has "upgrade"
+fn has_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    header(headers, name).map_or(false, |v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Value of Sec-WebSocket-Accept for a key
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    base64::encode(&sha1.digest().bytes())
+}
@@ -0,0 +1,607 @@
+/// Left-leaning Red-Black Tree with nodes allocated from an arena.
+///
+/// Nodes live in a single `Vec` and are linked by index instead of
+/// `Box`, so there is one allocation for the whole tree (amortized by
+/// the vector growth), nodes are close together in memory and
+/// dropping the tree frees a single buffer.
+///
+/// The arena is kept compact: removing an entry moves the last node
+/// of the arena into the freed slot.
+///
+/// Nodes also keep the index of their parent, so going to the
+/// successor/predecessor of a node needs no stack: iterators and
+/// cursors are O(1) amortized per step, and a cursor stays valid
+/// across removals made through it.
+///
+/// This is the parent-linked ("threaded") mode of the tree. The boxed
+/// `RBTree` doesn't have one: its nodes are owned by their parent's
+/// `Box`, so a link back up would have to be a raw pointer kept right
+/// through every rotation. Its iterators keep a stack of the path
+/// instead and its `Cursor` is a zipper that owns the path.
+
+use std::mem;
+use std::usize;
+use std::cmp::Ordering;
+use std::cmp::Ordering::*;
+
+use rbtree::{Color, Comparator};
+use rbtree::Color::*;
+
+/// Index of a node in the arena
+type Link = usize;
+
+const NIL: Link = usize::MAX;
+
+struct Node<K,V> {
+    key: K,
+    value: V,
+    color: Color,
+    left: Link,
+    right: Link,
+    parent: Link,
+}
+
+pub struct RBTree<K, V, C = Comparator<K>> {
+    nodes: Vec<Node<K,V>>,
+    root: Link,
+    cmp: C,
+}
+
+impl<K,V> RBTree<K,V> where K: Ord {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Empty tree with room for `capacity` entries before the arena
+    /// needs to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut tree = Self::with_comparator(K::cmp);
+        tree.nodes.reserve(capacity);
+        tree
+    }
+}
+
+impl<K,V,C> RBTree<K,V,C> where C: Fn(&K, &K) -> Ordering {
+    /// Empty tree ordered by the given function
+    pub fn with_comparator(cmp: C) -> Self {
+        RBTree {
+            nodes: vec![],
+            root: NIL,
+            cmp: cmp,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        match self.find(key) {
+            NIL => None,
+            n => Some(&self.nodes[n].value),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let root = self.root;
+        let (root, ret) = self.insert_at(root, key, value);
+        self.set_root(root);
+        self.nodes[root].color = Black;
+        ret
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        match self.find(key) {
+            NIL => None,
+            n => Some(self.remove_node(n).1),
+        }
+    }
+
+    /// Entry with the smallest key greater than `key`
+    pub fn successor(&self, key: &K) -> Option<(&K, &V)> {
+        self.entry(self.find_successor(key))
+    }
+
+    /// Entry with the greatest key smaller than `key`
+    pub fn predecessor(&self, key: &K) -> Option<(&K, &V)> {
+        let mut found = NIL;
+        let mut curr = self.root;
+        while curr != NIL {
+            if (self.cmp)(key, &self.nodes[curr].key) == Greater {
+                found = curr;
+                curr = self.nodes[curr].right;
+            } else {
+                curr = self.nodes[curr].left;
+            }
+        }
+        self.entry(found)
+    }
+
+    fn entry(&self, n: Link) -> Option<(&K, &V)> {
+        match n {
+            NIL => None,
+            n => Some((&self.nodes[n].key, &self.nodes[n].value)),
+        }
+    }
+
+    /// Index of the node with the given key, or NIL
+    fn find(&self, key: &K) -> Link {
+        let mut curr = self.root;
+        while curr != NIL {
+            let n = &self.nodes[curr];
+            match (self.cmp)(key, &n.key) {
+                Equal => { break }
+                Less => { curr = n.left; }
+                Greater => { curr = n.right; }
+            }
+        }
+        curr
+    }
+
+    /// Index of the node with the smallest key greater than `key`, or NIL
+    fn find_successor(&self, key: &K) -> Link {
+        let mut found = NIL;
+        let mut curr = self.root;
+        while curr != NIL {
+            if (self.cmp)(key, &self.nodes[curr].key) == Less {
+                found = curr;
+                curr = self.nodes[curr].left;
+            } else {
+                curr = self.nodes[curr].right;
+            }
+        }
+        found
+    }
+
+    /// Remove the node at the given index from the tree and the arena.
+    /// The tree is rebalanced bottom up from where the node is
+    /// unlinked, following the parent links, so its key isn't looked
+    /// up again. Returns the entry and the index of the node that was
+    /// moved into its slot (or NIL if none was).
+    fn remove_node(&mut self, target: Link) -> (K, V, Link) {
+        // unlink the successor (a leaf) instead if there's a right
+        // subtree, and put it in place of the target at the end
+        let right = self.nodes[target].right;
+        let leaf = if right != NIL { leftmost(&self.nodes, right) } else { target };
+        // without a right child, at most a red leaf on the left
+        let left = self.nodes[leaf].left;
+        let mut parent = self.nodes[leaf].parent;
+        let mut on_left = parent != NIL && self.nodes[parent].left == leaf;
+        self.replace_child(parent, leaf, left);
+        let mut short = self.nodes[leaf].color == Black;
+        if short && left != NIL {
+            self.nodes[left].color = Black;
+            short = false;
+        }
+        while short && parent != NIL {
+            let grand = self.nodes[parent].parent;
+            let parent_on_left = grand != NIL && self.nodes[grand].left == parent;
+            let (top, still_short) = self.fix_short(parent, on_left);
+            self.replace_child(grand, parent, top);
+            short = still_short;
+            parent = grand;
+            on_left = parent_on_left;
+        }
+        if leaf != target {
+            let (parent, left, right) = {
+                let n = &self.nodes[target];
+                (n.parent, n.left, n.right)
+            };
+            self.replace_child(parent, target, leaf);
+            self.set_left(leaf, left);
+            self.set_right(leaf, right);
+            self.nodes[leaf].color = self.nodes[target].color.clone();
+        }
+        let root = self.root;
+        if root != NIL {
+            self.nodes[root].color = Black;
+        }
+        self.free(target)
+    }
+
+    /// Make up for the subtree on the left (or right) of `h` having
+    /// lost a black node, by borrowing from its sibling or merging
+    /// with it. Returns the new subtree root, and whether this whole
+    /// subtree is short of a black node then.
+    fn fix_short(&mut self, h: Link, on_left: bool) -> (Link, bool) {
+        if on_left {
+            let right = self.nodes[h].right;
+            if !self.is_red(self.nodes[right].left) {
+                // merge with the sibling
+                let short = self.nodes[h].color == Black;
+                self.nodes[h].color = Black;
+                self.nodes[right].color = Red;
+                (self.fix_up(h), short)
+            } else {
+                // borrow the red node of the sibling
+                let right = self.rotate_right(right);
+                self.set_right(h, right);
+                let h = self.rotate_left(h);
+                let (left, right) = (self.nodes[h].left, self.nodes[h].right);
+                self.nodes[left].color = Black;
+                self.nodes[right].color = Black;
+                (h, false)
+            }
+        } else {
+            let left = self.nodes[h].left;
+            if self.is_red(left) {
+                // the sibling is below the red left node
+                let top = self.rotate_right(h);
+                let (right, _) = self.fix_short(h, false);
+                self.set_right(top, right);
+                (self.fix_up(top), false)
+            } else if !self.is_red(self.nodes[left].left) {
+                let short = self.nodes[h].color == Black;
+                self.nodes[h].color = Black;
+                self.nodes[left].color = Red;
+                (h, short)
+            } else {
+                let h = self.rotate_right(h);
+                let (left, right) = (self.nodes[h].left, self.nodes[h].right);
+                self.nodes[left].color = Black;
+                self.nodes[right].color = Black;
+                (h, false)
+            }
+        }
+    }
+
+    /// Link `new` where `old` was below `parent` (or as the root)
+    fn replace_child(&mut self, parent: Link, old: Link, new: Link) {
+        if parent == NIL {
+            self.set_root(new);
+        } else if self.nodes[parent].left == old {
+            self.set_left(parent, new);
+        } else {
+            self.set_right(parent, new);
+        }
+    }
+
+    fn is_red(&self, h: Link) -> bool {
+        h != NIL && self.nodes[h].color == Red
+    }
+
+    fn set_root(&mut self, h: Link) {
+        self.root = h;
+        if h != NIL {
+            self.nodes[h].parent = NIL;
+        }
+    }
+
+    fn set_left(&mut self, h: Link, child: Link) {
+        self.nodes[h].left = child;
+        if child != NIL {
+            self.nodes[child].parent = h;
+        }
+    }
+
+    fn set_right(&mut self, h: Link, child: Link) {
+        self.nodes[h].right = child;
+        if child != NIL {
+            self.nodes[child].parent = h;
+        }
+    }
+
+    /// "split" a 4-node
+    fn color_flip(&mut self, h: Link) {
+        let (left, right) = (self.nodes[h].left, self.nodes[h].right);
+        for &n in [h, left, right].iter() {
+            if n != NIL {
+                self.nodes[n].color = self.nodes[n].color.inverse();
+            }
+        }
+    }
+
+    /// rotate left to fix right-leaning red. Returns the new subtree
+    /// root, whose parent is set by the caller when linking it.
+    fn rotate_left(&mut self, h: Link) -> Link {
+        let x = self.nodes[h].right;
+        let middle = self.nodes[x].left;
+        self.set_right(h, middle);
+        self.set_left(x, h);
+        self.nodes[x].color = self.nodes[h].color.clone();
+        self.nodes[h].color = Red;
+        x
+    }
+
+    /// rotate right to fix red-red egde. Returns the new subtree
+    /// root, whose parent is set by the caller when linking it.
+    fn rotate_right(&mut self, h: Link) -> Link {
+        let x = self.nodes[h].left;
+        let middle = self.nodes[x].right;
+        self.set_left(h, middle);
+        self.set_right(x, h);
+        self.nodes[x].color = self.nodes[h].color.clone();
+        self.nodes[h].color = Red;
+        x
+    }
+
+    /// restore the left-leaning invariants on the way up
+    fn fix_up(&mut self, mut h: Link) -> Link {
+        // fix right-leaning red
+        if self.is_red(self.nodes[h].right) && !self.is_red(self.nodes[h].left) {
+            h = self.rotate_left(h);
+        }
+        // fix two reds in a row
+        let left = self.nodes[h].left;
+        if self.is_red(left) && self.is_red(self.nodes[left].left) {
+            h = self.rotate_right(h);
+        }
+        // split 4-nodes
+        if self.is_red(self.nodes[h].left) && self.is_red(self.nodes[h].right) {
+            self.color_flip(h);
+        }
+        h
+    }
+
+    fn insert_at(&mut self, h: Link, key: K, value: V) -> (Link, Option<V>) {
+        if h == NIL {
+            self.nodes.push(Node {
+                key: key,
+                value: value,
+                color: Red,
+                left: NIL,
+                right: NIL,
+                parent: NIL,
+            });
+            return (self.nodes.len() - 1, None);
+        }
+        let ret = match (self.cmp)(&key, &self.nodes[h].key) {
+            Equal => Some(mem::replace(&mut self.nodes[h].value, value)),
+            Less => {
+                let left = self.nodes[h].left;
+                let (left, ret) = self.insert_at(left, key, value);
+                self.set_left(h, left);
+                ret
+            }
+            Greater => {
+                let right = self.nodes[h].right;
+                let (right, ret) = self.insert_at(right, key, value);
+                self.set_right(h, right);
+                ret
+            }
+        };
+        (self.fix_up(h), ret)
+    }
+
+    /// Release the arena slot of a detached node. The last node of the
+    /// arena is moved into the slot and its links are updated.
+    fn free(&mut self, slot: Link) -> (K, V, Link) {
+        let last = self.nodes.len() - 1;
+        let mut moved = NIL;
+        if slot != last {
+            let (parent, left, right) = {
+                let n = &self.nodes[last];
+                (n.parent, n.left, n.right)
+            };
+            if parent == NIL {
+                self.root = slot;
+            } else if self.nodes[parent].left == last {
+                self.nodes[parent].left = slot;
+            } else {
+                self.nodes[parent].right = slot;
+            }
+            for &child in [left, right].iter() {
+                if child != NIL {
+                    self.nodes[child].parent = slot;
+                }
+            }
+            moved = slot;
+        }
+        let n = self.nodes.swap_remove(slot);
+        (n.key, n.value, moved)
+    }
+}
+
+#[cfg(test)]
+impl<K,V,C> RBTree<K,V,C> where C: Fn(&K, &K) -> Ordering {
+    /// Check the tree is ordered, balanced (same number of black nodes
+    /// on every path), left-leaning, has no red node with a red child,
+    /// and that every node is reachable and linked back to its parent.
+    /// Returns the black height.
+    pub fn check_invariants(&self) -> usize {
+        fn check<K,V,C>(tree: &RBTree<K,V,C>, h: Link, parent: Link, count: &mut usize) -> usize
+            where C: Fn(&K, &K) -> Ordering {
+            if h == NIL {
+                return 0;
+            }
+            *count += 1;
+            let n = &tree.nodes[h];
+            assert_eq!(n.parent, parent);
+            if n.left != NIL {
+                assert_eq!((tree.cmp)(&tree.nodes[n.left].key, &n.key), Less);
+            }
+            if n.right != NIL {
+                assert_eq!((tree.cmp)(&tree.nodes[n.right].key, &n.key), Greater);
+            }
+            assert!(!tree.is_red(n.right));
+            if n.color == Red {
+                assert!(!tree.is_red(n.left));
+            }
+            let left = check(tree, n.left, h, count);
+            let right = check(tree, n.right, h, count);
+            assert_eq!(left, right);
+            if n.color == Black { left + 1 } else { left }
+        }
+        assert!(!self.is_red(self.root));
+        let mut count = 0;
+        let height = check(self, self.root, NIL, &mut count);
+        assert_eq!(count, self.nodes.len());
+        height
+    }
+}
+
+fn leftmost<K,V>(nodes: &[Node<K,V>], mut h: Link) -> Link {
+    if h != NIL {
+        while nodes[h].left != NIL {
+            h = nodes[h].left;
+        }
+    }
+    h
+}
+
+fn rightmost<K,V>(nodes: &[Node<K,V>], mut h: Link) -> Link {
+    if h != NIL {
+        while nodes[h].right != NIL {
+            h = nodes[h].right;
+        }
+    }
+    h
+}
+
+/// Node following `h` in key order, or NIL
+fn next_node<K,V>(nodes: &[Node<K,V>], mut h: Link) -> Link {
+    if nodes[h].right != NIL {
+        return leftmost(nodes, nodes[h].right);
+    }
+    // go up until we leave a left subtree
+    let mut parent = nodes[h].parent;
+    while parent != NIL && nodes[parent].right == h {
+        h = parent;
+        parent = nodes[h].parent;
+    }
+    parent
+}
+
+/// Node preceding `h` in key order, or NIL. Mirror of `next_node()`
+fn prev_node<K,V>(nodes: &[Node<K,V>], mut h: Link) -> Link {
+    if nodes[h].left != NIL {
+        return rightmost(nodes, nodes[h].left);
+    }
+    let mut parent = nodes[h].parent;
+    while parent != NIL && nodes[parent].left == h {
+        h = parent;
+        parent = nodes[h].parent;
+    }
+    parent
+}
+
+// Iterators ---------------------------------------------
+
+pub struct Iter<'a, K, V> where K: 'a, V: 'a {
+    nodes: &'a [Node<K,V>],
+    front: Link,
+    back: Link,
+    // entries not yet returned from either end
+    remaining: usize,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> where K: 'a, V: 'a {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let nodes = self.nodes;
+        let n = &nodes[self.front];
+        self.front = next_node(nodes, self.front);
+        self.remaining -= 1;
+        Some((&n.key, &n.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> where K: 'a, V: 'a {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let nodes = self.nodes;
+        let n = &nodes[self.back];
+        self.back = prev_node(nodes, self.back);
+        self.remaining -= 1;
+        Some((&n.key, &n.value))
+    }
+}
+
+impl<'a, K, V, C> RBTree<K, V, C> {
+    /// Iterate over the entries in key order
+    pub fn iter(&'a self) -> Iter<'a, K, V> {
+        Iter {
+            nodes: &self.nodes,
+            front: leftmost(&self.nodes, self.root),
+            back: rightmost(&self.nodes, self.root),
+            remaining: self.nodes.len(),
+        }
+    }
+}
+
+// Cursor ---------------------------------------------
+
+/// A cursor over the entries of an arena `RBTree`, in key order. Like
+/// the cursor of the boxed `RBTree` it has a "ghost" position between
+/// the last and first entries, where `current()` is `None`. Moving
+/// only follows the parent/child links.
+pub struct Cursor<'a, K, V, C = Comparator<K>> where K: 'a, V: 'a, C: 'a {
+    tree: &'a mut RBTree<K,V,C>,
+    // current node, NIL at the ghost position
+    node: Link,
+}
+
+impl<K,V,C> RBTree<K,V,C> where C: Fn(&K, &K) -> Ordering {
+    /// Cursor positioned at the smallest entry
+    pub fn cursor(&mut self) -> Cursor<K,V,C> {
+        let first = leftmost(&self.nodes, self.root);
+        Cursor {
+            tree: self,
+            node: first,
+        }
+    }
+}
+
+impl<'a, K, V, C> Cursor<'a, K, V, C> where K: 'a, V: 'a, C: 'a + Fn(&K, &K) -> Ordering {
+    /// Entry at the cursor position
+    pub fn current(&self) -> Option<(&K, &V)> {
+        self.tree.entry(self.node)
+    }
+
+    /// Move the cursor to the smallest entry with a key greater or
+    /// equal to `key` (or to the ghost position if there is none).
+    pub fn seek(&mut self, key: &K) {
+        self.node = match self.tree.find(key) {
+            NIL => self.tree.find_successor(key),
+            n => n,
+        };
+    }
+
+    /// Move to the next entry and return it. Moving past the last
+    /// entry goes to the ghost position, and from there to the first
+    /// entry.
+    pub fn next(&mut self) -> Option<(&K, &V)> {
+        self.node = match self.node {
+            NIL => leftmost(&self.tree.nodes, self.tree.root),
+            n => next_node(&self.tree.nodes, n),
+        };
+        self.current()
+    }
+
+    /// Move to the previous entry and return it. Mirror of `next()`.
+    pub fn prev(&mut self) -> Option<(&K, &V)> {
+        self.node = match self.node {
+            NIL => rightmost(&self.tree.nodes, self.tree.root),
+            n => prev_node(&self.tree.nodes, n),
+        };
+        self.current()
+    }
+
+    /// Remove the current entry, returning it, and move the cursor to
+    /// the next entry. The node is unlinked and the tree rebalanced
+    /// from the cursor position up, through the parent links, without
+    /// looking its key up again.
+    pub fn remove_current(&mut self) -> Option<(K, V)> {
+        if self.node == NIL {
+            return None;
+        }
+        let mut next = next_node(&self.tree.nodes, self.node);
+        let (key, value, moved) = self.tree.remove_node(self.node);
+        // the last node of the arena may have been moved into the freed slot
+        if moved != NIL && next == self.tree.nodes.len() {
+            next = moved;
+        }
+        self.node = next;
+        Some((key, value))
+    }
+}
@@ -4,9 +4,18 @@
 
 #[cfg(test)]
 mod test;
+pub mod set;
+pub mod multimap;
+pub mod persistent;
+pub mod arena;
+
+pub use self::set::RBSet;
+pub use self::multimap::RBMultiMap;
 
 use std::mem;
+use std::cmp::Ordering;
 use std::cmp::Ordering::*;
+use std::fmt::{Debug, Write};
 
 use self::Color::*;
 #[derive(Debug, PartialEq, Clone)]
@@ -24,57 +33,166 @@ impl Color {
     }
 }
 
+/// Comparator of the trees ordered by the keys' `Ord` implementation.
+/// Any `Fn(&K, &K) -> Ordering` can be used, including closures with
+/// state (e.g. a collation table).
+pub type Comparator<K> = fn(&K, &K) -> Ordering;
+
 /// Left Leaning Red-Black Tree
-pub struct RBTree<K,V> where K: Ord {
+pub struct RBTree<K, V, C = Comparator<K>> {
     root: Option<Box<Node<K,V>>>,
-    len: usize,
+    cmp: C,
 }
 
 impl<K,V> RBTree<K,V> where K: Ord {
+    /// Empty tree ordered by the keys' `Ord` implementation
     pub fn new() -> Self {
+        Self::with_comparator(K::cmp)
+    }
+}
+
+impl<K, V, C> RBTree<K, V, C> where C: Fn(&K, &K) -> Ordering {
+    /// Empty tree ordered by the given function, for keys without a
+    /// natural order or needing a different one (e.g. reversed).
+    pub fn with_comparator(cmp: C) -> Self {
         RBTree {
             root: None,
-            len: 0,
+            cmp: cmp,
         }
     }
 
     pub fn get(&self, key: &K) -> Option<&V> {
-        self.root.get(key)
+        self.root.get_node(key, &self.cmp).and_then(|n| n.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.root.get_node_mut(key, &self.cmp).and_then(|n| n.value.as_mut())
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.root.get_node(key, &self.cmp).is_some()
+    }
+
+    /// Entry with the given key. The returned key is the one stored in
+    /// the tree, which may differ from `key` in data not used for the
+    /// ordering.
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)> {
+        self.root.get_node(key, &self.cmp).map(|n| (&n.key, n.value.as_ref().unwrap()))
     }
 
     pub fn min(&self) -> Option<&V> {
-        self.root.min()
+        self.root.min_node().and_then(|n| n.value.as_ref())
     }
 
     pub fn max(&self) -> Option<&V> {
-        self.root.max()
+        self.root.max_node().and_then(|n| n.value.as_ref())
+    }
+
+    /// Entry with the greatest key smaller or equal to `key`
+    pub fn floor(&self, key: &K) -> Option<(&K, &V)> {
+        self.root.floor_node(key, &self.cmp).map(|n| (&n.key, n.value.as_ref().unwrap()))
+    }
+
+    /// Entry with the smallest key greater or equal to `key`
+    pub fn ceiling(&self, key: &K) -> Option<(&K, &V)> {
+        self.root.ceiling_node(key, &self.cmp).map(|n| (&n.key, n.value.as_ref().unwrap()))
     }
 
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        let ret = self.root.insert(key,value);
-        if let None = ret {
-            self.len += 1;
-        }
+        let ret = self.root.insert(key, value, &self.cmp);
+        self.set_root_black();
         return ret;
     }
 
     pub fn remove(&mut self, key: &K) -> Option<V> {
-        self.root.remove(key)
+        self.remove_node(key).and_then(|mut n| n.value.take())
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.size()
+    }
+
+
+    /// Moves all entries of `other` into this tree in O(log n). All
+    /// keys in `other` must be greater than the keys in `self`.
+    pub fn join(&mut self, mut other: Self) {
+        let pivot = match other.remove_min_node() {
+            Some(pivot) => pivot,
+            None => return,
+        };
+        if let Some(n) = self.root.max_node() {
+            assert!((self.cmp)(&n.key, &pivot.key) == Less,
+                    "joined tree keys should be greater than the existing keys");
+        }
+        let (height, other_height) = (self.root.black_height(), other.root.black_height());
+        self.root = self.root.take().join(height, pivot, other.root.take(), other_height).0;
+        self.set_root_black();
+    }
+
+    /// Detach the node with the given key
+    fn remove_node(&mut self, key: &K) -> Option<Box<Node<K,V>>> {
+        self.prepare_root_for_removal();
+        let ret = self.root.remove(key, &self.cmp);
+        self.set_root_black();
+        ret
+    }
+
+    /// Detach the node with the smallest key
+    fn remove_min_node(&mut self) -> Option<Box<Node<K,V>>> {
+        self.prepare_root_for_removal();
+        let ret = self.root.remove_min();
+        self.set_root_black();
+        ret
+    }
+
+    fn prepare_root_for_removal(&mut self) {
+        if let Some(n) = self.root.as_mut() {
+            // let the root lend a node to its children if needed
+            if !n.left.is_red() && !n.right.is_red() {
+                n.color = Red;
+            }
+        }
+    }
+
+    fn set_root_black(&mut self) {
+        if let Some(n) = self.root.as_mut() {
+            n.color = Black;
+        }
+    }
+}
+
+impl<K, V, C> RBTree<K, V, C> where C: Fn(&K, &K) -> Ordering + Clone {
+    /// Splits the tree in two at the given key, in O(log n). Entries
+    /// with keys greater or equal to `key` are moved to the returned
+    /// tree, which gets a clone of the comparator.
+    pub fn split_off(&mut self, key: &K) -> Self {
+        let height = self.root.black_height();
+        let ((left, _), (right, _)) = self.root.take().split(height, key, &self.cmp);
+        self.root = left;
+        self.set_root_black();
+        let mut other = RBTree {
+            root: right,
+            cmp: self.cmp.clone(),
+        };
+        other.set_root_black();
+        other
     }
 }
 
 
 /// Node ------------------
 #[derive(Debug)]
-struct Node<K,V> where K: Ord {
+struct Node<K,V> {
     key: K,
     value: Option<V>,
     color: Color,
+    // number of nodes in the subtree rooted here
+    size: usize,
     left: Option<Box<Node<K,V>>>,
     right: Option<Box<Node<K,V>>>,
 }
 
-impl<K,V> Node<K,V> where K: Ord {
+impl<K,V> Node<K,V> {
     fn new_boxed(k: K, v: V, color: Color) -> Option<Box<Node<K,V>>> {
         Some(Box::new(Self::new(k,v,color)))
     }
@@ -86,9 +204,21 @@ impl<K,V> Node<K,V> where K: Ord {
             left: None,
             right: None,
             color: color,
+            size: 1,
         }
     }
 
+    fn child(&self, side: Side) -> &Option<Box<Node<K,V>>> {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+
+    fn update_size(&mut self) {
+        self.size = 1 + self.left.size() + self.right.size();
+    }
+
     /// "split" a 4-node
     fn color_flip(&mut self) {
         self.color = self.color.inverse();
@@ -110,6 +240,8 @@ impl<K,V> Node<K,V> where K: Ord {
         mem::swap(&mut right, &mut self.left);
         self.color = self.left.as_ref().unwrap().color.clone();
         self.left.as_mut().unwrap().color = Red;
+        self.left.as_mut().unwrap().update_size();
+        self.update_size();
     }
 
     /// rotate right to fix red-red egde
@@ -122,6 +254,71 @@ impl<K,V> Node<K,V> where K: Ord {
         mem::swap(&mut left, &mut self.right);
         self.color = self.right.as_ref().unwrap().color.clone();
         self.right.as_mut().unwrap().color = Red;
+        self.right.as_mut().unwrap().update_size();
+        self.update_size();
+    }
+
+    /// restore the left-leaning invariants (and the size) on the way up
+    fn fix_up(&mut self) {
+        // fix right-leaning red
+        if self.right.is_red() && !self.left.is_red() {
+            self.rotate_left();
+        }
+        // fix two reds in a row
+        if self.left.is_red() && self.left.as_ref().unwrap().left.is_red() {
+            self.rotate_right();
+        }
+        // split 4-nodes
+        if self.left.is_red() && self.right.is_red() {
+            self.color_flip();
+        }
+        self.update_size();
+    }
+
+    /// Make up for the subtree on `side` having lost a black node
+    /// (with a black root), by borrowing from its sibling or merging
+    /// with it. Returns true if this whole subtree is short of a black
+    /// node then, to be fixed further up.
+    fn fix_short(&mut self, side: Side) -> bool {
+        match side {
+            Side::Left => {
+                if !self.right.as_ref().unwrap().left.is_red() {
+                    // merge with the sibling
+                    let short = self.color == Black;
+                    self.color = Black;
+                    self.right.as_mut().unwrap().color = Red;
+                    self.fix_up();
+                    short
+                } else {
+                    // borrow the red node of the sibling
+                    self.right.as_mut().unwrap().rotate_right();
+                    self.rotate_left();
+                    self.left.as_mut().unwrap().color = Black;
+                    self.right.as_mut().unwrap().color = Black;
+                    false
+                }
+            }
+            Side::Right => {
+                if self.left.is_red() {
+                    // the sibling is below the red left node
+                    self.rotate_right();
+                    self.right.as_mut().unwrap().fix_short(Side::Right);
+                    self.fix_up();
+                    false
+                } else if !self.left.as_ref().unwrap().left.is_red() {
+                    let short = self.color == Black;
+                    self.color = Black;
+                    self.left.as_mut().unwrap().color = Red;
+                    self.update_size();
+                    short
+                } else {
+                    self.rotate_right();
+                    self.left.as_mut().unwrap().color = Black;
+                    self.right.as_mut().unwrap().color = Black;
+                    false
+                }
+            }
+        }
     }
 
     fn move_red_right(&mut self) {
@@ -144,19 +341,38 @@ impl<K,V> Node<K,V> where K: Ord {
 
 /// BoxedNode ------------------
 trait BoxedNode {
-    type K: Ord;
+    type K;
     type V;
-    fn get(&self, key: &Self::K) -> Option<&Self::V>;
-    fn min(&self) -> Option<&Self::V>;
-    fn max(&self) -> Option<&Self::V>;
-    fn insert(&mut self, key: Self::K, value: Self::V) -> Option<Self::V>;
-    fn remove(&mut self, key: &Self::K) -> Option<Self::V>;
+    fn get_node<C>(&self, key: &Self::K, cmp: &C) -> Option<&Node<Self::K,Self::V>>
+        where C: Fn(&Self::K, &Self::K) -> Ordering;
+    fn get_node_mut<C>(&mut self, key: &Self::K, cmp: &C) -> Option<&mut Node<Self::K,Self::V>>
+        where C: Fn(&Self::K, &Self::K) -> Ordering;
+    fn min_node(&self) -> Option<&Node<Self::K,Self::V>>;
+    fn max_node(&self) -> Option<&Node<Self::K,Self::V>>;
+    fn floor_node<C>(&self, key: &Self::K, cmp: &C) -> Option<&Node<Self::K,Self::V>>
+        where C: Fn(&Self::K, &Self::K) -> Ordering;
+    fn ceiling_node<C>(&self, key: &Self::K, cmp: &C) -> Option<&Node<Self::K,Self::V>>
+        where C: Fn(&Self::K, &Self::K) -> Ordering;
+    fn insert<C>(&mut self, key: Self::K, value: Self::V, cmp: &C) -> Option<Self::V>
+        where C: Fn(&Self::K, &Self::K) -> Ordering;
+    fn remove<C>(&mut self, key: &Self::K, cmp: &C) -> Option<Box<Node<Self::K,Self::V>>>
+        where C: Fn(&Self::K, &Self::K) -> Ordering;
+    fn split<C>(self, height: usize, key: &Self::K, cmp: &C) -> ((Self, usize), (Self, usize))
+        where Self: Sized, C: Fn(&Self::K, &Self::K) -> Ordering;
+    fn join(self, height: usize, pivot: Box<Node<Self::K,Self::V>>, right: Self, right_height: usize)
+            -> (Self, usize) where Self: Sized;
     // helpers
     fn remove_min(&mut self) -> Option<Box<Node<Self::K,Self::V>>>;
+    fn join_right(&mut self, height: usize, right_height: usize,
+                  pivot: Box<Node<Self::K,Self::V>>, right: Self) where Self: Sized;
+    fn join_left(&mut self, height: usize, left_height: usize,
+                 left: Self, pivot: Box<Node<Self::K,Self::V>>) where Self: Sized;
+    fn black_height(&self) -> usize;
+    fn size(&self) -> usize;
     fn is_red(&self) -> bool;
 }
 
-impl<K,V> Drop for Node<K,V> where K: Ord {
+impl<K,V> Drop for Node<K,V> {
     fn drop(&mut self) {
         let mut to_drop = vec![];
         to_drop.push(mem::replace(&mut self.left, None));
@@ -171,7 +387,7 @@ impl<K,V> Drop for Node<K,V> where K: Ord {
 }
 
 ///
-impl<K,V> BoxedNode for Option<Box<Node<K,V>>> where K: Ord {
+impl<K,V> BoxedNode for Option<Box<Node<K,V>>> {
     type K = K;
     type V = V;
 
@@ -182,13 +398,14 @@ impl<K,V> BoxedNode for Option<Box<Node<K,V>>> where K: Ord {
         }
     }
 
-    fn get(&self, key: &K) -> Option<&V> {
+    fn get_node<C>(&self, key: &K, cmp: &C) -> Option<&Node<K,V>>
+        where C: Fn(&K, &K) -> Ordering {
         let mut curr = self;
         loop {
             match curr.as_ref() {
                 Some(n) => {
-                    match key.cmp(&n.key) {
-                        Equal => { return n.value.as_ref() }
+                    match cmp(key, &n.key) {
+                        Equal => { return Some(n) }
                         Less => { curr = &n.left; }
                         Greater => { curr = &n.right; }
                     }
@@ -198,14 +415,28 @@ impl<K,V> BoxedNode for Option<Box<Node<K,V>>> where K: Ord {
         }
     }
 
-    fn min(&self) -> Option<&V> {
+    fn get_node_mut<C>(&mut self, key: &K, cmp: &C) -> Option<&mut Node<K,V>>
+        where C: Fn(&K, &K) -> Ordering {
+        match self.as_mut() {
+            Some(n) => {
+                match cmp(key, &n.key) {
+                    Equal => Some(&mut **n),
+                    Less => n.left.get_node_mut(key, cmp),
+                    Greater => n.right.get_node_mut(key, cmp),
+                }
+            }
+            None => None,
+        }
+    }
+
+    fn min_node(&self) -> Option<&Node<K,V>> {
         let mut curr = self;
         loop {
             match curr.as_ref() {
                 Some(n) => {
                     match n.left {
                         Some(_) => curr = &n.left,
-                        None => return n.value.as_ref(),
+                        None => return Some(n),
                     }
                 }
                 None => return None,
@@ -213,14 +444,14 @@ impl<K,V> BoxedNode for Option<Box<Node<K,V>>> where K: Ord {
         }
     }
 
-    fn max(&self) -> Option<&V> {
+    fn max_node(&self) -> Option<&Node<K,V>> {
         let mut curr = self;
         loop {
             match curr.as_ref() {
                 Some(n) => {
                     match n.right.as_ref() {
                         Some(_) => curr = &n.right,
-                        None => return n.value.as_ref(),
+                        None => return Some(n),
                     }
                 }
                 None => return None,
@@ -228,7 +459,50 @@ impl<K,V> BoxedNode for Option<Box<Node<K,V>>> where K: Ord {
         }
     }
 
-    fn insert(&mut self, key: K, value: V) -> Option<V> {
+    fn floor_node<C>(&self, key: &K, cmp: &C) -> Option<&Node<K,V>>
+        where C: Fn(&K, &K) -> Ordering {
+        let mut curr = self;
+        let mut found = None;
+        loop {
+            match curr.as_ref() {
+                Some(n) => {
+                    match cmp(key, &n.key) {
+                        Equal => { return Some(n) }
+                        Less => { curr = &n.left; }
+                        Greater => {
+                            found = Some(&**n);
+                            curr = &n.right;
+                        }
+                    }
+                }
+                None => return found,
+            }
+        }
+    }
+
+    fn ceiling_node<C>(&self, key: &K, cmp: &C) -> Option<&Node<K,V>>
+        where C: Fn(&K, &K) -> Ordering {
+        let mut curr = self;
+        let mut found = None;
+        loop {
+            match curr.as_ref() {
+                Some(n) => {
+                    match cmp(key, &n.key) {
+                        Equal => { return Some(n) }
+                        Less => {
+                            found = Some(&**n);
+                            curr = &n.left;
+                        }
+                        Greater => { curr = &n.right; }
+                    }
+                }
+                None => return found,
+            }
+        }
+    }
+
+    fn insert<C>(&mut self, key: K, value: V, cmp: &C) -> Option<V>
+        where C: Fn(&K, &K) -> Ordering {
         // can't use match Some(n) here because we assign to self...
         if let None = self.as_mut() {
             *self = Node::new_boxed(key, value, Red);
@@ -236,35 +510,22 @@ impl<K,V> BoxedNode for Option<Box<Node<K,V>>> where K: Ord {
         } else {
             let mut n = self.as_mut().unwrap();
 
-            // break 4-nodes
-            if n.left.is_red() && n.right.is_red() {
-                n.color_flip();
-            }
-
             let ret;
-            match key.cmp(&n.key) {
+            match cmp(&key, &n.key) {
                 Equal => { // replace value
                     let mut old = Some(value);
                     mem::swap(&mut n.value, &mut old);
                     ret = old;
                 }
                 Less => {
-                    ret = n.left.insert(key,value);
+                    ret = n.left.insert(key, value, cmp);
                 }
                 Greater => {
-                    ret = n.right.insert(key,value);
+                    ret = n.right.insert(key, value, cmp);
                 }
             }
 
-            // fix right-leaning red
-            if n.right.is_red() {
-                n.rotate_left();
-            }
-            // fix two reds in a row
-            if n.left.is_red() && n.left.as_ref().unwrap().left.is_red() {
-                n.rotate_right();
-            }
-
+            n.fix_up();
             return ret;
         }
     }
@@ -282,18 +543,7 @@ impl<K,V> BoxedNode for Option<Box<Node<K,V>>> where K: Ord {
                         n.move_red_left();
                     }
                     ret = n.left.remove_min();
-                    // fix right-leaning red
-                    if n.right.is_red() {
-                        n.rotate_left();
-                    }
-                    // fix two reds in a row
-                    if n.left.is_red() && n.left.as_ref().unwrap().left.is_red() {
-                        n.rotate_right();
-                    }
-                    // break 4-nodes
-                    if n.left.is_red() && n.right.is_red() {
-                        n.color_flip();
-                    }
+                    n.fix_up();
                 }
             }
         }
@@ -304,55 +554,501 @@ impl<K,V> BoxedNode for Option<Box<Node<K,V>>> where K: Ord {
         return ret;
     }
 
-    fn remove(&mut self, key: &K) -> Option<V> {
+    /// Detach the node with the given key. Rebalances on the way down
+    /// so the node removed is never a 2-node.
+    fn remove<C>(&mut self, key: &K, cmp: &C) -> Option<Box<Node<K,V>>>
+        where C: Fn(&K, &K) -> Ordering {
         let mut remove_self = false;
         let mut ret = None;
         match self.as_mut() {
             None => return None,
             Some(n) => {
-                if *key < n.key {
+                if cmp(key, &n.key) == Less {
+                    if n.left.is_none() { // not found
+                        return None;
+                    }
                     if !n.left.is_red() && !n.left.as_ref().unwrap().left.is_red() {
                         n.move_red_left();
                     }
-                    ret = n.left.remove(key);
+                    ret = n.left.remove(key, cmp);
                 }
                 else {
                     if n.left.is_red() {
                         n.rotate_right();
                     }
-                    if *key == n.key && n.right.is_none() {
+                    if cmp(key, &n.key) == Equal && n.right.is_none() {
                         remove_self = true;
-                    } else {
+                    } else if n.right.is_some() {
                         if !n.right.is_red() && !n.right.as_ref().unwrap().left.is_red() {
                             n.move_red_right();
                         }
-                        if key == &n.key {
-                            let mut min_right = n.right.remove_min();
-                            mem::swap(n, &mut min_right.as_mut().unwrap());
-                            ret = mem::replace(&mut min_right.unwrap().value, None);
+                        if cmp(key, &n.key) == Equal {
+                            // take the place of the successor and detach it instead
+                            let mut min_right = n.right.remove_min().unwrap();
+                            mem::swap(&mut n.key, &mut min_right.key);
+                            mem::swap(&mut n.value, &mut min_right.value);
+                            ret = Some(min_right);
+                        } else {
+                            ret = n.right.remove(key, cmp);
                         }
                     }
                 }
                 if !remove_self {
-                    // fix right-leaning red
-                    if n.right.is_red() {
-                        n.rotate_left();
+                    n.fix_up();
+                }
+            }
+        }
+        if remove_self { // modify self outside due to the borrow checker...
+            return self.take();
+        }
+        return ret;
+    }
+
+    /// Number of black nodes in a path from the root to a leaf
+    fn black_height(&self) -> usize {
+        let mut height = 0;
+        let mut curr = self;
+        while let Some(n) = curr.as_ref() {
+            if n.color == Black {
+                height += 1;
+            }
+            curr = &n.left;
+        }
+        height
+    }
+
+    fn size(&self) -> usize {
+        match self.as_ref() {
+            Some(n) => n.size,
+            None => 0,
+        }
+    }
+
+    /// Split into a tree with the keys smaller than `key` and a tree
+    /// with the remaining ones, along with their black heights
+    /// (`height` is the one of `self`). Each node on the search path
+    /// is joined back into one of the sides.
+    fn split<C>(self, height: usize, key: &K, cmp: &C) -> ((Self, usize), (Self, usize))
+        where C: Fn(&K, &K) -> Ordering {
+        match self {
+            None => ((None, 0), (None, 0)),
+            Some(mut n) => {
+                let left = n.left.take();
+                let right = n.right.take();
+                // both children are one black node lower, if n is black
+                let child_height = if n.color == Black { height - 1 } else { height };
+                match cmp(key, &n.key) {
+                    Equal => {
+                        ((left, child_height), None.join(0, n, right, child_height))
                     }
-                    // fix two reds in a row
-                    if n.left.is_red() && n.left.as_ref().unwrap().left.is_red() {
-                        n.rotate_right();
+                    Less => {
+                        let (left_left, left_right) = left.split(child_height, key, cmp);
+                        (left_left, left_right.0.join(left_right.1, n, right, child_height))
                     }
-                    // break 4-nodes
-                    if n.left.is_red() && n.right.is_red() {
-                        n.color_flip();
+                    Greater => {
+                        let (right_left, right_right) = right.split(child_height, key, cmp);
+                        (left.join(child_height, n, right_left.0, right_left.1), right_right)
                     }
                 }
             }
         }
-        if remove_self { // modify self outside due to the borrow checker...
-            let old = self.take();
-            return mem::replace(&mut old.unwrap().value, None);
+    }
+
+    /// Join two trees of the given black heights using `pivot` as the
+    /// middle node, returning the result and its black height. Keys in
+    /// `self` should be smaller than the pivot's and keys in `right`
+    /// larger. The pivot is placed where black heights match, so the
+    /// cost is proportional to the difference in black height.
+    fn join(self, height: usize, mut pivot: Box<Node<K,V>>, right: Self, right_height: usize)
+            -> (Self, usize) {
+        debug_assert!(pivot.left.is_none() && pivot.right.is_none());
+        let (mut left, mut left_height) = (self, height);
+        let (mut right, mut right_height) = (right, right_height);
+        // blackening a red root adds one to the height
+        if left.is_red() {
+            left.as_mut().unwrap().color = Black;
+            left_height += 1;
         }
-        return ret;
+        if right.is_red() {
+            right.as_mut().unwrap().color = Black;
+            right_height += 1;
+        }
+        if left_height == right_height {
+            pivot.color = Black;
+            pivot.left = left;
+            pivot.right = right;
+            pivot.update_size();
+            (Some(pivot), left_height + 1)
+        } else if left_height > right_height {
+            // rotations and color flips on the way up keep the
+            // number of black nodes on every path
+            left.join_right(left_height, right_height, pivot, right);
+            (left, left_height)
+        } else {
+            right.join_left(right_height, left_height, left, pivot);
+            (right, right_height)
+        }
+    }
+
+    /// Descend the right spine until a black subtree of
+    /// `right_height` and hang `pivot` there. Fixing up on the way
+    /// back is the same as for an insertion.
+    fn join_right(&mut self, height: usize, right_height: usize,
+                  mut pivot: Box<Node<K,V>>, right: Self) {
+        if height == right_height && !self.is_red() {
+            pivot.color = Red;
+            pivot.left = self.take();
+            pivot.right = right;
+            pivot.update_size();
+            *self = Some(pivot);
+            return;
+        }
+        let n = self.as_mut().unwrap();
+        let child_height = if n.color == Black { height - 1 } else { height };
+        n.right.join_right(child_height, right_height, pivot, right);
+        n.fix_up();
+    }
+
+    /// Mirror of `join_right`, descending the left spine.
+    fn join_left(&mut self, height: usize, left_height: usize,
+                 left: Self, mut pivot: Box<Node<K,V>>) {
+        if height == left_height && !self.is_red() {
+            pivot.color = Red;
+            pivot.left = left;
+            pivot.right = self.take();
+            pivot.update_size();
+            *self = Some(pivot);
+            return;
+        }
+        let n = self.as_mut().unwrap();
+        let child_height = if n.color == Black { height - 1 } else { height };
+        n.left.join_left(child_height, left_height, left, pivot);
+        n.fix_up();
+    }
+}
+
+
+// Debugging ---------------------------------------------
+
+impl<K, V, C> RBTree<K, V, C> where K: Debug {
+    /// Graphviz (DOT) representation of the tree, with nodes colored
+    /// red/black and labeled with their keys. Missing children are
+    /// drawn as points so left and right links can be told apart.
+    /// Render with e.g. `dot -Tsvg tree.dot > tree.svg`.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph rbtree {{").unwrap();
+        writeln!(out, "    node [style=filled, fontcolor=white];").unwrap();
+        let mut next_id = 0;
+        dot_node(&self.root, &mut out, &mut next_id);
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+/// Write the subtree to `out`, returning the id given to its root
+fn dot_node<K,V>(node: &Option<Box<Node<K,V>>>, out: &mut String, next_id: &mut usize) -> usize where K: Debug {
+    let id = *next_id;
+    *next_id += 1;
+    match node.as_ref() {
+        Some(n) => {
+            let label = format!("{:?}", n.key).replace("\\", "\\\\").replace("\"", "\\\"");
+            let color = if n.color == Red { "red" } else { "black" };
+            writeln!(out, "    n{} [label=\"{}\", fillcolor={}];", id, label, color).unwrap();
+            let left = dot_node(&n.left, out, next_id);
+            let right = dot_node(&n.right, out, next_id);
+            writeln!(out, "    n{} -> n{};", id, left).unwrap();
+            writeln!(out, "    n{} -> n{};", id, right).unwrap();
+        }
+        None => {
+            writeln!(out, "    n{} [shape=point];", id).unwrap();
+        }
+    }
+    id
+}
+
+// Iterators ---------------------------------------------
+
+pub struct Iter<'a, K, V> where K: 'a, V: 'a {
+    // nodes still to be visited, the next one on top
+    stack: Vec<&'a Node<K,V>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> where K: 'a, V: 'a {
+    fn push_leftmost(&mut self, mut curr: &'a Option<Box<Node<K,V>>>) {
+        while let Some(n) = curr.as_ref() {
+            self.stack.push(&**n);
+            curr = &n.left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> where K: 'a, V: 'a {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.stack.pop() {
+            Some(n) => {
+                self.push_leftmost(&n.right);
+                Some((&n.key, n.value.as_ref().unwrap()))
+            }
+            None => None,
+        }
+    }
+}
+
+impl<'a, K, V, C> RBTree<K, V, C> {
+    /// Iterate over the entries in key order
+    pub fn iter(&'a self) -> Iter<'a, K, V> {
+        let mut iter = Iter {
+            stack: vec![],
+        };
+        iter.push_leftmost(&self.root);
+        iter
+    }
+}
+
+impl<'a, K, V, C> IntoIterator for &'a RBTree<K, V, C> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Cursor ---------------------------------------------
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    Right,
+}
+
+/// A cursor over the entries of a `RBTree`, in key order. Besides
+/// pointing at an entry, the cursor can be at a "ghost" position
+/// between the last and first entries, where `current()` is `None`.
+///
+/// The nodes from the root to the cursor are detached from each other
+/// while it exists (a zipper), so moving to the next or previous entry
+/// is O(1) amortized. They are put back together when it's dropped.
+pub struct Cursor<'a, K, V, C = Comparator<K>> where K: 'a, V: 'a, C: 'a {
+    tree: &'a mut RBTree<K,V,C>,
+    // ancestors of `curr`, each missing its child on the path
+    path: Vec<(Box<Node<K,V>>, Side)>,
+    // subtree at the cursor position, the whole tree at the ghost position
+    curr: Option<Box<Node<K,V>>>,
+    ghost: bool,
+}
+
+impl<K, V, C> RBTree<K, V, C> where C: Fn(&K, &K) -> Ordering {
+    /// Cursor positioned at the smallest entry
+    pub fn cursor(&mut self) -> Cursor<K,V,C> {
+        let root = self.root.take();
+        let mut cursor = Cursor {
+            tree: self,
+            path: vec![],
+            curr: root,
+            ghost: true,
+        };
+        cursor.next();
+        cursor
+    }
+}
+
+impl<'a, K, V, C> Cursor<'a, K, V, C> where K: 'a, V: 'a, C: 'a {
+    /// Entry at the cursor position
+    pub fn current(&self) -> Option<(&K, &V)> {
+        if self.ghost {
+            return None;
+        }
+        self.curr.as_ref().map(|n| (&n.key, n.value.as_ref().unwrap()))
+    }
+
+    /// Move the cursor to the smallest entry with a key greater or
+    /// equal to `key` (or to the ghost position if there is none).
+    pub fn seek(&mut self, key: &K) where C: Fn(&K, &K) -> Ordering {
+        self.up_to_root();
+        self.ghost = false;
+        loop {
+            let ord = match self.curr.as_ref() {
+                Some(n) => (self.tree.cmp)(key, &n.key),
+                None => break,
+            };
+            match ord {
+                Equal => return,
+                Less => self.down(Side::Left),
+                Greater => self.down(Side::Right),
+            }
+        }
+        // the closest ancestor with the key in its left subtree
+        self.up_from(Side::Left);
+    }
+
+    /// Move to the next entry and return it. Moving past the last
+    /// entry goes to the ghost position, and from there to the first
+    /// entry.
+    pub fn next(&mut self) -> Option<(&K, &V)> {
+        self.step(Side::Right);
+        self.current()
+    }
+
+    /// Move to the previous entry and return it. Mirror of `next()`.
+    pub fn prev(&mut self) -> Option<(&K, &V)> {
+        self.step(Side::Left);
+        self.current()
+    }
+
+    /// Remove the current entry, returning its value, and move the
+    /// cursor to the next entry. The node is detached where the
+    /// cursor is (its successor, always a leaf, takes its place if it
+    /// has a right child), and the tree is rebalanced bottom up along
+    /// the path, only as far as the lost black node has to be made up
+    /// for.
+    pub fn remove_current(&mut self) -> Option<V> {
+        if self.ghost {
+            return None;
+        }
+        let depth = self.path.len();
+        // position of the next entry in the subtree at `depth`
+        let mut index = self.curr.as_ref().unwrap().left.size();
+        let mut removed = if self.curr.as_ref().unwrap().right.is_some() {
+            self.down(Side::Right);
+            self.down_most(Side::Left);
+            let succ = self.curr.as_mut().unwrap();
+            let n = &mut self.path[depth].0;
+            mem::swap(&mut n.key, &mut succ.key);
+            mem::swap(&mut n.value, &mut succ.value);
+            self.curr.take().unwrap()
+        } else {
+            // no right child, so at most a red leaf on the left
+            let mut n = self.curr.take().unwrap();
+            self.curr = n.left.take();
+            n
+        };
+        let mut short = removed.color == Black;
+        loop {
+            if short && self.curr.is_red() {
+                self.curr.as_mut().unwrap().color = Black;
+                short = false;
+            }
+            if !short || self.path.is_empty() {
+                break;
+            }
+            let side = self.up_from_removal(depth, &mut index);
+            short = self.curr.as_mut().unwrap().fix_short(side);
+        }
+        while self.path.len() > depth || (index >= self.curr.size() && !self.path.is_empty()) {
+            self.up_from_removal(depth, &mut index);
+        }
+        if self.path.is_empty() {
+            if let Some(n) = self.curr.as_mut() {
+                n.color = Black;
+            }
+        }
+        self.down_to_nth(index);
+        removed.value.take()
+    }
+
+    // up() for remove_current(), keeping `index` relative to the
+    // current subtree once above the removal at `depth`
+    fn up_from_removal(&mut self, depth: usize, index: &mut usize) -> Side {
+        let side = self.up().unwrap();
+        if self.path.len() < depth && side == Side::Right {
+            *index += self.curr.as_ref().unwrap().left.size() + 1;
+        }
+        side
+    }
+
+    // in-order step towards `side` (Right for next, Left for prev)
+    fn step(&mut self, side: Side) {
+        let other = if side == Side::Right { Side::Left } else { Side::Right };
+        if self.ghost {
+            self.ghost = self.curr.is_none();
+            self.down_most(other);
+        } else if self.curr.as_ref().unwrap().child(side).is_some() {
+            self.down(side);
+            self.down_most(other);
+        } else {
+            self.up_from(other);
+        }
+    }
+
+    // go up until leaving a subtree on `side`, or to the ghost position
+    // if there is none
+    fn up_from(&mut self, side: Side) {
+        loop {
+            match self.up() {
+                Some(s) if s == side => return,
+                Some(_) => (),
+                None => {
+                    self.ghost = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn down_to_nth(&mut self, mut index: usize) {
+        if index >= self.curr.size() {
+            self.ghost = true;
+            return;
+        }
+        self.ghost = false;
+        loop {
+            let left = self.curr.as_ref().unwrap().left.size();
+            match index.cmp(&left) {
+                Equal => return,
+                Less => self.down(Side::Left),
+                Greater => {
+                    index -= left + 1;
+                    self.down(Side::Right);
+                }
+            }
+        }
+    }
+
+    fn down_most(&mut self, side: Side) {
+        while self.curr.as_ref().map_or(false, |n| n.child(side).is_some()) {
+            self.down(side);
+        }
+    }
+
+    fn down(&mut self, side: Side) {
+        let mut n = self.curr.take().unwrap();
+        self.curr = match side {
+            Side::Left => n.left.take(),
+            Side::Right => n.right.take(),
+        };
+        self.path.push((n, side));
+    }
+
+    // reattach the current subtree to its parent and move there,
+    // returning the side it was on
+    fn up(&mut self) -> Option<Side> {
+        match self.path.pop() {
+            Some((mut n, side)) => {
+                match side {
+                    Side::Left => n.left = self.curr.take(),
+                    Side::Right => n.right = self.curr.take(),
+                }
+                // the subtree may have lost an entry meanwhile
+                n.update_size();
+                self.curr = Some(n);
+                Some(side)
+            }
+            None => None,
+        }
+    }
+
+    fn up_to_root(&mut self) {
+        while self.up().is_some() {}
+    }
+}
+
+impl<'a, K, V, C> Drop for Cursor<'a, K, V, C> where K: 'a, V: 'a, C: 'a {
+    fn drop(&mut self) {
+        self.up_to_root();
+        self.tree.root = self.curr.take();
     }
 }
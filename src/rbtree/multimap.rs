@@ -0,0 +1,154 @@
+/// Ordered multimap on top of `RBTree`, storing the values of each key
+/// in insertion order.
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::collections::vec_deque;
+
+use rbtree::{RBTree, Comparator};
+use rbtree;
+
+pub struct RBMultiMap<K, V, C = Comparator<K>> {
+    tree: RBTree<K,VecDeque<V>,C>,
+    // number of values, the tree only counts keys
+    len: usize,
+}
+
+impl<K,V> RBMultiMap<K,V> where K: Ord {
+    pub fn new() -> Self {
+        RBMultiMap {
+            tree: RBTree::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<K, V, C> RBMultiMap<K, V, C> where C: Fn(&K, &K) -> Ordering {
+    /// Empty multimap ordered by the given function
+    pub fn with_comparator(cmp: C) -> Self {
+        RBMultiMap {
+            tree: RBTree::with_comparator(cmp),
+            len: 0,
+        }
+    }
+
+    /// Number of values, counting each entry of a repeated key
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.tree.contains_key(key)
+    }
+
+    /// Adds a value after any others already stored for the key
+    pub fn insert(&mut self, key: K, value: V) {
+        let rest = match self.tree.get_mut(&key) {
+            Some(values) => {
+                values.push_back(value);
+                None
+            }
+            None => Some(value),
+        };
+        if let Some(value) = rest {
+            let mut values = VecDeque::new();
+            values.push_back(value);
+            self.tree.insert(key, values);
+        }
+        self.len += 1;
+    }
+
+    /// Values stored for the key, in insertion order
+    pub fn get_all(&self, key: &K) -> GetAll<V> {
+        GetAll {
+            iter: self.tree.get(key).map(|values| values.iter()),
+        }
+    }
+
+    /// Removes the oldest value stored for the key
+    pub fn remove_one(&mut self, key: &K) -> Option<V> {
+        let (value, emptied) = match self.tree.get_mut(key) {
+            Some(values) => (values.pop_front(), values.is_empty()),
+            None => return None,
+        };
+        if emptied {
+            self.tree.remove(key);
+        }
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+
+    /// Removes all the values stored for the key, returning them in
+    /// insertion order
+    pub fn remove_all(&mut self, key: &K) -> Vec<V> {
+        match self.tree.remove(key) {
+            Some(values) => {
+                self.len -= values.len();
+                values.into_iter().collect()
+            }
+            None => vec![],
+        }
+    }
+}
+
+// Iterators ---------------------------------------------
+
+/// Values of one key, in insertion order
+pub struct GetAll<'a, V> where V: 'a {
+    iter: Option<vec_deque::Iter<'a, V>>,
+}
+
+impl<'a, V> Iterator for GetAll<'a, V> where V: 'a {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        match self.iter.as_mut() {
+            Some(iter) => iter.next(),
+            None => None,
+        }
+    }
+}
+
+/// Entries in key order, values of the same key in insertion order
+pub struct Iter<'a, K, V> where K: 'a, V: 'a {
+    iter: rbtree::Iter<'a, K, VecDeque<V>>,
+    current: Option<(&'a K, vec_deque::Iter<'a, V>)>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> where K: 'a, V: 'a {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, ref mut values)) = self.current {
+                if let Some(value) = values.next() {
+                    return Some((key, value));
+                }
+            }
+            match self.iter.next() {
+                Some((key, values)) => self.current = Some((key, values.iter())),
+                None => return None,
+            }
+        }
+    }
+}
+
+impl<'a, K, V, C> RBMultiMap<K, V, C> {
+    pub fn iter(&'a self) -> Iter<'a, K, V> {
+        Iter {
+            iter: self.tree.iter(),
+            current: None,
+        }
+    }
+}
+
+impl<'a, K, V, C> IntoIterator for &'a RBMultiMap<K, V, C> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
@@ -0,0 +1,351 @@
+/// Persistent Left-leaning Red-Black Tree.
+///
+/// Nodes are shared through `Arc`, so cloning a tree is O(1) and
+/// `insert`/`remove` return a new tree that shares all the subtrees
+/// not in the modified path. Snapshots can be sent to other threads.
+///
+/// The algorithms are the same as the mutable `RBTree`, using
+/// `Arc::make_mut` to get a private copy of each node before changing
+/// it (nodes not shared with another tree are changed in place).
+
+use std::sync::Arc;
+use std::mem;
+use std::cmp::Ordering;
+use std::cmp::Ordering::*;
+
+use rbtree::{Color, Comparator};
+use rbtree::Color::*;
+
+type Link<K,V> = Option<Arc<Node<K,V>>>;
+
+pub struct RBTree<K, V, C = Comparator<K>> {
+    root: Link<K,V>,
+    len: usize,
+    // shared by all the versions of the tree
+    cmp: Arc<C>,
+}
+
+impl<K,V,C> Clone for RBTree<K,V,C> {
+    fn clone(&self) -> Self {
+        RBTree {
+            root: self.root.clone(),
+            len: self.len,
+            cmp: self.cmp.clone(),
+        }
+    }
+}
+
+impl<K,V> RBTree<K,V> where K: Ord {
+    pub fn new() -> Self {
+        Self::with_comparator(K::cmp)
+    }
+}
+
+impl<K,V,C> RBTree<K,V,C> where C: Fn(&K, &K) -> Ordering {
+    /// Empty tree ordered by the given function
+    pub fn with_comparator(cmp: C) -> Self {
+        RBTree {
+            root: None,
+            len: 0,
+            cmp: Arc::new(cmp),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let mut curr = &self.root;
+        loop {
+            match curr.as_ref() {
+                Some(n) => {
+                    match (self.cmp)(key, &n.key) {
+                        Equal => { return Some(&n.value) }
+                        Less => { curr = &n.left; }
+                        Greater => { curr = &n.right; }
+                    }
+                }
+                None => return None,
+            }
+        }
+    }
+}
+
+impl<K,V,C> RBTree<K,V,C> where K: Clone, V: Clone, C: Fn(&K, &K) -> Ordering {
+    /// Returns a new tree with the entry inserted (or its value
+    /// replaced). `self` is left unchanged.
+    pub fn insert(&self, key: K, value: V) -> Self {
+        let mut tree = self.clone();
+        if insert(&mut tree.root, key, value, &*self.cmp).is_none() {
+            tree.len += 1;
+        }
+        tree.set_root_black();
+        tree
+    }
+
+    /// Returns a new tree without the given key. `self` is left
+    /// unchanged.
+    pub fn remove(&self, key: &K) -> Self {
+        let mut tree = self.clone();
+        if self.get(key).is_none() {
+            // avoid copying the search path
+            return tree;
+        }
+        if let Some(n) = tree.root.as_mut() {
+            if !is_red(&n.left) && !is_red(&n.right) {
+                Arc::make_mut(n).color = Red;
+            }
+        }
+        if remove(&mut tree.root, key, &*self.cmp).is_some() {
+            tree.len -= 1;
+        }
+        tree.set_root_black();
+        tree
+    }
+
+    fn set_root_black(&mut self) {
+        if let Some(n) = self.root.as_mut() {
+            if n.color != Black {
+                Arc::make_mut(n).color = Black;
+            }
+        }
+    }
+}
+
+/// Node ------------------
+#[derive(Clone)]
+struct Node<K,V> {
+    key: K,
+    value: V,
+    color: Color,
+    left: Link<K,V>,
+    right: Link<K,V>,
+}
+
+fn is_red<K,V>(link: &Link<K,V>) -> bool {
+    match link.as_ref() {
+        Some(n) => n.color == Red,
+        None => false,
+    }
+}
+
+/// Private copy of the node pointed by `link`, which should not be empty
+fn node_mut<K,V>(link: &mut Link<K,V>) -> &mut Node<K,V> where K: Clone, V: Clone {
+    Arc::make_mut(link.as_mut().unwrap())
+}
+
+/// Take the node out of the `Arc`, copying it if it is shared
+fn into_node<K,V>(node: Arc<Node<K,V>>) -> Node<K,V> where K: Clone, V: Clone {
+    match Arc::try_unwrap(node) {
+        Ok(n) => n,
+        Err(shared) => (*shared).clone(),
+    }
+}
+
+impl<K,V> Node<K,V> where K: Clone, V: Clone {
+    /// "split" a 4-node
+    fn color_flip(&mut self) {
+        self.color = self.color.inverse();
+        if self.left.is_some() {
+            let n = node_mut(&mut self.left);
+            n.color = n.color.inverse();
+        }
+        if self.right.is_some() {
+            let n = node_mut(&mut self.right);
+            n.color = n.color.inverse();
+        }
+    }
+
+    /// rotate left to fix right-leaning red
+    fn rotate_left(&mut self) {
+        let mut right = self.right.take().unwrap();
+        {
+            let r = Arc::make_mut(&mut right);
+            self.right = r.left.take();
+            mem::swap(self, r);
+            // self is now the old right node and `r` the old self
+            self.color = r.color.clone();
+            r.color = Red;
+        }
+        self.left = Some(right);
+    }
+
+    /// rotate right to fix red-red egde
+    fn rotate_right(&mut self) {
+        let mut left = self.left.take().unwrap();
+        {
+            let l = Arc::make_mut(&mut left);
+            self.left = l.right.take();
+            mem::swap(self, l);
+            // self is now the old left node and `l` the old self
+            self.color = l.color.clone();
+            l.color = Red;
+        }
+        self.right = Some(left);
+    }
+
+    /// restore the left-leaning invariants on the way up
+    fn fix_up(&mut self) {
+        // fix right-leaning red
+        if is_red(&self.right) && !is_red(&self.left) {
+            self.rotate_left();
+        }
+        // fix two reds in a row
+        if is_red(&self.left) && is_red(&self.left.as_ref().unwrap().left) {
+            self.rotate_right();
+        }
+        // split 4-nodes
+        if is_red(&self.left) && is_red(&self.right) {
+            self.color_flip();
+        }
+    }
+
+    fn move_red_right(&mut self) {
+        self.color_flip();
+        if is_red(&self.left.as_ref().unwrap().left) {
+            self.rotate_right();
+            self.color_flip();
+        }
+    }
+
+    fn move_red_left(&mut self) {
+        self.color_flip();
+        if is_red(&self.right.as_ref().unwrap().left) {
+            node_mut(&mut self.right).rotate_right();
+            self.rotate_left();
+            self.color_flip();
+        }
+    }
+}
+
+fn insert<K,V,C>(link: &mut Link<K,V>, key: K, value: V, cmp: &C) -> Option<V>
+    where K: Clone, V: Clone, C: Fn(&K, &K) -> Ordering {
+    if let Some(n) = link.as_mut() {
+        let n = Arc::make_mut(n);
+        let ret = match cmp(&key, &n.key) {
+            Equal => Some(mem::replace(&mut n.value, value)),
+            Less => insert(&mut n.left, key, value, cmp),
+            Greater => insert(&mut n.right, key, value, cmp),
+        };
+        n.fix_up();
+        return ret;
+    }
+    *link = Some(Arc::new(Node {
+        key: key,
+        value: value,
+        color: Red,
+        left: None,
+        right: None,
+    }));
+    None
+}
+
+/// Detach the node with the smallest key
+fn remove_min<K,V>(link: &mut Link<K,V>) -> Option<Arc<Node<K,V>>>
+    where K: Clone, V: Clone {
+    match link.as_mut() {
+        None => return None,
+        Some(n) => {
+            let n = Arc::make_mut(n);
+            if n.left.is_some() {
+                if !is_red(&n.left) && !is_red(&n.left.as_ref().unwrap().left) {
+                    n.move_red_left();
+                }
+                let ret = remove_min(&mut n.left);
+                n.fix_up();
+                return ret;
+            }
+        }
+    }
+    link.take()
+}
+
+fn remove<K,V,C>(link: &mut Link<K,V>, key: &K, cmp: &C) -> Option<V>
+    where K: Clone, V: Clone, C: Fn(&K, &K) -> Ordering {
+    let mut remove_self = false;
+    let mut ret = None;
+    match link.as_mut() {
+        None => return None,
+        Some(n) => {
+            let n = Arc::make_mut(n);
+            if cmp(key, &n.key) == Less {
+                if n.left.is_none() { // not found
+                    return None;
+                }
+                if !is_red(&n.left) && !is_red(&n.left.as_ref().unwrap().left) {
+                    n.move_red_left();
+                }
+                ret = remove(&mut n.left, key, cmp);
+            }
+            else {
+                if is_red(&n.left) {
+                    n.rotate_right();
+                }
+                if cmp(key, &n.key) == Equal && n.right.is_none() {
+                    remove_self = true;
+                } else if n.right.is_some() {
+                    if !is_red(&n.right) && !is_red(&n.right.as_ref().unwrap().left) {
+                        n.move_red_right();
+                    }
+                    if cmp(key, &n.key) == Equal {
+                        // take the place of the successor
+                        let min_right = into_node(remove_min(&mut n.right).unwrap());
+                        n.key = min_right.key;
+                        ret = Some(mem::replace(&mut n.value, min_right.value));
+                    } else {
+                        ret = remove(&mut n.right, key, cmp);
+                    }
+                }
+            }
+            if !remove_self {
+                n.fix_up();
+            }
+        }
+    }
+    if remove_self {
+        return link.take().map(|n| into_node(n).value);
+    }
+    ret
+}
+
+// Iterators ---------------------------------------------
+
+pub struct Iter<'a, K, V> where K: 'a, V: 'a {
+    // nodes still to be visited, the next one on top
+    stack: Vec<&'a Node<K,V>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> where K: 'a, V: 'a {
+    fn push_leftmost(&mut self, mut curr: &'a Link<K,V>) {
+        while let Some(n) = curr.as_ref() {
+            self.stack.push(&**n);
+            curr = &n.left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> where K: 'a, V: 'a {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.stack.pop() {
+            Some(n) => {
+                self.push_leftmost(&n.right);
+                Some((&n.key, &n.value))
+            }
+            None => None,
+        }
+    }
+}
+
+impl<'a, K, V, C> RBTree<K, V, C> {
+    /// Iterate over the entries in key order
+    pub fn iter(&'a self) -> Iter<'a, K, V> {
+        let mut iter = Iter {
+            stack: vec![],
+        };
+        iter.push_leftmost(&self.root);
+        iter
+    }
+}
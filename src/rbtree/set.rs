@@ -0,0 +1,191 @@
+/// Ordered set on top of `RBTree`, storing `()` as values.
+
+use std::cmp::Ordering;
+use std::cmp::Ordering::*;
+use std::iter::Peekable;
+
+use rbtree::{RBTree, Comparator};
+use rbtree;
+
+pub struct RBSet<K, C = Comparator<K>> {
+    tree: RBTree<K,(),C>,
+}
+
+impl<K> RBSet<K> where K: Ord {
+    pub fn new() -> Self {
+        RBSet {
+            tree: RBTree::new(),
+        }
+    }
+}
+
+impl<K, C> RBSet<K, C> where C: Fn(&K, &K) -> Ordering {
+    /// Empty set ordered by the given function
+    pub fn with_comparator(cmp: C) -> Self {
+        RBSet {
+            tree: RBTree::with_comparator(cmp),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Adds a key to the set. Returns false if it was already present.
+    pub fn insert(&mut self, key: K) -> bool {
+        self.tree.insert(key, ()).is_none()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.tree.contains_key(key)
+    }
+
+    /// Removes a key from the set. Returns false if it was not present.
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.tree.remove(key).is_some()
+    }
+}
+
+// Iterators ---------------------------------------------
+
+/// Keys of the set, in order
+pub struct Iter<'a, K> where K: 'a {
+    iter: rbtree::Iter<'a, K, ()>,
+}
+
+impl<'a, K> Iterator for Iter<'a, K> where K: 'a {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.iter.next().map(|(k, _)| k)
+    }
+}
+
+/// Keys in either set, in order and without repetition
+pub struct Union<'a, K, C = Comparator<K>> where K: 'a, C: 'a {
+    a: Peekable<Iter<'a, K>>,
+    b: Peekable<Iter<'a, K>>,
+    cmp: &'a C,
+}
+
+impl<'a, K, C> Iterator for Union<'a, K, C> where K: 'a, C: Fn(&K, &K) -> Ordering {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        let order = match (self.a.peek(), self.b.peek()) {
+            (Some(x), Some(y)) => (self.cmp)(x, y),
+            (Some(_), None) => Less,
+            (None, Some(_)) => Greater,
+            (None, None) => return None,
+        };
+        match order {
+            Less => self.a.next(),
+            Greater => self.b.next(),
+            Equal => {
+                self.b.next();
+                self.a.next()
+            }
+        }
+    }
+}
+
+/// Keys in both sets, in order
+pub struct Intersection<'a, K, C = Comparator<K>> where K: 'a, C: 'a {
+    a: Peekable<Iter<'a, K>>,
+    b: Peekable<Iter<'a, K>>,
+    cmp: &'a C,
+}
+
+impl<'a, K, C> Iterator for Intersection<'a, K, C> where K: 'a, C: Fn(&K, &K) -> Ordering {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        loop {
+            let order = match (self.a.peek(), self.b.peek()) {
+                (Some(x), Some(y)) => (self.cmp)(x, y),
+                _ => return None,
+            };
+            match order {
+                Less => { self.a.next(); }
+                Greater => { self.b.next(); }
+                Equal => {
+                    self.b.next();
+                    return self.a.next();
+                }
+            }
+        }
+    }
+}
+
+/// Keys in the first set but not in the second, in order
+pub struct Difference<'a, K, C = Comparator<K>> where K: 'a, C: 'a {
+    a: Peekable<Iter<'a, K>>,
+    b: Peekable<Iter<'a, K>>,
+    cmp: &'a C,
+}
+
+impl<'a, K, C> Iterator for Difference<'a, K, C> where K: 'a, C: Fn(&K, &K) -> Ordering {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        loop {
+            let order = match (self.a.peek(), self.b.peek()) {
+                (Some(x), Some(y)) => (self.cmp)(x, y),
+                (Some(_), None) => Less,
+                (None, _) => return None,
+            };
+            match order {
+                Less => return self.a.next(),
+                Greater => { self.b.next(); }
+                Equal => {
+                    self.a.next();
+                    self.b.next();
+                }
+            }
+        }
+    }
+}
+
+// The set operations walk both sets in order, so both should use the
+// same ordering. `self`'s comparator is used.
+impl<'a, K, C> RBSet<K, C> {
+    /// Iterate over the keys in order
+    pub fn iter(&'a self) -> Iter<'a, K> {
+        Iter {
+            iter: self.tree.iter(),
+        }
+    }
+
+    pub fn union(&'a self, other: &'a RBSet<K, C>) -> Union<'a, K, C> {
+        Union {
+            a: self.iter().peekable(),
+            b: other.iter().peekable(),
+            cmp: &self.tree.cmp,
+        }
+    }
+
+    pub fn intersection(&'a self, other: &'a RBSet<K, C>) -> Intersection<'a, K, C> {
+        Intersection {
+            a: self.iter().peekable(),
+            b: other.iter().peekable(),
+            cmp: &self.tree.cmp,
+        }
+    }
+
+    pub fn difference(&'a self, other: &'a RBSet<K, C>) -> Difference<'a, K, C> {
+        Difference {
+            a: self.iter().peekable(),
+            b: other.iter().peekable(),
+            cmp: &self.tree.cmp,
+        }
+    }
+}
+
+impl<'a, K, C> IntoIterator for &'a RBSet<K, C> {
+    type Item = &'a K;
+    type IntoIter = Iter<'a, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
@@ -1,7 +1,8 @@
-use rbtree::{RBTree, BoxedNode};
-use std::fmt::Debug;
-use std::io::{stdout, Write};
+use rbtree::{RBTree, RBSet, RBMultiMap, BoxedNode, Node, Color};
+use rbtree::persistent;
+use rbtree::arena;
 use rand::{thread_rng, Rng};
+use std::thread;
 
 #[test]
 fn test_get() {
@@ -20,6 +21,325 @@ fn test_get() {
     assert_eq!(tree.max(), Some(&999));
 }
 
+#[test]
+fn test_get_key_value() {
+    // keys carry a name that is not used for the ordering
+    let mut tree = RBTree::with_comparator(|a: &(i32, &str), b: &(i32, &str)| a.0.cmp(&b.0));
+    tree.insert((1, "one"), 10);
+    tree.insert((2, "two"), 20);
+    assert!(tree.contains_key(&(1, "")));
+    assert!(!tree.contains_key(&(3, "")));
+    assert_eq!(tree.get_key_value(&(2, "")), Some((&(2, "two"), &20)));
+    assert_eq!(tree.get_key_value(&(3, "")), None);
+}
+
+#[test]
+fn test_iter() {
+    let mut tree = RBTree::new();
+    let mut rng = thread_rng();
+    let mut shuffled = (0..1000).collect::<Vec<_>>();
+    rng.shuffle(shuffled.as_mut_slice());
+    for i in shuffled.into_iter() {
+        tree.insert(i,i*2);
+    }
+    let items = tree.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+    assert_eq!(items, (0..1000).map(|i| (i, i*2)).collect::<Vec<_>>());
+    assert_eq!(RBTree::<i32,i32>::new().iter().next(), None);
+}
+
+#[test]
+fn test_set() {
+    let mut a = RBSet::new();
+    let mut b = RBSet::new();
+    for i in 0..20 {
+        assert!(a.insert(i));
+        if i % 3 == 0 {
+            assert!(b.insert(i));
+        }
+    }
+    for i in 20..25 {
+        b.insert(i);
+    }
+    assert!(!a.insert(0));
+    assert!(a.contains(&10));
+    assert!(a.remove(&10));
+    assert!(!a.remove(&10));
+    assert!(!a.contains(&10));
+    assert_eq!(a.len(), 19);
+
+    let union = a.union(&b).cloned().collect::<Vec<_>>();
+    let mut expected = (0..25).collect::<Vec<_>>();
+    expected.retain(|&i| i != 10);
+    assert_eq!(union, expected);
+
+    let intersection = a.intersection(&b).cloned().collect::<Vec<_>>();
+    assert_eq!(intersection, vec![0, 3, 6, 9, 12, 15, 18]);
+
+    let difference = a.difference(&b).cloned().collect::<Vec<_>>();
+    assert_eq!(difference, vec![1, 2, 4, 5, 7, 8, 11, 13, 14, 16, 17, 19]);
+    let difference = b.difference(&a).cloned().collect::<Vec<_>>();
+    assert_eq!(difference, vec![20, 21, 22, 23, 24]);
+}
+
+#[test]
+fn test_multimap() {
+    let mut map = RBMultiMap::new();
+    for i in 0..30 {
+        map.insert(i % 3, i);
+    }
+    assert_eq!(map.len(), 30);
+    assert!(map.contains_key(&2));
+    assert_eq!(map.get_all(&1).cloned().collect::<Vec<_>>(), (0..10).map(|i| i*3 + 1).collect::<Vec<_>>());
+    assert_eq!(map.get_all(&3).next(), None);
+
+    let entries = map.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+    let mut expected = (0..30).map(|i| (i % 3, i)).collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(entries, expected);
+
+    assert_eq!(map.remove_one(&0), Some(0));
+    assert_eq!(map.remove_one(&0), Some(3));
+    assert_eq!(map.remove_one(&3), None);
+    assert_eq!(map.len(), 28);
+    assert_eq!(map.remove_all(&0), (2..10).map(|i| i*3).collect::<Vec<_>>());
+    assert!(!map.contains_key(&0));
+    assert_eq!(map.remove_all(&0), vec![]);
+    assert_eq!(map.len(), 20);
+
+    map.insert(5, 50);
+    assert_eq!(map.remove_one(&5), Some(50));
+    assert!(!map.contains_key(&5));
+    assert_eq!(map.len(), 20);
+}
+
+#[test]
+fn test_comparator() {
+    let mut tree = RBTree::with_comparator(|a: &i32, b: &i32| b.cmp(a));
+    for i in 0..100 {
+        tree.insert(i,i);
+    }
+    let keys = tree.iter().map(|(&k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys, (0..100).rev().collect::<Vec<_>>());
+    assert_eq!(tree.min(), Some(&99));
+    assert_eq!(tree.floor(&100), None);
+    assert_eq!(tree.ceiling(&100), Some((&99, &99)));
+    let right = tree.split_off(&50);
+    assert_eq!(right.get(&10), Some(&10));
+    assert_eq!(tree.get(&10), None);
+    tree.join(right);
+    for i in 0..100 {
+        assert_eq!(tree.remove(&i), Some(i));
+    }
+
+    // keys without a total order
+    let mut tree = RBTree::with_comparator(|a: &f64, b: &f64| a.partial_cmp(b).unwrap());
+    for i in 0..100 {
+        tree.insert(i as f64 / 2.0, i);
+    }
+    assert_eq!(tree.get(&1.5), Some(&3));
+    assert_eq!(tree.ceiling(&1.2), Some((&1.5, &3)));
+
+    let reversed = |a: &i32, b: &i32| b.cmp(a);
+    let mut a = RBSet::with_comparator(reversed);
+    let mut b = RBSet::with_comparator(reversed);
+    for i in 0..10 {
+        a.insert(i);
+        b.insert(i+5);
+    }
+    assert_eq!(a.intersection(&b).cloned().collect::<Vec<_>>(), vec![9, 8, 7, 6, 5]);
+
+    // comparators with state, e.g. a collation table
+    let rank = vec![3, 0, 4, 1, 2];
+    let mut tree = RBTree::with_comparator(move |a: &usize, b: &usize| rank[*a].cmp(&rank[*b]));
+    for i in 0..5 {
+        tree.insert(i, ());
+    }
+    assert_eq!(tree.iter().map(|(&k, _)| k).collect::<Vec<_>>(), vec![1, 3, 4, 0, 2]);
+    let right = tree.split_off(&0);
+    assert_eq!(right.iter().map(|(&k, _)| k).collect::<Vec<_>>(), vec![0, 2]);
+    let mut cursor = tree.cursor();
+    cursor.seek(&3);
+    assert_eq!(cursor.current().map(|(&k, _)| k), Some(3));
+
+    let names = vec!["b", "c", "a"];
+    let mut map = RBMultiMap::with_comparator(|a: &usize, b: &usize| names[*a].cmp(names[*b]));
+    map.insert(0, 'x');
+    map.insert(2, 'y');
+    map.insert(0, 'z');
+    assert_eq!(map.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(),
+               vec![(2, 'y'), (0, 'x'), (0, 'z')]);
+
+    let rank = [2, 1, 0];
+    let tree = persistent::RBTree::with_comparator(move |a: &usize, b: &usize| rank[*a].cmp(&rank[*b]));
+    let tree = tree.insert(0, 'a').insert(1, 'b').insert(2, 'c');
+    assert_eq!(tree.iter().map(|(&k, _)| k).collect::<Vec<_>>(), vec![2, 1, 0]);
+    let removed = tree.remove(&1);
+    assert_eq!(removed.get(&1), None);
+    assert_eq!(tree.get(&1), Some(&'b'));
+
+    let mut tree = arena::RBTree::with_comparator(move |a: &usize, b: &usize| rank[*a].cmp(&rank[*b]));
+    for i in 0..3 {
+        tree.insert(i, ());
+    }
+    assert_eq!(tree.iter().map(|(&k, _)| k).collect::<Vec<_>>(), vec![2, 1, 0]);
+    assert_eq!(tree.successor(&1).map(|(&k, _)| k), Some(0));
+    assert_eq!(tree.remove(&2), Some(()));
+    assert_eq!(tree.iter().map(|(&k, _)| k).collect::<Vec<_>>(), vec![1, 0]);
+}
+
+#[test]
+fn test_persistent() {
+    let mut rng = thread_rng();
+    let mut shuffled = (0..1000).collect::<Vec<_>>();
+    rng.shuffle(shuffled.as_mut_slice());
+
+    let mut tree = persistent::RBTree::new();
+    let mut snapshots = vec![];
+    for (n, &i) in shuffled.iter().enumerate() {
+        if n % 100 == 0 {
+            snapshots.push(tree.clone());
+        }
+        tree = tree.insert(i, i);
+    }
+    assert_eq!(tree.len(), 1000);
+    let items = tree.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+    assert_eq!(items, (0..1000).map(|i| (i, i)).collect::<Vec<_>>());
+    // snapshots are unchanged
+    for (s, snapshot) in snapshots.iter().enumerate() {
+        assert_eq!(snapshot.len(), s*100);
+        for (n, i) in shuffled.iter().enumerate() {
+            assert_eq!(snapshot.get(i).is_some(), n < s*100);
+        }
+    }
+
+    let full = tree.clone();
+    let replaced = tree.insert(10, 100);
+    assert_eq!(replaced.get(&10), Some(&100));
+    assert_eq!(tree.get(&10), Some(&10));
+    assert_eq!(replaced.len(), 1000);
+
+    rng.shuffle(shuffled.as_mut_slice());
+    for (n, i) in shuffled.iter().enumerate() {
+        assert_eq!(tree.remove(&-1).len(), 1000 - n);
+        tree = tree.remove(i);
+        assert_eq!(tree.get(i), None);
+        assert_eq!(tree.len(), 999 - n);
+    }
+    assert_eq!(full.len(), 1000);
+    assert_eq!(full.iter().count(), 1000);
+}
+
+#[test]
+fn test_persistent_threads() {
+    let mut tree = persistent::RBTree::new();
+    for i in 0..100 {
+        tree = tree.insert(i, i.to_string());
+    }
+    let snapshot = tree.clone();
+    let reader = thread::spawn(move || {
+        (0..100).all(|i| snapshot.get(&i) == Some(&i.to_string()))
+    });
+    for i in 0..100 {
+        tree = tree.remove(&i);
+    }
+    assert!(reader.join().unwrap());
+    assert_eq!(tree.len(), 0);
+}
+
+#[test]
+fn test_arena() {
+    let mut rng = thread_rng();
+    let mut shuffled = (0..1000).collect::<Vec<_>>();
+    rng.shuffle(shuffled.as_mut_slice());
+
+    let mut tree = arena::RBTree::with_capacity(1000);
+    for &i in shuffled.iter() {
+        assert_eq!(tree.insert(i, i.to_string()), None);
+        tree.check_invariants();
+    }
+    assert_eq!(tree.insert(10, "ten".to_string()), Some("10".to_string()));
+    tree.check_invariants();
+    assert_eq!(tree.len(), 1000);
+    let keys = tree.iter().map(|(&k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+
+    rng.shuffle(shuffled.as_mut_slice());
+    for (n, i) in shuffled.iter().enumerate() {
+        assert_eq!(tree.remove(&-1), None);
+        let expected = if *i == 10 { "ten".to_string() } else { i.to_string() };
+        assert_eq!(tree.remove(i), Some(expected));
+        assert_eq!(tree.get(i), None);
+        assert_eq!(tree.len(), 999 - n);
+        tree.check_invariants();
+        // the entries moved around the arena are still reachable
+        for j in shuffled[n+1..].iter().take(10) {
+            assert!(tree.get(j).is_some());
+        }
+    }
+    assert_eq!(tree.iter().count(), 0);
+}
+
+#[test]
+fn test_arena_traversal() {
+    let mut rng = thread_rng();
+    let mut shuffled = (0..200).map(|i| i*2).collect::<Vec<_>>();
+    rng.shuffle(shuffled.as_mut_slice());
+
+    let mut tree = arena::RBTree::new();
+    for &i in shuffled.iter() {
+        tree.insert(i, i);
+    }
+    let keys = tree.iter().rev().map(|(&k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys, (0..200).rev().map(|i| i*2).collect::<Vec<_>>());
+    assert_eq!(tree.successor(&10), Some((&12, &12)));
+    assert_eq!(tree.successor(&11), Some((&12, &12)));
+    assert_eq!(tree.successor(&398), None);
+    assert_eq!(tree.predecessor(&10), Some((&8, &8)));
+    assert_eq!(tree.predecessor(&0), None);
+
+    let mut cursor = tree.cursor();
+    assert_eq!(cursor.current(), Some((&0, &0)));
+    assert_eq!(cursor.prev(), None);
+    assert_eq!(cursor.prev(), Some((&398, &398)));
+    cursor.seek(&101);
+    assert_eq!(cursor.current(), Some((&102, &102)));
+    // remove every other entry, the cursor keeps its place
+    while let Some((k, _)) = cursor.remove_current() {
+        assert_eq!(cursor.current().map(|(&k, _)| k), if k < 398 { Some(k + 2) } else { None });
+        if cursor.current().is_none() {
+            break;
+        }
+        cursor.next();
+    }
+    assert_eq!(cursor.next(), Some((&0, &0)));
+    tree.check_invariants();
+    let keys = tree.iter().map(|(&k, _)| k).collect::<Vec<_>>();
+    let expected = (0..200).map(|i| i*2).filter(|&i| i < 102 || (i - 102) % 4 == 2).collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+    assert_eq!(tree.len(), expected.len());
+}
+
+#[test]
+fn test_floor_ceiling() {
+    let mut tree = RBTree::new();
+    assert_eq!(tree.floor(&1), None);
+    assert_eq!(tree.ceiling(&1), None);
+
+    for i in 1..100 {
+        tree.insert(i*10,i);
+    }
+    assert_eq!(tree.floor(&5), None);
+    assert_eq!(tree.ceiling(&5), Some((&10, &1)));
+    assert_eq!(tree.floor(&995), Some((&990, &99)));
+    assert_eq!(tree.ceiling(&995), None);
+    for i in 1..100 {
+        assert_eq!(tree.floor(&(i*10)), Some((&(i*10), &i)));
+        assert_eq!(tree.ceiling(&(i*10)), Some((&(i*10), &i)));
+        assert_eq!(tree.floor(&(i*10+5)), Some((&(i*10), &i)));
+        assert_eq!(tree.ceiling(&(i*10-5)), Some((&(i*10), &i)));
+    }
+}
+
 #[test]
 fn test_remove_min() {
     let mut tree = RBTree::new();
@@ -57,51 +377,290 @@ fn test_remove() {
     }
 }
 
+#[test]
+fn test_remove_random() {
+    let mut tree = RBTree::new();
+    let mut rng = thread_rng();
+    let mut shuffled = (0..1000).collect::<Vec<_>>();
+    rng.shuffle(shuffled.as_mut_slice());
+    for &i in shuffled.iter() {
+        tree.insert(i*2,i);
+    }
+    rng.shuffle(shuffled.as_mut_slice());
+    for (n, &i) in shuffled.iter().enumerate() {
+        assert_eq!(tree.remove(&(i*2+1)), None);
+        assert_eq!(tree.remove(&(i*2)), Some(i));
+        assert_eq!(tree.len(), 999 - n);
+        if n % 100 == 0 {
+            check_invariants(&tree);
+        }
+    }
+    assert_eq!(tree.remove(&0), None);
+}
+
+#[test]
+fn test_cursor() {
+    let mut tree = RBTree::new();
+    for i in 0..100 {
+        tree.insert(i*2,i);
+    }
+    let mut cursor = tree.cursor();
+    assert_eq!(cursor.current(), Some((&0, &0)));
+    for i in 1..100 {
+        assert_eq!(cursor.next(), Some((&(i*2), &i)));
+    }
+    assert_eq!(cursor.next(), None);
+    assert_eq!(cursor.next(), Some((&0, &0)));
+    assert_eq!(cursor.prev(), None);
+    for i in (0..100).rev() {
+        assert_eq!(cursor.prev(), Some((&(i*2), &i)));
+    }
+
+    cursor.seek(&50);
+    assert_eq!(cursor.current(), Some((&50, &25)));
+    cursor.seek(&51);
+    assert_eq!(cursor.current(), Some((&52, &26)));
+    assert_eq!(cursor.prev(), Some((&50, &25)));
+    cursor.seek(&-1);
+    assert_eq!(cursor.current(), Some((&0, &0)));
+    cursor.seek(&199);
+    assert_eq!(cursor.current(), None);
+    assert_eq!(cursor.prev(), Some((&198, &99)));
+}
+
+#[test]
+fn test_cursor_remove() {
+    let mut tree = RBTree::new();
+    for i in 0..1000 {
+        tree.insert(i,i);
+    }
+    {
+        // remove odd keys in a single scan
+        let mut cursor = tree.cursor();
+        while let Some((&k, _)) = cursor.current() {
+            if k % 2 == 1 {
+                assert_eq!(cursor.remove_current(), Some(k));
+            } else {
+                cursor.next();
+            }
+        }
+        cursor.seek(&500);
+        assert_eq!(cursor.remove_current(), Some(500));
+        assert_eq!(cursor.current(), Some((&502, &502)));
+    }
+    check_invariants(&tree);
+    assert_eq!(tree.len(), 499);
+    for i in 0..1000 {
+        if i % 2 == 1 || i == 500 {
+            assert_eq!(tree.get(&i), None);
+        } else {
+            assert_eq!(tree.get(&i), Some(&i));
+        }
+    }
+}
+
+#[test]
+fn test_cursor_remove_random() {
+    let mut rng = thread_rng();
+    let mut keys = (0..500).collect::<Vec<_>>();
+    rng.shuffle(keys.as_mut_slice());
+    let mut tree = RBTree::new();
+    for &i in keys.iter() {
+        tree.insert(i,i);
+    }
+    rng.shuffle(keys.as_mut_slice());
+    for (n, &i) in keys.iter().enumerate() {
+        {
+            let mut cursor = tree.cursor();
+            cursor.seek(&i);
+            assert_eq!(cursor.remove_current(), Some(i));
+            // moved to the next key still in the tree
+            let next = keys[n+1..].iter().cloned().filter(|&k| k > i).min();
+            assert_eq!(cursor.current().map(|(&k, _)| k), next);
+        }
+        check_invariants(&tree);
+        assert_eq!(tree.len(), 500 - n - 1);
+    }
+}
+
+#[test]
+fn test_cursor_remove_all() {
+    // keys without Clone
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Key(i32);
+    let mut tree = RBTree::new();
+    for i in 0..500 {
+        tree.insert(Key(i), i);
+    }
+    {
+        let mut cursor = tree.cursor();
+        cursor.seek(&Key(250));
+        for i in 250..500 {
+            assert_eq!(cursor.remove_current(), Some(i));
+        }
+        assert_eq!(cursor.current(), None);
+        assert_eq!(cursor.remove_current(), None);
+        assert_eq!(cursor.prev(), Some((&Key(249), &249)));
+        for i in (0..250).rev() {
+            assert_eq!(cursor.remove_current(), Some(i));
+            cursor.prev();
+        }
+        assert_eq!(cursor.next(), None);
+    }
+    assert_eq!(tree.len(), 0);
+    check_invariants(&tree);
+}
 
-#[allow(dead_code)]
-fn debug_breadth_print<K,V>(tree: &RBTree<K,V>) where K: Ord + Debug {
-    if tree.root.is_none() {
-        println!("Empty tree");
-        return;
+/// Check the tree is ordered, balanced (same number of black nodes
+/// on every path), left-leaning and has no red node with a red
+/// child. Returns the black height.
+fn check_invariants<K,V>(tree: &RBTree<K,V>) -> usize where K: Ord {
+    fn check<K,V>(node: &Option<Box<Node<K,V>>>) -> usize where K: Ord {
+        match node.as_ref() {
+            None => 0,
+            Some(n) => {
+                if let Some(l) = n.left.as_ref() {
+                    assert!(l.key < n.key);
+                }
+                if let Some(r) = n.right.as_ref() {
+                    assert!(r.key > n.key);
+                }
+                assert!(!n.right.is_red());
+                if n.color == Color::Red {
+                    assert!(!n.left.is_red());
+                }
+                assert_eq!(n.size, 1 + n.left.size() + n.right.size());
+                let left = check(&n.left);
+                let right = check(&n.right);
+                assert_eq!(left, right);
+                if n.color == Color::Black { left + 1 } else { left }
+            }
+        }
     }
-    let mut queue = vec![];
-    queue.push(tree.root.as_ref().unwrap());
-    let mut height = 0;
-    let mut height_nodes = 1; // tracks how many nodes we still need to pop in this height
-    let mut next_height_nodes = 0; // accumulator for the number of nodes on the next height
-    while !queue.is_empty() {
-        let n = queue.pop().unwrap();
-        height_nodes -= 1;
-        print!("{:?}:{:?} ", n.key, n.color);
+    assert!(!tree.root.is_red());
+    assert_eq!(tree.iter().count(), tree.len());
+    check(&tree.root)
+}
 
-        if n.left.is_some() {
-            next_height_nodes += 1;
-            queue.insert(0, n.left.as_ref().unwrap());
+#[test]
+fn test_split_off() {
+    let mut tree = RBTree::new();
+    for i in 0..1000 {
+        tree.insert(i,i);
+    }
+    for &at in [0, 1, 333, 500, 998, 999, 1000].iter() {
+        let mut left = RBTree::new();
+        for i in 0..1000 {
+            left.insert(i,i);
+        }
+        let right = left.split_off(&at);
+        check_invariants(&left);
+        check_invariants(&right);
+        assert_eq!(left.len(), at as usize);
+        assert_eq!(right.len(), 1000 - at as usize);
+        for i in 0..at {
+            assert_eq!(left.get(&i), Some(&i));
+            assert_eq!(right.get(&i), None);
         }
-        if n.right.is_some() {
-            next_height_nodes += 1;
-            queue.insert(0, n.right.as_ref().unwrap());
+        for i in at..1000 {
+            assert_eq!(left.get(&i), None);
+            assert_eq!(right.get(&i), Some(&i));
         }
+    }
+}
 
-        if height_nodes == 0 {
-            // finished printing this height
-            height += 1;
-            height_nodes = next_height_nodes;
-            next_height_nodes = 0;
-            println!("");
+#[test]
+fn test_split_heights() {
+    // split() hands back the black heights of both sides without
+    // walking them again
+    let mut rng = thread_rng();
+    let mut keys = (0..500).collect::<Vec<_>>();
+    rng.shuffle(keys.as_mut_slice());
+    for &at in [0, 1, 77, 250, 499, 500].iter() {
+        let mut tree = RBTree::new();
+        for &i in keys.iter() {
+            tree.insert(i,i);
         }
+        let height = check_invariants(&tree);
+        let ((left, left_height), (right, right_height)) = tree.root.take().split(height, &at, &tree.cmp);
+        assert_eq!(left_height, left.black_height());
+        assert_eq!(right_height, right.black_height());
+        assert_eq!(left.size() + right.size(), 500);
     }
-    stdout().flush().unwrap();
-    println!("Tree has height {}", height);
+}
+
+#[test]
+fn test_join() {
+    let mut rng = thread_rng();
+    for &(small, large) in [(0, 10), (10, 0), (1, 1000), (1000, 1), (300, 700), (500, 500)].iter() {
+        let mut left = RBTree::new();
+        let mut right = RBTree::new();
+        let mut shuffled = (0..small+large).collect::<Vec<_>>();
+        rng.shuffle(shuffled.as_mut_slice());
+        for i in shuffled.into_iter() {
+            if i < small {
+                left.insert(i,i);
+            } else {
+                right.insert(i,i);
+            }
+        }
+        check_invariants(&left);
+        check_invariants(&right);
+        left.join(right);
+        check_invariants(&left);
+        assert_eq!(left.len(), (small + large) as usize);
+        for i in 0..small+large {
+            assert_eq!(left.get(&i), Some(&i));
+        }
+    }
+}
+
+#[test]
+fn test_split_join_roundtrip() {
+    let mut tree = RBTree::new();
+    for i in 0..2000 {
+        tree.insert(i,i);
+    }
+    let mut parts = vec![];
+    for &at in [1500, 1000, 250, 10].iter() {
+        parts.push(tree.split_off(&at));
+    }
+    while let Some(part) = parts.pop() {
+        tree.join(part);
+        check_invariants(&tree);
+    }
+    assert_eq!(tree.len(), 2000);
+    for i in 0..2000 {
+        assert_eq!(tree.get(&i), Some(&i));
+    }
+}
+
+#[test]
+fn test_to_dot() {
+    let mut tree = RBTree::new();
+    assert_eq!(tree.to_dot(), "digraph rbtree {\n    node [style=filled, fontcolor=white];\n    n0 [shape=point];\n}\n");
+
+    for i in 1..4 {
+        tree.insert(i,i);
+    }
+    let dot = tree.to_dot();
+    assert!(dot.contains("n0 [label=\"2\", fillcolor=black];"));
+    assert!(dot.contains("n1 [label=\"1\", fillcolor=black];"));
+    assert!(dot.contains("n0 -> n1;"));
+    assert_eq!(dot.matches("[shape=point]").count(), 4);
+
+    let mut tree = RBTree::new();
+    tree.insert("a", 1);
+    assert!(tree.to_dot().contains(r#"n0 [label="\"a\"", fillcolor=black];"#));
 }
 
 #[test]
 #[ignore]
-fn test_debug_breadth_print() {
+fn test_print_dot() {
     let mut tree = RBTree::new();
 
     for i in (1..100).rev() {
         tree.insert(i,i);
     }
-    debug_breadth_print(&tree);
+    println!("{}", tree.to_dot());
 }